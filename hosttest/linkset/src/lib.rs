@@ -0,0 +1,16 @@
+// Stand-in for the linkset crate, on the host sets are always empty.
+// Entries are still checked against the set's type. Tests open filesystems directly instead of mounting them by name.
+
+#[macro_export]
+macro_rules! declare {
+    ($vis:vis $name:ident : $ty:ty) => {
+        $vis static $name: [$ty; 0] = [];
+    };
+}
+
+#[macro_export]
+macro_rules! entry {
+    ($set:path, $ty:ty, $entry:expr) => {
+        const _: $ty = $entry;
+    };
+}
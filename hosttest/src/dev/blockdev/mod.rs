@@ -0,0 +1,218 @@
+// Stand-ins for the block device layer.
+// Disks are byte vectors and requests complete as they're made, so the buffer cache has nothing to hold and passes
+// straight through. Partitions are registered from the real GPT code, in the same table the kernel uses.
+
+use alloc::{collections::BTreeMap, string::String, vec::Vec};
+use spin::Mutex;
+
+#[allow(clippy::manual_is_multiple_of)]
+#[path = "../../../../kernel/src/dev/blockdev/gpt/mod.rs"]
+pub mod gpt;
+
+static DISKS: Mutex<BTreeMap<usize, &'static DiskQueue>> = Mutex::new(BTreeMap::new());
+pub static PARTS: Mutex<BTreeMap<usize, &'static mut Partition>> = Mutex::new(BTreeMap::new());
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DiskError {
+    InvalidBlock,
+    PartitionRangeError,
+    NoDisk,
+    /// The disk reported a failure
+    IoError,
+    /// The disk doesn't support the request
+    Unsupported,
+    ReadOnly,
+    Timeout,
+}
+
+pub struct DiskQueue {
+    blocksize: usize,
+    data: Mutex<Vec<u8>>,
+}
+
+impl DiskQueue {
+    pub fn blocksize(&self) -> usize {
+        self.blocksize
+    }
+
+    pub fn blocks(&self) -> usize {
+        self.data.lock().len() / self.blocksize
+    }
+
+    /// The bytes `len` bytes from `lba` cover, they must be within the disk
+    fn range(&self, len: usize, lba: usize) -> Result<core::ops::Range<usize>, DiskError> {
+        let start = lba.checked_mul(self.blocksize).ok_or(DiskError::InvalidBlock)?;
+
+        match start.checked_add(len) {
+            Some(end) if end <= self.data.lock().len() => Ok(start..end),
+            _ => Err(DiskError::InvalidBlock),
+        }
+    }
+
+    /// Read `buffer.len()` bytes starting at `lba`, the last block may be partial
+    pub fn read(&'static self, buffer: &mut [u8], lba: usize) -> Result<(), DiskError> {
+        let range = self.range(buffer.len(), lba)?;
+
+        buffer.copy_from_slice(&self.data.lock()[range]);
+
+        Ok(())
+    }
+
+    /// Write `data` starting at `lba`, a partial last block keeps whatever was on disk after the data
+    pub fn write(&'static self, data: &[u8], lba: usize) -> Result<(), DiskError> {
+        let range = self.range(data.len(), lba)?;
+
+        self.data.lock()[range].copy_from_slice(data);
+
+        Ok(())
+    }
+}
+
+/// Add a disk holding `image`, which must be whole blocks, returns its disk ID
+pub fn add_disk(image: Vec<u8>, blocksize: usize) -> usize {
+    assert_eq!(image.len() % blocksize, 0);
+
+    let queue = alloc::boxed::Box::leak(alloc::boxed::Box::new(DiskQueue {
+        blocksize,
+        data: Mutex::new(image),
+    }));
+
+    let mut disks = DISKS.lock();
+    let id = disks.keys().next_back().map_or(0, |id| id + 1);
+    disks.insert(id, queue);
+
+    id
+}
+
+/// The current contents of disk `id`
+pub fn image(id: usize) -> Vec<u8> {
+    disk(id).unwrap().data.lock().clone()
+}
+
+/// The request queue of a disk
+pub fn disk(id: usize) -> Option<&'static DiskQueue> {
+    DISKS.lock().get(&id).copied()
+}
+
+/// Register the partitions of disk `id` from its GPT, returns the IDs of the partitions
+pub fn scan_partitions(id: usize) -> Result<Vec<usize>, DiskError> {
+    gpt::scan(id)
+}
+
+/// Forget the partitions of disk `id` and read its partition table again, returns the IDs of the partitions
+pub fn rescan_partitions(id: usize) -> Result<Vec<usize>, DiskError> {
+    PARTS.lock().retain(|_, part| part.disk_id != id);
+
+    scan_partitions(id)
+}
+
+/// Add a partition, returns its partition ID
+pub fn register_partition(partition: Partition) -> usize {
+    let mut parts = PARTS.lock();
+    let id = parts.keys().next_back().map_or(0, |id| id + 1);
+
+    parts.insert(id, alloc::boxed::Box::leak(alloc::boxed::Box::new(partition)));
+
+    id
+}
+
+/// The partitions of disk `id`, in the order they were registered
+pub fn partitions(id: usize) -> Vec<usize> {
+    PARTS.lock().iter().filter(|(_, part)| part.disk_id == id).map(|(id, _)| *id).collect()
+}
+
+pub struct Partition {
+    disk_id: usize,
+    /// First block on the disk
+    start: usize,
+    blocksize: usize,
+    blocks: usize,
+    type_guid: gpt::Guid,
+    name: String,
+}
+
+impl Partition {
+    pub fn new(disk_id: usize, block_base: usize, blocks: usize, blocksize: usize) -> Self {
+        Self {
+            disk_id,
+            start: block_base,
+            blocksize,
+            blocks,
+            type_guid: gpt::GPTPartType::UNUSED,
+            name: String::new(),
+        }
+    }
+
+    /// Set the type and name given by the partition table
+    pub fn with_info(mut self, type_guid: gpt::Guid, name: String) -> Self {
+        self.type_guid = type_guid;
+        self.name = name;
+        self
+    }
+
+    pub fn diskid(&self) -> usize {
+        self.disk_id
+    }
+
+    pub fn start(&self) -> usize {
+        self.start
+    }
+
+    pub fn type_guid(&self) -> gpt::Guid {
+        self.type_guid
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn blocksize(&self) -> usize {
+        self.blocksize
+    }
+
+    pub fn blocks(&self) -> usize {
+        self.blocks
+    }
+}
+
+/// The buffer cache, requests go straight to the disk
+pub mod cache {
+    use super::DiskError;
+
+    pub fn read(disk: usize, buffer: &mut [u8], lba: usize) -> Result<(), DiskError> {
+        super::disk(disk).ok_or(DiskError::NoDisk)?.read(buffer, lba)
+    }
+
+    pub fn write(disk: usize, data: &[u8], lba: usize) -> Result<(), DiskError> {
+        super::disk(disk).ok_or(DiskError::NoDisk)?.write(data, lba)
+    }
+
+    pub fn sync(_disk: Option<usize>) -> Result<(), DiskError> {
+        Ok(())
+    }
+
+    pub fn invalidate(_disk: usize, _lba: usize, _blocks: usize) {}
+
+    /// Block `lba` of `disk`, used straight from the disk
+    pub fn get(disk: usize, lba: usize) -> Result<Buffer, DiskError> {
+        let queue = super::disk(disk).ok_or(DiskError::NoDisk)?;
+        let range = queue.range(queue.blocksize, lba)?;
+
+        Ok(Buffer { queue, range })
+    }
+
+    pub struct Buffer {
+        queue: &'static super::DiskQueue,
+        range: core::ops::Range<usize>,
+    }
+
+    impl Buffer {
+        pub fn read<R>(&self, f: impl FnOnce(&[u8]) -> R) -> R {
+            f(&self.queue.data.lock()[self.range.clone()])
+        }
+
+        pub fn write<R>(&self, f: impl FnOnce(&mut [u8]) -> R) -> R {
+            f(&mut self.queue.data.lock()[self.range.clone()])
+        }
+    }
+}
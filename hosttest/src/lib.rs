@@ -0,0 +1,50 @@
+// The kernel's filesystem and partition table code, built for the host.
+// Modules are compiled from the kernel's own source files. What they use from the rest of the kernel (disks, the
+// buffer cache, the clock, the console) is replaced by the stand-ins here, with disks being images in memory.
+
+extern crate alloc;
+
+pub mod dev {
+    pub mod blockdev;
+}
+
+// The kernel's lints are checked by its own build, on its own toolchain
+#[allow(clippy::manual_is_multiple_of, clippy::type_complexity)]
+#[path = "../../kernel/src/fs/mod.rs"]
+pub mod fs;
+
+pub mod time;
+
+#[macro_export]
+macro_rules! println {
+    ($($arg:tt)*) => {
+        std::println!($($arg)*)
+    };
+}
+
+/// There are never any boot modules, so there's no initramfs
+pub static MODULES: Modules = Modules;
+
+pub struct Modules;
+
+pub struct ModuleResponse;
+
+pub struct Module;
+
+impl Modules {
+    pub fn response(&self) -> Option<&'static ModuleResponse> {
+        None
+    }
+}
+
+impl ModuleResponse {
+    pub fn modules(&self) -> &[Module] {
+        &[]
+    }
+}
+
+impl Module {
+    pub fn data(&self) -> &[u8] {
+        &[]
+    }
+}
@@ -0,0 +1,15 @@
+// Monotonic time from the host clock, and the kernel's wall clock on top of it.
+
+use std::sync::OnceLock;
+use std::time::Instant;
+
+#[path = "../../../kernel/src/time/wall.rs"]
+pub mod wall;
+
+pub const NS_PER_SEC: u128 = 1_000_000_000;
+
+pub fn monotonic_ns() -> u128 {
+    static START: OnceLock<Instant> = OnceLock::new();
+
+    START.get_or_init(Instant::now).elapsed().as_nanos()
+}
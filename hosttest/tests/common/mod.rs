@@ -0,0 +1,96 @@
+// Helpers shared by the tests: temporary files, the host tools that make and check images, and disks.
+// Not every test uses every helper.
+
+#![allow(dead_code)]
+
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use hosttest::dev::blockdev::{self, Partition};
+
+/// The path of the tool `name` if it's installed. The sbin directories aren't always in `PATH`, but the mkfs and
+/// fsck tools live there
+pub fn tool(name: &str) -> Option<PathBuf> {
+    let path = std::env::var_os("PATH").unwrap_or_default();
+
+    std::env::split_paths(&path)
+        .chain(["/usr/local/sbin", "/usr/sbin", "/sbin"].map(PathBuf::from))
+        .map(|dir| dir.join(name))
+        .find(|path| path.is_file())
+}
+
+/// A file in the temporary directory, removed when dropped
+pub struct TempFile(PathBuf);
+
+impl TempFile {
+    pub fn new(tag: &str) -> Self {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+
+        let name = format!("gent-hosttest-{}-{}-{}", std::process::id(), NEXT.fetch_add(1, Ordering::Relaxed), tag);
+
+        Self(std::env::temp_dir().join(name))
+    }
+
+    /// A zeroed file of `len` bytes
+    pub fn zeroed(tag: &str, len: usize) -> Self {
+        let file = Self::new(tag);
+        std::fs::write(&file.0, vec![0; len]).unwrap();
+        file
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    pub fn read(&self) -> Vec<u8> {
+        std::fs::read(&self.0).unwrap()
+    }
+
+    pub fn write(&self, data: &[u8]) {
+        std::fs::write(&self.0, data).unwrap();
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// Run a command, returns its exit code and everything it printed
+pub fn run(command: &mut Command) -> (i32, String) {
+    let output = command.output().unwrap();
+    let mut text = String::from_utf8_lossy(&output.stdout).into_owned();
+    text.push_str(&String::from_utf8_lossy(&output.stderr));
+
+    (output.status.code().unwrap_or(-1), text)
+}
+
+/// Run a command that has to succeed, returns everything it printed
+pub fn run_ok(command: &mut Command) -> String {
+    let (code, text) = run(command);
+    assert_eq!(code, 0, "{:?} failed:\n{}", command, text);
+
+    text
+}
+
+/// Blocks before the partition on disks from `disk_with_partition`, so partition offsets get translated
+pub const PART_OFFSET: usize = 34;
+
+/// Add a disk holding `image` in a partition that starts `PART_OFFSET` blocks in, returns the disk and partition IDs
+pub fn disk_with_partition(image: &[u8], blocksize: usize) -> (usize, usize) {
+    let blocks = image.len().div_ceil(blocksize);
+    let mut disk = vec![0; (PART_OFFSET + blocks + 1) * blocksize];
+    disk[PART_OFFSET * blocksize..][..image.len()].copy_from_slice(image);
+
+    let id = blockdev::add_disk(disk, blocksize);
+    let part = blockdev::register_partition(Partition::new(id, PART_OFFSET, blocks, blocksize));
+
+    (id, part)
+}
+
+/// The partition's contents, as left by the code under test
+pub fn partition_image(disk: usize, blocksize: usize, len: usize) -> Vec<u8> {
+    blockdev::image(disk)[PART_OFFSET * blocksize..][..len].to_vec()
+}
@@ -0,0 +1,370 @@
+// ext2 volumes made by mke2fs.
+// debugfs puts files on the volumes for the kernel to read, and reads back what the kernel wrote, along with inode
+// link counts and directory entries. Every volume the kernel wrote to has to pass `e2fsck -fn`. The tests are
+// skipped when mke2fs isn't installed.
+
+mod common;
+
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::Arc;
+
+use common::TempFile;
+use hosttest::fs::{ext2::Ext2Fs, Filesystem, FsError, Vnode, VnodeKind};
+
+const SECTOR: usize = 512;
+const BLOCK: usize = 1024;
+const SIZE: usize = 16 << 20;
+
+/// Blocks mapped by the direct pointers, a single indirect block, and a double indirect block
+const DIRECT: usize = 12;
+const SINGLE: usize = BLOCK / 4;
+const DOUBLE: usize = SINGLE * SINGLE;
+
+struct Tools {
+    debugfs: PathBuf,
+    e2fsck: PathBuf,
+}
+
+/// A new volume with 1KiB blocks, so indirect blocks come quickly
+fn mkfs() -> Option<(TempFile, Tools)> {
+    let tools = (common::tool("mke2fs"), common::tool("debugfs"), common::tool("e2fsck"));
+
+    let (Some(mke2fs), Some(debugfs), Some(e2fsck)) = tools else {
+        println!("mke2fs, debugfs or e2fsck isn't installed, skipping");
+        return None;
+    };
+
+    let file = TempFile::zeroed("ext2.img", SIZE);
+
+    common::run_ok(Command::new(mke2fs)
+        .args(["-q", "-F", "-t", "ext2", "-b", &BLOCK.to_string(), "-L", "hosttest"])
+        .arg(file.path()));
+
+    Some((file, Tools { debugfs, e2fsck }))
+}
+
+impl Tools {
+    /// Run debugfs requests against the volume, writable if `write` is set
+    fn debugfs(&self, file: &TempFile, write: bool, requests: &[&str]) -> String {
+        let script = TempFile::new("debugfs");
+        script.write(requests.join("\n").as_bytes());
+
+        let mut command = Command::new(&self.debugfs);
+
+        if write {
+            command.arg("-w");
+        }
+
+        common::run_ok(command.arg("-f").arg(script.path()).arg(file.path()))
+    }
+
+    fn e2fsck(&self, file: &TempFile) {
+        let (code, output) = common::run(Command::new(&self.e2fsck).arg("-fn").arg(file.path()));
+        assert_eq!(code, 0, "e2fsck -fn:\n{}", output);
+    }
+
+    /// The contents of the file at `path`
+    fn cat(&self, file: &TempFile, path: &str) -> Vec<u8> {
+        let out = TempFile::new("dump");
+        self.debugfs(file, false, &[&format!("dump {} {}", path, out.path().display())]);
+        out.read()
+    }
+
+    /// The value after `label` in the output of a request, such as `Links:` from `stat`
+    fn field(&self, file: &TempFile, request: &str, label: &str) -> u64 {
+        let output = self.debugfs(file, false, &[request]);
+        let start = output.find(label).unwrap_or_else(|| panic!("no {} in:\n{}", label, output)) + label.len();
+
+        output[start..].split_whitespace().next().unwrap().parse().unwrap()
+    }
+
+    fn links(&self, file: &TempFile, path: &str) -> u64 {
+        self.field(file, &format!("stat {}", path), "Links:")
+    }
+
+    fn free_blocks(&self, file: &TempFile) -> u64 {
+        self.field(file, "stats", "Free blocks:")
+    }
+
+    fn free_inodes(&self, file: &TempFile) -> u64 {
+        self.field(file, "stats", "Free inodes:")
+    }
+
+    /// The entries of the directory at `path`, `.` and `..` included, as inode numbers and names
+    fn ls(&self, file: &TempFile, path: &str) -> Vec<(u64, String)> {
+        self.debugfs(file, false, &[&format!("ls -p {}", path)])
+            .lines()
+            .filter(|line| line.starts_with('/'))
+            .map(|line| {
+                let fields: Vec<&str> = line.split('/').collect();
+                (fields[1].parse().unwrap(), String::from(fields[5]))
+            })
+            .collect()
+    }
+
+    fn ino(&self, file: &TempFile, dir: &str, name: &str) -> u64 {
+        self.ls(file, dir).into_iter().find(|(_, entry)| entry == name).unwrap().0
+    }
+}
+
+/// Put the volume on a disk and open it with the kernel
+fn open(file: &TempFile) -> (usize, Arc<Ext2Fs>) {
+    let (disk, part) = common::disk_with_partition(&file.read(), SECTOR);
+
+    (disk, Ext2Fs::open(part).unwrap())
+}
+
+/// Write everything back and copy the volume out of the disk, for the tools
+fn save(disk: usize, fs: &Ext2Fs, file: &TempFile) {
+    fs.sync().unwrap();
+    file.write(&common::partition_image(disk, SECTOR, SIZE));
+}
+
+/// Some bytes that differ from block to block
+fn pattern(len: usize, seed: u8) -> Vec<u8> {
+    (0..len).map(|i| (i * 7 + i / 1021) as u8 ^ seed).collect()
+}
+
+fn names(dir: &Arc<dyn Vnode>) -> Vec<String> {
+    let mut names = Vec::new();
+
+    while let Some(entry) = dir.readdir(names.len()).unwrap() {
+        names.push(entry.name);
+    }
+
+    names.sort();
+    names
+}
+
+fn read_all(node: &Arc<dyn Vnode>) -> Vec<u8> {
+    let mut data = vec![0; node.metadata().unwrap().size as usize];
+    assert_eq!(node.read(0, &mut data).unwrap(), data.len());
+    data
+}
+
+fn links(node: &Arc<dyn Vnode>) -> u32 {
+    node.metadata().unwrap().links
+}
+
+#[test]
+fn reads_debugfs_files() {
+    let Some((file, tools)) = mkfs() else {
+        return;
+    };
+
+    let small = TempFile::new("small");
+    small.write(b"hello from debugfs\n");
+
+    // Past the single indirect block, into the double indirect one
+    let big = TempFile::new("big");
+    big.write(&pattern((DIRECT + SINGLE + 100) * BLOCK + 123, 1));
+
+    tools.debugfs(&file, true, &[
+        &format!("write {} small.txt", small.path().display()),
+        &format!("write {} big.bin", big.path().display()),
+        "mkdir dir",
+        "mkdir dir/sub",
+        &format!("write {} dir/inner", small.path().display()),
+    ]);
+
+    let (_, fs) = open(&file);
+    let root = fs.root();
+
+    assert_eq!(names(&root), ["big.bin", "dir", "lost+found", "small.txt"]);
+    assert_eq!(read_all(&root.lookup("small.txt").unwrap()), b"hello from debugfs\n");
+    assert_eq!(read_all(&root.lookup("big.bin").unwrap()), big.read());
+
+    // Reads that start and end in the middle of blocks, across the indirect boundaries
+    let node = root.lookup("big.bin").unwrap();
+    let mut buf = vec![0; 3 * BLOCK];
+    let offset = (DIRECT * BLOCK - 100) as u64;
+    node.read(offset, &mut buf).unwrap();
+    assert_eq!(buf, big.read()[offset as usize..][..3 * BLOCK]);
+
+    let dir = root.lookup("dir").unwrap();
+    assert_eq!(names(&dir), ["inner", "sub"]);
+    assert_eq!(read_all(&dir.lookup("inner").unwrap()), b"hello from debugfs\n");
+
+    // `.`, `..` from lost+found and `..` from dir
+    assert_eq!(links(&root), 4);
+    assert_eq!(links(&dir), 3);
+    assert_eq!(dir.lookup("..").unwrap().metadata().unwrap().ino, root.metadata().unwrap().ino);
+}
+
+#[test]
+fn indirect_blocks() {
+    let Some((file, tools)) = mkfs() else {
+        return;
+    };
+
+    let free = tools.free_blocks(&file);
+    let (disk, fs) = open(&file);
+    let root = fs.root();
+
+    // Written in uneven pieces, through the direct, single and double indirect blocks
+    let big = pattern((DIRECT + SINGLE + 300) * BLOCK + 77, 2);
+    let node = root.create("big", VnodeKind::File).unwrap();
+
+    for (i, chunk) in big.chunks(3 * BLOCK + 5).enumerate() {
+        node.write((i * (3 * BLOCK + 5)) as u64, chunk).unwrap();
+    }
+
+    // A block at the start of each level, leaving holes, the last needs a triple indirect block
+    let offsets = [0, DIRECT, DIRECT + SINGLE, DIRECT + SINGLE + DOUBLE].map(|block| block * BLOCK);
+    let sparse = root.create("sparse", VnodeKind::File).unwrap();
+
+    for (i, offset) in offsets.iter().enumerate() {
+        sparse.write(*offset as u64 + 10, &pattern(BLOCK, i as u8)).unwrap();
+    }
+
+    let mut expected = vec![0; offsets[3] + 10 + BLOCK];
+
+    for (i, offset) in offsets.iter().enumerate() {
+        expected[offset + 10..][..BLOCK].copy_from_slice(&pattern(BLOCK, i as u8));
+    }
+
+    assert_eq!(read_all(&node), big);
+    assert_eq!(read_all(&sparse), expected);
+
+    save(disk, &fs, &file);
+    tools.e2fsck(&file);
+
+    assert_eq!(tools.cat(&file, "big"), big);
+    assert_eq!(tools.cat(&file, "sparse"), expected);
+
+    // Shrinking has to free indirect blocks as well as data blocks
+    node.truncate(5000).unwrap();
+    assert_eq!(read_all(&node), big[..5000]);
+
+    sparse.truncate((DIRECT + 1) as u64 * BLOCK as u64).unwrap();
+    assert_eq!(read_all(&sparse), expected[..(DIRECT + 1) * BLOCK]);
+
+    save(disk, &fs, &file);
+    tools.e2fsck(&file);
+    assert_eq!(tools.cat(&file, "big"), big[..5000]);
+
+    // Growing again reads zeroes where the old data was
+    node.truncate(20000).unwrap();
+    let mut grown = big[..5000].to_vec();
+    grown.resize(20000, 0);
+    assert_eq!(read_all(&node), grown);
+
+    root.unlink("big").unwrap();
+    root.unlink("sparse").unwrap();
+
+    save(disk, &fs, &file);
+    tools.e2fsck(&file);
+    assert_eq!(tools.free_blocks(&file), free);
+}
+
+#[test]
+fn link_counts() {
+    let Some((file, tools)) = mkfs() else {
+        return;
+    };
+
+    let free_inodes = tools.free_inodes(&file);
+    let (disk, fs) = open(&file);
+    let root = fs.root();
+
+    let f = root.create("f", VnodeKind::File).unwrap();
+    f.write(0, b"linked").unwrap();
+
+    let d = root.create("d", VnodeKind::Directory).unwrap();
+    assert_eq!(links(&root), 4);
+    assert_eq!(links(&d), 2);
+
+    d.link("g", &f).unwrap();
+    root.link("h", &f).unwrap();
+    assert_eq!(links(&f), 3);
+    assert_eq!(d.link("g", &f), Err(FsError::Exists));
+    assert_eq!(root.link("dir link", &d), Err(FsError::IsDirectory));
+
+    save(disk, &fs, &file);
+    tools.e2fsck(&file);
+    assert_eq!(tools.links(&file, "/f"), 3);
+    assert_eq!(tools.ino(&file, "/d", "g"), tools.ino(&file, "/", "f"));
+    assert_eq!(tools.links(&file, "/"), 4);
+    assert_eq!(tools.links(&file, "/d"), 2);
+
+    root.unlink("f").unwrap();
+    root.unlink("h").unwrap();
+    assert_eq!(links(&f), 1);
+    assert_eq!(read_all(&d.lookup("g").unwrap()), b"linked");
+
+    save(disk, &fs, &file);
+    tools.e2fsck(&file);
+    assert_eq!(tools.links(&file, "/d/g"), 1);
+    assert_eq!(tools.cat(&file, "/d/g"), b"linked");
+
+    assert_eq!(root.unlink("d"), Err(FsError::NotEmpty));
+    d.unlink("g").unwrap();
+    root.unlink("d").unwrap();
+    assert_eq!(links(&root), 3);
+
+    save(disk, &fs, &file);
+    tools.e2fsck(&file);
+    assert_eq!(tools.links(&file, "/"), 3);
+    assert_eq!(tools.free_inodes(&file), free_inodes);
+}
+
+#[test]
+fn rename_directories() {
+    let Some((file, tools)) = mkfs() else {
+        return;
+    };
+
+    let (disk, fs) = open(&file);
+    let root = fs.root();
+
+    let a = root.create("a", VnodeKind::Directory).unwrap();
+    let b = root.create("b", VnodeKind::Directory).unwrap();
+    let sub = a.create("sub", VnodeKind::Directory).unwrap();
+    sub.create("file", VnodeKind::File).unwrap().write(0, b"moved along").unwrap();
+    let deeper = sub.create("deeper", VnodeKind::Directory).unwrap();
+    a.create("empty", VnodeKind::Directory).unwrap();
+
+    assert_eq!((links(&a), links(&b), links(&sub)), (4, 2, 3));
+
+    // Into itself, or anywhere beneath itself
+    assert_eq!(a.rename("sub", &sub, "x"), Err(FsError::InvalidArgument));
+    assert_eq!(a.rename("sub", &deeper, "x"), Err(FsError::InvalidArgument));
+    assert_eq!(root.rename("a", &deeper, "x"), Err(FsError::InvalidArgument));
+
+    a.rename("sub", &b, "moved").unwrap();
+    assert_eq!((links(&a), links(&b), links(&sub)), (3, 3, 3));
+    assert_eq!(sub.lookup("..").unwrap().metadata().unwrap().ino, b.metadata().unwrap().ino);
+    assert_eq!(names(&a), ["empty"]);
+    assert_eq!(names(&b), ["moved"]);
+
+    save(disk, &fs, &file);
+    tools.e2fsck(&file);
+
+    let b_ino = tools.ino(&file, "/", "b");
+    assert_eq!(tools.ino(&file, "/b/moved", ".."), b_ino);
+    assert_eq!((tools.links(&file, "/a"), tools.links(&file, "/b"), tools.links(&file, "/b/moved")), (3, 3, 3));
+    assert_eq!(tools.cat(&file, "/b/moved/file"), b"moved along");
+
+    // A directory replaces an empty one, and the parents end up with the same counts as before
+    b.rename("moved", &a, "empty").unwrap();
+    assert_eq!((links(&a), links(&b), links(&sub)), (3, 2, 3));
+
+    // Renaming within a directory leaves the counts alone
+    a.rename("empty", &a, "renamed").unwrap();
+    assert_eq!(links(&a), 3);
+
+    let other = b.create("other", VnodeKind::Directory).unwrap();
+    other.create("occupant", VnodeKind::File).unwrap();
+    assert_eq!(a.rename("renamed", &b, "other"), Err(FsError::NotEmpty));
+    assert_eq!(a.rename("renamed", &other, "occupant"), Err(FsError::NotDirectory));
+
+    save(disk, &fs, &file);
+    tools.e2fsck(&file);
+
+    let a_ino = tools.ino(&file, "/", "a");
+    assert_eq!(tools.ino(&file, "/a/renamed", ".."), a_ino);
+    assert_eq!(tools.ino(&file, "/a/renamed/deeper", ".."), tools.ino(&file, "/a", "renamed"));
+    assert_eq!((tools.links(&file, "/a"), tools.links(&file, "/b")), (3, 3));
+    assert_eq!(tools.cat(&file, "/a/renamed/file"), b"moved along");
+}
@@ -0,0 +1,483 @@
+// FAT12, FAT16 and FAT32 volumes made by mkfs.fat.
+// Files are put on the volumes by the fatfs crate and read by the kernel, and what the kernel writes is read back by
+// fatfs. Without mkfs.fat, fatfs formats the volumes. The tests that also check the kernel's writes with fsck.fat are
+// ignored, run them with `--ignored` where it's installed.
+
+mod common;
+
+use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+use std::process::Command;
+use std::sync::Arc;
+
+use common::TempFile;
+use hosttest::fs::{fat::FatFs, Filesystem, FsError, Vnode, VnodeKind};
+
+const SECTOR: usize = 512;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Kind {
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+impl Kind {
+    fn bits(self) -> u32 {
+        match self {
+            Kind::Fat12 => 12,
+            Kind::Fat16 => 16,
+            Kind::Fat32 => 32,
+        }
+    }
+
+    /// Big enough for the FAT type with one sector clusters, FAT32 needs at least 65525 of them
+    fn size(self) -> usize {
+        match self {
+            Kind::Fat12 => 1 << 20,
+            Kind::Fat16 => 8 << 20,
+            Kind::Fat32 => 40 << 20,
+        }
+    }
+}
+
+/// An empty volume of the given type, with one sector clusters so files span plenty of them
+fn format(kind: Kind) -> Vec<u8> {
+    if let Some(mkfs) = common::tool("mkfs.fat") {
+        let file = TempFile::zeroed("fat.img", kind.size());
+
+        common::run_ok(Command::new(mkfs)
+            .args(["-F", &kind.bits().to_string(), "-s", "1", "-S", &SECTOR.to_string(), "-n", "HOSTTEST"])
+            .arg(file.path()));
+
+        return file.read();
+    }
+
+    let fat_type = match kind {
+        Kind::Fat12 => fatfs::FatType::Fat12,
+        Kind::Fat16 => fatfs::FatType::Fat16,
+        Kind::Fat32 => fatfs::FatType::Fat32,
+    };
+
+    let mut image = vec![0; kind.size()];
+    let options = fatfs::FormatVolumeOptions::new().fat_type(fat_type).bytes_per_cluster(SECTOR as u32);
+    fatfs::format_volume(Cursor::new(&mut image), options).unwrap();
+
+    image
+}
+
+/// Run fsck.fat over the volume without changing it
+fn fsck(image: &[u8]) {
+    let fsck = common::tool("fsck.fat").expect("fsck.fat isn't installed");
+
+    let file = TempFile::new("fat.img");
+    file.write(image);
+
+    let (code, output) = common::run(Command::new(fsck).arg("-n").arg(file.path()));
+    assert_eq!(code, 0, "fsck.fat -n:\n{}", output);
+}
+
+/// Edit the volume with fatfs
+fn with_fatfs<R>(image: &mut Vec<u8>, f: impl FnOnce(&fatfs::FileSystem<Cursor<&mut Vec<u8>>>) -> R) -> R {
+    let fs = fatfs::FileSystem::new(Cursor::new(image), fatfs::FsOptions::new()).unwrap();
+    let result = f(&fs);
+    fs.unmount().unwrap();
+
+    result
+}
+
+/// Some bytes that differ from block to block
+fn pattern(len: usize, seed: u8) -> Vec<u8> {
+    (0..len).map(|i| (i * 7 + i / 509) as u8 ^ seed).collect()
+}
+
+fn fatfs_read(dir: &fatfs::Dir<Cursor<&mut Vec<u8>>>, path: &str) -> Vec<u8> {
+    let mut data = Vec::new();
+    dir.open_file(path).unwrap().read_to_end(&mut data).unwrap();
+    data
+}
+
+fn fatfs_names(dir: &fatfs::Dir<Cursor<&mut Vec<u8>>>) -> Vec<String> {
+    let mut names: Vec<String> = dir.iter()
+        .map(|entry| entry.unwrap().file_name())
+        .filter(|name| name != "." && name != "..")
+        .collect();
+
+    names.sort();
+    names
+}
+
+/// Put the volume on a disk and open it with the kernel
+fn open(image: &[u8]) -> (usize, Arc<FatFs>) {
+    let (disk, part) = common::disk_with_partition(image, SECTOR);
+
+    (disk, FatFs::open(part).unwrap())
+}
+
+fn names(dir: &Arc<dyn Vnode>) -> Vec<String> {
+    let mut names = Vec::new();
+
+    while let Some(entry) = dir.readdir(names.len()).unwrap() {
+        names.push(entry.name);
+    }
+
+    names.sort();
+    names
+}
+
+fn read_all(node: &Arc<dyn Vnode>) -> Vec<u8> {
+    let mut data = vec![0; node.metadata().unwrap().size as usize];
+    assert_eq!(node.read(0, &mut data).unwrap(), data.len());
+    data
+}
+
+const LONG_FILE: &str = "A long file name, with spaces and ünïcode.txt";
+const LONG_DIR: &str = "Some Directory";
+const NESTED: &str = "nested file with a long name.dat";
+
+/// A volume holding files with long and short names, written by fatfs
+fn populated(kind: Kind) -> Vec<u8> {
+    let mut image = format(kind);
+
+    with_fatfs(&mut image, |fs| {
+        let root = fs.root_dir();
+        root.create_file(LONG_FILE).unwrap().write_all(&pattern(3000, 1)).unwrap();
+        root.create_file("SHORT.TXT").unwrap().write_all(b"short").unwrap();
+        root.create_file("lower.txt").unwrap().write_all(b"lower").unwrap();
+
+        let dir = root.create_dir(LONG_DIR).unwrap();
+        dir.create_file(NESTED).unwrap().write_all(&pattern(700, 2)).unwrap();
+    });
+
+    image
+}
+
+fn reads_long_names(kind: Kind) {
+    let (_, fs) = open(&populated(kind));
+    let root = fs.root();
+
+    assert_eq!(names(&root), [LONG_FILE, "SHORT.TXT", LONG_DIR, "lower.txt"]);
+
+    assert_eq!(read_all(&root.lookup(LONG_FILE).unwrap()), pattern(3000, 1));
+    assert_eq!(read_all(&root.lookup("SHORT.TXT").unwrap()), b"short");
+    assert_eq!(read_all(&root.lookup("lower.txt").unwrap()), b"lower");
+
+    // Names match without regard to case, like everywhere else FAT is used
+    assert_eq!(read_all(&root.lookup("a long FILE name, with spaces and ünïcode.TXT").unwrap()), pattern(3000, 1));
+    assert_eq!(read_all(&root.lookup("short.txt").unwrap()), b"short");
+
+    let dir = root.lookup(LONG_DIR).unwrap();
+    assert_eq!(dir.metadata().unwrap().kind, VnodeKind::Directory);
+    assert_eq!(names(&dir), [NESTED]);
+    assert_eq!(read_all(&dir.lookup(NESTED).unwrap()), pattern(700, 2));
+
+    assert_eq!(root.lookup("missing").err(), Some(FsError::NotFound));
+}
+
+/// Returns the volume as the kernel left it
+fn creates_and_unlinks(kind: Kind) -> Vec<u8> {
+    let (disk, fs) = open(&populated(kind));
+    let root = fs.root();
+
+    let file = root.create("Created by the kernel with a long name.bin", VnodeKind::File).unwrap();
+    assert_eq!(file.write(0, &pattern(5000, 3)).unwrap(), 5000);
+
+    // Lower case short names keep their case
+    root.create("lower2.txt", VnodeKind::File).unwrap().write(0, b"lower too").unwrap();
+
+    let dir = root.create("A New Directory", VnodeKind::Directory).unwrap();
+    dir.create("inside the new directory.bin", VnodeKind::File).unwrap().write(0, &pattern(1500, 4)).unwrap();
+
+    // Enough entries to grow the directory past its first cluster
+    for i in 0..40 {
+        dir.create(&format!("entry number {} of many", i), VnodeKind::File).unwrap();
+    }
+
+    assert_eq!(root.create("short.TXT", VnodeKind::File).err(), Some(FsError::Exists));
+
+    root.unlink(LONG_FILE).unwrap();
+    root.lookup(LONG_DIR).unwrap().unlink(NESTED).unwrap();
+    root.unlink(LONG_DIR).unwrap();
+    dir.unlink("entry number 7 of many").unwrap();
+
+    assert_eq!(root.lookup(LONG_FILE).err(), Some(FsError::NotFound));
+    assert_eq!(root.unlink("A New Directory"), Err(FsError::NotEmpty));
+
+    fs.sync().unwrap();
+
+    let written = common::partition_image(disk, SECTOR, kind.size());
+    let mut image = written.clone();
+
+    with_fatfs(&mut image, |fs| {
+        let root = fs.root_dir();
+
+        assert_eq!(fatfs_names(&root), [
+            "A New Directory",
+            "Created by the kernel with a long name.bin",
+            "SHORT.TXT",
+            "lower.txt",
+            "lower2.txt",
+        ]);
+
+        assert_eq!(fatfs_read(&root, "Created by the kernel with a long name.bin"), pattern(5000, 3));
+        assert_eq!(fatfs_read(&root, "lower2.txt"), b"lower too");
+        assert_eq!(fatfs_read(&root, "A New Directory/inside the new directory.bin"), pattern(1500, 4));
+
+        let dir = root.open_dir("A New Directory").unwrap();
+        let names = fatfs_names(&dir);
+        assert_eq!(names.len(), 40);
+        assert!(names.contains(&String::from("entry number 39 of many")));
+        assert!(!names.contains(&String::from("entry number 7 of many")));
+
+        // Every cluster that was freed can be used again
+        let stats = fs.stats().unwrap();
+        let used = stats.total_clusters() - stats.free_clusters();
+        assert!(used < 30, "{} clusters still in use", used);
+    });
+
+    written
+}
+
+#[test]
+fn fat12_reads_long_names() {
+    reads_long_names(Kind::Fat12);
+}
+
+#[test]
+fn fat16_reads_long_names() {
+    reads_long_names(Kind::Fat16);
+}
+
+#[test]
+fn fat32_reads_long_names() {
+    reads_long_names(Kind::Fat32);
+}
+
+#[test]
+fn fat12_creates_and_unlinks() {
+    creates_and_unlinks(Kind::Fat12);
+}
+
+#[test]
+fn fat16_creates_and_unlinks() {
+    creates_and_unlinks(Kind::Fat16);
+}
+
+#[test]
+fn fat32_creates_and_unlinks() {
+    creates_and_unlinks(Kind::Fat32);
+}
+
+#[test]
+#[ignore = "needs fsck.fat"]
+fn fat12_fsck_after_creates_and_unlinks() {
+    fsck(&creates_and_unlinks(Kind::Fat12));
+}
+
+#[test]
+#[ignore = "needs fsck.fat"]
+fn fat16_fsck_after_creates_and_unlinks() {
+    fsck(&creates_and_unlinks(Kind::Fat16));
+}
+
+#[test]
+#[ignore = "needs fsck.fat"]
+fn fat32_fsck_after_creates_and_unlinks() {
+    fsck(&creates_and_unlinks(Kind::Fat32));
+}
+
+/// Returns the volume as the kernel left it
+fn renames(kind: Kind) -> Vec<u8> {
+    let (disk, fs) = open(&populated(kind));
+    let root = fs.root();
+
+    let file = root.lookup(LONG_FILE).unwrap();
+    root.rename(LONG_FILE, &root, "Renamed, with another long name.txt").unwrap();
+
+    // Only the case changes, the short entry stays the same file
+    root.rename("SHORT.TXT", &root, "short.txt").unwrap();
+
+    // A directory moves with what's in it, and its `..` follows
+    let target = root.create("Target Directory", VnodeKind::Directory).unwrap();
+    root.rename(LONG_DIR, &target, "Moved Directory").unwrap();
+    let moved = target.lookup("Moved Directory").unwrap();
+
+    assert_eq!(root.rename("Target Directory", &moved, "inside itself"), Err(FsError::InvalidArgument));
+
+    // Files replace files, but not directories
+    root.create("replaced", VnodeKind::File).unwrap().write(0, b"old").unwrap();
+    root.rename("lower.txt", &root, "replaced").unwrap();
+    assert_eq!(root.rename("replaced", &root, "Target Directory"), Err(FsError::IsDirectory));
+    assert_eq!(root.rename("missing", &root, "anything"), Err(FsError::NotFound));
+
+    // Vnodes looked up before keep working after their entry moved
+    file.write(3000, b"appended").unwrap();
+
+    assert_eq!(names(&root), ["Renamed, with another long name.txt", "Target Directory", "replaced", "short.txt"]);
+    assert_eq!(names(&moved), [NESTED]);
+
+    fs.sync().unwrap();
+
+    let written = common::partition_image(disk, SECTOR, kind.size());
+    let mut image = written.clone();
+
+    with_fatfs(&mut image, |fs| {
+        let root = fs.root_dir();
+
+        assert_eq!(fatfs_names(&root), [
+            "Renamed, with another long name.txt",
+            "Target Directory",
+            "replaced",
+            "short.txt",
+        ]);
+
+        let mut renamed = pattern(3000, 1);
+        renamed.extend_from_slice(b"appended");
+        assert_eq!(fatfs_read(&root, "Renamed, with another long name.txt"), renamed);
+        assert_eq!(fatfs_read(&root, "short.txt"), b"short");
+        assert_eq!(fatfs_read(&root, "replaced"), b"lower");
+        assert_eq!(fatfs_read(&root, &format!("Target Directory/Moved Directory/{}", NESTED)), pattern(700, 2));
+
+        // Going up from the moved directory leads to where it is now
+        let parent = root.open_dir("Target Directory/Moved Directory/..").unwrap();
+        assert_eq!(fatfs_names(&parent), ["Moved Directory"]);
+    });
+
+    written
+}
+
+#[test]
+fn fat12_renames() {
+    renames(Kind::Fat12);
+}
+
+#[test]
+fn fat32_renames() {
+    renames(Kind::Fat32);
+}
+
+#[test]
+#[ignore = "needs fsck.fat"]
+fn fat12_fsck_after_renames() {
+    fsck(&renames(Kind::Fat12));
+}
+
+#[test]
+#[ignore = "needs fsck.fat"]
+fn fat32_fsck_after_renames() {
+    fsck(&renames(Kind::Fat32));
+}
+
+/// The FAT12 chain from `first`, decoded straight from the first FAT
+fn fat12_chain(image: &[u8], first: u32) -> Vec<u32> {
+    let reserved = u16::from_le_bytes([image[14], image[15]]) as usize;
+    let fat = &image[reserved * SECTOR..];
+
+    let mut chain = vec![first];
+
+    loop {
+        let cluster = *chain.last().unwrap() as usize;
+        let pair = u16::from_le_bytes([fat[cluster * 3 / 2], fat[cluster * 3 / 2 + 1]]);
+
+        // Even clusters are the low 12 bits of their pair of bytes, odd ones the high 12
+        let next = if cluster & 1 == 0 { pair & 0xfff } else { pair >> 4 };
+
+        if next >= 0xff8 {
+            return chain;
+        }
+
+        chain.push(next as u32);
+    }
+}
+
+/// The first cluster of a root directory file on FAT12, found by its short name
+fn first_cluster(image: &[u8], name: &str) -> u32 {
+    let reserved = u16::from_le_bytes([image[14], image[15]]) as usize;
+    let fats = image[16] as usize;
+    let root_entries = u16::from_le_bytes([image[17], image[18]]) as usize;
+    let fat_sectors = u16::from_le_bytes([image[22], image[23]]) as usize;
+
+    let mut short = [b' '; 11];
+    short[..name.len()].copy_from_slice(name.to_uppercase().as_bytes());
+
+    let root = &image[(reserved + fats * fat_sectors) * SECTOR..][..root_entries * 32];
+    let entry = root.chunks(32).find(|entry| entry[..11] == short && entry[11] & 0x0f != 0x0f).unwrap();
+
+    u16::from_le_bytes([entry[26], entry[27]]) as u32
+}
+
+/// Returns the volume as the kernel left it
+fn odd_and_even_entries() -> Vec<u8> {
+    let mut image = format(Kind::Fat12);
+
+    // A file split around another, so its chain jumps between odd and even entries
+    let split = pattern(7 * SECTOR + 100, 8);
+
+    with_fatfs(&mut image, |fs| {
+        let root = fs.root_dir();
+        root.create_file("a").unwrap().write_all(&pattern(3 * SECTOR, 5)).unwrap();
+        root.create_file("split").unwrap().write_all(&split[..3 * SECTOR]).unwrap();
+        root.create_file("gap").unwrap().write_all(&pattern(3 * SECTOR, 6)).unwrap();
+
+        let mut file = root.open_file("split").unwrap();
+        file.seek(SeekFrom::End(0)).unwrap();
+        file.write_all(&split[3 * SECTOR..]).unwrap();
+    });
+
+    let first = first_cluster(&image, "split");
+    let chain = fat12_chain(&image, first);
+    assert_eq!(chain.len(), 8);
+    assert!(chain.windows(2).any(|pair| pair[1] != pair[0] + 1), "chain isn't split: {:?}", chain);
+
+    let (disk, fs) = open(&image);
+    let root = fs.root();
+
+    assert_eq!(read_all(&root.lookup("split").unwrap()), split);
+
+    // Allocating from the kernel writes entries on both sides of every byte boundary
+    let long = root.create("long", VnodeKind::File).unwrap();
+    long.write(0, &pattern(37 * SECTOR, 9)).unwrap();
+
+    // Shrinking frees the end of the chain and marks the new end
+    let short = root.create("shrunk", VnodeKind::File).unwrap();
+    short.write(0, &pattern(10 * SECTOR, 10)).unwrap();
+    short.truncate(3 * SECTOR as u64 + 1).unwrap();
+
+    fs.sync().unwrap();
+
+    let written = common::partition_image(disk, SECTOR, Kind::Fat12.size());
+    let mut image = written.clone();
+
+    let first = first_cluster(&image, "long");
+    let chain = fat12_chain(&image, first);
+    assert_eq!(chain.len(), 37);
+    assert!(chain.iter().any(|c| c & 1 == 0) && chain.iter().any(|c| c & 1 == 1));
+
+    let first = first_cluster(&image, "shrunk");
+    assert_eq!(fat12_chain(&image, first).len(), 4);
+
+    // Both FATs say the same
+    let reserved = u16::from_le_bytes([image[14], image[15]]) as usize;
+    let fat_sectors = u16::from_le_bytes([image[22], image[23]]) as usize;
+    let fats = &image[reserved * SECTOR..][..2 * fat_sectors * SECTOR];
+    assert_eq!(fats[..fat_sectors * SECTOR], fats[fat_sectors * SECTOR..]);
+
+    with_fatfs(&mut image, |fs| {
+        let root = fs.root_dir();
+        assert_eq!(fatfs_read(&root, "long"), pattern(37 * SECTOR, 9));
+        assert_eq!(fatfs_read(&root, "shrunk"), pattern(3 * SECTOR + 1, 10));
+        assert_eq!(fatfs_read(&root, "split"), split);
+    });
+
+    written
+}
+
+#[test]
+fn fat12_odd_and_even_entries() {
+    odd_and_even_entries();
+}
+
+#[test]
+#[ignore = "needs fsck.fat"]
+fn fat12_fsck_after_odd_and_even_entries() {
+    fsck(&odd_and_even_entries());
+}
@@ -0,0 +1,509 @@
+// GPT reading and writing.
+// Tables written by `Gpt::write` are checked against the UEFI layout by a reader written from the spec, not the
+// kernel's. Tables built by hand the same way are read back by `gpt::read`. The tests that also check tables with
+// `sgdisk -v` are ignored, run them with `--ignored` where it's installed.
+
+mod common;
+
+use std::process::Command;
+
+use common::TempFile;
+use hosttest::dev::blockdev::{self, gpt::{self, GPTPartType, Gpt, GptError, Guid}, PARTS};
+
+const BLOCKSIZE: usize = 512;
+/// 1MiB disks
+const BLOCKS: usize = 2048;
+
+fn le32(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+}
+
+fn le64(bytes: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap())
+}
+
+/// Bit at a time, so it shares nothing with the kernel's table driven version
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+
+    for byte in data {
+        crc ^= *byte as u32;
+
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB88320 } else { crc >> 1 };
+        }
+    }
+
+    !crc
+}
+
+/// A GUID's on-disk bytes from its text form, the first three fields are little endian
+fn guid_bytes(guid: u128) -> [u8; 16] {
+    let mut bytes = [0; 16];
+    bytes[0..4].copy_from_slice(&((guid >> 96) as u32).to_le_bytes());
+    bytes[4..6].copy_from_slice(&((guid >> 80) as u16).to_le_bytes());
+    bytes[6..8].copy_from_slice(&((guid >> 64) as u16).to_le_bytes());
+    bytes[8..16].copy_from_slice(&(guid as u64).to_be_bytes());
+    bytes
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Entry {
+    index: usize,
+    type_guid: [u8; 16],
+    start: u64,
+    end: u64,
+    name: String,
+}
+
+struct Header {
+    alt: u64,
+    first: u64,
+    last: u64,
+    disk_guid: [u8; 16],
+    array_lba: u64,
+    entries: usize,
+    entry_size: usize,
+    array_crc: u32,
+}
+
+/// Check the header at `lba` by the spec, and return it
+fn check_header(image: &[u8], blocksize: usize, lba: u64) -> Header {
+    let block = &image[lba as usize * blocksize..][..blocksize];
+
+    assert_eq!(&block[0..8], b"EFI PART", "signature at LBA {}", lba);
+    assert_eq!(le32(block, 8), 0x0001_0000, "revision");
+
+    let size = le32(block, 12) as usize;
+    assert!((92..=blocksize).contains(&size), "header size {}", size);
+
+    let mut copy = block[..size].to_vec();
+    copy[16..20].fill(0);
+    assert_eq!(crc32(&copy), le32(block, 16), "header CRC at LBA {}", lba);
+
+    assert_eq!(le32(block, 20), 0, "reserved field");
+    assert_eq!(le64(block, 24), lba, "header's own LBA");
+    assert!(block[size..].iter().all(|b| *b == 0), "reserved end of the header block");
+
+    let header = Header {
+        alt: le64(block, 32),
+        first: le64(block, 40),
+        last: le64(block, 48),
+        disk_guid: block[56..72].try_into().unwrap(),
+        array_lba: le64(block, 72),
+        entries: le32(block, 80) as usize,
+        entry_size: le32(block, 84) as usize,
+        array_crc: le32(block, 88),
+    };
+
+    let entry_size = header.entry_size;
+    assert!(entry_size >= 128 && entry_size.is_multiple_of(128) && (entry_size / 128).is_power_of_two());
+
+    header
+}
+
+/// Check a whole table the way a firmware or partitioning tool would, returns the used entries
+fn verify(image: &[u8], blocksize: usize) -> Vec<Entry> {
+    let blocks = (image.len() / blocksize) as u64;
+
+    // Protective MBR, one 0xEE partition from LBA 1 over the rest of the disk
+    assert_eq!(&image[510..512], &[0x55, 0xAA]);
+    let mbr_entry = &image[446..462];
+    assert_eq!(mbr_entry[4], 0xEE);
+    assert_eq!(le32(mbr_entry, 8), 1);
+    assert_eq!(le32(mbr_entry, 12) as u64, (blocks - 1).min(0xffff_ffff));
+    assert!(image[462..510].iter().all(|b| *b == 0), "other MBR entries");
+
+    let primary = check_header(image, blocksize, 1);
+    assert_eq!(primary.alt, blocks - 1, "backup header at the last block");
+
+    let backup = check_header(image, blocksize, primary.alt);
+    assert_eq!(backup.alt, 1);
+    assert_eq!((backup.first, backup.last, backup.disk_guid), (primary.first, primary.last, primary.disk_guid));
+    assert_eq!((backup.entries, backup.entry_size), (primary.entries, primary.entry_size));
+
+    let array_len = primary.entries * primary.entry_size;
+    let array_blocks = array_len.div_ceil(blocksize) as u64;
+
+    assert_eq!(primary.array_lba, 2);
+    assert_eq!(backup.array_lba + array_blocks, primary.alt, "backup array right before the backup header");
+    assert!(primary.first >= primary.array_lba + array_blocks, "usable blocks after the primary array");
+    assert!(primary.last < backup.array_lba, "usable blocks before the backup array");
+
+    let array = &image[primary.array_lba as usize * blocksize..][..array_len];
+    let backup_array = &image[backup.array_lba as usize * blocksize..][..array_len];
+
+    assert_eq!(crc32(array), primary.array_crc, "primary array CRC");
+    assert_eq!(crc32(backup_array), backup.array_crc, "backup array CRC");
+    assert_eq!(array, backup_array, "both arrays hold the same entries");
+
+    let mut entries = Vec::new();
+
+    for (index, raw) in array.chunks(primary.entry_size).enumerate() {
+        let type_guid: [u8; 16] = raw[0..16].try_into().unwrap();
+
+        if type_guid == [0; 16] {
+            assert!(raw.iter().all(|b| *b == 0), "unused entry {} is zeroed", index);
+            continue;
+        }
+
+        let units: Vec<u16> = raw[56..128].chunks(2).map(|c| u16::from_le_bytes([c[0], c[1]])).collect();
+        let len = units.iter().position(|c| *c == 0).unwrap_or(units.len());
+
+        let entry = Entry {
+            index,
+            type_guid,
+            start: le64(raw, 32),
+            end: le64(raw, 40),
+            name: String::from_utf16(&units[..len]).unwrap(),
+        };
+
+        assert!(primary.first <= entry.start && entry.start <= entry.end && entry.end <= primary.last);
+        entries.push(entry);
+    }
+
+    for (i, a) in entries.iter().enumerate() {
+        for b in &entries[i + 1..] {
+            assert!(a.end < b.start || b.end < a.start, "entries {} and {} overlap", a.index, b.index);
+        }
+    }
+
+    entries
+}
+
+/// Run `sgdisk -v` on the image
+fn sgdisk_verify(image: &[u8]) {
+    let sgdisk = common::tool("sgdisk").expect("sgdisk isn't installed");
+
+    let file = TempFile::new("gpt.img");
+    file.write(image);
+
+    let output = common::run_ok(Command::new(sgdisk).arg("-v").arg(file.path()));
+    assert!(output.contains("No problems found"), "sgdisk -v:\n{}", output);
+}
+
+/// A table laid out by hand from the spec, 128 entries of 128 bytes
+fn build_table(blocks: usize, blocksize: usize, parts: &[(u128, u64, u64, &str)]) -> Vec<u8> {
+    let mut image = vec![0; blocks * blocksize];
+    let array_blocks = (128 * 128usize).div_ceil(blocksize) as u64;
+    let last = blocks as u64 - 1;
+
+    let mbr = &mut image[446..462];
+    mbr[1..4].copy_from_slice(&[0x00, 0x02, 0x00]);
+    mbr[4] = 0xEE;
+    mbr[5..8].copy_from_slice(&[0xff, 0xff, 0xff]);
+    mbr[8..12].copy_from_slice(&1u32.to_le_bytes());
+    mbr[12..16].copy_from_slice(&((last).min(0xffff_ffff) as u32).to_le_bytes());
+    image[510] = 0x55;
+    image[511] = 0xAA;
+
+    let mut array = vec![0; 128 * 128];
+
+    for ((type_guid, start, end, name), raw) in parts.iter().zip(array.chunks_mut(128)) {
+        raw[0..16].copy_from_slice(&guid_bytes(*type_guid));
+        raw[16..32].copy_from_slice(&guid_bytes(0x5EED_0000_0000_4000_8000_0000_0000_0000 | *start as u128));
+        raw[32..40].copy_from_slice(&start.to_le_bytes());
+        raw[40..48].copy_from_slice(&end.to_le_bytes());
+
+        for (unit, c) in raw[56..128].chunks_mut(2).zip(name.encode_utf16()) {
+            unit.copy_from_slice(&c.to_le_bytes());
+        }
+    }
+
+    let array_crc = crc32(&array);
+    let backup_array = last - array_blocks;
+
+    for (lba, alt, array_lba) in [(1, last, 2), (last, 1, backup_array)] {
+        let mut header = vec![0; 92];
+        header[0..8].copy_from_slice(b"EFI PART");
+        header[8..12].copy_from_slice(&0x0001_0000u32.to_le_bytes());
+        header[12..16].copy_from_slice(&92u32.to_le_bytes());
+        header[24..32].copy_from_slice(&lba.to_le_bytes());
+        header[32..40].copy_from_slice(&alt.to_le_bytes());
+        header[40..48].copy_from_slice(&(2 + array_blocks).to_le_bytes());
+        header[48..56].copy_from_slice(&(backup_array - 1).to_le_bytes());
+        header[56..72].copy_from_slice(&guid_bytes(0x0123_4567_89AB_4CDE_8F01_2345_6789_ABCD));
+        header[72..80].copy_from_slice(&array_lba.to_le_bytes());
+        header[80..84].copy_from_slice(&128u32.to_le_bytes());
+        header[84..88].copy_from_slice(&128u32.to_le_bytes());
+        header[88..92].copy_from_slice(&array_crc.to_le_bytes());
+
+        let crc = crc32(&header);
+        header[16..20].copy_from_slice(&crc.to_le_bytes());
+
+        image[lba as usize * blocksize..][..92].copy_from_slice(&header);
+        image[array_lba as usize * blocksize..][..array.len()].copy_from_slice(&array);
+    }
+
+    image
+}
+
+/// The partitions registered for disk `id`, as `(start, blocks, type)`
+fn registered(id: usize) -> Vec<(usize, usize, Guid)> {
+    let ids = blockdev::partitions(id);
+    let parts = PARTS.lock();
+
+    ids.iter().map(|part| {
+        let part = &parts[part];
+        (part.start(), part.blocks(), part.type_guid())
+    }).collect()
+}
+
+#[test]
+fn guid_text_form() {
+    let esp = Guid::from_u128(0xC12A7328_F81F_11D2_BA4B_00A0C93EC93B);
+
+    assert_eq!(esp.0, guid_bytes(0xC12A7328_F81F_11D2_BA4B_00A0C93EC93B));
+    assert_eq!(esp.to_string(), "C12A7328-F81F-11D2-BA4B-00A0C93EC93B");
+}
+
+#[test]
+fn new_table_layout() {
+    let gpt = Gpt::new(BLOCKS, BLOCKSIZE, Guid::generate()).unwrap();
+
+    assert_eq!(gpt.parts.len(), 128);
+    assert_eq!(gpt.header.first_lba, 34);
+    assert_eq!(gpt.header.last_lba, BLOCKS as u64 - 34);
+    assert_eq!(gpt.largest_free(), Some((34, BLOCKS as u64 - 34)));
+
+    // Too small for a full table gets one block of entries, too small for that fails
+    assert_eq!(Gpt::new(16, BLOCKSIZE, Guid::generate()).unwrap().parts.len(), 4);
+    assert_eq!(Gpt::new(5, BLOCKSIZE, Guid::generate()).err(), Some(GptError::DiskTooSmall));
+}
+
+/// Two partitions written by `Gpt::write` and read back, returns the disk's image
+fn written_table() -> Vec<u8> {
+    let id = blockdev::add_disk(vec![0; BLOCKS * BLOCKSIZE], BLOCKSIZE);
+
+    let mut gpt = Gpt::new(BLOCKS, BLOCKSIZE, Guid::generate()).unwrap();
+    let esp = gpt.add(GPTPartType::EFI_SYS_PART, Guid::generate(), 34, 1057, "EFI system").unwrap();
+    let data = gpt.add(GPTPartType::GENERAL_PART, Guid::generate(), 1058, 2014, "data ünïcode").unwrap();
+    gpt.write(id).unwrap();
+
+    let image = blockdev::image(id);
+    let entries = verify(&image, BLOCKSIZE);
+
+    assert_eq!(entries.len(), 2);
+    assert_eq!((entries[0].index, entries[0].start, entries[0].end), (esp, 34, 1057));
+    assert_eq!(entries[0].type_guid, guid_bytes(0xC12A7328_F81F_11D2_BA4B_00A0C93EC93B));
+    assert_eq!(entries[0].name, "EFI system");
+    assert_eq!((entries[1].index, entries[1].start, entries[1].end), (data, 1058, 2014));
+    assert_eq!(entries[1].name, "data ünïcode");
+
+    let read = gpt::read(blockdev::disk(id).unwrap()).unwrap().unwrap();
+    assert_eq!(read.header.disk_guid, gpt.header.disk_guid);
+    assert_eq!(read.parts.len(), gpt.parts.len());
+
+    for (read, written) in read.parts.iter().zip(&gpt.parts) {
+        assert_eq!(read.to_bytes(), written.to_bytes());
+    }
+
+    // Writing registers the new partitions
+    assert_eq!(registered(id), [
+        (34, 1024, GPTPartType::EFI_SYS_PART),
+        (1058, 957, GPTPartType::GENERAL_PART),
+    ]);
+
+    image
+}
+
+/// Partitions deleted and resized before the table is written again, returns the disk's image
+fn resized_table() -> Vec<u8> {
+    let id = blockdev::add_disk(vec![0; BLOCKS * BLOCKSIZE], BLOCKSIZE);
+
+    let mut gpt = Gpt::new(BLOCKS, BLOCKSIZE, Guid::generate()).unwrap();
+    let a = gpt.add(GPTPartType::GENERAL_PART, Guid::generate(), 34, 99, "a").unwrap();
+    let b = gpt.add(GPTPartType::GENERAL_PART, Guid::generate(), 100, 199, "b").unwrap();
+    let c = gpt.add(GPTPartType::BASIC_DATA_PART, Guid::generate(), 200, 299, "c").unwrap();
+    gpt.write(id).unwrap();
+    assert_eq!(registered(id).len(), 3);
+
+    assert_eq!(gpt.add(GPTPartType::GENERAL_PART, Guid::generate(), 150, 250, "x"), Err(GptError::Overlap));
+    assert_eq!(gpt.add(GPTPartType::GENERAL_PART, Guid::generate(), 10, 20, "x"), Err(GptError::OutOfRange));
+    assert_eq!(gpt.resize(a, 100), Err(GptError::Overlap));
+    assert_eq!(gpt.resize(c, BLOCKS as u64), Err(GptError::OutOfRange));
+
+    gpt.delete(b).unwrap();
+    assert_eq!(gpt.delete(b), Err(GptError::NoPartition));
+    assert_eq!(gpt.resize(b, 150), Err(GptError::NoPartition));
+
+    // The space b left can now be taken
+    gpt.resize(a, 199).unwrap();
+    gpt.resize(c, 1999).unwrap();
+    gpt.write(id).unwrap();
+
+    let image = blockdev::image(id);
+    let entries = verify(&image, BLOCKSIZE);
+
+    let ranges: Vec<_> = entries.iter().map(|entry| (entry.index, entry.start, entry.end)).collect();
+    assert_eq!(ranges, [(a, 34, 199), (c, 200, 1999)]);
+
+    // The old registrations are replaced, not added to
+    assert_eq!(registered(id), [(34, 166, GPTPartType::GENERAL_PART), (200, 1800, GPTPartType::BASIC_DATA_PART)]);
+
+    assert_eq!(gpt.largest_free(), Some((2000, BLOCKS as u64 - 34)));
+
+    // A freed entry is the first to be reused
+    assert_eq!(gpt.add(GPTPartType::GENERAL_PART, Guid::generate(), 2000, 2014, "d"), Ok(b));
+
+    image
+}
+
+/// Two partitions in a table built by hand, out of order
+fn hand_built_table() -> Vec<u8> {
+    build_table(BLOCKS, BLOCKSIZE, &[
+        (0x0FC63DAF_8483_4772_8E79_3D69D8477DE4, 2048 - 1000, 2048 - 901, "linux"),
+        (0xC12A7328_F81F_11D2_BA4B_00A0C93EC93B, 34, 133, "esp"),
+    ])
+}
+
+#[test]
+fn write_and_read_back() {
+    written_table();
+}
+
+#[test]
+fn delete_and_resize() {
+    resized_table();
+}
+
+#[test]
+#[ignore = "needs sgdisk"]
+fn sgdisk_accepts_written_table() {
+    sgdisk_verify(&written_table());
+}
+
+#[test]
+#[ignore = "needs sgdisk"]
+fn sgdisk_accepts_resized_table() {
+    sgdisk_verify(&resized_table());
+}
+
+#[test]
+#[ignore = "needs sgdisk"]
+fn sgdisk_accepts_hand_built_table() {
+    sgdisk_verify(&hand_built_table());
+}
+
+#[test]
+fn large_blocks() {
+    let blocks = 512;
+    let id = blockdev::add_disk(vec![0; blocks * 4096], 4096);
+
+    let mut gpt = Gpt::new(blocks, 4096, Guid::generate()).unwrap();
+    let (first, last) = (gpt.header.first_lba, gpt.header.last_lba);
+    gpt.add(GPTPartType::GENERAL_PART, Guid::generate(), first, last, "everything").unwrap();
+    gpt.write(id).unwrap();
+
+    let entries = verify(&blockdev::image(id), 4096);
+    assert_eq!((entries[0].start, entries[0].end), (6, blocks as u64 - 6));
+}
+
+#[test]
+fn reads_hand_built_table() {
+    let image = hand_built_table();
+    verify(&image, BLOCKSIZE);
+
+    let id = blockdev::add_disk(image, BLOCKSIZE);
+    let gpt = gpt::read(blockdev::disk(id).unwrap()).unwrap().unwrap();
+
+    let used: Vec<_> = gpt.used().map(|(index, part)| (index, part.start_lba, part.end_lba, part.name())).collect();
+    assert_eq!(used, [(0, 1048, 1147, "linux".into()), (1, 34, 133, "esp".into())]);
+    assert_eq!(gpt.header.disk_guid.0, guid_bytes(0x0123_4567_89AB_4CDE_8F01_2345_6789_ABCD));
+
+    assert_eq!(gpt::scan(id).unwrap().len(), 2);
+    assert_eq!(registered(id), [(1048, 100, GPTPartType::GENERAL_PART), (34, 100, GPTPartType::EFI_SYS_PART)]);
+}
+
+/// A disk with a table written by `Gpt::write`, returns it and the table
+fn written_disk() -> (usize, Gpt) {
+    let id = blockdev::add_disk(vec![0; BLOCKS * BLOCKSIZE], BLOCKSIZE);
+
+    let mut gpt = Gpt::new(BLOCKS, BLOCKSIZE, Guid::generate()).unwrap();
+    gpt.add(GPTPartType::GENERAL_PART, Guid::generate(), 34, 1000, "kept").unwrap();
+    gpt.write(id).unwrap();
+
+    (id, gpt)
+}
+
+fn read_ranges(id: usize) -> Option<Vec<(u64, u64)>> {
+    let gpt = gpt::read(blockdev::disk(id).unwrap()).unwrap()?;
+
+    Some(gpt.used().map(|(_, part)| (part.start_lba, part.end_lba)).collect())
+}
+
+#[test]
+fn damaged_primary_header_falls_back() {
+    let (id, _) = written_disk();
+    let disk = blockdev::disk(id).unwrap();
+
+    // One flipped bit in the primary header fails its CRC
+    let mut block = vec![0; BLOCKSIZE];
+    disk.read(&mut block, 1).unwrap();
+    block[40] ^= 1;
+    disk.write(&block, 1).unwrap();
+
+    assert_eq!(read_ranges(id), Some(vec![(34, 1000)]));
+
+    // A wiped primary header can't say where the backup is, it's found at the end of the disk anyway
+    disk.write(&vec![0; BLOCKSIZE], 1).unwrap();
+    assert_eq!(read_ranges(id), Some(vec![(34, 1000)]));
+}
+
+#[test]
+fn damaged_primary_array_falls_back() {
+    let (id, _) = written_disk();
+    let disk = blockdev::disk(id).unwrap();
+
+    // The header is fine, but the entries it points at don't match its CRC
+    let mut block = vec![0; BLOCKSIZE];
+    disk.read(&mut block, 2).unwrap();
+    block[32] ^= 0x80;
+    disk.write(&block, 2).unwrap();
+
+    assert_eq!(read_ranges(id), Some(vec![(34, 1000)]));
+}
+
+#[test]
+fn rewriting_repairs_the_primary() {
+    let (id, gpt) = written_disk();
+    let disk = blockdev::disk(id).unwrap();
+
+    disk.write(&vec![0xA5; 33 * BLOCKSIZE], 1).unwrap();
+
+    let read = gpt::read(disk).unwrap().unwrap();
+    assert_eq!(read.header.host_lba, BLOCKS as u64 - 1, "read from the backup");
+
+    gpt.write(id).unwrap();
+    verify(&blockdev::image(id), BLOCKSIZE);
+    assert_eq!(gpt::read(disk).unwrap().unwrap().header.host_lba, 1);
+}
+
+#[test]
+fn both_tables_damaged() {
+    let (id, _) = written_disk();
+    let disk = blockdev::disk(id).unwrap();
+
+    disk.write(&vec![0; BLOCKSIZE], 1).unwrap();
+    disk.write(&vec![0; BLOCKSIZE], BLOCKS - 1).unwrap();
+
+    assert_eq!(read_ranges(id), None);
+    assert_eq!(gpt::scan(id).unwrap(), []);
+}
+
+#[test]
+fn insane_header_is_rejected() {
+    // A header with a valid CRC claiming a huge entry array must not be trusted or allocated for
+    let mut image = build_table(BLOCKS, BLOCKSIZE, &[(0x0FC63DAF_8483_4772_8E79_3D69D8477DE4, 34, 100, "x")]);
+
+    for lba in [1, BLOCKS - 1] {
+        let header = &mut image[lba * BLOCKSIZE..][..92];
+        header[80..84].copy_from_slice(&u32::MAX.to_le_bytes());
+        header[16..20].fill(0);
+        let crc = crc32(header);
+        header[16..20].copy_from_slice(&crc.to_le_bytes());
+    }
+
+    let id = blockdev::add_disk(image, BLOCKSIZE);
+    assert_eq!(read_ranges(id), None);
+}
@@ -0,0 +1,42 @@
+// tmpfs directory operations.
+
+use std::sync::Arc;
+
+use hosttest::fs::{tmpfs::TmpFs, Filesystem, FsError, Vnode, VnodeKind};
+
+fn names(dir: &Arc<dyn Vnode>) -> Vec<String> {
+    let mut names = Vec::new();
+
+    while let Some(entry) = dir.readdir(names.len()).unwrap() {
+        names.push(entry.name);
+    }
+
+    names
+}
+
+#[test]
+fn rename_beneath_itself() {
+    let fs = TmpFs::new();
+    let root = fs.root();
+
+    let a = root.create("a", VnodeKind::Directory).unwrap();
+    let b = a.create("b", VnodeKind::Directory).unwrap();
+    let c = b.create("c", VnodeKind::Directory).unwrap();
+
+    assert_eq!(root.rename("a", &a, "x"), Err(FsError::InvalidArgument));
+    assert_eq!(root.rename("a", &c, "x"), Err(FsError::InvalidArgument));
+    assert_eq!(a.rename("b", &c, "x"), Err(FsError::InvalidArgument));
+
+    // Nothing moved
+    assert_eq!(names(&root), ["a"]);
+    assert_eq!(names(&a), ["b"]);
+
+    // Moving up, or across, is fine
+    b.rename("c", &root, "c").unwrap();
+    root.rename("a", &c, "a").unwrap();
+    assert_eq!(names(&root), ["c"]);
+    assert_eq!(names(&c), ["a"]);
+    assert_eq!(names(&a), ["b"]);
+
+    assert_eq!(root.rename("missing", &a, "x"), Err(FsError::NotFound));
+}
@@ -6,6 +6,7 @@ pub mod raw_lai {
 }
 
 pub mod tables;
+pub mod resource;
 
 pub fn init_acpi() {
     let rsdp = crate::RSDP.response().unwrap().rsdp_addr as *mut tables::Rsdp;
@@ -160,32 +161,33 @@ impl lai::Host for Host {
         }
     }
 
-    fn pci_readb(&self,_seg:u16,_bus:u8,_slot:u8,_fun:u8,_offset:u16) -> u8 {
-        todo!()
+    fn pci_readb(&self, seg: u16, bus: u8, slot: u8, fun: u8, offset: u16) -> u8 {
+        crate::dev::pci::read(pci_address(seg, bus, slot, fun), offset)
     }
     
-    fn pci_readw(&self,_seg:u16,_bus:u8,_slot:u8,_fun:u8,_offset:u16) -> u16 {
-        todo!()
+    fn pci_readw(&self, seg: u16, bus: u8, slot: u8, fun: u8, offset: u16) -> u16 {
+        crate::dev::pci::read(pci_address(seg, bus, slot, fun), offset)
     }
 
-    fn pci_readd(&self,_seg:u16,_bus:u8,_slot:u8,_fun:u8,_offset:u16) -> u32 {
-        todo!()
+    fn pci_readd(&self, seg: u16, bus: u8, slot: u8, fun: u8, offset: u16) -> u32 {
+        crate::dev::pci::read(pci_address(seg, bus, slot, fun), offset)
     }
 
-    fn pci_writeb(&self, _seg: u16, _bus: u8, _slot: u8, _fun: u8, _offset: u16, _value: u8) {
-        todo!()
+    fn pci_writeb(&self, seg: u16, bus: u8, slot: u8, fun: u8, offset: u16, value: u8) {
+        crate::dev::pci::write(pci_address(seg, bus, slot, fun), offset, value)
     }
 
-    fn pci_writew(&self, _seg: u16, _bus: u8, _slot: u8, _fun: u8, _offset: u16, _value: u16) {
-        todo!()
+    fn pci_writew(&self, seg: u16, bus: u8, slot: u8, fun: u8, offset: u16, value: u16) {
+        crate::dev::pci::write(pci_address(seg, bus, slot, fun), offset, value)
     }
 
-    fn pci_writed(&self, _seg: u16, _bus: u8, _slot: u8, _fun: u8, _offset: u16, _value: u32) {
-        todo!()
+    fn pci_writed(&self, seg: u16, bus: u8, slot: u8, fun: u8, offset: u16, value: u32) {
+        crate::dev::pci::write(pci_address(seg, bus, slot, fun), offset, value)
     }
 
+    /// Monotonic time in 100ns units, as AML's `Timer` expects
     fn timer(&self) -> u64 {
-        todo!()
+        (crate::time::monotonic_ns() / 100) as u64
     }
 
     fn scan(&self, signature: &str, _index: usize) ->  *mut u8 {
@@ -210,7 +212,11 @@ impl lai::Host for Host {
         (*lookup.unwrap() as *const tables::SdtHeader).cast_mut() as *mut u8
     }
 
-    fn sleep(&self,_ms:u64) {
-        todo!()
+    fn sleep(&self, ms: u64) {
+        crate::time::busy_wait(ms as u128 * 1_000_000);
     }
-}
\ No newline at end of file
+}
+
+fn pci_address(segment: u16, bus: u8, device: u8, function: u8) -> crate::dev::pci::Address {
+    crate::dev::pci::Address { segment, bus, device, function }
+}
@@ -0,0 +1,198 @@
+// Parser for ACPI resource templates, as returned by `_CRS` methods.
+// Only the descriptors we actually have a use for are decoded, everything else is skipped.
+
+use alloc::vec::Vec;
+
+use crate::dev::intc::Trigger;
+
+#[derive(Clone, Copy, Debug)]
+pub enum Resource {
+    /// Fixed memory range, from a Memory32Fixed descriptor
+    Memory { base: u64, len: u64, writable: bool },
+    /// Interrupt lines, from an Extended Interrupt or IRQ descriptor
+    Interrupt { gsi: u32, trigger: Trigger, shared: bool },
+    /// Bridge window, from a Word, DWord or QWord address space descriptor
+    AddressSpace(AddressSpace),
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct AddressSpace {
+    pub kind: SpaceKind,
+    pub min: u64,
+    pub max: u64,
+    /// Offset to add to a bus address to get the CPU address
+    pub translation: u64,
+    pub len: u64,
+    /// Only meaningful for memory ranges
+    pub prefetchable: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SpaceKind {
+    Memory,
+    Io,
+    BusNumber,
+    Other(u8),
+}
+
+const SMALL_IRQ: u8 = 0x04;
+const SMALL_END: u8 = 0x0F;
+
+const LARGE_MEM32: u8 = 0x05;
+const LARGE_MEM32_FIXED: u8 = 0x06;
+const LARGE_DWORD: u8 = 0x07;
+const LARGE_WORD: u8 = 0x08;
+const LARGE_EXT_IRQ: u8 = 0x09;
+const LARGE_QWORD: u8 = 0x0A;
+
+/// Decode a resource template buffer
+pub fn parse(buf: &[u8]) -> Vec<Resource> {
+    let mut resources = Vec::new();
+    let mut offset = 0;
+
+    while offset < buf.len() {
+        let tag = buf[offset];
+
+        if tag & 0x80 == 0 {
+            // Small resource
+            let kind = (tag >> 3) & 0xF;
+            let len = (tag & 0x7) as usize;
+            let data = &buf[offset + 1..core::cmp::min(offset + 1 + len, buf.len())];
+
+            match kind {
+                SMALL_END => break,
+                SMALL_IRQ if data.len() >= 2 => {
+                    let mask = u16::from_le_bytes([data[0], data[1]]);
+                    let info = data.get(2).copied().unwrap_or(0x01);
+
+                    for irq in (0..16).filter(|irq| mask & (1 << irq) != 0) {
+                        resources.push(Resource::Interrupt {
+                            gsi: irq,
+                            trigger: trigger(info & 0x1 != 0, info & 0x8 != 0),
+                            shared: info & 0x10 != 0,
+                        });
+                    }
+                },
+                _ => {}
+            }
+
+            offset += 1 + len;
+        } else {
+            // Large resource
+            if offset + 3 > buf.len() {
+                break;
+            }
+
+            let kind = tag & 0x7F;
+            let len = u16::from_le_bytes([buf[offset + 1], buf[offset + 2]]) as usize;
+            let data = &buf[offset + 3..core::cmp::min(offset + 3 + len, buf.len())];
+
+            match kind {
+                LARGE_MEM32_FIXED if data.len() >= 9 => {
+                    resources.push(Resource::Memory {
+                        base: read_u32(data, 1) as u64,
+                        len: read_u32(data, 5) as u64,
+                        writable: data[0] & 0x1 != 0,
+                    });
+                },
+                LARGE_MEM32 if data.len() >= 17 => {
+                    resources.push(Resource::Memory {
+                        base: read_u32(data, 1) as u64,
+                        len: read_u32(data, 13) as u64,
+                        writable: data[0] & 0x1 != 0,
+                    });
+                },
+                LARGE_EXT_IRQ if data.len() >= 2 => {
+                    let flags = data[0];
+                    let count = data[1] as usize;
+
+                    for i in 0..count {
+                        if 2 + i * 4 + 4 > data.len() {
+                            break;
+                        }
+
+                        resources.push(Resource::Interrupt {
+                            gsi: read_u32(data, 2 + i * 4),
+                            trigger: trigger(flags & 0x2 != 0, flags & 0x4 != 0),
+                            shared: flags & 0x8 != 0,
+                        });
+                    }
+                },
+                LARGE_WORD => if let Some(space) = address_space(data, 2) {
+                    resources.push(Resource::AddressSpace(space));
+                },
+                LARGE_DWORD => if let Some(space) = address_space(data, 4) {
+                    resources.push(Resource::AddressSpace(space));
+                },
+                LARGE_QWORD => if let Some(space) = address_space(data, 8) {
+                    resources.push(Resource::AddressSpace(space));
+                },
+                _ => {}
+            }
+
+            offset += 3 + len;
+        }
+    }
+
+    resources
+}
+
+/// Evaluate the `_CRS` of `node` and decode it
+pub fn current_resources(node: &lai::Node) -> Vec<Resource> {
+    let crs = match node.child("_CRS") {
+        Some(crs) => crs,
+        None => return Vec::new(),
+    };
+
+    match crs.eval() {
+        Ok(obj) => match obj.get_buffer() {
+            Ok(buf) => parse(buf),
+            Err(_) => Vec::new(),
+        },
+        Err(_) => Vec::new(),
+    }
+}
+
+fn trigger(edge: bool, active_low: bool) -> Trigger {
+    match (edge, active_low) {
+        (true, false) => Trigger::EdgeRising,
+        (true, true) => Trigger::EdgeFalling,
+        (false, false) => Trigger::LevelHigh,
+        (false, true) => Trigger::LevelLow,
+    }
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]])
+}
+
+fn read_sized(data: &[u8], offset: usize, width: usize) -> u64 {
+    let mut bytes = [0; 8];
+    bytes[..width].copy_from_slice(&data[offset..offset + width]);
+
+    u64::from_le_bytes(bytes)
+}
+
+/// Address space descriptors share a layout, only the width of the fields differs
+fn address_space(data: &[u8], width: usize) -> Option<AddressSpace> {
+    // Resource type, general flags, type specific flags, then 5 fields of `width`
+    if data.len() < 3 + width * 5 {
+        return None;
+    }
+
+    let kind = match data[0] {
+        0 => SpaceKind::Memory,
+        1 => SpaceKind::Io,
+        2 => SpaceKind::BusNumber,
+        kind => SpaceKind::Other(kind),
+    };
+
+    Some(AddressSpace {
+        kind,
+        min: read_sized(data, 3 + width, width),
+        max: read_sized(data, 3 + width * 2, width),
+        translation: read_sized(data, 3 + width * 3, width),
+        len: read_sized(data, 3 + width * 4, width),
+        prefetchable: kind == SpaceKind::Memory && (data[2] >> 1) & 0x3 == 0x3,
+    })
+}
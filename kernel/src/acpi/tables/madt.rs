@@ -16,22 +16,38 @@ impl Madt {
     pub fn entry(&self, index: usize) -> Option<&'static MadtEntry> {
         unsafe {
             let mut ptr = self.first_entry();
-            let mut total_len = core::mem::size_of::<Madt>() + (*ptr).len as usize;
+            let mut offset = core::mem::size_of::<Madt>();
             
             for _ in 0..index {
-                if total_len >= self.header.len as usize {
+                let len = (*ptr).len;
+
+                // A zero length entry would have us loop forever
+                if len == 0 {
                     return None;
                 }
-                
-                let len = (*ptr).len;
+
                 ptr = ptr.byte_add(len as usize);
-                total_len += len as usize;
+                offset += len as usize;
+            }
+
+            if offset + core::mem::size_of::<MadtEntry>() > self.header.len as usize {
+                return None;
             }
 
             Some(&*ptr)
         }
     }
 
+    /// Find the MADT through the ACPI lookup table
+    pub fn get() -> Option<&'static Madt> {
+        let lock = super::LOOKUP_TABLE.lock();
+        let header = *lock.get(b"APIC")?;
+
+        unsafe {
+            Some(&*((header as *const super::SdtHeader) as *const Madt))
+        }
+    }
+
     pub fn iter(&self) -> IterMadt {
         IterMadt { madt: self, cur: 0 }
     }
@@ -108,6 +124,32 @@ impl Aplic {
 
         supported
     }
+
+    pub fn id(&self) -> u8 {
+        self.aplic_id
+    }
+
+    /// Number of interrupt delivery controls, zero if the APLIC only supports MSI mode
+    pub fn idcs(&self) -> u16 {
+        unsafe {core::ptr::addr_of!(self.idcs).read_unaligned()}
+    }
+
+    pub fn ext_ints(&self) -> u16 {
+        unsafe {core::ptr::addr_of!(self.ext_ints).read_unaligned()}
+    }
+
+    /// First GSI handled by this APLIC
+    pub fn int_base(&self) -> u32 {
+        unsafe {core::ptr::addr_of!(self.int_base).read_unaligned()}
+    }
+
+    pub fn addr(&self) -> u64 {
+        unsafe {core::ptr::addr_of!(self.aplic_addr).read_unaligned()}
+    }
+
+    pub fn size(&self) -> u32 {
+        unsafe {core::ptr::addr_of!(self.aplic_size).read_unaligned()}
+    }
 }
 
 #[repr(C)]
@@ -144,6 +186,31 @@ impl Plic {
 
         supported
     }
+
+    pub fn id(&self) -> u8 {
+        self.plic_id
+    }
+
+    pub fn ext_ints(&self) -> u16 {
+        unsafe {core::ptr::addr_of!(self.ext_int).read_unaligned()}
+    }
+
+    pub fn max_priority(&self) -> u16 {
+        unsafe {core::ptr::addr_of!(self.max_priority).read_unaligned()}
+    }
+
+    /// First GSI handled by this PLIC
+    pub fn int_base(&self) -> u32 {
+        unsafe {core::ptr::addr_of!(self.int_base).read_unaligned()}
+    }
+
+    pub fn addr(&self) -> u64 {
+        unsafe {core::ptr::addr_of!(self.plic_addr).read_unaligned()}
+    }
+
+    pub fn size(&self) -> u32 {
+        unsafe {core::ptr::addr_of!(self.plic_size).read_unaligned()}
+    }
 }
 
 /// RINTC structure as of revision 1
@@ -183,15 +250,43 @@ impl RiscvIntController {
 
         supported
     }
+
+    pub fn enabled(&self) -> bool {
+        unsafe {core::ptr::addr_of!(self.flags).read_unaligned() & 1 == 1}
+    }
+
+    pub fn hartid(&self) -> u64 {
+        unsafe {core::ptr::addr_of!(self.hartid).read_unaligned()}
+    }
+
+    pub fn acpi_proc_id(&self) -> u32 {
+        unsafe {core::ptr::addr_of!(self.acpi_proc_id).read_unaligned()}
+    }
+
+    pub fn ext_int_id(&self) -> ExtIntID {
+        unsafe {core::ptr::addr_of!(self.ext_int_id).read_unaligned()}
+    }
+
+    /// Physical address of this hart's S-mode IMSIC interrupt file
+    pub fn imsic_addr(&self) -> u64 {
+        unsafe {core::ptr::addr_of!(self.imsic_addr).read_unaligned()}
+    }
+
+    pub fn imsic_size(&self) -> u32 {
+        unsafe {core::ptr::addr_of!(self.imsic_size).read_unaligned()}
+    }
 }
 
 bitfield::bitfield! {
     #[repr(transparent)]
+    #[derive(Clone, Copy)]
     pub struct ExtIntID(u32);
     impl Debug;
-    idc_id, _: 15, 0;
+    /// PLIC context or APLIC IDC index for this hart
+    pub idc_id, _: 15, 0;
     _res, _: 23, 16;
-    plic_id, _: 31, 24;
+    /// ID of the PLIC or APLIC this hart is connected to
+    pub plic_id, _: 31, 24;
 }
 
 #[repr(C)]
@@ -229,4 +324,25 @@ impl Imsic {
 
         supported
     }
+
+    /// Number of interrupt identities supported by S-mode interrupt files
+    pub fn smode_int_ids(&self) -> u16 {
+        unsafe {core::ptr::addr_of!(self.smode_int_ids).read_unaligned()}
+    }
+
+    pub fn guest_idx(&self) -> u8 {
+        self.guest_idx
+    }
+
+    pub fn hart_idx(&self) -> u8 {
+        self.hart_idx
+    }
+
+    pub fn group_idx(&self) -> u8 {
+        self.group_idx
+    }
+
+    pub fn group_idx_shift(&self) -> u8 {
+        self.group_idx_shift
+    }
 }
\ No newline at end of file
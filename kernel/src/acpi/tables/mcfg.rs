@@ -0,0 +1,40 @@
+// PCI Express memory mapped configuration space table
+
+#[repr(C)]
+pub struct Mcfg {
+    header: super::SdtHeader,
+    _res: [u8; 8],
+}
+
+/// An ECAM region, covering the configuration space of a range of buses in one segment
+#[repr(C, packed)]
+#[derive(Clone, Copy, Debug)]
+pub struct McfgEntry {
+    pub base: u64,
+    pub segment: u16,
+    pub start_bus: u8,
+    pub end_bus: u8,
+    _res: u32,
+}
+
+impl Mcfg {
+    /// Find the MCFG through the ACPI lookup table
+    pub fn get() -> Option<&'static Mcfg> {
+        let lock = super::LOOKUP_TABLE.lock();
+        let header = *lock.get(b"MCFG")?;
+
+        unsafe {
+            Some(&*((header as *const super::SdtHeader) as *const Mcfg))
+        }
+    }
+
+    pub fn entries(&self) -> alloc::vec::Vec<McfgEntry> {
+        let count = (self.header.len as usize).saturating_sub(core::mem::size_of::<Mcfg>()) / core::mem::size_of::<McfgEntry>();
+
+        unsafe {
+            let first = (self as *const Mcfg).add(1) as *const McfgEntry;
+
+            (0..count).map(|i| first.add(i).read_unaligned()).collect()
+        }
+    }
+}
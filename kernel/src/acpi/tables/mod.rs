@@ -4,6 +4,7 @@ use spin::Mutex;
 use crate::println;
 
 pub mod madt;
+pub mod mcfg;
 
 #[repr(C)]
 pub struct Rsdp {
@@ -270,3 +271,68 @@ pub struct Rhct {
     pub nodess: u32,
     pub offset: u32,
 }
+
+impl Rhct {
+    const NODE_ISA_STRING: u16 = 0;
+
+    /// Find the RHCT through the ACPI lookup table
+    pub fn get() -> Option<&'static Rhct> {
+        let lock = LOOKUP_TABLE.lock();
+        let header = *lock.get(b"RHCT")?;
+
+        unsafe {
+            Some(&*((header as *const SdtHeader) as *const Rhct))
+        }
+    }
+
+    /// Every ISA string node, such as `rv64imafdc_zicsr_sstc`
+    pub fn isa_strings(&self) -> alloc::vec::Vec<&'static str> {
+        let base = core::ptr::addr_of!(*self) as *const u8;
+        let len = self.header.len as usize;
+        let mut strings = alloc::vec::Vec::new();
+        let mut offset = self.offset as usize;
+
+        for _ in 0..self.nodess {
+            // Node header is the type, length, and revision, each a u16
+            if offset + 6 > len {
+                break;
+            }
+
+            unsafe {
+                let node = base.add(offset);
+                let ntype = (node as *const u16).read_unaligned();
+                let nlen = (node.add(2) as *const u16).read_unaligned() as usize;
+
+                if nlen == 0 || offset + nlen > len {
+                    break;
+                }
+
+                if ntype == Self::NODE_ISA_STRING {
+                    let isa_len = (node.add(6) as *const u16).read_unaligned() as usize;
+                    let bytes = core::slice::from_raw_parts(node.add(8), isa_len.min(nlen - 8));
+
+                    // The string is null terminated, and the length includes the terminator
+                    let bytes = bytes.split(|byte| *byte == 0).next().unwrap_or(&[]);
+
+                    if let Ok(isa) = core::str::from_utf8(bytes) {
+                        strings.push(isa);
+                    }
+                }
+
+                offset += nlen;
+            }
+        }
+
+        strings
+    }
+
+    /// Whether every ISA string reports the multi letter extension `name`, such as "sstc"
+    pub fn has_extension(&self, name: &str) -> bool {
+        let strings = self.isa_strings();
+
+        !strings.is_empty() && strings.iter().all(|isa| {
+            // The first component holds the base ISA and single letter extensions
+            isa.split('_').skip(1).any(|ext| ext.eq_ignore_ascii_case(name))
+        })
+    }
+}
@@ -0,0 +1,128 @@
+// Inter-processor interrupts.
+// Each hart has a mailbox of messages, sending an IPI pushes to the mailbox and then pokes the target hart.
+
+use alloc::{collections::BTreeMap, sync::Arc};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use crossbeam_queue::SegQueue;
+use spin::Mutex;
+
+/// Key: hart ID
+/// Value: Messages waiting for that hart
+static MAILBOXES: Mutex<BTreeMap<usize, Arc<SegQueue<IpiMsg>>>> = Mutex::new(BTreeMap::new());
+
+pub enum IpiMsg {
+    /// Run the scheduler on the target hart when it leaves the interrupt
+    Reschedule,
+    /// Flush TLB entries covering `size` bytes at `start`, a size of 0 flushes everything
+    TlbShootdown { start: usize, size: usize },
+    /// Run a function on the target hart
+    Call(Arc<CallData>),
+}
+
+pub struct CallData {
+    func: fn(usize),
+    arg: usize,
+    /// Number of harts that haven't run `func` yet
+    pending: AtomicUsize,
+}
+
+impl CallData {
+    fn new(func: fn(usize), arg: usize, harts: usize) -> Arc<Self> {
+        Arc::new(Self {
+            func,
+            arg,
+            pending: AtomicUsize::new(harts),
+        })
+    }
+
+    fn wait(&self) {
+        while self.pending.load(Ordering::Acquire) != 0 {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+/// The lock is also taken by the IPI handler, so it is only held with interrupts off
+fn mailbox(hartid: usize) -> Arc<SegQueue<IpiMsg>> {
+    crate::arch::trap::without_interrupts(|| {
+        MAILBOXES.lock().entry(hartid).or_insert_with(|| Arc::new(SegQueue::new())).clone()
+    })
+}
+
+/// Queue `msg` for `hartid` and interrupt it
+pub fn send(hartid: usize, msg: IpiMsg) {
+    let hart = crate::cpu::hart(hartid).expect("Attempt to send IPI to unknown hart");
+
+    assert!(hart.online(), "Attempt to send IPI to offline hart {}", hartid);
+
+    mailbox(hartid).push(msg);
+    crate::arch::ipi::send_ipi(hart);
+}
+
+/// Ask `hartid` to run its scheduler
+pub fn reschedule(hartid: usize) {
+    if hartid == crate::cpu::hart_id() {
+        crate::scheduler::set_need_resched();
+    } else {
+        send(hartid, IpiMsg::Reschedule);
+    }
+}
+
+/// Flush the TLB on every online hart, including this one
+pub fn tlb_shootdown(start: usize, size: usize) {
+    let this = crate::cpu::hart_id();
+
+    for hart in crate::cpu::online_harts() {
+        if hart.hartid != this {
+            send(hart.hartid, IpiMsg::TlbShootdown { start, size });
+        }
+    }
+
+    crate::arch::ipi::flush_tlb(start, size);
+}
+
+/// Run `func` on `hartid`, and wait for it to finish
+pub fn call_on_hart(hartid: usize, func: fn(usize), arg: usize) {
+    if hartid == crate::cpu::hart_id() {
+        func(arg);
+        return;
+    }
+
+    let data = CallData::new(func, arg, 1);
+    send(hartid, IpiMsg::Call(data.clone()));
+    data.wait();
+}
+
+/// Run `func` on every online hart, and wait for all of them to finish
+pub fn call_on_all(func: fn(usize), arg: usize) {
+    let this = crate::cpu::hart_id();
+    let others = crate::cpu::online_harts()
+        .into_iter()
+        .filter(|hart| hart.hartid != this)
+        .collect::<alloc::vec::Vec<_>>();
+
+    let data = CallData::new(func, arg, others.len());
+
+    for hart in others {
+        send(hart.hartid, IpiMsg::Call(data.clone()));
+    }
+
+    func(arg);
+    data.wait();
+}
+
+/// Process every message waiting for the current hart
+pub fn handle_pending() {
+    let mailbox = mailbox(crate::cpu::hart_id());
+
+    while let Some(msg) = mailbox.pop() {
+        match msg {
+            IpiMsg::Reschedule => crate::scheduler::set_need_resched(),
+            IpiMsg::TlbShootdown { start, size } => crate::arch::ipi::flush_tlb(start, size),
+            IpiMsg::Call(data) => {
+                (data.func)(data.arg);
+                data.pending.fetch_sub(1, Ordering::Release);
+            }
+        }
+    }
+}
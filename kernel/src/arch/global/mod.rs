@@ -1,4 +1,5 @@
 pub(crate) mod trap;
+pub mod ipi;
 
 use core::mem::MaybeUninit;
 
@@ -124,9 +125,3 @@ impl PortAccess for BigEndianU32 {
         PortAccess::write(location, val.get())
     }
 }
-
-pub struct IpiTarg(usize);
-pub enum IpiMsg {
-    Kernel,
-    Driver,
-}
\ No newline at end of file
@@ -24,31 +24,119 @@ pub enum TrapExternal {
     ExternalDevice
 }
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug)]
 pub enum AccessFault {
     Load,
     Store,
     Exec,
 }
 
+/// NT style exception codes, delivered to user threads that fault
+#[repr(u32)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExceptionCode {
+    AccessViolation = 0xC0000005,
+    InPageError = 0xC0000006,
+    IllegalInstruction = 0xC000001D,
+    DatatypeMisalignment = 0x80000002,
+    Breakpoint = 0x80000003,
+}
+
+/// Passed to a thread's exception dispatcher, alongside the trap frame at the time of the fault
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct ExceptionRecord {
+    pub code: ExceptionCode,
+    /// Only meaningful for access faults
+    pub access: u32,
+    /// Address that caused the fault, if any
+    pub address: usize,
+    /// PC of the faulting instruction
+    pub pc: usize,
+}
+
+impl TrapInternal {
+    fn exception(&self) -> ExceptionCode {
+        match self {
+            TrapInternal::UnalignedAccess(_) => ExceptionCode::DatatypeMisalignment,
+            TrapInternal::InvalidAccess(_) => ExceptionCode::AccessViolation,
+            TrapInternal::PageFault(_) => ExceptionCode::AccessViolation,
+            TrapInternal::UnknownInstruction => ExceptionCode::IllegalInstruction,
+            TrapInternal::Breakpoint => ExceptionCode::Breakpoint,
+            TrapInternal::SystemCall => unreachable!("System calls are not exceptions"),
+        }
+    }
+
+    fn access(&self) -> u32 {
+        match self {
+            TrapInternal::UnalignedAccess(fault) 
+            | TrapInternal::InvalidAccess(fault) 
+            | TrapInternal::PageFault(fault) => *fault as u32,
+            _ => 0,
+        }
+    }
+}
+
 pub fn trap_main(trapcause: TrapCause, regframe: &mut crate::arch::trap::TrapFrame) {
     match trapcause {
         TrapCause::External(cause) => {
             match cause {
-                TrapExternal::Timer => {
-                    crate::arch::timer::set_timer(u128::MAX);
-                    crate::scheduler::next(regframe)
+                TrapExternal::Timer => crate::time::handle_timer(),
+                TrapExternal::ExternalDevice => crate::dev::intc::handle_external(),
+                TrapExternal::InterProcInt => {
+                    crate::arch::ipi::clear_soft_int();
+                    crate::arch::global::ipi::handle_pending();
                 }
-                _ => todo!("Handle external interrupts"),
+            }
+
+            if crate::scheduler::take_need_resched() {
+                crate::scheduler::next(regframe)
             }
         },
         TrapCause::Internal(cause) => {
-            match cause {
+            let address = match cause {
                 TrapInternal::PageFault(fault) => {
-                    println!("Register frame dump {:#x?}", regframe);
-                    page_fault(fault, regframe.pagefault_addr())
+                    let vaddr = regframe.pagefault_addr();
+
+                    if page_fault(fault, vaddr) {
+                        return;
+                    }
+
+                    vaddr
+                },
+                TrapInternal::SystemCall => {
+                    system_call(regframe);
+                    return;
+                },
+                TrapInternal::UnalignedAccess(AccessFault::Load | AccessFault::Store) => {
+                    if crate::arch::misaligned::emulate(regframe) {
+                        return;
+                    }
+
+                    regframe.unaligned_addr()
                 },
-                _ => panic!("Hit unhandled cause {:?} frame {:#x?} address 0x{:x}", cause, regframe, regframe.invalid_addr().addr())
+                TrapInternal::UnalignedAccess(AccessFault::Exec) => regframe.unaligned_addr(),
+                TrapInternal::UnknownInstruction => {
+                    if crate::scheduler::fpu_trap(regframe) {
+                        return;
+                    }
+
+                    regframe.invalid_addr()
+                },
+                _ => regframe.invalid_addr(),
+            };
+
+            if regframe.from_user() {
+                let record = ExceptionRecord {
+                    code: cause.exception(),
+                    access: cause.access(),
+                    address: address.addr(),
+                    pc: regframe.pc(),
+                };
+
+                user_exception(record, regframe);
+            } else {
+                crash(&cause, address, regframe);
             }
         }
     }
@@ -58,23 +146,87 @@ pub trait Frame {
     fn pagefault_addr(&self) -> crate::mem::VirtualAddress;
     fn invalid_addr(&self) -> crate::mem::VirtualAddress;
     fn unaligned_addr(&self) -> crate::mem::VirtualAddress;
+    /// Whether the trap was taken from user mode
+    fn from_user(&self) -> bool;
 }
 
-pub fn page_fault(reason: AccessFault, vaddr: crate::mem::VirtualAddress) {
+/// Try to resolve a page fault, returns false if the access was invalid
+pub fn page_fault(reason: AccessFault, vaddr: crate::mem::VirtualAddress) -> bool {
     let mut root = crate::arch::paging::get_root_table();
 
     let entry = root.get_entry(vaddr);
-    match reason {
-        AccessFault::Exec => assert!(entry.is_exec(), "Entry faulted but was not executable 0x{:x}", vaddr.addr()),
-        AccessFault::Store => assert!(entry.is_write(), "Entry faulted but was not writeable 0x{:x}", vaddr.addr()),
-        AccessFault::Load => assert!(entry.is_read(), "Entry faulted but was not readable 0x{:x}", vaddr.addr()),
-    }
+    let permitted = match reason {
+        AccessFault::Exec => entry.is_exec(),
+        AccessFault::Store => entry.is_write(),
+        AccessFault::Load => entry.is_read(),
+    };
 
-    if entry.swapped() {
+    if permitted && entry.swapped() {
         root.swap(vaddr, crate::cpu::THREAD_CTRL_BLOCK.lock().proc_id()).unwrap();
-    } else if false {
-        
+        true
     } else {
-        panic!("Entry was invalid despite fault 0x{:x}", vaddr.addr())
+        false
     }
+}
+
+/// There are no system calls yet, so every call fails
+fn system_call(regframe: &mut crate::arch::trap::TrapFrame) {
+    regframe.regs.a0 = usize::MAX;
+    regframe.set_pc(regframe.pc() + 4);
+}
+
+/// Hand an exception to the faulting thread's dispatcher, or kill the thread if it has none
+fn user_exception(record: ExceptionRecord, regframe: &mut crate::arch::trap::TrapFrame) {
+    match crate::scheduler::exception_handler() {
+        Some(dispatcher) => {
+            let context = *regframe;
+            let stack = regframe.regs.sp;
+
+            // Copy the record and context to the user stack, the whole range has to be user memory the thread can
+            // write, or a stack pointer aimed at the kernel would have us write there for it
+            let addrs = stack
+                .checked_sub(core::mem::size_of_val(&context))
+                .map(|addr| addr & !0xf)
+                .and_then(|context_addr| {
+                    let record_addr = context_addr.checked_sub(core::mem::size_of_val(&record))? & !0xf;
+
+                    Some((record_addr, context_addr))
+                })
+                .filter(|&(record_addr, _)| {
+                    !crate::mem::VirtualAddress::new(record_addr).is_kern()
+                        && crate::arch::insn::accessible(record_addr, stack - record_addr, true, true)
+                });
+
+            if let Some((record_addr, context_addr)) = addrs {
+                crate::arch::user_access(|| unsafe {
+                    (context_addr as *mut crate::arch::trap::TrapFrame).write(context);
+                    (record_addr as *mut ExceptionRecord).write(record);
+                });
+
+                regframe.regs.a0 = record_addr;
+                regframe.regs.a1 = context_addr;
+                regframe.set_stack(record_addr);
+                regframe.set_pc(dispatcher);
+                return;
+            }
+
+            println!("Failed to deliver {:?}, stack is invalid", record.code);
+        },
+        None => println!("Unhandled {:?} at 0x{:x} address 0x{:x}", record.code, record.pc, record.address),
+    }
+
+    crate::scheduler::kill_current(regframe);
+}
+
+/// Kernel faults are unrecoverable, print everything we know and panic
+fn crash(cause: &TrapInternal, address: crate::mem::VirtualAddress, regframe: &crate::arch::trap::TrapFrame) -> ! {
+    println!("==== KERNEL FAULT ====");
+    println!("Cause: {:?}", cause);
+    println!("PC: 0x{:x}", regframe.pc());
+    println!("Address: 0x{:x}", address.addr());
+    println!("Hart: {}", crate::cpu::hart_id());
+    crate::scheduler::dump_current();
+    println!("Registers: {:#x?}", regframe.regs);
+
+    panic!("Kernel fault {:?} at 0x{:x}", cause, regframe.pc())
 }
\ No newline at end of file
@@ -21,4 +21,23 @@ unsafe extern "C" fn _boot() -> ! {
         2:
             j kinit
     ", sym stvec_trap_shim, options(noreturn));
-}
\ No newline at end of file
+}
+/// Entry point of the other harts, Limine starts them here on its own stack, with the kernel's page tables and
+/// the hart's `limine_smp_info` in a0
+#[naked]
+pub unsafe extern "C" fn _ap_boot() -> ! {
+    core::arch::asm!("
+        csrw sie, zero
+        csrci sstatus, 2
+
+        .option push
+        .option norelax
+        lla gp, __global_pointer
+        .option pop
+
+        lla t1, {}
+        csrw stvec, t1
+
+        j {}
+    ", sym stvec_trap_shim, sym crate::cpu::ap_main, options(noreturn));
+}
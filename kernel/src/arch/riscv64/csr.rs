@@ -85,10 +85,10 @@ bitfield::bitfield! {
     spie, set_spie: 5;
     ube, set_ube: 6;
     pub spp, set_spp: 8;
-    vs, set_vs: 10, 9;
-    fs, set_fs: 14, 13;
+    pub vs, set_vs: 10, 9;
+    pub fs, set_fs: 14, 13;
     xs, set_xs: 16, 15;
-    sum, set_sum: 18;
+    pub sum, set_sum: 18;
     mxr, set_mxr: 19;
     uxl, set_uxl: 33, 32;
     sd, set_sd: 63;
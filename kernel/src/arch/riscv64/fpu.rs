@@ -0,0 +1,334 @@
+// Lazy floating point and vector state switching.
+// The kernel never touches these registers, so a thread's state only needs saving when `sstatus` reports it dirty,
+// and only needs restoring once the thread actually uses the unit again.
+
+use alloc::{boxed::Box, vec::Vec};
+
+use super::csr::Sstatus;
+use super::insn;
+
+/// `sstatus.FS` and `sstatus.VS` encodings
+const OFF: u64 = 0;
+const INITIAL: u64 = 1;
+const CLEAN: u64 = 2;
+const DIRTY: u64 = 3;
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct FpState {
+    pub f: [u64; 32],
+    pub fcsr: u64,
+}
+
+#[derive(Clone, Default)]
+pub struct VecState {
+    /// Contents of v0 through v31, `vlenb` bytes each
+    pub regs: Vec<u8>,
+    pub vstart: usize,
+    pub vcsr: usize,
+    pub vl: usize,
+    pub vtype: usize,
+}
+
+/// Per thread save areas, only allocated once a thread uses the unit
+#[derive(Default)]
+pub struct FpuContext {
+    fp: Option<Box<FpState>>,
+    vec: Option<Box<VecState>>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Unit {
+    Float,
+    Vector,
+}
+
+/// Save any dirty state of the outgoing thread, call before switching away from it
+pub fn save(ctx: &mut FpuContext) {
+    let mut sstatus = Sstatus::default();
+
+    if sstatus.fs() == DIRTY {
+        let state = ctx.fp.get_or_insert_with(Default::default);
+
+        unsafe {save_fp(state)};
+        sstatus.set_fs(CLEAN);
+    }
+
+    if sstatus.vs() == DIRTY {
+        let state = ctx.vec.get_or_insert_with(Default::default);
+
+        unsafe {save_vec(state)};
+        sstatus.set_vs(CLEAN);
+    }
+
+    unsafe {sstatus.load()};
+}
+
+/// Turn both units off, so the incoming thread traps on its first use
+pub fn disable() {
+    let mut sstatus = Sstatus::default();
+    sstatus.set_fs(OFF);
+    sstatus.set_vs(OFF);
+
+    unsafe {sstatus.load()};
+}
+
+/// Whether the hart implements the vector extension
+pub fn vector_supported() -> bool {
+    // VS is read only zero without V, so try setting it
+    let mut sstatus = Sstatus::default();
+    let old = sstatus.vs();
+
+    sstatus.set_vs(INITIAL);
+    unsafe {sstatus.load()};
+
+    let mut sstatus = Sstatus::default();
+    let supported = sstatus.vs() != OFF;
+
+    sstatus.set_vs(old);
+    unsafe {sstatus.load()};
+
+    supported
+}
+
+/// Called on illegal instruction traps, if the instruction used a disabled unit, restore the thread's
+/// state and enable it. Returns true if the instruction should be retried
+pub fn handle_illegal(frame: &super::trap::TrapFrame, ctx: &mut FpuContext) -> bool {
+    use crate::arch::global::trap::Frame;
+
+    let unit = match insn::fetch(frame.pc(), frame.from_user()) {
+        Some((raw, len)) => classify(raw, len),
+        None => None,
+    };
+
+    let mut sstatus = Sstatus::default();
+
+    match unit {
+        Some(Unit::Float) if sstatus.fs() == OFF => {
+            sstatus.set_fs(INITIAL);
+            unsafe {sstatus.load()};
+
+            let state = ctx.fp.get_or_insert_with(Default::default);
+            unsafe {restore_fp(state)};
+
+            let mut sstatus = Sstatus::default();
+            sstatus.set_fs(CLEAN);
+            unsafe {sstatus.load()};
+
+            true
+        },
+        Some(Unit::Vector) if sstatus.vs() == OFF && vector_supported() => {
+            sstatus.set_vs(INITIAL);
+            unsafe {sstatus.load()};
+
+            // A first use starts from zeroed registers, not whatever the last thread left in them
+            let state = ctx.vec.get_or_insert_with(|| {
+                let mut state = Box::<VecState>::default();
+                state.regs.resize(vlenb() * 8 * 4, 0);
+                state
+            });
+            unsafe {restore_vec(state)};
+
+            let mut sstatus = Sstatus::default();
+            sstatus.set_vs(CLEAN);
+            unsafe {sstatus.load()};
+
+            true
+        },
+        _ => false,
+    }
+}
+
+fn classify(raw: u32, len: usize) -> Option<Unit> {
+    use insn::bits;
+
+    if len == 2 {
+        // C.FLD, C.FSD, C.FLDSP and C.FSDSP
+        return match (bits(raw, 1, 0), bits(raw, 15, 13)) {
+            (0b00 | 0b10, 0b001 | 0b101) => Some(Unit::Float),
+            _ => None,
+        };
+    }
+
+    match insn::opcode(raw) {
+        // LOAD-FP and STORE-FP, the width decides between scalar and vector
+        0x07 | 0x27 => match insn::funct3(raw) {
+            1..=4 => Some(Unit::Float),
+            _ => Some(Unit::Vector),
+        },
+        // Fused multiply adds, and OP-FP
+        0x43 | 0x47 | 0x4B | 0x4F | 0x53 => Some(Unit::Float),
+        // OP-V
+        0x57 => Some(Unit::Vector),
+        // CSR accesses to fflags, frm, fcsr, or the vector CSRs
+        0x73 if insn::funct3(raw) != 0 => match bits(raw, 31, 20) {
+            0x001..=0x003 => Some(Unit::Float),
+            0x008..=0x00F | 0xC20..=0xC22 => Some(Unit::Vector),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+unsafe fn save_fp(state: &mut FpState) {
+    core::arch::asm!(
+        r#"
+            .attribute arch, "rv64imafdc"
+            fsd f0, 0({ptr})
+            fsd f1, 8({ptr})
+            fsd f2, 16({ptr})
+            fsd f3, 24({ptr})
+            fsd f4, 32({ptr})
+            fsd f5, 40({ptr})
+            fsd f6, 48({ptr})
+            fsd f7, 56({ptr})
+            fsd f8, 64({ptr})
+            fsd f9, 72({ptr})
+            fsd f10, 80({ptr})
+            fsd f11, 88({ptr})
+            fsd f12, 96({ptr})
+            fsd f13, 104({ptr})
+            fsd f14, 112({ptr})
+            fsd f15, 120({ptr})
+            fsd f16, 128({ptr})
+            fsd f17, 136({ptr})
+            fsd f18, 144({ptr})
+            fsd f19, 152({ptr})
+            fsd f20, 160({ptr})
+            fsd f21, 168({ptr})
+            fsd f22, 176({ptr})
+            fsd f23, 184({ptr})
+            fsd f24, 192({ptr})
+            fsd f25, 200({ptr})
+            fsd f26, 208({ptr})
+            fsd f27, 216({ptr})
+            fsd f28, 224({ptr})
+            fsd f29, 232({ptr})
+            fsd f30, 240({ptr})
+            fsd f31, 248({ptr})
+            frcsr {tmp}
+            sd {tmp}, 256({ptr})
+            .attribute arch, "rv64imac"
+        "#,
+        ptr = in(reg) state as *mut FpState,
+        tmp = out(reg) _,
+    );
+}
+
+unsafe fn restore_fp(state: &FpState) {
+    core::arch::asm!(
+        r#"
+            .attribute arch, "rv64imafdc"
+            fld f0, 0({ptr})
+            fld f1, 8({ptr})
+            fld f2, 16({ptr})
+            fld f3, 24({ptr})
+            fld f4, 32({ptr})
+            fld f5, 40({ptr})
+            fld f6, 48({ptr})
+            fld f7, 56({ptr})
+            fld f8, 64({ptr})
+            fld f9, 72({ptr})
+            fld f10, 80({ptr})
+            fld f11, 88({ptr})
+            fld f12, 96({ptr})
+            fld f13, 104({ptr})
+            fld f14, 112({ptr})
+            fld f15, 120({ptr})
+            fld f16, 128({ptr})
+            fld f17, 136({ptr})
+            fld f18, 144({ptr})
+            fld f19, 152({ptr})
+            fld f20, 160({ptr})
+            fld f21, 168({ptr})
+            fld f22, 176({ptr})
+            fld f23, 184({ptr})
+            fld f24, 192({ptr})
+            fld f25, 200({ptr})
+            fld f26, 208({ptr})
+            fld f27, 216({ptr})
+            fld f28, 224({ptr})
+            fld f29, 232({ptr})
+            fld f30, 240({ptr})
+            fld f31, 248({ptr})
+            ld {tmp}, 256({ptr})
+            fscsr {tmp}
+            .attribute arch, "rv64imac"
+        "#,
+        ptr = in(reg) state as *const FpState,
+        tmp = out(reg) _,
+    );
+}
+
+fn vlenb() -> usize {
+    let vlenb: usize;
+
+    unsafe {
+        core::arch::asm!("csrr {vlenb}, 0xC22", vlenb = out(reg) vlenb);
+    }
+
+    vlenb
+}
+
+unsafe fn save_vec(state: &mut VecState) {
+    let group = vlenb() * 8;
+    state.regs.resize(group * 4, 0);
+
+    core::arch::asm!(
+        "csrr {vstart}, 0x008",
+        "csrr {vcsr}, 0x00F",
+        "csrr {vl}, 0xC20",
+        "csrr {vtype}, 0xC21",
+        vstart = out(reg) state.vstart,
+        vcsr = out(reg) state.vcsr,
+        vl = out(reg) state.vl,
+        vtype = out(reg) state.vtype,
+    );
+
+    let base = state.regs.as_mut_ptr();
+
+    core::arch::asm!(
+        r#"
+            .attribute arch, "rv64imafdcv"
+            vs8r.v v0, ({v0})
+            vs8r.v v8, ({v8})
+            vs8r.v v16, ({v16})
+            vs8r.v v24, ({v24})
+            .attribute arch, "rv64imac"
+        "#,
+        v0 = in(reg) base,
+        v8 = in(reg) base.add(group),
+        v16 = in(reg) base.add(group * 2),
+        v24 = in(reg) base.add(group * 3),
+    );
+}
+
+unsafe fn restore_vec(state: &VecState) {
+    let group = vlenb() * 8;
+    let base = state.regs.as_ptr();
+
+    core::arch::asm!(
+        r#"
+            .attribute arch, "rv64imafdcv"
+            vl8r.v v0, ({v0})
+            vl8r.v v8, ({v8})
+            vl8r.v v16, ({v16})
+            vl8r.v v24, ({v24})
+            vsetvl zero, {vl}, {vtype}
+            .attribute arch, "rv64imac"
+        "#,
+        v0 = in(reg) base,
+        v8 = in(reg) base.add(group),
+        v16 = in(reg) base.add(group * 2),
+        v24 = in(reg) base.add(group * 3),
+        vl = in(reg) state.vl,
+        vtype = in(reg) state.vtype,
+    );
+
+    core::arch::asm!(
+        "csrw 0x008, {vstart}",
+        "csrw 0x00F, {vcsr}",
+        vstart = in(reg) state.vstart,
+        vcsr = in(reg) state.vcsr,
+    );
+}
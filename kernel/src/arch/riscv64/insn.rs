@@ -0,0 +1,110 @@
+// Helpers for reading and decoding the instruction that caused a trap
+
+use crate::mem::VirtualAddress;
+
+/// Check that `len` bytes at `addr` are mapped with the needed permissions, `user` also requires user pages
+pub fn accessible(addr: usize, len: usize, write: bool, user: bool) -> bool {
+    let root = super::paging::get_root_table();
+
+    // A range wrapping around the address space is never accessible
+    let Some(end) = len.checked_sub(1).and_then(|len| addr.checked_add(len)) else {
+        return len == 0;
+    };
+
+    let first = addr & !0xfff;
+    let last = end & !0xfff;
+
+    for page in (first..=last).step_by(0x1000) {
+        let vaddr = VirtualAddress::new(page);
+
+        if user && vaddr.is_kern() {
+            return false;
+        }
+
+        let entry = root.get_entry(vaddr);
+
+        if !entry.valid() || !entry.is_read() || (write && !entry.is_write()) || (user && !entry.is_user()) {
+            return false;
+        }
+    }
+
+    true
+}
+
+/// Read the instruction at `pc`, returns it and its length in bytes.
+/// Compressed instructions are returned in the low 16 bits
+pub fn fetch(pc: usize, user: bool) -> Option<(u32, usize)> {
+    let read = || unsafe {
+        if !accessible(pc, 2, false, user) {
+            return None;
+        }
+
+        // Instructions are only 2 byte aligned, so a 4 byte instruction is read as 2 parcels
+        let low = (pc as *const u16).read_volatile() as u32;
+
+        if low & 0b11 != 0b11 {
+            return Some((low, 2));
+        }
+
+        let next = pc.checked_add(2)?;
+
+        if !accessible(next, 2, false, user) {
+            return None;
+        }
+
+        let high = (next as *const u16).read_volatile() as u32;
+
+        Some((low | (high << 16), 4))
+    };
+
+    if user {
+        super::user_access(read)
+    } else {
+        read()
+    }
+}
+
+pub fn bits(insn: u32, hi: u32, lo: u32) -> u32 {
+    (insn >> lo) & ((1 << (hi - lo + 1)) - 1)
+}
+
+pub fn opcode(insn: u32) -> u32 {
+    bits(insn, 6, 0)
+}
+
+pub fn rd(insn: u32) -> usize {
+    bits(insn, 11, 7) as usize
+}
+
+pub fn rs1(insn: u32) -> usize {
+    bits(insn, 19, 15) as usize
+}
+
+pub fn rs2(insn: u32) -> usize {
+    bits(insn, 24, 20) as usize
+}
+
+pub fn funct3(insn: u32) -> u32 {
+    bits(insn, 14, 12)
+}
+
+/// Compressed register fields only name x8 through x15
+pub fn creg(field: u32) -> usize {
+    8 + field as usize
+}
+
+fn sign_extend(val: u32, width: u32) -> isize {
+    let shift = 32 - width;
+
+    (((val << shift) as i32) >> shift) as isize
+}
+
+/// Immediate of an I-type instruction
+pub fn imm_i(insn: u32) -> isize {
+    sign_extend(bits(insn, 31, 20), 12)
+}
+
+/// Immediate of an S-type instruction
+pub fn imm_s(insn: u32) -> isize {
+    sign_extend((bits(insn, 31, 25) << 5) | bits(insn, 11, 7), 12)
+}
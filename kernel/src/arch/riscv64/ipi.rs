@@ -0,0 +1,48 @@
+use crate::cpu::Hart;
+
+/// SBI IPI extension, "sPI"
+const SBI_EXT_IPI: usize = 0x735049;
+
+/// IMSIC identity reserved for IPIs, the same on every hart
+pub const IPI_ID: u32 = 1;
+
+/// Interrupt `hart`, through its IMSIC if it has one, and SBI otherwise
+pub fn send_ipi(hart: &Hart) {
+    match (hart.imsic, crate::dev::imsic::get()) {
+        (Some(imsic), Some(_)) => unsafe {
+            // Writing an identity to `seteipnum_le` makes it pending on that hart
+            let seteipnum: *mut u32 = crate::mem::PhysicalAddress::new(imsic).to_virt().to_mut_ptr();
+            seteipnum.write_volatile(IPI_ID);
+        },
+        _ => unsafe {
+            // Hart mask of 1, with the mask base as the target hart
+            sbi::ecall3(1, hart.hartid, 0, SBI_EXT_IPI, 0).unwrap();
+        }
+    }
+}
+
+/// Clear a pending supervisor software interrupt
+pub fn clear_soft_int() {
+    unsafe {
+        core::arch::asm!("csrc sip, {ssip}", ssip = in(reg) 1 << 1);
+    }
+}
+
+/// Flush the local TLB, a size of 0 flushes everything
+pub fn flush_tlb(start: usize, size: usize) {
+    if size == 0 {
+        super::paging::sfence();
+        return;
+    }
+
+    for addr in (start..start + size).step_by(0x1000) {
+        unsafe {
+            core::arch::asm!("sfence.vma {addr}, zero", addr = in(reg) addr);
+        }
+    }
+}
+
+/// IMSIC vector for `IPI_ID`
+pub fn imsic_ipi(_id: u32) {
+    crate::arch::global::ipi::handle_pending();
+}
@@ -0,0 +1,261 @@
+// Some harts trap on misaligned loads and stores instead of handling them in hardware.
+// We decode the faulting instruction, do the access a byte at a time, and step over it.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use super::insn;
+use super::trap::TrapFrame;
+use crate::arch::global::trap::Frame;
+
+static LOADS: AtomicUsize = AtomicUsize::new(0);
+static STORES: AtomicUsize = AtomicUsize::new(0);
+static FAILED: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Clone, Copy, Debug)]
+pub struct MisalignedStats {
+    /// Loads emulated
+    pub loads: usize,
+    /// Stores emulated
+    pub stores: usize,
+    /// Accesses that could not be emulated, and were delivered as faults
+    pub failed: usize,
+}
+
+pub fn stats() -> MisalignedStats {
+    MisalignedStats {
+        loads: LOADS.load(Ordering::Relaxed),
+        stores: STORES.load(Ordering::Relaxed),
+        failed: FAILED.load(Ordering::Relaxed),
+    }
+}
+
+#[derive(Debug)]
+enum Access {
+    Load {
+        rd: usize,
+        base: usize,
+        offset: isize,
+        size: usize,
+        signed: bool,
+    },
+    Store {
+        rs2: usize,
+        base: usize,
+        offset: isize,
+        size: usize,
+    },
+}
+
+/// Emulate the misaligned access at `frame.pc()`, returns false if the instruction couldn't be emulated
+pub fn emulate(frame: &mut TrapFrame) -> bool {
+    let user = frame.from_user();
+
+    let emulated = match insn::fetch(frame.pc(), user) {
+        Some((raw, len)) => match decode(raw, len) {
+            Some(access) => perform(access, frame, user).map(|_| len),
+            None => None,
+        },
+        None => None,
+    };
+
+    match emulated {
+        Some(len) => {
+            frame.set_pc(frame.pc() + len);
+            true
+        },
+        None => {
+            FAILED.fetch_add(1, Ordering::Relaxed);
+            false
+        }
+    }
+}
+
+fn decode(raw: u32, len: usize) -> Option<Access> {
+    if len == 4 {
+        decode_full(raw)
+    } else {
+        decode_compressed(raw)
+    }
+}
+
+fn decode_full(raw: u32) -> Option<Access> {
+    match insn::opcode(raw) {
+        // LOAD
+        0x03 => {
+            let (size, signed) = match insn::funct3(raw) {
+                0 => (1, true),
+                1 => (2, true),
+                2 => (4, true),
+                3 => (8, true),
+                4 => (1, false),
+                5 => (2, false),
+                6 => (4, false),
+                _ => return None,
+            };
+
+            Some(Access::Load {
+                rd: insn::rd(raw),
+                base: insn::rs1(raw),
+                offset: insn::imm_i(raw),
+                size,
+                signed,
+            })
+        },
+        // STORE
+        0x23 => {
+            let size = match insn::funct3(raw) {
+                0 => 1,
+                1 => 2,
+                2 => 4,
+                3 => 8,
+                _ => return None,
+            };
+
+            Some(Access::Store {
+                rs2: insn::rs2(raw),
+                base: insn::rs1(raw),
+                offset: insn::imm_s(raw),
+                size,
+            })
+        },
+        // Floating point accesses aren't emulated, the registers aren't in the trap frame
+        _ => None,
+    }
+}
+
+fn decode_compressed(raw: u32) -> Option<Access> {
+    use insn::bits;
+
+    let quadrant = bits(raw, 1, 0);
+    let funct3 = bits(raw, 15, 13);
+
+    // Offsets for the register based forms
+    let word_offset = (bits(raw, 12, 10) << 3) | (bits(raw, 6, 6) << 2) | (bits(raw, 5, 5) << 6);
+    let double_offset = (bits(raw, 12, 10) << 3) | (bits(raw, 6, 5) << 6);
+
+    let access = match (quadrant, funct3) {
+        // C.LW
+        (0b00, 0b010) => Access::Load {
+            rd: insn::creg(bits(raw, 4, 2)),
+            base: insn::creg(bits(raw, 9, 7)),
+            offset: word_offset as isize,
+            size: 4,
+            signed: true,
+        },
+        // C.LD
+        (0b00, 0b011) => Access::Load {
+            rd: insn::creg(bits(raw, 4, 2)),
+            base: insn::creg(bits(raw, 9, 7)),
+            offset: double_offset as isize,
+            size: 8,
+            signed: true,
+        },
+        // C.SW
+        (0b00, 0b110) => Access::Store {
+            rs2: insn::creg(bits(raw, 4, 2)),
+            base: insn::creg(bits(raw, 9, 7)),
+            offset: word_offset as isize,
+            size: 4,
+        },
+        // C.SD
+        (0b00, 0b111) => Access::Store {
+            rs2: insn::creg(bits(raw, 4, 2)),
+            base: insn::creg(bits(raw, 9, 7)),
+            offset: double_offset as isize,
+            size: 8,
+        },
+        // C.LWSP
+        (0b10, 0b010) => Access::Load {
+            rd: bits(raw, 11, 7) as usize,
+            base: 2,
+            offset: ((bits(raw, 12, 12) << 5) | (bits(raw, 6, 4) << 2) | (bits(raw, 3, 2) << 6)) as isize,
+            size: 4,
+            signed: true,
+        },
+        // C.LDSP
+        (0b10, 0b011) => Access::Load {
+            rd: bits(raw, 11, 7) as usize,
+            base: 2,
+            offset: ((bits(raw, 12, 12) << 5) | (bits(raw, 6, 5) << 3) | (bits(raw, 4, 2) << 6)) as isize,
+            size: 8,
+            signed: true,
+        },
+        // C.SWSP
+        (0b10, 0b110) => Access::Store {
+            rs2: bits(raw, 6, 2) as usize,
+            base: 2,
+            offset: ((bits(raw, 12, 9) << 2) | (bits(raw, 8, 7) << 6)) as isize,
+            size: 4,
+        },
+        // C.SDSP
+        (0b10, 0b111) => Access::Store {
+            rs2: bits(raw, 6, 2) as usize,
+            base: 2,
+            offset: ((bits(raw, 12, 10) << 3) | (bits(raw, 9, 7) << 6)) as isize,
+            size: 8,
+        },
+        _ => return None,
+    };
+
+    Some(access)
+}
+
+fn perform(access: Access, frame: &mut TrapFrame, user: bool) -> Option<()> {
+    let copy = |addr: usize, size: usize, write: Option<u64>| -> Option<u64> {
+        if !insn::accessible(addr, size, write.is_some(), user) {
+            return None;
+        }
+
+        let run = || unsafe {
+            let ptr = addr as *mut u8;
+
+            match write {
+                Some(val) => {
+                    for (i, byte) in val.to_le_bytes()[..size].iter().enumerate() {
+                        ptr.add(i).write_volatile(*byte);
+                    }
+
+                    0
+                },
+                None => {
+                    let mut bytes = [0; 8];
+
+                    for (i, byte) in bytes[..size].iter_mut().enumerate() {
+                        *byte = ptr.add(i).read_volatile();
+                    }
+
+                    u64::from_le_bytes(bytes)
+                }
+            }
+        };
+
+        if user {
+            Some(super::user_access(run))
+        } else {
+            Some(run())
+        }
+    };
+
+    match access {
+        Access::Load { rd, base, offset, size, signed } => {
+            let addr = frame.regs.get(base).wrapping_add_signed(offset);
+            let mut val = copy(addr, size, None)?;
+
+            if signed && size < 8 {
+                let shift = 64 - size * 8;
+                val = (((val << shift) as i64) >> shift) as u64;
+            }
+
+            frame.regs.set(rd, val as usize);
+            LOADS.fetch_add(1, Ordering::Relaxed);
+        },
+        Access::Store { rs2, base, offset, size } => {
+            let addr = frame.regs.get(base).wrapping_add_signed(offset);
+
+            copy(addr, size, Some(frame.regs.get(rs2) as u64))?;
+            STORES.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    Some(())
+}
@@ -5,8 +5,12 @@ pub mod paging;
 pub mod trap;
 pub mod mem;
 mod csr;
-mod boot;
+pub mod boot;
 pub mod timer;
+pub mod ipi;
+pub mod insn;
+pub mod misaligned;
+pub mod fpu;
 pub mod utils;
 
 pub fn init() {
@@ -35,9 +39,10 @@ pub fn init() {
         }
     }
 
-    let table = (*crate::acpi::tables::LOOKUP_TABLE.lock().get(b"RHCT").unwrap()) as *const _;
-    let table = unsafe {&*(table as *const crate::acpi::tables::Rhct)};
-    super::timer::FREQ.store(table.timer_freq as usize, core::sync::atomic::Ordering::Relaxed)
+    let table = crate::acpi::tables::Rhct::get().expect("No RHCT present");
+    super::timer::FREQ.store(table.timer_freq as usize, core::sync::atomic::Ordering::Relaxed);
+    super::timer::init(table);
+    paging::SVPBMT.store(table.has_extension("svpbmt"), core::sync::atomic::Ordering::Relaxed);
 }
 
 pub fn set_mode(mode: super::Mode) {
@@ -51,6 +56,34 @@ pub fn set_mode(mode: super::Mode) {
     unsafe {sstatus.load()};
 }
 
+/// Run `f` with supervisor access to user memory enabled, calls can nest
+pub fn user_access<R>(f: impl FnOnce() -> R) -> R {
+    // Only SUM is touched, a trap in between may change other fields such as FS and VS
+    const SUM: usize = 1 << 18;
+    let prev: usize;
+
+    unsafe {
+        core::arch::asm!(
+            "csrrs {prev}, sstatus, {sum}",
+            prev = out(reg) prev,
+            sum = in(reg) SUM,
+        );
+    }
+
+    let result = f();
+
+    if prev & SUM == 0 {
+        unsafe {
+            core::arch::asm!(
+                "csrc sstatus, {sum}",
+                sum = in(reg) SUM,
+            );
+        }
+    }
+
+    result
+}
+
 #[naked]
 pub extern "C" fn idle_thread() -> ! {
     unsafe {core::arch::asm!(
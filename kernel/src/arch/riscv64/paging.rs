@@ -203,6 +203,19 @@ impl PagePermissions {
     };
 }
 
+/// Memory types, selected through Svpbmt
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MemoryType {
+    /// Whatever the platform's PMAs say, cached for RAM
+    Normal = 0,
+    NonCacheable = 1,
+    /// Non cacheable, strongly ordered, for device registers
+    Io = 2,
+}
+
+/// Whether the harts implement Svpbmt, without it memory types are ignored
+pub(super) static SVPBMT: core::sync::atomic::AtomicBool = core::sync::atomic::AtomicBool::new(false);
+
 #[derive(Debug)]
 pub enum PageError {
     MappingExists(PageTableEntry),
@@ -292,6 +305,26 @@ impl RootTable {
         unreachable!()
     }
 
+    /// Map a page with a memory type, see `map`
+    /// # Safety
+    /// Can change what memory addresses are valid to access, and how its valid to access it.
+    pub unsafe fn map_typed(
+        &mut self, 
+        vaddr: crate::mem::VirtualAddress, 
+        paddr: crate::mem::PhysicalAddress, 
+        perms: PagePermissions, 
+        size: PageSize,
+        mtype: MemoryType,
+    ) -> Result<(), PageError> {
+        self.map(vaddr, paddr, perms, size)?;
+
+        if SVPBMT.load(core::sync::atomic::Ordering::Relaxed) {
+            self.get_entry(vaddr).set_pbmt(mtype as u64);
+        }
+
+        Ok(())
+    }
+
     /// # Safety
     /// Deletes tables, be careful jfc
     pub unsafe fn remove_entries(
@@ -446,7 +479,7 @@ impl RootTable {
 
         let result = if entry.swapped() {
             // Swap it back in
-            let phys = crate::mem::PHYS.alloc(size as usize, vmem::AllocStrategy::NextFit).unwrap();
+            let phys = crate::mem::swap::alloc_frame(size as usize);
 
             let disk_info = if vaddr.is_kern() {
                 let mut lock = crate::mem::swap::KERN_SWAP.lock();
@@ -463,12 +496,15 @@ impl RootTable {
             let disk = lock.get(&disk_id).unwrap();
             println!("Getting disks");
 
-            let buf = unsafe {core::slice::from_raw_parts_mut(
-                (phys + crate::mem::HHDM_OFFSET.load(core::sync::atomic::Ordering::Relaxed)) as *mut u8, 
-                size as usize
-            )};
+            let request = crate::dev::blockdev::Request::new(
+                crate::dev::blockdev::Op::Read,
+                block,
+                (size as usize).div_ceil(disk.blocksize()),
+                alloc::vec![crate::dev::blockdev::Segment::new(phys, size as usize)]
+            );
 
-            disk.read(buf, block).unwrap();
+            // The page isn't mapped until the read is done
+            unsafe {disk.submit(request)}.and_then(|handle| handle.wait()).unwrap();
 
             entry.set_swapped(false);
             entry.set_ppn((phys >> 12) as u64);
@@ -478,25 +514,30 @@ impl RootTable {
             // Swap it back out
             entry.set_swapped(true);
 
+            let part_id = *crate::mem::swap::SWAP_PARTS.lock().first().expect("No swap partition");
+
             let mut lock = crate::dev::blockdev::PARTS.lock();
-            let part = lock.get_mut(&0).unwrap();
+            let part = lock.get_mut(&part_id).unwrap();
     
             let block_base = part.alloc_blocks((size as usize).div_ceil(part.blocksize()));
 
             if vaddr.is_kern() {
                 let mut swaplock = crate::mem::swap::KERN_SWAP.lock();
-                swaplock.insert(vaddr, (0, block_base));
+                swaplock.insert(vaddr, (part_id, block_base));
             } else {
                 let mut swaplock = crate::mem::swap::SWAP_MAN.lock();
-                swaplock.insert((procid, vaddr), (0, block_base));
+                swaplock.insert((procid, vaddr), (part_id, block_base));
             }
 
-            let buf = unsafe {core::slice::from_raw_parts(
-                entry.phys().to_virt().to_ptr(), 
-                size as usize
-            )};
+            let request = crate::dev::blockdev::Request::new(
+                crate::dev::blockdev::Op::Write,
+                block_base,
+                (size as usize).div_ceil(part.blocksize()),
+                alloc::vec![crate::dev::blockdev::Segment::new(entry.phys().addr(), size as usize)]
+            );
 
-            part.write(buf, block_base).unwrap();
+            // The page is already unmapped, so nothing changes it while it's written
+            unsafe {part.submit(request)}.and_then(|handle| handle.wait()).unwrap();
             Ok(())
         };
 
@@ -572,6 +613,10 @@ impl PageTableEntry {
     pub fn is_exec(&self) -> bool {
         self.exec()
     }
+
+    pub fn is_user(&self) -> bool {
+        self.user()
+    }
 }
 
 pub enum Entry {
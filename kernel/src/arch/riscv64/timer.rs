@@ -1,7 +1,23 @@
-use core::sync::atomic::AtomicUsize;
+use core::sync::atomic::{AtomicBool, AtomicUsize};
 
 pub(super) static FREQ: AtomicUsize = AtomicUsize::new(0);
 
+/// Whether the harts implement Sstc, and the comparator can be written directly through `stimecmp`
+static SSTC: AtomicBool = AtomicBool::new(false);
+
+/// Pick how the comparator is programmed, from the RHCT ISA strings
+pub(super) fn init(rhct: &crate::acpi::tables::Rhct) {
+    let sstc = rhct.has_extension("sstc");
+
+    SSTC.store(sstc, core::sync::atomic::Ordering::Relaxed);
+
+    crate::println!(
+        "Timer at {}Hz, programmed through {}",
+        rhct.timer_freq,
+        if sstc {"stimecmp"} else {"SBI"}
+    );
+}
+
 pub fn get_timer() -> u128 {
     let val: u64;
 
@@ -16,11 +32,27 @@ pub fn get_timer() -> u128 {
 }
 
 pub fn set_timer(val: u128) {
-    sbi::timer::set_timer(val as u64).unwrap();
+    let val = val.min(u64::MAX as u128) as u64;
+
+    if SSTC.load(core::sync::atomic::Ordering::Relaxed) {
+        unsafe {
+            core::arch::asm!(
+                "csrw 0x14D, {val}",
+                val = in(reg) val
+            );
+        }
+    } else {
+        sbi::timer::set_timer(val).unwrap();
+    }
 }
 
 pub fn ticks_from_ms(ms: usize) -> u128 {
     let freq = FREQ.load(core::sync::atomic::Ordering::Relaxed) / 1000;
     let freq = freq as u128;
     freq * ms as u128
-}
\ No newline at end of file
+}
+
+/// Timer ticks per second, as reported by the RHCT
+pub fn freq() -> u128 {
+    FREQ.load(core::sync::atomic::Ordering::Relaxed) as u128
+}
@@ -24,6 +24,7 @@ pub fn disable() {
     let mut sie = super::csr::Sie::default();
     sie.set_seie(false);
     sie.set_stie(false);
+    sie.set_ssie(false);
     unsafe {sie.load()}
 }
 
@@ -31,9 +32,30 @@ pub fn enable() {
     let mut sie = super::csr::Sie::default();
     sie.set_seie(true);
     sie.set_stie(true);
+    sie.set_ssie(true);
     unsafe {sie.load()}
 }
 
+/// Whether interrupts are enabled on this hart
+pub fn enabled() -> bool {
+    let sie = super::csr::Sie::default();
+    sie.seie() || sie.stie() || sie.ssie()
+}
+
+/// Run `f` with interrupts disabled, then put them back the way they were
+pub fn without_interrupts<R>(f: impl FnOnce() -> R) -> R {
+    let enabled = enabled();
+    disable();
+
+    let result = f();
+
+    if enabled {
+        enable();
+    }
+
+    result
+}
+
 pub fn init_traps() {
     unsafe {
         const STACK_SIZE: usize = 0x1000;
@@ -84,6 +106,7 @@ pub fn init_traps() {
         let mut sie = super::csr::Sie::default();
         sie.set_stie(true);
         sie.set_seie(true);
+        sie.set_ssie(true);
         sie.load();
     }
 }
@@ -212,112 +235,10 @@ pub(crate) unsafe extern "C" fn stvec_trap_shim() -> ! {
         mv a0, sp
         csrr a1, scause
 
-        // Check floating point registers
-        csrr s0, sstatus
-        srli s0, s0, 13
-        andi s0, s0, 3
-        li s1, 3
-
-        // Skip FP reg saving if they're clean
-        bne s0, s1, 1f
-
-            // Save floating point registers
-            addi sp, sp, -264
-            
-            .attribute arch, "rv64imafdc"
-            fsd f0, 0(sp)
-            fsd f1, 8(sp)
-            fsd f2, 16(sp)
-            fsd f3, 24(sp)
-            fsd f4, 32(sp)
-            fsd f5, 40(sp)
-            fsd f6, 48(sp)
-            fsd f7, 56(sp)
-            fsd f8, 64(sp)
-            fsd f9, 72(sp)
-            fsd f10, 80(sp)
-            fsd f11, 88(sp)
-            fsd f12, 96(sp)
-            fsd f13, 104(sp)
-            fsd f14, 112(sp)
-            fsd f15, 120(sp)
-            fsd f16, 128(sp)
-            fsd f17, 136(sp)
-            fsd f18, 144(sp)
-            fsd f19, 152(sp)
-            fsd f20, 160(sp)
-            fsd f21, 168(sp)
-            fsd f22, 176(sp)
-            fsd f23, 184(sp)
-            fsd f24, 192(sp)
-            fsd f25, 200(sp)
-            fsd f26, 208(sp)
-            fsd f27, 216(sp)
-            fsd f28, 224(sp)
-            fsd f29, 232(sp)
-            fsd f30, 240(sp)
-            fsd f31, 248(sp)
-
-            // Save the floating point flags
-            frcsr t1
-            sd t1, 256(sp)
-
-            // Clear dirty floating point bits
-            .attribute arch, "rv64imac"
-            li t1, (0b01 << 13)
-            csrc sstatus, t1
-
-            // Floating point registers are clean
-            1:
-
         call {trap}
 
         // Load back into userspace
 
-        // Check FP register status again
-        bne s0, s1, 2f
-            // Restore if they were dirty
-            .attribute arch, "rv64imafdc"
-            fld f0, 0(sp)
-            fld f1, 8(sp)
-            fld f2, 16(sp)
-            fld f3, 24(sp)
-            fld f4, 32(sp)
-            fld f5, 40(sp)
-            fld f6, 48(sp)
-            fld f7, 56(sp)
-            fld f8, 64(sp)
-            fld f9, 72(sp)
-            fld f10, 80(sp)
-            fld f11, 88(sp)
-            fld f12, 96(sp)
-            fld f13, 104(sp)
-            fld f14, 112(sp)
-            fld f15, 120(sp)
-            fld f16, 128(sp)
-            fld f17, 136(sp)
-            fld f18, 144(sp)
-            fld f19, 152(sp)
-            fld f20, 160(sp)
-            fld f21, 168(sp)
-            fld f22, 176(sp)
-            fld f23, 184(sp)
-            fld f24, 192(sp)
-            fld f25, 200(sp)
-            fld f26, 208(sp)
-            fld f27, 216(sp)
-            fld f28, 224(sp)
-            fld f29, 232(sp)
-            fld f30, 240(sp)
-            fld f31, 248(sp)
-            ld t1, 256(sp)
-            fscsr t1
-            .attribute arch, "rv64imac"
-            addi sp, sp, 264
-
-            // FP registers clean
-            2:
-
         // Load sepc
         ld t0, 248(sp)
 
@@ -401,6 +322,88 @@ pub struct GeneralRegisters {
     pub t6: usize,
 }
 
+impl GeneralRegisters {
+    /// Read register `x{idx}`
+    pub fn get(&self, idx: usize) -> usize {
+        match idx {
+            0 => 0,
+            1 => self.ra,
+            2 => self.sp,
+            3 => self.gp,
+            4 => self.tp,
+            5 => self.t0,
+            6 => self.t1,
+            7 => self.t2,
+            8 => self.s0,
+            9 => self.s1,
+            10 => self.a0,
+            11 => self.a1,
+            12 => self.a2,
+            13 => self.a3,
+            14 => self.a4,
+            15 => self.a5,
+            16 => self.a6,
+            17 => self.a7,
+            18 => self.s2,
+            19 => self.s3,
+            20 => self.s4,
+            21 => self.s5,
+            22 => self.s6,
+            23 => self.s7,
+            24 => self.s8,
+            25 => self.s9,
+            26 => self.s10,
+            27 => self.s11,
+            28 => self.t3,
+            29 => self.t4,
+            30 => self.t5,
+            31 => self.t6,
+            _ => panic!("Invalid register x{}", idx)
+        }
+    }
+
+    /// Write register `x{idx}`, writes to x0 are discarded
+    pub fn set(&mut self, idx: usize, val: usize) {
+        let reg = match idx {
+            0 => return,
+            1 => &mut self.ra,
+            2 => &mut self.sp,
+            3 => &mut self.gp,
+            4 => &mut self.tp,
+            5 => &mut self.t0,
+            6 => &mut self.t1,
+            7 => &mut self.t2,
+            8 => &mut self.s0,
+            9 => &mut self.s1,
+            10 => &mut self.a0,
+            11 => &mut self.a1,
+            12 => &mut self.a2,
+            13 => &mut self.a3,
+            14 => &mut self.a4,
+            15 => &mut self.a5,
+            16 => &mut self.a6,
+            17 => &mut self.a7,
+            18 => &mut self.s2,
+            19 => &mut self.s3,
+            20 => &mut self.s4,
+            21 => &mut self.s5,
+            22 => &mut self.s6,
+            23 => &mut self.s7,
+            24 => &mut self.s8,
+            25 => &mut self.s9,
+            26 => &mut self.s10,
+            27 => &mut self.s11,
+            28 => &mut self.t3,
+            29 => &mut self.t4,
+            30 => &mut self.t5,
+            31 => &mut self.t6,
+            _ => panic!("Invalid register x{}", idx)
+        };
+
+        *reg = val;
+    }
+}
+
 #[repr(C)]
 #[derive(Clone, Copy, Default, Debug)]
 pub struct TrapFrame {
@@ -420,6 +423,10 @@ impl crate::arch::global::trap::Frame for TrapFrame {
     fn unaligned_addr(&self) -> crate::mem::VirtualAddress {
         super::csr::Stval::new().addr()
     }
+
+    fn from_user(&self) -> bool {
+        !super::csr::Sstatus::default().spp()
+    }
 }
 
 impl TrapFrame {
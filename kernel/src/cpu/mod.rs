@@ -1,5 +1,11 @@
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use alloc::{collections::BTreeMap, vec::Vec};
 use spin::Mutex;
 
+use crate::acpi::tables::madt;
+use crate::arch::trap::without_interrupts;
+
 #[thread_local]
 // The ID of the current process
 pub static THREAD_CTRL_BLOCK: Mutex<ThreadCtrlBlock> = Mutex::new(
@@ -8,6 +14,41 @@ pub static THREAD_CTRL_BLOCK: Mutex<ThreadCtrlBlock> = Mutex::new(
     }
 );
 
+#[thread_local]
+/// The ID of the hart this thread of execution runs on
+static HART_ID: AtomicUsize = AtomicUsize::new(0);
+
+static SMP: limine::SmpRequest = limine::SmpRequest::new(limine::SmpRequestFlags::empty());
+
+/// Key: hart ID
+/// Value: Every hart described by the MADT, whether or not its running
+static HARTS: Mutex<BTreeMap<usize, &'static Hart>> = Mutex::new(BTreeMap::new());
+
+pub struct Hart {
+    pub hartid: usize,
+    pub acpi_id: u32,
+    /// Physical address of the hart's supervisor IMSIC interrupt file
+    pub imsic: Option<usize>,
+    online: AtomicBool,
+    idle: AtomicBool,
+}
+
+impl Hart {
+    /// Whether the hart is running the kernel, and can receive IPIs
+    pub fn online(&self) -> bool {
+        self.online.load(Ordering::Acquire)
+    }
+
+    /// Whether the hart is running its idle thread, and has no time slice to end it
+    pub fn idle(&self) -> bool {
+        self.idle.load(Ordering::Acquire)
+    }
+
+    pub fn set_idle(&self, idle: bool) {
+        self.idle.store(idle, Ordering::Release);
+    }
+}
+
 /// Allows for interaction and control of the processor core/thread you're hosted on
 pub struct ThreadCtrlBlock {
     proc_id: u128,
@@ -18,4 +59,112 @@ impl ThreadCtrlBlock {
     pub fn proc_id(&self) -> u128 {
         self.proc_id
     }
-}
\ No newline at end of file
+}
+
+/// Record the hart ID of the bootstrap hart, and find every other hart in the MADT.
+/// Must be called after TLS is set up
+pub fn init_bsp() {
+    let hartid = SMP.response().unwrap().bsp_hartid as usize;
+
+    HART_ID.store(hartid, Ordering::Relaxed);
+
+    let madt = madt::Madt::get().expect("No MADT present");
+    let mut harts = HARTS.lock();
+
+    for entry in madt.iter() {
+        if let Some(rintc) = madt::RiscvIntController::from_entry(entry) {
+            if !rintc.enabled() {
+                continue;
+            }
+
+            let hart = alloc::boxed::Box::leak(alloc::boxed::Box::new(Hart {
+                hartid: rintc.hartid() as usize,
+                acpi_id: rintc.acpi_proc_id(),
+                imsic: match rintc.imsic_addr() {
+                    0 => None,
+                    addr => Some(addr as usize),
+                },
+                online: AtomicBool::new(rintc.hartid() as usize == hartid),
+                idle: AtomicBool::new(false),
+            }));
+
+            harts.insert(hart.hartid, hart);
+        }
+    }
+}
+
+/// Limine's `limine_smp_info` on RISC-V, written to start a hart
+#[repr(C)]
+#[allow(dead_code)]
+pub struct SmpInfo {
+    processor_id: u64,
+    hartid: u64,
+    reserved: u64,
+    goto_address: AtomicUsize,
+    extra_argument: u64,
+}
+
+/// Start every other hart Limine found, one at a time.
+/// Harts map their TLS and trap stack into the shared kernel page table as they start, which nothing else
+/// serializes, so each one is waited for before the next is started
+pub fn start_aps() {
+    let response = SMP.response().unwrap();
+    let bsp = hart_id() as u64;
+    let mut started = 0;
+
+    for cpu in response.cpus() {
+        let info = unsafe { &*(*cpu as *const _ as *const SmpInfo) };
+
+        if info.hartid == bsp {
+            continue;
+        }
+        let Some(hart) = hart(info.hartid as usize) else {
+            continue;
+        };
+
+        info.goto_address.store(crate::arch::boot::_ap_boot as usize, Ordering::SeqCst);
+
+        while !hart.online() {
+            core::hint::spin_loop();
+        }
+
+        started += 1;
+    }
+
+    crate::println!("{} harts online", started + 1);
+}
+
+/// Rust entry point of the other harts, after `_ap_boot`
+pub extern "C" fn ap_main(info: &SmpInfo) -> ! {
+    // Mappings made by the boot hart since Limine set up this one may be stale in the TLB
+    crate::arch::paging::sfence();
+
+    crate::parse_kern_file();
+    HART_ID.store(info.hartid as usize, Ordering::Relaxed);
+    crate::arch::trap::init_traps();
+    crate::dev::intc::init_ap();
+
+    let hart = hart(info.hartid as usize).expect("Started hart missing from the MADT");
+    hart.online.store(true, Ordering::Release);
+
+    crate::scheduler::yield_now();
+
+    loop {
+        crate::arch::utils::slow();
+    }
+}
+
+pub fn hart_id() -> usize {
+    HART_ID.load(Ordering::Relaxed)
+}
+
+// The scheduler and IPI handlers look harts up from interrupts, so the lock is only held with interrupts off
+
+pub fn hart(hartid: usize) -> Option<&'static Hart> {
+    without_interrupts(|| HARTS.lock().get(&hartid).copied())
+}
+
+/// Every hart currently running the kernel
+pub fn online_harts() -> Vec<&'static Hart> {
+    without_interrupts(|| HARTS.lock().values().filter(|hart| hart.online()).copied().collect())
+}
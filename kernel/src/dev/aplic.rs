@@ -1,30 +1,66 @@
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use alloc::collections::BTreeMap;
+use spin::Mutex;
+
+use crate::acpi::tables::madt;
+use crate::arch::trap::without_interrupts;
+use super::intc::Trigger;
+
 #[repr(C)]
 pub struct AplicDomain {
     domain_cfg: DomainCfg,
-    src_cfg: [u32; 1024],
+    /// Source 0 does not exist, so `src_cfg[0]` is the config for source 1
+    src_cfg: [u32; 1023],
+    _res0: [u8; 0xBC0],
     mmsi_addr_cfg: u32,
     mmsi_addr_cfgh: u32,
     smsi_addr_cfg: u32,
     smsi_addr_cfgh: u32,
+    _res1: [u8; 0x30],
     set_ip: [u32; 32],
+    _res2: [u8; 0x5C],
     set_ip_num: u32,
+    _res3: [u8; 0x20],
     in_clr_ip: [u32; 32],
+    _res4: [u8; 0x5C],
     clr_ip_num: u32,
+    _res5: [u8; 0x20],
     set_ie: [u32; 32],
+    _res6: [u8; 0x5C],
     set_ie_num: u32,
+    _res7: [u8; 0x20],
     clr_ie: [u32; 32],
+    _res8: [u8; 0x5C],
     clr_ie_num: u32,
+    _res9: [u8; 0x20],
     set_ip_num_le: u32,
     set_ip_num_be: libsa::endian::BigEndianU32,
+    _res10: [u8; 0xFF8],
     gen_msi: u32,
-    target: [u32; 1024],
+    /// Like `src_cfg`, `target[0]` belongs to source 1
+    target: [u32; 1023],
+    /// Only present in direct delivery mode
+    idc: [Idc; 0],
+}
+
+/// Interrupt delivery control, one exists for each hart in direct delivery mode
+#[repr(C)]
+pub struct Idc {
+    idelivery: u32,
+    iforce: u32,
+    ithreshold: u32,
+    _res: [u8; 0xC],
+    topi: u32,
+    claimi: u32,
 }
 
 bitfield::bitfield! {
     #[repr(transparent)]
+    #[derive(Clone, Copy)]
     pub struct DomainCfg(u32);
     impl Debug;
-    
+
     pub big_endian, set_big_endian: 0;
     pub msi, set_msi: 2;
     pub int_enable, set_int_enable: 8;
@@ -44,4 +80,185 @@ bitfield::bitfield! {
     pub src_mode, set_src_mode: 2, 0;
     pub child_idx, set_child_idx: 9, 0;
     pub d, _: 10;
-}
\ No newline at end of file
+}
+
+bitfield::bitfield! {
+    #[repr(transparent)]
+    pub struct Target(u32);
+    impl Debug;
+
+    /// Only valid in MSI mode
+    pub eiid, set_eiid: 10, 0;
+    /// Only valid in MSI mode
+    pub guest_idx, set_guest_idx: 17, 12;
+    /// Only valid in direct mode
+    pub priority, set_priority: 7, 0;
+    pub hart_idx, set_hart_idx: 31, 18;
+}
+
+impl From<Trigger> for SourceConfig {
+    fn from(value: Trigger) -> Self {
+        let mut cfg = SourceConfig(0);
+
+        cfg.set_src_mode(match value {
+            Trigger::EdgeRising => 4,
+            Trigger::EdgeFalling => 5,
+            Trigger::LevelHigh => 6,
+            Trigger::LevelLow => 7,
+        });
+
+        cfg
+    }
+}
+
+pub struct Aplic {
+    domain: *mut AplicDomain,
+    gsi_base: u32,
+    sources: u32,
+    /// Hart index of the current hart, used as the interrupt target
+    hart_idx: u32,
+    /// IDC of the current hart, only used in direct mode
+    idc: usize,
+    msi: bool,
+    /// Key: source
+    /// Value: IMSIC identity the source is delivered as
+    eiids: Mutex<BTreeMap<u32, u32>>,
+    enabled: AtomicU32,
+}
+
+unsafe impl Send for Aplic {}
+unsafe impl Sync for Aplic {}
+
+impl Aplic {
+    /// # Safety
+    /// The MADT entries must describe the supervisor level APLIC domain
+    pub unsafe fn new(aplic: &madt::Aplic, rintc: &madt::RiscvIntController) -> Self {
+        let domain: *mut AplicDomain = crate::mem::PhysicalAddress::new(aplic.addr() as usize).to_virt().to_mut_ptr();
+
+        let (msi, hart_idx) = match super::imsic::get() {
+            Some(imsic) => (true, imsic.hart_idx()),
+            None => (false, rintc.ext_int_id().idc_id()),
+        };
+
+        Self {
+            domain,
+            gsi_base: aplic.int_base(),
+            sources: aplic.ext_ints() as u32,
+            hart_idx,
+            idc: rintc.ext_int_id().idc_id() as usize,
+            msi,
+            eiids: Mutex::new(BTreeMap::new()),
+            enabled: AtomicU32::new(0),
+        }
+    }
+
+    pub fn init(&self) {
+        unsafe {
+            // Inactivate every source until a handler asks for it
+            for source in 1..=self.sources {
+                self.src_cfg(source).write_volatile(0);
+            }
+
+            let mut cfg = DomainCfg(0);
+            cfg.set_msi(self.msi);
+            cfg.set_int_enable(true);
+            core::ptr::addr_of_mut!((*self.domain).domain_cfg).write_volatile(cfg);
+
+            if !self.msi {
+                let idc = self.idc_ptr();
+
+                core::ptr::addr_of_mut!((*idc).ithreshold).write_volatile(0);
+                core::ptr::addr_of_mut!((*idc).idelivery).write_volatile(1);
+            }
+        }
+    }
+
+    unsafe fn src_cfg(&self, source: u32) -> *mut u32 {
+        core::ptr::addr_of_mut!((*self.domain).src_cfg[source as usize - 1])
+    }
+
+    unsafe fn target(&self, source: u32) -> *mut u32 {
+        core::ptr::addr_of_mut!((*self.domain).target[source as usize - 1])
+    }
+
+    unsafe fn idc_ptr(&self) -> *mut Idc {
+        core::ptr::addr_of_mut!((*self.domain).idc).cast::<Idc>().add(self.idc)
+    }
+}
+
+impl super::intc::IntController for Aplic {
+    fn name(&self) -> &'static str {
+        if self.msi {
+            "APLIC (MSI mode)"
+        } else {
+            "APLIC (direct mode)"
+        }
+    }
+
+    fn gsi_base(&self) -> u32 {
+        self.gsi_base
+    }
+
+    fn sources(&self) -> u32 {
+        self.sources
+    }
+
+    fn enable(&self, source: u32, trigger: Trigger) {
+        let mut target = Target(0);
+        target.set_hart_idx(self.hart_idx);
+
+        if self.msi {
+            let imsic = super::imsic::get().unwrap();
+            let eiid = without_interrupts(|| *self.eiids.lock().entry(source).or_insert_with(|| {
+                imsic.alloc_id().expect("Ran out of IMSIC identities")
+            }));
+
+            imsic.set_vector(eiid, super::intc::dispatch, self.gsi_base + source);
+            target.set_eiid(eiid);
+        } else {
+            target.set_priority(1);
+        }
+
+        unsafe {
+            self.src_cfg(source).write_volatile(SourceConfig::from(trigger).0);
+            self.target(source).write_volatile(target.0);
+            core::ptr::addr_of_mut!((*self.domain).set_ie_num).write_volatile(source);
+        }
+
+        self.enabled.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn disable(&self, source: u32) {
+        unsafe {
+            core::ptr::addr_of_mut!((*self.domain).clr_ie_num).write_volatile(source);
+        }
+
+        if self.msi {
+            if let Some(eiid) = without_interrupts(|| self.eiids.lock().get(&source).copied()) {
+                super::imsic::get().unwrap().disable(eiid);
+            }
+        }
+
+        self.enabled.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    fn claim(&self) -> Option<u32> {
+        if self.msi {
+            return None;
+        }
+
+        // Reading claimi also clears the pending bit of the source
+        let claim = unsafe {
+            core::ptr::addr_of!((*self.idc_ptr()).claimi).read_volatile()
+        };
+
+        match claim >> 16 {
+            0 => None,
+            source => Some(source),
+        }
+    }
+
+    fn complete(&self, _source: u32) {
+        // Nothing to do, claiming already acknowledged the interrupt
+    }
+}
@@ -0,0 +1,372 @@
+// Block buffer cache.
+// Buffers hold one block each, keyed by disk ID and LBA, in frames taken from `mem::PHYS`. Blocks smaller than a page
+// share frames. Writes only mark buffers dirty, the flusher thread writes them back periodically. Swap I/O bypasses
+// the cache, so evicting buffers under memory pressure never needs to swap.
+
+use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use spin::Mutex;
+
+use super::{DiskError, Op, Request, Segment};
+
+/// Most buffers kept before clean ones are evicted to make room
+const MAX_BUFFERS: usize = 1024;
+
+/// How often the flusher writes back dirty buffers
+const FLUSH_INTERVAL_NS: u128 = 5 * crate::time::NS_PER_SEC;
+
+/// Key: disk ID, LBA
+static BUFFERS: Mutex<BTreeMap<(usize, usize), Arc<Buffer>>> = Mutex::new(BTreeMap::new());
+
+/// Bumped on every access, orders buffers for eviction
+static CLOCK: AtomicU64 = AtomicU64::new(0);
+
+const PAGE_SIZE: usize = 0x1000;
+
+/// Frames split into slots for blocks smaller than a page
+static SLABS: Mutex<Slabs> = Mutex::new(Slabs {
+    free: BTreeMap::new(),
+    used: BTreeMap::new(),
+});
+
+struct Slabs {
+    /// Key: block size
+    /// Value: free slots
+    free: BTreeMap<usize, Vec<usize>>,
+    /// Key: frame
+    /// Value: slots of it in use
+    used: BTreeMap<usize, usize>,
+}
+
+/// Physical memory for a block of `len` bytes
+fn alloc_block(len: usize) -> usize {
+    if len >= PAGE_SIZE {
+        return crate::mem::swap::alloc_frame(len.next_multiple_of(PAGE_SIZE));
+    }
+
+    if let Some(slot) = take_slot(len) {
+        return slot;
+    }
+
+    // Not under the lock, getting a frame may evict buffers and free their slots
+    let frame = crate::mem::swap::alloc_frame(PAGE_SIZE);
+
+    let mut slabs = SLABS.lock();
+    slabs.used.insert(frame, 1);
+    slabs.free.entry(len).or_default().extend((len..PAGE_SIZE).step_by(len).map(|offset| frame + offset));
+
+    frame
+}
+
+fn take_slot(len: usize) -> Option<usize> {
+    let mut slabs = SLABS.lock();
+    let slot = slabs.free.get_mut(&len)?.pop()?;
+
+    *slabs.used.get_mut(&(slot & !(PAGE_SIZE - 1))).unwrap() += 1;
+
+    Some(slot)
+}
+
+/// Give back memory from `alloc_block`, frames are freed once none of their slots are in use
+fn free_block(phys: usize, len: usize) {
+    if len >= PAGE_SIZE {
+        unsafe {crate::mem::PHYS.free(phys, len.next_multiple_of(PAGE_SIZE))};
+        return;
+    }
+
+    let frame = phys & !(PAGE_SIZE - 1);
+    let mut slabs = SLABS.lock();
+    let used = slabs.used.get_mut(&frame).unwrap();
+
+    *used -= 1;
+
+    if *used == 0 {
+        slabs.used.remove(&frame);
+        slabs.free.get_mut(&len).unwrap().retain(|slot| *slot & !(PAGE_SIZE - 1) != frame);
+        drop(slabs);
+
+        unsafe {crate::mem::PHYS.free(frame, PAGE_SIZE)};
+    } else {
+        slabs.free.get_mut(&len).unwrap().push(phys);
+    }
+}
+
+/// Memory a block of `len` bytes takes up in the cache
+fn footprint(len: usize) -> usize {
+    if len >= PAGE_SIZE {
+        len.next_multiple_of(PAGE_SIZE)
+    } else {
+        len
+    }
+}
+
+pub struct Buffer {
+    disk: usize,
+    lba: usize,
+    phys: usize,
+    len: usize,
+    /// Held while the data is read or written
+    lock: Mutex<()>,
+    dirty: AtomicBool,
+    last_used: AtomicU64,
+}
+
+impl Buffer {
+    pub fn disk(&self) -> usize {
+        self.disk
+    }
+
+    pub fn lba(&self) -> usize {
+        self.lba
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        self.dirty.load(Ordering::Relaxed)
+    }
+
+    fn ptr(&self) -> *mut u8 {
+        crate::mem::PhysicalAddress::new(self.phys).to_virt().to_mut_ptr()
+    }
+
+    fn touch(&self) {
+        self.last_used.store(CLOCK.fetch_add(1, Ordering::Relaxed), Ordering::Relaxed);
+    }
+
+    /// Run `f` on the block's data
+    pub fn read<R>(&self, f: impl FnOnce(&[u8]) -> R) -> R {
+        let _guard = self.lock.lock();
+        self.touch();
+
+        f(unsafe {core::slice::from_raw_parts(self.ptr(), self.len)})
+    }
+
+    /// Run `f` on the block's data, and mark it dirty
+    pub fn write<R>(&self, f: impl FnOnce(&mut [u8]) -> R) -> R {
+        let _guard = self.lock.lock();
+        self.touch();
+        self.dirty.store(true, Ordering::Relaxed);
+
+        f(unsafe {core::slice::from_raw_parts_mut(self.ptr(), self.len)})
+    }
+}
+
+impl Drop for Buffer {
+    fn drop(&mut self) {
+        free_block(self.phys, self.len);
+    }
+}
+
+/// Signalled by the flush timer
+static FLUSH: crate::scheduler::Event = crate::scheduler::Event::new();
+
+/// Start the flusher thread, and the timer that wakes it
+pub fn init() {
+    crate::scheduler::spawn_kernel_thread(flusher, 2);
+    crate::time::add_periodic(FLUSH_INTERVAL_NS, |_| FLUSH.signal(), 0);
+}
+
+fn flusher() -> ! {
+    loop {
+        FLUSH.wait();
+
+        if let Err(err) = sync(None) {
+            crate::println!("Buffer cache writeback failed: {:?}", err);
+        }
+    }
+}
+
+/// The buffer for `lba` of `disk`, read from the disk if it isn't cached
+pub fn get(disk: usize, lba: usize) -> Result<Arc<Buffer>, DiskError> {
+    if let Some(buffer) = BUFFERS.lock().get(&(disk, lba)) {
+        buffer.touch();
+        return Ok(buffer.clone());
+    }
+
+    let queue = super::disk(disk).ok_or(DiskError::NoDisk)?;
+    let buffer = new_buffer(disk, lba, queue.blocksize());
+
+    let request = Request::new(Op::Read, lba, 1, alloc::vec![Segment::new(buffer.phys, buffer.len)]);
+
+    // Nothing else can see the buffer until it's inserted
+    unsafe {queue.submit(request)}?.wait()?;
+
+    // Someone else may have read the same block meanwhile, theirs wins
+    let mut buffers = BUFFERS.lock();
+    Ok(buffers.entry((disk, lba)).or_insert(buffer).clone())
+}
+
+/// A buffer for `lba` of `disk` that isn't in the cache yet, holding whatever was in its memory
+fn new_buffer(disk: usize, lba: usize, len: usize) -> Arc<Buffer> {
+    if BUFFERS.lock().len() >= MAX_BUFFERS {
+        shrink(MAX_BUFFERS / 8 * footprint(len));
+    }
+
+    let buffer = Arc::new(Buffer {
+        disk,
+        lba,
+        phys: alloc_block(len),
+        len,
+        lock: Mutex::new(()),
+        dirty: AtomicBool::new(false),
+        last_used: AtomicU64::new(0),
+    });
+    buffer.touch();
+
+    buffer
+}
+
+/// Replace all of block `lba` of `disk` with `data`, without reading it first if it isn't cached
+fn overwrite(disk: usize, data: &[u8], lba: usize) {
+    let cached = BUFFERS.lock().get(&(disk, lba)).cloned();
+
+    if let Some(buffer) = cached {
+        buffer.write(|buf| buf.copy_from_slice(data));
+        return;
+    }
+
+    // Filled before it's inserted, so nobody sees what was in its memory
+    let buffer = new_buffer(disk, lba, data.len());
+    buffer.write(|buf| buf.copy_from_slice(data));
+
+    // Someone else may have cached the same block meanwhile, theirs is kept and written instead
+    let cached = BUFFERS.lock().entry((disk, lba)).or_insert(buffer.clone()).clone();
+
+    if !Arc::ptr_eq(&cached, &buffer) {
+        cached.write(|buf| buf.copy_from_slice(data));
+    }
+}
+
+/// Read `buffer.len()` bytes starting at `lba` of `disk` through the cache
+pub fn read(disk: usize, buffer: &mut [u8], lba: usize) -> Result<(), DiskError> {
+    let blocksize = super::disk(disk).ok_or(DiskError::NoDisk)?.blocksize();
+
+    for (i, chunk) in buffer.chunks_mut(blocksize).enumerate() {
+        get(disk, lba + i)?.read(|data| chunk.copy_from_slice(&data[..chunk.len()]));
+    }
+
+    Ok(())
+}
+
+/// Write `data` starting at `lba` of `disk` through the cache, a partial last block keeps the rest of its data
+pub fn write(disk: usize, data: &[u8], lba: usize) -> Result<(), DiskError> {
+    let blocksize = super::disk(disk).ok_or(DiskError::NoDisk)?.blocksize();
+
+    for (i, chunk) in data.chunks(blocksize).enumerate() {
+        if chunk.len() == blocksize {
+            overwrite(disk, chunk, lba + i);
+        } else {
+            get(disk, lba + i)?.write(|buf| buf[..chunk.len()].copy_from_slice(chunk));
+        }
+    }
+
+    Ok(())
+}
+
+/// Write back every dirty buffer of `disk`, or of every disk, then flush the disks written to
+pub fn sync(disk: Option<usize>) -> Result<(), DiskError> {
+    let dirty: Vec<Arc<Buffer>> = BUFFERS.lock()
+        .values()
+        .filter(|buffer| buffer.is_dirty() && disk.is_none_or(|disk| buffer.disk == disk))
+        .cloned()
+        .collect();
+
+    write_back(&dirty)
+}
+
+/// Submit writes for `buffers` all at once so the queues can merge them, then wait for every one
+fn write_back(buffers: &[Arc<Buffer>]) -> Result<(), DiskError> {
+    let mut handles = Vec::new();
+    let mut disks = Vec::new();
+    let mut result = Ok(());
+
+    for buffer in buffers {
+        let Some(queue) = super::disk(buffer.disk) else {
+            continue;
+        };
+
+        // Held until the write finishes, so the data can't change underneath it
+        let guard = buffer.lock.lock();
+        buffer.dirty.store(false, Ordering::Relaxed);
+
+        let request = Request::new(Op::Write, buffer.lba, 1, alloc::vec![Segment::new(buffer.phys, buffer.len)]);
+
+        match unsafe {queue.submit(request)} {
+            Ok(handle) => handles.push((buffer, guard, handle)),
+            Err(err) => {
+                buffer.dirty.store(true, Ordering::Relaxed);
+                result = Err(err);
+            },
+        }
+
+        if !disks.contains(&buffer.disk) {
+            disks.push(buffer.disk);
+        }
+    }
+
+    for (buffer, guard, handle) in handles {
+        if let Err(err) = handle.wait() {
+            buffer.dirty.store(true, Ordering::Relaxed);
+            result = Err(err);
+        }
+
+        drop(guard);
+    }
+
+    for disk in disks {
+        if let Some(queue) = super::disk(disk) {
+            queue.flush()?;
+        }
+    }
+
+    result
+}
+
+/// Drop cached blocks of `disk` without writing them back, for blocks that were discarded or written around the cache
+pub fn invalidate(disk: usize, lba: usize, blocks: usize) {
+    BUFFERS.lock().retain(|(d, l), _| *d != disk || !(lba..lba + blocks).contains(l));
+}
+
+/// Evict least recently used buffers nobody holds until at least `bytes` bytes are freed, writing back dirty ones.
+/// Returns the number of bytes freed
+pub fn shrink(bytes: usize) -> usize {
+    let mut candidates: Vec<Arc<Buffer>> = BUFFERS.lock()
+        .values()
+        .filter(|buffer| Arc::strong_count(buffer) == 1)
+        .cloned()
+        .collect();
+
+    candidates.sort_by_key(|buffer| buffer.last_used.load(Ordering::Relaxed));
+
+    let mut victims = Vec::new();
+    let mut freed = 0;
+
+    for buffer in candidates {
+        if freed >= bytes {
+            break;
+        }
+
+        freed += footprint(buffer.len);
+        victims.push(buffer);
+    }
+
+    let dirty: Vec<Arc<Buffer>> = victims.iter().filter(|buffer| buffer.is_dirty()).cloned().collect();
+
+    if let Err(err) = write_back(&dirty) {
+        crate::println!("Buffer cache writeback failed while shrinking: {:?}", err);
+    }
+
+    drop(dirty);
+
+    let mut buffers = BUFFERS.lock();
+    freed = 0;
+
+    for buffer in victims {
+        // Only the map and `victims` hold it, and it's clean
+        if Arc::strong_count(&buffer) == 2 && !buffer.is_dirty() {
+            buffers.remove(&(buffer.disk, buffer.lba));
+            freed += footprint(buffer.len);
+        }
+    }
+
+    freed
+}
@@ -1,134 +1,176 @@
+// GUID partition tables.
+// The primary header is at LBA 1 and the backup at the end of the disk, both must pass their CRC32 checks, as must
+// the partition entry array they point to. The backup is only used when the primary is damaged.
+// Tables are edited and written back by `writer`.
+
+use alloc::vec::Vec;
+
+pub use structs::{GPTHeader, GPTPart, GPTPartType, Guid};
+pub use writer::GptError;
+
+use crate::println;
+use super::{DiskError, DiskQueue};
+
 mod structs;
+mod writer;
+
+/// A partition table read from a disk
+pub struct Gpt {
+    pub header: GPTHeader,
+    /// Every entry in the table, used or not
+    pub parts: Vec<GPTPart>,
+}
 
-pub fn init_disk_gpt(disk: &mut dyn super::Disk) {
-    let mut block = [0; 512];
+impl Gpt {
+    /// Entries that describe a partition, with their index in the table
+    pub fn used(&self) -> impl Iterator<Item = (usize, &GPTPart)> {
+        self.parts.iter().enumerate().filter(|(_, part)| part.is_used())
+    }
+}
 
-    disk.read(&mut block, 0);
+/// Read a header from `lba`, returns it if it's valid and describes itself as being at `lba`
+fn read_header(disk: &'static DiskQueue, lba: usize) -> Result<Option<GPTHeader>, DiskError> {
+    let blocksize = disk.blocksize();
+    let mut block = alloc::vec![0; blocksize];
 
-    // Set MBR
-    if !((block[510] == 0x55) && (block[511] == 0xAA)) {
-        for byte in block[0..512].iter_mut() {
-            *byte = 0;
-        }
+    disk.read(&mut block, lba)?;
+
+    let header = GPTHeader::from_bytes(&block);
+    let size = header.size as usize;
+
+    if header.sig != GPTHeader::SIG || !(GPTHeader::SIZE..=blocksize).contains(&size) {
+        return Ok(None);
+    }
 
-        let log_blocks = disk.blocks();
-
-        let log_end_blk = if log_blocks >= 0xffffffff {
-            [0xff_u8; 4]
-        } else {
-            let le_bytes = log_blocks.to_le_bytes();
-
-            [
-                le_bytes[0],
-                le_bytes[1],
-                le_bytes[2],
-                le_bytes[3],
-            ]
-        };
-
-        let chs_blocks = disk.chs_end();
-
-        let chs_end_blk = if chs_blocks == 0xffffffff {
-            [0xff_u8; 3]
-        } else {
-            let le_bytes = chs_blocks.to_le_bytes();
-
-            [
-                le_bytes[0],
-                le_bytes[1],
-                le_bytes[2],
-            ]
-        };
-
-        // Boot indicator is already 0
-
-        // Start CHS
-        //block[447] = 0x0;
-        block[448] = 0x02;
-        //block[449] = 0x0;
-
-        // OS type
-        block[450] = 0xEE;
-
-        // End CHS
-        block[451] = chs_end_blk[0];
-        block[452] = chs_end_blk[1];
-        block[453] = chs_end_blk[2];
-
-        // Start LBA
-        block[454] = 0x01;
-        //block[455] = 0x00;
-        //block[456] = 0x00;
-        //block[457] = 0x00;
-
-        // End LBA
-        block[458] = log_end_blk[0];
-        block[459] = log_end_blk[1];
-        block[460] = log_end_blk[2];
-        block[461] = log_end_blk[3];
-
-        // MBR Magic number
-        block[510] = 0x55;
-        block[511] = 0xAA;
-
-        disk.write(&block, 0);
+    // The CRC covers the whole header as it was written, including anything past the fields we know
+    block[16..20].fill(0);
+
+    if crc32(&block[..size]) != header.crc32 || header.host_lba != lba as u64 {
+        return Ok(None);
+    }
+
+    // Entries are 128 bytes times a power of two
+    let part_size = header.part_size as usize;
+    if part_size < GPTPart::SIZE || !(part_size / GPTPart::SIZE).is_power_of_two() || part_size % GPTPart::SIZE != 0 {
+        return Ok(None);
     }
 
-    let mut part = structs::GPTPart::default();
-    part.start_lba = 4;
-    part.end_lba = (disk.blocks() - 1) as u64;
-    part.type_guid = structs::GPTPartType::SwapPart;
-    part.unique_guid = 0xc31345c1bbe82ca639519f54bed57569;
-    part.set_name("GENT SWAP PART");
-
-    let mut header = structs::GPTHeader::default();
-    header.host_lba = 1;
-    header.alt_lba = 2;
-    header.first_lba = 4;
-    header.last_lba = (disk.blocks() - 1) as u64;
-    header.disk_guid = 0x052428fae046c21b4c7fcff4d186f5d4;
-    header.part_lba = 3;
-    header.part_num = 1;
-    header.part_checksum = part.crc32();
-    header.crc32 = header.crc32();
-
-    gpt_header(
-        disk, 
-        header
-    );
-
-    gpt_part(
-        disk, 
-        part,
-        3
-    );
+    Ok(Some(header))
 }
 
-#[inline(always)]
-fn gpt_part(
-    disk: &mut dyn super::Disk,
-    part: structs::GPTPart,
-    lba: usize
-) {
-    let array: [u8; 128] = unsafe {
-        core::mem::transmute(part)
+/// Largest entry array we read, far more than the 16KiB tables are normally given
+const MAX_ARRAY_SIZE: usize = 0x10_0000;
+
+/// Read the entry array a header points to, returns it if its CRC matches
+fn read_parts(disk: &'static DiskQueue, header: &GPTHeader) -> Result<Option<Vec<GPTPart>>, DiskError> {
+    // The header's CRC only proves it wasn't damaged, not that its values are sane
+    let len = match (header.part_num as usize).checked_mul(header.part_size as usize) {
+        Some(len) if len <= MAX_ARRAY_SIZE => len,
+        _ => return Ok(None),
     };
-    disk.write(&array, lba)
+
+    let end = (header.part_lba as usize).checked_add(len.div_ceil(disk.blocksize()));
+
+    if end.is_none_or(|end| end > disk.blocks()) {
+        return Ok(None);
+    }
+
+    let mut array = alloc::vec![0; len];
+    disk.read(&mut array, header.part_lba as usize)?;
+
+    if crc32(&array) != header.part_checksum {
+        return Ok(None);
+    }
+
+    Ok(Some(array.chunks(header.part_size as usize).map(GPTPart::from_bytes).collect()))
+}
+
+/// Read the partition table of `disk`, falling back to the backup table if the primary is damaged.
+/// Returns `None` if the disk has no valid GPT
+pub fn read(disk: &'static DiskQueue) -> Result<Option<Gpt>, DiskError> {
+    if disk.blocks() < 3 {
+        return Ok(None);
+    }
+
+    let primary = read_header(disk, 1)?;
+
+    if let Some(header) = primary {
+        if let Some(parts) = read_parts(disk, &header)? {
+            return Ok(Some(Gpt { header, parts }));
+        }
+    }
+
+    // A damaged primary header can't be trusted to say where the backup is
+    let last = disk.blocks() - 1;
+    let alt = primary.map_or(last, |header| header.alt_lba as usize);
+
+    for lba in [alt, last] {
+        if lba >= disk.blocks() {
+            continue;
+        }
+
+        if let Some(header) = read_header(disk, lba)? {
+            if let Some(parts) = read_parts(disk, &header)? {
+                println!("GPT: primary table is damaged, using the backup at LBA {}", lba);
+                return Ok(Some(Gpt { header, parts }));
+            }
+        }
+    }
+
+    Ok(None)
 }
 
-#[inline(always)]
-#[allow(clippy::too_many_arguments)]
-fn gpt_header(
-    disk: &mut dyn super::Disk, 
-    header: structs::GPTHeader,
-) {
-    let array: [u8; 112] = unsafe {
-        core::mem::transmute(header)
+/// Register every partition in the GPT of disk `id`, returns the IDs of the partitions
+pub fn scan(id: usize) -> Result<Vec<usize>, DiskError> {
+    let disk = super::disk(id).ok_or(DiskError::NoDisk)?;
+
+    let Some(gpt) = read(disk)? else {
+        return Ok(Vec::new());
     };
-    disk.write(&array, header.host_lba as usize);
+
+    let mut ids = Vec::new();
+
+    for (index, part) in gpt.used() {
+        if part.end_lba < part.start_lba || part.end_lba as usize >= disk.blocks() {
+            println!("GPT: disk {} entry {} is out of range, skipping", id, index);
+            continue;
+        }
+
+        let name = part.name();
+
+        println!(
+            "GPT: disk {} partition {} \"{}\" type {} LBA {}..={}",
+            id,
+            index,
+            name,
+            part.type_guid,
+            part.start_lba,
+            part.end_lba
+        );
+
+        let partition = super::Partition::new(
+            id,
+            part.start_lba as usize,
+            (part.end_lba - part.start_lba + 1) as usize,
+            disk.blocksize(),
+        ).with_info(part.type_guid, name);
+
+        ids.push(super::register_partition(partition));
+    }
+
+    Ok(ids)
 }
 
+/// Overwrite disk `id` with a new GPT holding a single swap partition over every usable block
+pub fn format(id: usize) -> Result<(), GptError> {
+    let disk = super::disk(id).ok_or(DiskError::NoDisk)?;
 
+    let mut gpt = Gpt::new(disk.blocks(), disk.blocksize(), Guid::generate())?;
+    let (first, last) = (gpt.header.first_lba, gpt.header.last_lba);
+
+    gpt.add(GPTPartType::SWAP_PART, Guid::generate(), first, last, "GENT SWAP PART")?;
+    gpt.write(id)
+}
 
 fn crc32_compute_table() -> [u32; 256] {
     let mut crc32_table = [0; 256];
@@ -136,7 +178,8 @@ fn crc32_compute_table() -> [u32; 256] {
     for n in 0..256 {
         crc32_table[n as usize] = (0..8).fold(n as u32, |acc, _| {
             match acc & 1 {
-                1 => 0x04c11db7 ^ (acc >> 1),
+                // Reflected form of the polynomial 0x04C11DB7, as bits are processed LSB first
+                1 => 0xEDB88320 ^ (acc >> 1),
                 _ => acc >> 1,
             }
         });
@@ -145,10 +188,10 @@ fn crc32_compute_table() -> [u32; 256] {
     crc32_table
 }
 
-fn crc32(buf: &[u8]) -> u32 {
+pub fn crc32(buf: &[u8]) -> u32 {
     let crc_table = crc32_compute_table();
 
     !buf.iter().fold(!0, |acc, octet| {
         (acc >> 8) ^ crc_table[((acc & 0xff) ^ *octet as u32) as usize]
     })
-}
\ No newline at end of file
+}
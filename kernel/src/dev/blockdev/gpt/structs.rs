@@ -1,39 +1,145 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// A GUID as stored on disk, the first three fields are little endian
+#[repr(transparent)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Guid(pub [u8; 16]);
+
+impl Guid {
+    pub const UNUSED: Guid = Guid([0; 16]);
+
+    /// Build a GUID from its usual text form, `0x12345678_9abc_def0_1234_56789abcdef0`
+    pub const fn from_u128(val: u128) -> Self {
+        let be = val.to_be_bytes();
+
+        Guid([
+            be[3], be[2], be[1], be[0],
+            be[5], be[4],
+            be[7], be[6],
+            be[8], be[9], be[10], be[11], be[12], be[13], be[14], be[15],
+        ])
+    }
+
+    pub fn is_unused(&self) -> bool {
+        *self == Self::UNUSED
+    }
+
+    /// A version 4 GUID. There's no entropy source, so the bits come from the clocks and a counter
+    pub fn generate() -> Self {
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+        let mut state = crate::time::monotonic_ns() as u64
+            ^ (crate::time::wall::unix_ns().unwrap_or(0) as u64).rotate_left(32)
+            ^ COUNTER.fetch_add(1, Ordering::Relaxed).wrapping_mul(0x9E3779B97F4A7C15);
+
+        let mut bytes = [0; 16];
+
+        for chunk in bytes.chunks_mut(8) {
+            chunk.copy_from_slice(&splitmix64(&mut state).to_le_bytes());
+        }
+
+        // Version 4 in the top of the third field, variant 1 in the top of the fourth
+        bytes[7] = (bytes[7] & 0x0f) | 0x40;
+        bytes[8] = (bytes[8] & 0x3f) | 0x80;
+
+        Guid(bytes)
+    }
+}
+
+impl core::fmt::Display for Guid {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let b = &self.0;
+
+        write!(
+            f,
+            "{:02X}{:02X}{:02X}{:02X}-{:02X}{:02X}-{:02X}{:02X}-{:02X}{:02X}-{:02X}{:02X}{:02X}{:02X}{:02X}{:02X}",
+            b[3], b[2], b[1], b[0], b[5], b[4], b[7], b[6], b[8], b[9], b[10], b[11], b[12], b[13], b[14], b[15]
+        )
+    }
+}
+
+fn splitmix64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9E3779B97F4A7C15);
+
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+    z ^ (z >> 31)
+}
+
+pub struct GPTPartType;
+
+impl GPTPartType {
+    pub const UNUSED: Guid = Guid::UNUSED;
+    pub const EFI_SYS_PART: Guid = Guid::from_u128(0xC12A7328_F81F_11D2_BA4B_00A0C93EC93B);
+    pub const MBR_PART: Guid = Guid::from_u128(0x024DEE41_33E7_11D3_9D69_0008C781F39F);
+    pub const BASIC_DATA_PART: Guid = Guid::from_u128(0xEBD0A0A2_B9E5_4433_87C0_68B6B72699C7);
+    pub const GENERAL_PART: Guid = Guid::from_u128(0x0FC63DAF_8483_4772_8E79_3D69D8477DE4);
+    /// GeNT's own swap type, only partitions GeNT formatted have it.
+    /// Swap of other systems is never used automatically, it may hold their hibernation image
+    pub const SWAP_PART: Guid = Guid::from_u128(0x33766656_EA73_6A11_DDF8_B05435CC0685);
+    pub const LINUX_SWAP_PART: Guid = Guid::from_u128(0x0657FD6D_A4AB_43C4_84E5_0933C84B4F4F);
+}
+
+/// A partition entry, entries can be larger than this but the rest is reserved
 #[repr(C)]
 #[derive(Clone, Copy)]
 pub struct GPTPart {
-    pub type_guid: GPTPartType,
-    pub unique_guid: u128,
+    pub type_guid: Guid,
+    pub unique_guid: Guid,
     pub start_lba: u64,
+    /// Inclusive
     pub end_lba: u64,
     pub attributes: u64,
-    part_name: [u8; 72],
+    /// UTF-16LE, padded with zeroes
+    part_name: [u16; 36],
 }
 
 impl GPTPart {
-    pub fn set_name(&mut self, name: &str) {
-        for (index, character) in name.bytes().enumerate() {
-            self.part_name[index] = character;
-        }
+    pub const SIZE: usize = 128;
+
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        assert!(bytes.len() >= Self::SIZE);
+
+        unsafe {(bytes.as_ptr() as *const Self).read_unaligned()}
     }
 
-    pub fn crc32(&self) -> u32 {
-        let array: [u8; 128] = unsafe {
-            core::mem::transmute(*self)
-        };
+    pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+        unsafe {core::mem::transmute(*self)}
+    }
 
-        super::crc32(&array)
+    pub fn is_used(&self) -> bool {
+        !self.type_guid.is_unused()
+    }
+
+    pub fn name(&self) -> alloc::string::String {
+        let name = self.part_name;
+        let len = name.iter().position(|c| *c == 0).unwrap_or(name.len());
+
+        char::decode_utf16(name[..len].iter().copied())
+            .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+            .collect()
+    }
+
+    /// Set the name, anything past 36 UTF-16 code units is cut off
+    pub fn set_name(&mut self, name: &str) {
+        self.part_name = [0; 36];
+
+        for (unit, c) in self.part_name.iter_mut().zip(name.encode_utf16()) {
+            *unit = c;
+        }
     }
 }
 
 impl Default for GPTPart {
     fn default() -> Self {
         Self {
-            type_guid: GPTPartType::Unused,
-            unique_guid: 0,
+            type_guid: GPTPartType::UNUSED,
+            unique_guid: Guid::UNUSED,
             start_lba: 0,
             end_lba: 0,
             attributes: 0,
-            part_name: [0; 72]
+            part_name: [0; 36]
         }
     }
 }
@@ -42,12 +148,12 @@ impl Default for GPTPart {
 #[derive(Clone, Copy)]
 pub struct GPTHeader {
     /// Signature identifying a GPT Header, should be an ascii string "EFI PART”
-    sig: [u8; 8],
+    pub sig: [u8; 8],
     /// Revision, should be `0x00010000`
-    rev: u32,
-    /// Size of the GPT Header, must be 112
-    size: u32,
-    /// CRC32 checksum, calculate using `crc32_compute_table`
+    pub rev: u32,
+    /// Size of the GPT Header, at least 92
+    pub size: u32,
+    /// CRC32 of the first `size` bytes, with this field zeroed
     pub crc32: u32,
     /// Reserved, must be 0
     _res0: u32,
@@ -60,59 +166,63 @@ pub struct GPTHeader {
     /// Last partition usable LBA
     pub last_lba: u64,
     /// Unique ID for the disk
-    pub disk_guid: u128,
-    /// Starting partition for partition entry array
+    pub disk_guid: Guid,
+    /// Starting LBA of the partition entry array
     pub part_lba: u64,
-    /// Number of partitions
+    /// Number of partition entries
     pub part_num: u32,
-    /// Size of each partition entry, must be 128
-    part_size: u32,
+    /// Size of each partition entry, 128 times a power of 2
+    pub part_size: u32,
     /// Checksum of partition entry array
     pub part_checksum: u32,
-    /// Padding
-    _res1: u32,
 }
 
 impl GPTHeader {
-    pub fn crc32(&self) -> u32 {
-        let array: [u8; 112] = unsafe {
-            core::mem::transmute(*self)
+    pub const SIG: [u8; 8] = *b"EFI PART";
+    /// Bytes actually defined, the rest of the block is reserved
+    pub const SIZE: usize = 92;
+
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        assert!(bytes.len() >= core::mem::size_of::<Self>());
+
+        unsafe {(bytes.as_ptr() as *const Self).read_unaligned()}
+    }
+
+    pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+        let mut bytes = [0; Self::SIZE];
+        let raw = unsafe {
+            core::slice::from_raw_parts(self as *const Self as *const u8, Self::SIZE)
         };
 
-        super::crc32(&array)
+        bytes.copy_from_slice(raw);
+        bytes
+    }
+
+    pub fn crc32(&self) -> u32 {
+        let mut header = *self;
+        header.crc32 = 0;
+
+        super::crc32(&header.to_bytes())
     }
 }
 
 impl Default for GPTHeader {
     fn default() -> Self {
-        const GPT_SIG: &[u8; 8] = b"EFI PART";
-
         Self {
-            sig: *GPT_SIG,
+            sig: Self::SIG,
             rev: 0x00010000,
-            size: 112,
+            size: Self::SIZE as u32,
             crc32: 0,
             _res0: 0,
             host_lba: 0,
             alt_lba: 0,
             first_lba: 0,
             last_lba: 0,
-            disk_guid: 0,
+            disk_guid: Guid::UNUSED,
             part_lba: 0,
             part_num: 0,
-            part_size: 128,
+            part_size: GPTPart::SIZE as u32,
             part_checksum: 0,
-            _res1: 0
         }
     }
 }
-
-#[repr(u128)]
-#[derive(Clone, Copy)]
-pub enum GPTPartType {
-    Unused = 0x00,
-    EFISysPart = 0xC12A7328F81F11D2BA4B00A0C93EC93B,
-    MBRPart = 0x024DEE4133E711D39D690008C781F39F,
-    GeneralPart = 0x750f1f42f93f12ee1227e55c10bb3b96,
-    SwapPart = 0x33766656ea736a11ddf8b05435cc0685,
-}
\ No newline at end of file
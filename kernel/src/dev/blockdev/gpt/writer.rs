@@ -0,0 +1,232 @@
+// Creating and editing GPTs.
+// Changes are made to a `Gpt` in memory, `write` then lays out the protective MBR, the primary header and entry array
+// after it, and the backup entry array and header at the end of the disk, with every CRC recomputed.
+
+use alloc::vec::Vec;
+
+use super::{crc32, GPTHeader, GPTPart, Gpt, Guid};
+use crate::dev::blockdev::DiskError;
+
+/// Entries in a table on any disk with room for them, the minimum the spec allows
+const DEFAULT_ENTRIES: usize = 128;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GptError {
+    Disk(DiskError),
+    /// The disk is too small to hold a table
+    DiskTooSmall,
+    /// Every entry is in use
+    TableFull,
+    /// The range is outside the usable blocks
+    OutOfRange,
+    /// The range overlaps another partition
+    Overlap,
+    /// There's no partition at that index
+    NoPartition,
+}
+
+impl From<DiskError> for GptError {
+    fn from(err: DiskError) -> Self {
+        Self::Disk(err)
+    }
+}
+
+impl Gpt {
+    /// An empty table for a disk of `blocks` blocks, with as many entries as fit up to 128
+    pub fn new(blocks: usize, blocksize: usize, disk_guid: Guid) -> Result<Self, GptError> {
+        let per_block = blocksize / GPTPart::SIZE;
+
+        // Small disks, such as the RAM disk, only get a single block of entries
+        let entries = if blocks > 4 * (DEFAULT_ENTRIES / per_block + 1) {
+            DEFAULT_ENTRIES
+        } else {
+            per_block
+        };
+
+        let array_blocks = (entries * GPTPart::SIZE).div_ceil(blocksize);
+
+        // MBR, two headers, two arrays, and at least one usable block
+        if blocks < 3 + 2 * array_blocks + 1 {
+            return Err(GptError::DiskTooSmall);
+        }
+
+        let mut header = GPTHeader::default();
+        header.host_lba = 1;
+        header.alt_lba = (blocks - 1) as u64;
+        header.part_lba = 2;
+        header.first_lba = (2 + array_blocks) as u64;
+        header.last_lba = (blocks - 2 - array_blocks) as u64;
+        header.disk_guid = disk_guid;
+        header.part_num = entries as u32;
+
+        Ok(Self {
+            header,
+            parts: alloc::vec![GPTPart::default(); entries],
+        })
+    }
+
+    /// Check `start..=end` is usable and doesn't overlap any partition but `except`
+    fn check_range(&self, start: u64, end: u64, except: Option<usize>) -> Result<(), GptError> {
+        if start > end || start < self.header.first_lba || end > self.header.last_lba {
+            return Err(GptError::OutOfRange);
+        }
+
+        let overlaps = self.used()
+            .filter(|(index, _)| Some(*index) != except)
+            .any(|(_, part)| start <= part.end_lba && part.start_lba <= end);
+
+        if overlaps {
+            return Err(GptError::Overlap);
+        }
+
+        Ok(())
+    }
+
+    /// Add a partition covering `start..=end`, returns its index in the table
+    pub fn add(&mut self, type_guid: Guid, unique_guid: Guid, start: u64, end: u64, name: &str) -> Result<usize, GptError> {
+        self.check_range(start, end, None)?;
+
+        let index = self.parts.iter().position(|part| !part.is_used()).ok_or(GptError::TableFull)?;
+
+        let part = &mut self.parts[index];
+        *part = GPTPart::default();
+        part.type_guid = type_guid;
+        part.unique_guid = unique_guid;
+        part.start_lba = start;
+        part.end_lba = end;
+        part.set_name(name);
+
+        Ok(index)
+    }
+
+    pub fn delete(&mut self, index: usize) -> Result<(), GptError> {
+        match self.parts.get_mut(index) {
+            Some(part) if part.is_used() => {
+                *part = GPTPart::default();
+                Ok(())
+            },
+            _ => Err(GptError::NoPartition),
+        }
+    }
+
+    /// Move the last block of a partition to `end`, the start stays put
+    pub fn resize(&mut self, index: usize, end: u64) -> Result<(), GptError> {
+        let start = match self.parts.get(index) {
+            Some(part) if part.is_used() => part.start_lba,
+            _ => return Err(GptError::NoPartition),
+        };
+
+        self.check_range(start, end, Some(index))?;
+        self.parts[index].end_lba = end;
+
+        Ok(())
+    }
+
+    /// The largest run of free usable blocks, as `start..=end`
+    pub fn largest_free(&self) -> Option<(u64, u64)> {
+        let mut used: Vec<(u64, u64)> = self.used().map(|(_, part)| (part.start_lba, part.end_lba)).collect();
+        used.sort();
+
+        let mut best: Option<(u64, u64)> = None;
+        let mut next = self.header.first_lba;
+
+        for (start, end) in used.into_iter().chain([(self.header.last_lba + 1, self.header.last_lba + 1)]) {
+            if start > next && best.is_none_or(|(s, e)| e - s < start - 1 - next) {
+                best = Some((next, start - 1));
+            }
+
+            next = next.max(end + 1);
+        }
+
+        best
+    }
+
+    /// The entry array as stored on disk
+    fn array(&self) -> Vec<u8> {
+        let size = self.header.part_size as usize;
+        let mut array = alloc::vec![0; self.parts.len() * size];
+
+        for (part, bytes) in self.parts.iter().zip(array.chunks_mut(size)) {
+            bytes[..GPTPart::SIZE].copy_from_slice(&part.to_bytes());
+        }
+
+        array
+    }
+
+    /// Write the protective MBR, and the primary and backup tables, to disk `id`, then register its partitions again
+    pub fn write(&self, id: usize) -> Result<(), GptError> {
+        let disk = crate::dev::blockdev::disk(id).ok_or(DiskError::NoDisk)?;
+        let blocksize = disk.blocksize();
+
+        let array = self.array();
+        let array_blocks = array.len().div_ceil(blocksize);
+        let checksum = crc32(&array);
+
+        // Both headers describe the same table, from opposite ends of the disk
+        let mut primary = self.header;
+        primary.host_lba = 1;
+        primary.alt_lba = (disk.blocks() - 1) as u64;
+        primary.part_lba = 2;
+        primary.part_checksum = checksum;
+
+        let mut backup = primary;
+        backup.host_lba = primary.alt_lba;
+        backup.alt_lba = primary.host_lba;
+        backup.part_lba = backup.host_lba - array_blocks as u64;
+
+        if primary.first_lba < primary.part_lba + array_blocks as u64 || primary.last_lba >= backup.part_lba {
+            return Err(GptError::DiskTooSmall);
+        }
+
+        primary.size = GPTHeader::SIZE as u32;
+        backup.size = GPTHeader::SIZE as u32;
+        primary.crc32 = primary.crc32();
+        backup.crc32 = backup.crc32();
+
+        // Dirty cached copies of these blocks would be written back over the new table later, so get everything
+        // else out to disk and then drop them before writing underneath the cache
+        crate::dev::blockdev::cache::sync(Some(id))?;
+        crate::dev::blockdev::cache::invalidate(id, 0, primary.first_lba as usize);
+        crate::dev::blockdev::cache::invalidate(id, backup.part_lba as usize, array_blocks + 1);
+
+        disk.write(&protective_mbr(disk.blocks()), 0)?;
+
+        // Headers take up whole blocks, the rest of the block is reserved and zeroed
+        for (header, lba) in [(primary, 1), (backup, backup.host_lba as usize)] {
+            let mut block = alloc::vec![0; blocksize];
+            block[..GPTHeader::SIZE].copy_from_slice(&header.to_bytes());
+            disk.write(&block, lba)?;
+        }
+
+        let mut padded = array;
+        padded.resize(array_blocks * blocksize, 0);
+
+        disk.write(&padded, primary.part_lba as usize)?;
+        disk.write(&padded, backup.part_lba as usize)?;
+
+        // The registered partitions still reflect the old table
+        crate::dev::blockdev::rescan_partitions(id)?;
+
+        Ok(())
+    }
+}
+
+/// An MBR with a single partition of type 0xEE covering the disk, so MBR tools leave it alone
+fn protective_mbr(blocks: usize) -> [u8; 512] {
+    let mut block = [0; 512];
+    let entry = &mut block[446..462];
+
+    // Not bootable, starting at CHS 0/0/2, which is LBA 1
+    entry[0] = 0x00;
+    entry[1..4].copy_from_slice(&[0x00, 0x02, 0x00]);
+    entry[4] = 0xEE;
+    // Ending CHS is past what CHS can address
+    entry[5..8].copy_from_slice(&[0xff, 0xff, 0xff]);
+    entry[8..12].copy_from_slice(&1u32.to_le_bytes());
+    entry[12..16].copy_from_slice(&((blocks - 1).min(0xffffffff) as u32).to_le_bytes());
+
+    block[510] = 0x55;
+    block[511] = 0xAA;
+
+    block
+}
@@ -0,0 +1,240 @@
+// Master boot record partition tables.
+// The MBR in block 0 holds four primary entries. An extended partition holds a chain of extended boot records, each
+// describing one logical partition relative to itself and linking to the next relative to the extended partition.
+// An entry of type 0xEE means the MBR only protects a GPT, in which case it's left to `gpt`.
+
+use alloc::{string::String, vec::Vec};
+
+use crate::println;
+use super::gpt::{GPTPartType, Guid};
+use super::{DiskError, DiskQueue};
+
+/// Offset of the entries in a boot record
+const TABLE_OFFSET: usize = 446;
+const ENTRY_SIZE: usize = 16;
+const SIG: [u8; 2] = [0x55, 0xAA];
+
+/// Logical partitions followed before the chain is assumed to loop
+const MAX_LOGICAL: usize = 128;
+
+pub struct MbrType;
+
+impl MbrType {
+    pub const EMPTY: u8 = 0x00;
+    pub const FAT12: u8 = 0x01;
+    pub const FAT16_SMALL: u8 = 0x04;
+    pub const EXTENDED_CHS: u8 = 0x05;
+    pub const FAT16: u8 = 0x06;
+    pub const FAT32_CHS: u8 = 0x0B;
+    pub const FAT32_LBA: u8 = 0x0C;
+    pub const FAT16_LBA: u8 = 0x0E;
+    pub const EXTENDED_LBA: u8 = 0x0F;
+    pub const LINUX_SWAP: u8 = 0x82;
+    pub const LINUX: u8 = 0x83;
+    pub const LINUX_EXTENDED: u8 = 0x85;
+    pub const GPT_PROTECTIVE: u8 = 0xEE;
+    pub const EFI_SYS: u8 = 0xEF;
+
+    pub fn is_extended(kind: u8) -> bool {
+        matches!(kind, Self::EXTENDED_CHS | Self::EXTENDED_LBA | Self::LINUX_EXTENDED)
+    }
+
+    /// The GPT type with the same meaning, `UNUSED` if there isn't one
+    pub fn to_guid(kind: u8) -> Guid {
+        match kind {
+            Self::FAT12 | Self::FAT16_SMALL | Self::FAT16 | Self::FAT32_CHS | Self::FAT32_LBA | Self::FAT16_LBA => {
+                GPTPartType::BASIC_DATA_PART
+            },
+            Self::LINUX_SWAP => GPTPartType::LINUX_SWAP_PART,
+            Self::LINUX => GPTPartType::GENERAL_PART,
+            Self::EFI_SYS => GPTPartType::EFI_SYS_PART,
+            _ => GPTPartType::UNUSED,
+        }
+    }
+}
+
+/// An entry of a boot record, with its start made relative to the disk
+#[derive(Clone, Copy, Debug)]
+pub struct MbrPart {
+    /// 1 to 4 for primary partitions, 5 and up for logical ones
+    pub number: usize,
+    pub bootable: bool,
+    pub kind: u8,
+    pub start_lba: u64,
+    pub blocks: u64,
+}
+
+impl MbrPart {
+    fn from_bytes(number: usize, base: u64, bytes: &[u8]) -> Self {
+        Self {
+            number,
+            bootable: bytes[0] == 0x80,
+            kind: bytes[4],
+            start_lba: base + u32::from_le_bytes(bytes[8..12].try_into().unwrap()) as u64,
+            blocks: u32::from_le_bytes(bytes[12..16].try_into().unwrap()) as u64,
+        }
+    }
+
+    pub fn is_used(&self) -> bool {
+        self.kind != MbrType::EMPTY && self.blocks != 0
+    }
+}
+
+/// A partition table read from a boot record and its extended boot records
+pub struct Mbr {
+    /// Primary and logical partitions, extended partitions aren't included
+    pub parts: Vec<MbrPart>,
+    /// Whether there's a 0xEE entry, making the real table a GPT
+    pub protective: bool,
+}
+
+/// Read the boot record at `lba`, returns its four entries if it has a valid signature and boot indicators
+fn read_record(disk: &'static DiskQueue, lba: u64) -> Result<Option<[[u8; ENTRY_SIZE]; 4]>, DiskError> {
+    let mut block = alloc::vec![0; disk.blocksize().max(512)];
+
+    disk.read(&mut block, lba as usize)?;
+
+    if block[510..512] != SIG {
+        return Ok(None);
+    }
+
+    let mut entries = [[0; ENTRY_SIZE]; 4];
+
+    for (i, entry) in entries.iter_mut().enumerate() {
+        let offset = TABLE_OFFSET + i * ENTRY_SIZE;
+        entry.copy_from_slice(&block[offset..offset + ENTRY_SIZE]);
+
+        // A boot sector with no table, such as a FAT volume without partitions, has the signature but not these
+        if entry[0] != 0x00 && entry[0] != 0x80 {
+            return Ok(None);
+        }
+    }
+
+    Ok(Some(entries))
+}
+
+/// Follow the chain of extended boot records starting at `base`, the start of the extended partition
+fn read_logical(disk: &'static DiskQueue, base: u64, parts: &mut Vec<MbrPart>) -> Result<(), DiskError> {
+    let mut ebr = base;
+    let mut number = 5;
+
+    while number < 5 + MAX_LOGICAL {
+        if ebr >= disk.blocks() as u64 {
+            println!("MBR: extended boot record at LBA {} is past the end of the disk", ebr);
+            break;
+        }
+
+        let Some(entries) = read_record(disk, ebr)? else {
+            println!("MBR: invalid extended boot record at LBA {}", ebr);
+            break;
+        };
+
+        // The first entry is the logical partition, relative to this record
+        let part = MbrPart::from_bytes(number, ebr, &entries[0]);
+
+        if part.is_used() {
+            parts.push(part);
+            number += 1;
+        }
+
+        // The second links to the next record, relative to the extended partition
+        let next = MbrPart::from_bytes(0, base, &entries[1]);
+
+        if !MbrType::is_extended(next.kind) || next.start_lba <= ebr {
+            break;
+        }
+
+        ebr = next.start_lba;
+    }
+
+    Ok(())
+}
+
+/// Read the MBR of `disk`, returns `None` if block 0 doesn't hold a partition table
+pub fn read(disk: &'static DiskQueue) -> Result<Option<Mbr>, DiskError> {
+    if disk.blocks() == 0 || disk.blocksize() < 512 {
+        return Ok(None);
+    }
+
+    let Some(entries) = read_record(disk, 0)? else {
+        return Ok(None);
+    };
+
+    let mut mbr = Mbr {
+        parts: Vec::new(),
+        protective: false,
+    };
+
+    let mut extended = None;
+
+    for (i, entry) in entries.iter().enumerate() {
+        let part = MbrPart::from_bytes(i + 1, 0, entry);
+
+        if !part.is_used() {
+            continue;
+        }
+
+        if part.kind == MbrType::GPT_PROTECTIVE {
+            mbr.protective = true;
+        } else if MbrType::is_extended(part.kind) {
+            // Only one extended partition is allowed
+            extended.get_or_insert(part.start_lba);
+        } else {
+            mbr.parts.push(part);
+        }
+    }
+
+    if mbr.protective {
+        return Ok(Some(mbr));
+    }
+
+    if let Some(base) = extended {
+        read_logical(disk, base, &mut mbr.parts)?;
+    }
+
+    Ok(Some(mbr))
+}
+
+/// Register every partition in the MBR of disk `id`, returns the IDs of the partitions.
+/// Returns `None` if there's no MBR, or it's empty or protective and the partitions should come from the GPT
+pub fn scan(id: usize) -> Result<Option<Vec<usize>>, DiskError> {
+    let disk = super::disk(id).ok_or(DiskError::NoDisk)?;
+
+    let Some(mbr) = read(disk)? else {
+        return Ok(None);
+    };
+
+    if mbr.protective || mbr.parts.is_empty() {
+        return Ok(None);
+    }
+
+    let mut ids = Vec::new();
+
+    for part in mbr.parts {
+        if part.start_lba == 0 || part.start_lba + part.blocks > disk.blocks() as u64 {
+            println!("MBR: disk {} partition {} is out of range, skipping", id, part.number);
+            continue;
+        }
+
+        println!(
+            "MBR: disk {} partition {} type {:#04x}{} LBA {}..{}",
+            id,
+            part.number,
+            part.kind,
+            if part.bootable { " (bootable)" } else { "" },
+            part.start_lba,
+            part.start_lba + part.blocks
+        );
+
+        let partition = super::Partition::new(
+            id,
+            part.start_lba as usize,
+            part.blocks as usize,
+            disk.blocksize(),
+        ).with_info(MbrType::to_guid(part.kind), String::new()).with_mbr_type(part.kind);
+
+        ids.push(super::register_partition(partition));
+    }
+
+    Ok(Some(ids))
+}
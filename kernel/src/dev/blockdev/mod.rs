@@ -1,52 +1,162 @@
-use alloc::collections::BTreeMap;
+use alloc::{collections::BTreeMap, string::String};
 use spin::Mutex;
 
+pub use queue::{DiskQueue, Op, Request, RequestHandle, Segment};
+
 mod ramdisk;
-mod gpt;
+pub mod gpt;
+pub mod mbr;
+mod virtio_blk;
+mod nvme;
+pub mod cache;
+pub mod queue;
+
+static DISKS: Mutex<BTreeMap<usize, &'static DiskQueue>> = Mutex::new(BTreeMap::new());
 
-static DISKS: Mutex<BTreeMap<usize, &'static mut dyn Disk>> = Mutex::new(BTreeMap::new());
+/// Signalled when a request is submitted, wakes the block worker
+static WORK: crate::scheduler::Event = crate::scheduler::Event::new();
 pub static PARTS: Mutex<BTreeMap<usize, &'static mut Partition>> = Mutex::new(BTreeMap::new());
 
+/// Implemented by disk drivers, everything else goes through the disk's `DiskQueue`
 pub trait Disk: Send + Sync {
     fn blocksize(&self) -> usize;
     fn blocks(&self) -> usize;
     fn chs_end(&self) -> u32;
-    fn write(&mut self, data: &[u8], block: usize);
-    fn read(&self, buffer: &mut [u8], block: usize);
+
+    /// Carry out a request of `blocks` blocks at `lba`, with the data in `segments`.
+    /// Reads and writes are always whole blocks, and within the disk
+    fn execute(&self, op: Op, lba: usize, blocks: usize, segments: &[Segment]) -> Result<(), DiskError>;
+
+    /// Most blocks in one request
+    fn max_blocks(&self) -> usize {
+        usize::MAX
+    }
+
+    /// Most segments in one request
+    fn max_segments(&self) -> usize {
+        usize::MAX
+    }
+}
+
+/// Add a disk found by a driver and register its partitions, returns its disk ID
+pub fn register_disk(disk: &'static dyn Disk) -> usize {
+    let mut disks = DISKS.lock();
+    let id = disks.keys().next_back().map_or(0, |id| id + 1);
+
+    disks.insert(id, alloc::boxed::Box::leak(alloc::boxed::Box::new(DiskQueue::new(disk))));
+    drop(disks);
+
+    crate::println!("Registered disk {}", id);
+
+    if let Err(err) = scan_partitions(id) {
+        crate::println!("Failed to read the partition table of disk {}: {:?}", id, err);
+    }
+
+    id
+}
+
+/// Register the partitions of disk `id` from its MBR, or its GPT if the MBR is missing or protective.
+/// Returns the IDs of the partitions
+pub fn scan_partitions(id: usize) -> Result<alloc::vec::Vec<usize>, DiskError> {
+    match mbr::scan(id)? {
+        Some(ids) => Ok(ids),
+        None => gpt::scan(id),
+    }
+}
+
+/// Forget the partitions of disk `id` and read its partition table again, after the table was rewritten.
+/// Returns the IDs of the partitions
+pub fn rescan_partitions(id: usize) -> Result<alloc::vec::Vec<usize>, DiskError> {
+    let mut parts = PARTS.lock();
+    let stale: alloc::vec::Vec<usize> = parts.iter()
+        .filter(|(_, part)| part.disk_id == id)
+        .map(|(id, _)| *id)
+        .collect();
+
+    for part in stale.iter() {
+        parts.remove(part);
+    }
+
+    drop(parts);
+
+    crate::mem::swap::SWAP_PARTS.lock().retain(|part| !stale.contains(part));
+
+    scan_partitions(id)
 }
 
-#[derive(Debug)]
+/// Add a partition, returns its partition ID
+pub fn register_partition(partition: Partition) -> usize {
+    let mut parts = PARTS.lock();
+    let id = parts.keys().next_back().map_or(0, |id| id + 1);
+
+    let swap = partition.type_guid == gpt::GPTPartType::SWAP_PART;
+
+    parts.insert(id, alloc::boxed::Box::leak(alloc::boxed::Box::new(partition)));
+    drop(parts);
+
+    // Only swap GeNT formatted itself is used without being asked, anything else goes through `swap::add_partition`
+    if swap {
+        crate::mem::swap::add_partition(id);
+    }
+
+    id
+}
+
+/// The request queue of a disk
+pub fn disk(id: usize) -> Option<&'static DiskQueue> {
+    DISKS.lock().get(&id).copied()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum DiskError {
     InvalidBlock,
     PartitionRangeError,
+    NoDisk,
+    /// The disk reported a failure
+    IoError,
+    /// The disk doesn't support the request
+    Unsupported,
+    ReadOnly,
+    Timeout,
 }
 
 pub fn init() {
     let ramdisk = alloc::boxed::Box::new(ramdisk::RamDisk::new(16, 1024));
-    let ramdisk = alloc::boxed::Box::leak(ramdisk);
+    let ramdisk: &'static ramdisk::RamDisk = alloc::boxed::Box::leak(ramdisk);
+    let id = register_disk(ramdisk);
+
+    // The RAM disk starts out blank, give it a swap partition
+    if let Err(err) = gpt::format(id) {
+        crate::println!("Failed to format the RAM disk: {:?}", err);
+    }
 
-    gpt::init_disk_gpt(ramdisk);
+    crate::scheduler::spawn_kernel_thread(block_worker, 6);
+    cache::init();
+}
 
-    let swap_part = alloc::boxed::Box::new(
-        Partition::new(
-            0, 
-            4, 
-            ramdisk.blocks(),
-            ramdisk.blocksize()
-        )
-    );
-    let partition = alloc::boxed::Box::leak(swap_part);
+/// Dispatches requests submitted without anyone waiting on them
+fn block_worker() -> ! {
+    loop {
+        WORK.wait();
 
-    DISKS.lock().insert(0, ramdisk);
-    PARTS.lock().insert(0, partition);
+        let queues: alloc::vec::Vec<_> = DISKS.lock().values().copied().collect();
 
-    crate::mem::swap::SWAP_PARTS.lock().push(0);
+        for queue in queues {
+            queue.run();
+        }
+    }
 }
 
 pub struct Partition {
     disk_id: usize,
+    /// First block on the disk
+    start: usize,
     blocksize: usize,
     blocks: usize,
+    type_guid: gpt::Guid,
+    /// The type byte, for partitions from an MBR
+    mbr_type: Option<u8>,
+    name: String,
     block_map: vmem::Vmem<'static, 'static>
 }
 
@@ -62,16 +172,48 @@ impl Partition {
 
         Self { 
             disk_id,
+            start: block_base,
             blocksize,
             blocks,
+            type_guid: gpt::GPTPartType::UNUSED,
+            mbr_type: None,
+            name: String::new(),
             block_map
         }
     }
+
+    /// Set the type and name given by the partition table
+    pub fn with_info(mut self, type_guid: gpt::Guid, name: String) -> Self {
+        self.type_guid = type_guid;
+        self.name = name;
+        self
+    }
+
+    pub fn with_mbr_type(mut self, kind: u8) -> Self {
+        self.mbr_type = Some(kind);
+        self
+    }
     
     pub fn diskid(&self) -> usize {
         self.disk_id
     }
 
+    pub fn start(&self) -> usize {
+        self.start
+    }
+
+    pub fn type_guid(&self) -> gpt::Guid {
+        self.type_guid
+    }
+
+    pub fn mbr_type(&self) -> Option<u8> {
+        self.mbr_type
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
     pub fn blocksize(&self) -> usize {
         self.blocksize
     }
@@ -80,25 +222,54 @@ impl Partition {
         self.blocks
     }
 
-    pub fn write(&mut self, data: &[u8], block: usize) -> Result<(), DiskError> {
-        let mut diskslock = DISKS.lock();
-        let disk = diskslock.get_mut(&self.disk_id).unwrap();
+    fn queue(&self) -> Result<&'static DiskQueue, DiskError> {
+        disk(self.disk_id).ok_or(DiskError::NoDisk)
+    }
 
-        disk.write(data, block);
+    /// The disk LBA of `block`, if `blocks` blocks starting there are all within the partition
+    fn translate(&self, block: usize, blocks: usize) -> Result<usize, DiskError> {
+        match block.checked_add(blocks) {
+            Some(end) if end <= self.blocks => Ok(self.start + block),
+            _ => Err(DiskError::PartitionRangeError),
+        }
+    }
+
+    /// The number of blocks in `len` bytes, which must be a non-zero number of whole blocks
+    fn whole_blocks(&self, len: usize) -> Result<usize, DiskError> {
+        if len == 0 || len % self.blocksize != 0 {
+            return Err(DiskError::InvalidBlock);
+        }
 
-        Ok(())
+        Ok(len / self.blocksize)
     }
 
+    /// Write `data`, whole blocks, starting at `block` of the partition through the buffer cache
+    pub fn write(&mut self, data: &[u8], block: usize) -> Result<(), DiskError> {
+        let lba = self.translate(block, self.whole_blocks(data.len())?)?;
+        cache::write(self.disk_id, data, lba)
+    }
+
+    /// Read whole blocks into `data` starting at `block` of the partition through the buffer cache
     pub fn read(&self, data: &mut [u8], block: usize) -> Result<(), DiskError> {
-        let mut diskslock = DISKS.lock();
-        let disk = diskslock.get_mut(&self.disk_id).unwrap();
+        let lba = self.translate(block, self.whole_blocks(data.len())?)?;
+        cache::read(self.disk_id, data, lba)
+    }
 
-        disk.read(data, block);
+    /// Queue a request on the partition's disk, bypassing the buffer cache. The request's LBA is relative to the
+    /// partition, and it's rejected if it doesn't fit in the partition
+    /// # Safety
+    /// See `DiskQueue::submit`
+    pub unsafe fn submit(&self, mut request: Request) -> Result<RequestHandle, DiskError> {
+        if request.op != Op::Flush {
+            request.lba = self.translate(request.lba, request.blocks)?;
+        }
 
-        Ok(())
+        self.queue()?.submit(request)
     }
-    
+
+    /// Allocate `blocks` blocks, returns the first, relative to the partition
     pub fn alloc_blocks(&self, blocks: usize) -> usize {
-        self.block_map.alloc(blocks, vmem::AllocStrategy::NextFit).unwrap()
+        // The map holds disk LBAs, which keeps the partition's first block from looking like a null address
+        self.block_map.alloc(blocks, vmem::AllocStrategy::NextFit).unwrap() - self.start
     }
 }
\ No newline at end of file
@@ -0,0 +1,705 @@
+// NVMe driver.
+// One admin queue pair and one I/O queue pair per controller, each namespace is registered as its own disk.
+// Commands are issued one at a time. I/O completions are signalled by MSI-X when the controller has it, admin
+// commands, and anything before the scheduler runs, are polled.
+// Data goes straight to the request's segments when PRPs can describe them, otherwise through a bounce buffer.
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+
+use crate::dev::pci;
+use crate::dma::DmaRange;
+use crate::println;
+use crate::scheduler::Event;
+use super::{DiskError, Op, Segment};
+
+const PAGE_SIZE: usize = 0x1000;
+
+const ADMIN_QUEUE_SIZE: u16 = 32;
+const IO_QUEUE_SIZE: u16 = 64;
+
+/// Largest transfer in one command, so the PRP list always fits in one page
+const MAX_TRANSFER: usize = 0x2_0000;
+
+/// Give up on a command after this long
+const TIMEOUT_NS: u128 = 5_000_000_000;
+
+mod reg {
+    pub const CAP: usize = 0x00;
+    pub const VS: usize = 0x08;
+    pub const INTMS: usize = 0x0C;
+    pub const CC: usize = 0x14;
+    pub const CSTS: usize = 0x1C;
+    pub const AQA: usize = 0x24;
+    pub const ASQ: usize = 0x28;
+    pub const ACQ: usize = 0x30;
+    pub const DOORBELLS: usize = 0x1000;
+}
+
+mod admin {
+    pub const CREATE_IO_SQ: u8 = 0x01;
+    pub const CREATE_IO_CQ: u8 = 0x05;
+    pub const IDENTIFY: u8 = 0x06;
+}
+
+mod io {
+    pub const FLUSH: u8 = 0x00;
+    pub const WRITE: u8 = 0x01;
+    pub const READ: u8 = 0x02;
+    pub const DATASET_MANAGEMENT: u8 = 0x09;
+}
+
+mod identify {
+    pub const NAMESPACE: u32 = 0x00;
+    pub const CONTROLLER: u32 = 0x01;
+    pub const ACTIVE_NAMESPACES: u32 = 0x02;
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+struct Command {
+    /// Opcode, flags, and command ID
+    cdw0: u32,
+    nsid: u32,
+    _cdw2: u32,
+    _cdw3: u32,
+    mptr: u64,
+    prp1: u64,
+    prp2: u64,
+    cdw10: u32,
+    cdw11: u32,
+    cdw12: u32,
+    cdw13: u32,
+    cdw14: u32,
+    cdw15: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+struct Completion {
+    dw0: u32,
+    _dw1: u32,
+    sq_head: u16,
+    sq_id: u16,
+    cid: u16,
+    /// Phase tag in bit 0, status in the rest
+    status: u16,
+}
+
+#[derive(Debug)]
+pub enum NvmeError {
+    /// The controller didn't become ready, or a command never completed
+    Timeout,
+    /// The controller reported a failure, with its status code type and status code
+    Status(u8, u8),
+    /// The controller's BAR isn't assigned
+    NoBar,
+}
+
+struct QueuePair {
+    id: u16,
+    size: u16,
+    sq: DmaRange<[Command]>,
+    cq: DmaRange<[Completion]>,
+    sq_tail: u16,
+    cq_head: u16,
+    /// Expected phase tag of the next completion, flips every time the queue wraps
+    phase: bool,
+    next_cid: u16,
+    /// PRP list of the command in flight, commands go one at a time so one page is enough
+    prp_list: DmaRange<[u64]>,
+}
+
+impl QueuePair {
+    fn new(id: u16, size: u16) -> Self {
+        let mut sq = DmaRange::<[Command]>::new_many(size as usize);
+        let mut cq = DmaRange::<[Completion]>::new_many(size as usize);
+
+        sq.buf_mut().fill(Command::default());
+        cq.buf_mut().fill(Completion::default());
+
+        Self {
+            id,
+            size,
+            sq,
+            cq,
+            sq_tail: 0,
+            cq_head: 0,
+            phase: true,
+            next_cid: 0,
+            prp_list: DmaRange::new_many(PAGE_SIZE / core::mem::size_of::<u64>()),
+        }
+    }
+}
+
+pub struct Controller {
+    regs: usize,
+    /// Bytes between doorbells
+    doorbell_stride: usize,
+    admin: Mutex<QueuePair>,
+    io: Mutex<QueuePair>,
+    /// Largest transfer in one command
+    max_transfer: usize,
+    /// Optional NVM commands supported
+    oncs: u16,
+    /// Whether there's a volatile write cache that needs flushing
+    write_cache: bool,
+    /// Whether the I/O completion queue raises MSI-X vector 0
+    msix: AtomicBool,
+    /// Signalled by the interrupt, or when a sleeping command times out
+    done: &'static Event,
+}
+
+unsafe impl Send for Controller {}
+unsafe impl Sync for Controller {}
+
+pub struct Namespace {
+    controller: &'static Controller,
+    nsid: u32,
+    blocksize: usize,
+    blocks: usize,
+}
+
+linkset::entry!(pci::PCI_DRIVERS, pci::PciDriverEntry, pci::PciDriverEntry {
+    name: "nvme",
+    ids: &[pci::PciId::class(0x01, 0x08, 0x02)],
+    probe,
+});
+
+fn probe(function: &pci::Function) {
+    let controller: &'static Controller = match Controller::new(function) {
+        Ok(controller) => alloc::boxed::Box::leak(alloc::boxed::Box::new(controller)),
+        Err(err) => {
+            println!("nvme {}: failed to initialize: {:?}", function.addr, err);
+            return;
+        }
+    };
+
+    let data = controller as *const Controller as usize;
+
+    // The admin queue always uses vector 0 too, its completions only cause a spurious wakeup
+    match pci::msi::enable_msix(function, &[(handle_interrupt, data)]) {
+        Ok(_) => controller.msix.store(true, Ordering::Relaxed),
+        Err(err) => println!("nvme {}: no MSI-X ({:?}), polling", function.addr, err),
+    }
+
+    if let Err(err) = controller.create_io_queues() {
+        println!("nvme {}: failed to create I/O queues: {:?}", function.addr, err);
+        return;
+    }
+
+    if let Err(err) = controller.add_namespaces() {
+        println!("nvme {}: failed to enumerate namespaces: {:?}", function.addr, err);
+    }
+}
+
+fn handle_interrupt(data: usize) {
+    let controller = unsafe {&*(data as *const Controller)};
+
+    controller.done.signal();
+}
+
+/// Wake a command sleeping past its deadline
+fn timeout(data: usize) {
+    let event = unsafe {&*(data as *const Event)};
+
+    event.signal();
+}
+
+impl Controller {
+    fn new(function: &pci::Function) -> Result<Self, NvmeError> {
+        let regs = pci::map_bar(function, 0).ok_or(NvmeError::NoBar)?;
+        pci::enable(function.addr);
+
+        let mut controller = Self {
+            regs,
+            doorbell_stride: 4,
+            admin: Mutex::new(QueuePair::new(0, ADMIN_QUEUE_SIZE)),
+            io: Mutex::new(QueuePair::new(1, IO_QUEUE_SIZE)),
+            max_transfer: MAX_TRANSFER,
+            oncs: 0,
+            write_cache: false,
+            msix: AtomicBool::new(false),
+            done: alloc::boxed::Box::leak(alloc::boxed::Box::new(Event::new())),
+        };
+
+        let cap = controller.read64(reg::CAP);
+        let version = controller.read32(reg::VS);
+        controller.doorbell_stride = 4 << ((cap >> 32) & 0xf);
+
+        // CAP.TO is in units of 500ms
+        let timeout = ((cap >> 24) & 0xff) as u128 * 500_000_000;
+
+        // Disable, then wait for the controller to stop
+        controller.write32(reg::CC, 0);
+        controller.wait_ready(false, timeout)?;
+
+        {
+            let admin = controller.admin.lock();
+
+            controller.write32(reg::AQA, ((admin.size as u32 - 1) << 16) | (admin.size as u32 - 1));
+            controller.write64(reg::ASQ, admin.sq.phys() as u64);
+            controller.write64(reg::ACQ, admin.cq.phys() as u64);
+        }
+
+        // Pin and MSI interrupts are never used, this doesn't affect MSI-X
+        controller.write32(reg::INTMS, u32::MAX);
+
+        // 64 byte submission entries, 16 byte completion entries, 4KiB pages, NVM command set
+        controller.write32(reg::CC, (4 << 20) | (6 << 16) | 1);
+        controller.wait_ready(true, timeout)?;
+
+        let mut info = DmaRange::<[u8]>::new_many(PAGE_SIZE);
+        controller.admin_command(Command {
+            cdw0: admin::IDENTIFY as u32,
+            prp1: info.phys() as u64,
+            cdw10: identify::CONTROLLER,
+            ..Default::default()
+        })?;
+
+        let info = info.buf_mut();
+        let serial = core::str::from_utf8(&info[4..24]).unwrap_or("").trim();
+        let model = core::str::from_utf8(&info[24..64]).unwrap_or("").trim();
+
+        // MDTS is a power of two multiple of the minimum page size, 0 means no limit
+        let mdts = info[77];
+        if mdts != 0 {
+            let min_page = PAGE_SIZE << ((cap >> 48) & 0xf);
+            controller.max_transfer = controller.max_transfer.min(min_page << mdts);
+        }
+
+        controller.oncs = u16::from_le_bytes([info[520], info[521]]);
+        controller.write_cache = info[525] & 1 != 0;
+
+        println!(
+            "nvme {}: {} serial {} version {}.{}",
+            function.addr,
+            model,
+            serial,
+            version >> 16,
+            (version >> 8) & 0xff
+        );
+
+        Ok(controller)
+    }
+
+    fn create_io_queues(&self) -> Result<(), NvmeError> {
+        let (id, size, sq, cq) = {
+            let io = self.io.lock();
+            (io.id as u32, io.size as u32, io.sq.phys(), io.cq.phys())
+        };
+
+        // Physically contiguous, with interrupts on vector 0 if there's MSI-X
+        self.admin_command(Command {
+            cdw0: admin::CREATE_IO_CQ as u32,
+            prp1: cq as u64,
+            cdw10: ((size - 1) << 16) | id,
+            cdw11: if self.msix.load(Ordering::Relaxed) {0b11} else {1},
+            ..Default::default()
+        })?;
+
+        // Physically contiguous, bound to the completion queue of the same ID
+        self.admin_command(Command {
+            cdw0: admin::CREATE_IO_SQ as u32,
+            prp1: sq as u64,
+            cdw10: ((size - 1) << 16) | id,
+            cdw11: (id << 16) | 1,
+            ..Default::default()
+        })?;
+
+        Ok(())
+    }
+
+    fn add_namespaces(&'static self) -> Result<(), NvmeError> {
+        let list = DmaRange::<[u8]>::new_many(PAGE_SIZE);
+
+        self.admin_command(Command {
+            cdw0: admin::IDENTIFY as u32,
+            prp1: list.phys() as u64,
+            cdw10: identify::ACTIVE_NAMESPACES,
+            ..Default::default()
+        })?;
+
+        let nsids: Vec<u32> = list.buf()
+            .chunks(4)
+            .map(|id| u32::from_le_bytes([id[0], id[1], id[2], id[3]]))
+            .take_while(|id| *id != 0)
+            .collect();
+
+        let info = DmaRange::<[u8]>::new_many(PAGE_SIZE);
+
+        for nsid in nsids {
+            self.admin_command(Command {
+                cdw0: admin::IDENTIFY as u32,
+                nsid,
+                prp1: info.phys() as u64,
+                cdw10: identify::NAMESPACE,
+                ..Default::default()
+            })?;
+
+            let info = info.buf();
+            let size = u64::from_le_bytes(info[0..8].try_into().unwrap());
+            let format = (info[26] & 0xf) as usize;
+            let lbads = info[128 + format * 4 + 2];
+
+            let namespace = alloc::boxed::Box::leak(alloc::boxed::Box::new(Namespace {
+                controller: self,
+                nsid,
+                blocksize: 1 << lbads,
+                blocks: size as usize,
+            }));
+
+            println!("nvme: namespace {} has {} blocks of {} bytes", nsid, namespace.blocks, namespace.blocksize);
+
+            super::register_disk(namespace);
+        }
+
+        Ok(())
+    }
+
+    fn wait_ready(&self, ready: bool, timeout: u128) -> Result<(), NvmeError> {
+        let deadline = crate::time::monotonic_ns() + timeout.max(TIMEOUT_NS);
+
+        while (self.read32(reg::CSTS) & 1 != 0) != ready {
+            if crate::time::monotonic_ns() > deadline {
+                return Err(NvmeError::Timeout);
+            }
+
+            core::hint::spin_loop();
+        }
+
+        Ok(())
+    }
+
+    fn admin_command(&self, cmd: Command) -> Result<u32, NvmeError> {
+        self.submit(&mut self.admin.lock(), cmd)
+    }
+
+    fn io_command(&self, cmd: Command) -> Result<u32, NvmeError> {
+        self.submit(&mut self.io.lock(), cmd)
+    }
+
+    /// Submit an I/O command with data in `pages`, as listed by `prp_pages`
+    fn io_transfer(&self, mut cmd: Command, pages: &[u64]) -> Result<u32, NvmeError> {
+        let mut io = self.io.lock();
+
+        // The first PRP covers the first page, the second is either the next page or a list of the rest
+        cmd.prp1 = pages.first().copied().unwrap_or(0);
+        cmd.prp2 = match pages.len() {
+            0 | 1 => 0,
+            2 => pages[1],
+            _ => {
+                let list = io.prp_list.buf_mut();
+
+                for (entry, page) in list.iter_mut().zip(&pages[1..]) {
+                    unsafe {(entry as *mut u64).write_volatile(*page)};
+                }
+
+                io.prp_list.phys() as u64
+            }
+        };
+
+        self.submit(&mut io, cmd)
+    }
+
+    /// Submit a command and wait for its completion, returns the command specific result
+    fn submit(&self, queue: &mut QueuePair, mut cmd: Command) -> Result<u32, NvmeError> {
+        let cid = queue.next_cid;
+        queue.next_cid = queue.next_cid.wrapping_add(1);
+
+        cmd.cdw0 |= (cid as u32) << 16;
+
+        let tail = queue.sq_tail as usize;
+        unsafe {(&mut queue.sq.buf_mut()[tail] as *mut Command).write_volatile(cmd)};
+
+        queue.sq_tail = (queue.sq_tail + 1) % queue.size;
+        self.write32(self.sq_doorbell(queue.id), queue.sq_tail as u32);
+
+        let deadline = crate::time::monotonic_ns() + TIMEOUT_NS;
+
+        // Only the I/O queue raises interrupts. A signal left over from an earlier command only costs another check
+        let sleep = queue.id != 0 && self.msix.load(Ordering::Relaxed) && crate::scheduler::can_sleep();
+        let timer = sleep.then(|| crate::time::add_at(deadline, timeout, self.done as *const Event as usize));
+
+        let result = loop {
+            let head = queue.cq_head as usize;
+            let entry = unsafe {(&queue.cq.buf()[head] as *const Completion).read_volatile()};
+
+            if (entry.status & 1 != 0) == queue.phase {
+                queue.cq_head = (queue.cq_head + 1) % queue.size;
+
+                if queue.cq_head == 0 {
+                    queue.phase = !queue.phase;
+                }
+
+                self.write32(self.cq_doorbell(queue.id), queue.cq_head as u32);
+
+                // Commands are issued one at a time, anything else is stale
+                if entry.cid != cid {
+                    continue;
+                }
+
+                let status = entry.status >> 1;
+
+                break match status {
+                    0 => Ok(entry.dw0),
+                    _ => Err(NvmeError::Status(((status >> 8) & 0x7) as u8, status as u8)),
+                };
+            }
+
+            if crate::time::monotonic_ns() > deadline {
+                break Err(NvmeError::Timeout);
+            }
+
+            if sleep {
+                self.done.wait();
+            } else {
+                core::hint::spin_loop();
+            }
+        };
+
+        if let Some(timer) = timer {
+            crate::time::cancel(timer);
+        }
+
+        result
+    }
+
+    fn sq_doorbell(&self, queue: u16) -> usize {
+        reg::DOORBELLS + (2 * queue as usize) * self.doorbell_stride
+    }
+
+    fn cq_doorbell(&self, queue: u16) -> usize {
+        reg::DOORBELLS + (2 * queue as usize + 1) * self.doorbell_stride
+    }
+
+    fn read32(&self, reg: usize) -> u32 {
+        unsafe {((self.regs + reg) as *const u32).read_volatile()}
+    }
+
+    fn write32(&self, reg: usize, val: u32) {
+        unsafe {((self.regs + reg) as *mut u32).write_volatile(val)}
+    }
+
+    fn read64(&self, reg: usize) -> u64 {
+        self.read32(reg) as u64 | ((self.read32(reg + 4) as u64) << 32)
+    }
+
+    fn write64(&self, reg: usize, val: u64) {
+        self.write32(reg, val as u32);
+        self.write32(reg + 4, (val >> 32) as u32);
+    }
+}
+
+/// Physical address of every page a transfer touches, the first may start part way through a page.
+/// `None` if the segments can't be described by PRPs, as only the first may start and only the last may end
+/// part way through a page
+fn prp_pages(segments: &[Segment]) -> Option<Vec<u64>> {
+    let mut pages = Vec::new();
+
+    for (i, segment) in segments.iter().enumerate() {
+        let end = segment.phys + segment.len;
+
+        if (i != 0 && segment.phys % PAGE_SIZE != 0) || (i + 1 != segments.len() && end % PAGE_SIZE != 0) {
+            return None;
+        }
+
+        let mut addr = segment.phys;
+
+        while addr < end {
+            pages.push(addr as u64);
+            addr = (addr & !(PAGE_SIZE - 1)) + PAGE_SIZE;
+        }
+    }
+
+    Some(pages)
+}
+
+/// Split `segments` into the first `len` bytes and the rest
+fn split_segments(segments: &[Segment], len: usize) -> (Vec<Segment>, Vec<Segment>) {
+    let mut first = Vec::new();
+    let mut rest = Vec::new();
+    let mut left = len;
+
+    for segment in segments {
+        if left >= segment.len {
+            first.push(*segment);
+            left -= segment.len;
+        } else if left > 0 {
+            first.push(Segment::new(segment.phys, left));
+            rest.push(Segment::new(segment.phys + left, segment.len - left));
+            left = 0;
+        } else {
+            rest.push(*segment);
+        }
+    }
+
+    (first, rest)
+}
+
+impl From<NvmeError> for DiskError {
+    fn from(err: NvmeError) -> Self {
+        match err {
+            NvmeError::Timeout => DiskError::Timeout,
+            _ => DiskError::IoError,
+        }
+    }
+}
+
+impl Namespace {
+    /// Read or write whole blocks to or from `pages`, as listed by `prp_pages`
+    fn transfer(&self, opcode: u8, lba: usize, blocks: usize, pages: &[u64]) -> Result<(), NvmeError> {
+        self.controller.io_transfer(Command {
+            cdw0: opcode as u32,
+            nsid: self.nsid,
+            cdw10: lba as u32,
+            cdw11: (lba >> 32) as u32,
+            cdw12: (blocks - 1) as u32,
+            ..Default::default()
+        }, pages)?;
+
+        Ok(())
+    }
+
+    /// Read or write segments that PRPs can't describe, through a bounce buffer
+    fn transfer_bounced(&self, opcode: u8, lba: usize, blocks: usize, segments: &[Segment]) -> Result<(), NvmeError> {
+        let mut bounce = DmaRange::<[u8]>::new_many(blocks * self.blocksize);
+        let pages = prp_pages(&[Segment::from(&bounce)]).unwrap();
+
+        if opcode == io::WRITE {
+            let mut offset = 0;
+
+            for segment in segments {
+                let data = unsafe {core::slice::from_raw_parts(segment.virt(), segment.len)};
+                bounce.buf_mut()[offset..offset + segment.len].copy_from_slice(data);
+                offset += segment.len;
+            }
+        }
+
+        self.transfer(opcode, lba, blocks, &pages)?;
+
+        if opcode == io::READ {
+            let mut offset = 0;
+
+            for segment in segments {
+                let data = unsafe {core::slice::from_raw_parts_mut(segment.virt(), segment.len)};
+                data.copy_from_slice(&bounce.buf()[offset..offset + segment.len]);
+                offset += segment.len;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Read or write in commands of at most `max_transfer` bytes, which the PRP list always has room for
+    fn read_write(&self, opcode: u8, lba: usize, blocks: usize, segments: &[Segment]) -> Result<(), NvmeError> {
+        let per_command = (self.controller.max_transfer / self.blocksize).max(1);
+        let mut rest = segments.to_vec();
+        let mut done = 0;
+
+        while done < blocks {
+            let count = (blocks - done).min(per_command);
+            let (chunk, after) = split_segments(&rest, count * self.blocksize);
+
+            match prp_pages(&chunk) {
+                Some(pages) => self.transfer(opcode, lba + done, count, &pages)?,
+                None => self.transfer_bounced(opcode, lba + done, count, &chunk)?,
+            }
+
+            rest = after;
+            done += count;
+        }
+
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), NvmeError> {
+        if !self.controller.write_cache {
+            return Ok(());
+        }
+
+        self.controller.io_command(Command {
+            cdw0: io::FLUSH as u32,
+            nsid: self.nsid,
+            ..Default::default()
+        })?;
+
+        Ok(())
+    }
+
+    fn discard(&self, block: usize, blocks: usize) -> Result<(), NvmeError> {
+        // ONCS bit 2, Dataset Management
+        if self.controller.oncs & (1 << 2) == 0 || blocks == 0 {
+            return Ok(());
+        }
+
+        // A range covers at most u32::MAX blocks, and a command takes up to 256 ranges, which fill one page
+        const MAX_RANGE_BLOCKS: usize = u32::MAX as usize;
+        const MAX_RANGES: usize = 256;
+
+        let mut block = block;
+        let mut remaining = blocks;
+
+        while remaining > 0 {
+            let count = remaining.div_ceil(MAX_RANGE_BLOCKS).min(MAX_RANGES);
+
+            // Each range is context attributes, block count, starting LBA
+            let mut ranges = DmaRange::<[u32]>::new_many(count * 4);
+
+            for range in ranges.buf_mut().chunks_exact_mut(4) {
+                let len = remaining.min(MAX_RANGE_BLOCKS);
+                range.copy_from_slice(&[0, len as u32, block as u32, (block >> 32) as u32]);
+
+                block += len;
+                remaining -= len;
+            }
+
+            self.controller.io_command(Command {
+                cdw0: io::DATASET_MANAGEMENT as u32,
+                nsid: self.nsid,
+                prp1: ranges.phys() as u64,
+                // Number of ranges, minus one
+                cdw10: count as u32 - 1,
+                // Deallocate
+                cdw11: 1 << 2,
+                ..Default::default()
+            })?;
+        }
+
+        Ok(())
+    }
+}
+
+impl super::Disk for Namespace {
+    fn blocksize(&self) -> usize {
+        self.blocksize
+    }
+
+    fn blocks(&self) -> usize {
+        self.blocks
+    }
+
+    fn chs_end(&self) -> u32 {
+        0xffffffff
+    }
+
+    fn max_blocks(&self) -> usize {
+        self.controller.max_transfer / self.blocksize
+    }
+
+    fn execute(&self, op: Op, lba: usize, blocks: usize, segments: &[Segment]) -> Result<(), DiskError> {
+        let result = match op {
+            Op::Read => self.read_write(io::READ, lba, blocks, segments),
+            Op::Write => self.read_write(io::WRITE, lba, blocks, segments),
+            Op::Flush => self.flush(),
+            Op::Discard => self.discard(lba, blocks),
+        };
+
+        if let Err(err) = &result {
+            println!("nvme: {:?} of block {} failed: {:?}", op, lba, err);
+        }
+
+        result.map_err(DiskError::from)
+    }
+}
@@ -0,0 +1,370 @@
+// Block request queues.
+// Every disk has a queue of pending requests. Requests are dispatched in order, except that later reads or writes
+// of blocks adjacent to the one being dispatched are merged into it, as long as that doesn't reorder them around
+// a request touching the same blocks. Flushes and discards are never merged or reordered around.
+
+use alloc::{boxed::Box, sync::Arc, vec::Vec};
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll, Waker};
+use spin::Mutex;
+
+use crate::dma::DmaRange;
+use crate::scheduler::Event;
+use super::{Disk, DiskError};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Op {
+    Read,
+    Write,
+    /// Make every completed write persistent
+    Flush,
+    /// The blocks no longer hold data
+    Discard,
+}
+
+/// A physically contiguous part of a request's data
+#[derive(Clone, Copy, Debug)]
+pub struct Segment {
+    pub phys: usize,
+    pub len: usize,
+}
+
+impl Segment {
+    pub fn new(phys: usize, len: usize) -> Self {
+        Self { phys, len }
+    }
+
+    /// The segment through the higher half direct map
+    pub fn virt(&self) -> *mut u8 {
+        crate::mem::PhysicalAddress::new(self.phys).to_virt().to_mut_ptr()
+    }
+}
+
+impl From<&DmaRange<[u8]>> for Segment {
+    fn from(range: &DmaRange<[u8]>) -> Self {
+        Self::new(range.phys(), range.buf().len())
+    }
+}
+
+type Callback = Box<dyn FnOnce(Result<(), DiskError>) + Send>;
+
+struct State {
+    done: AtomicBool,
+    result: Mutex<Result<(), DiskError>>,
+    waker: Mutex<Option<Waker>>,
+    callback: Mutex<Option<Callback>>,
+    /// Signalled on completion, for a thread in `RequestHandle::wait`
+    event: Event,
+}
+
+impl State {
+    fn complete(&self, result: Result<(), DiskError>) {
+        *self.result.lock() = result;
+        self.done.store(true, Ordering::Release);
+
+        if let Some(callback) = self.callback.lock().take() {
+            callback(result);
+        }
+
+        if let Some(waker) = self.waker.lock().take() {
+            waker.wake();
+        }
+
+        self.event.signal();
+    }
+}
+
+pub struct Request {
+    pub op: Op,
+    pub lba: usize,
+    pub blocks: usize,
+    /// Data buffers in order, their lengths add up to `blocks` blocks. Empty for flushes and discards
+    pub segments: Vec<Segment>,
+    state: Arc<State>,
+}
+
+impl Request {
+    pub fn new(op: Op, lba: usize, blocks: usize, segments: Vec<Segment>) -> Self {
+        Self {
+            op,
+            lba,
+            blocks,
+            segments,
+            state: Arc::new(State {
+                done: AtomicBool::new(false),
+                result: Mutex::new(Ok(())),
+                waker: Mutex::new(None),
+                callback: Mutex::new(None),
+                event: Event::new(),
+            }),
+        }
+    }
+
+    /// Run `callback` when the request finishes, it may run in any thread
+    pub fn on_complete(self, callback: impl FnOnce(Result<(), DiskError>) + Send + 'static) -> Self {
+        *self.state.callback.lock() = Some(Box::new(callback));
+        self
+    }
+
+    fn end(&self) -> usize {
+        self.lba + self.blocks
+    }
+
+    fn overlaps(&self, other: &Request) -> bool {
+        self.lba < other.end() && other.lba < self.end()
+    }
+}
+
+/// Tracks a submitted request, await it or `wait` for it
+pub struct RequestHandle {
+    queue: &'static DiskQueue,
+    state: Arc<State>,
+}
+
+impl RequestHandle {
+    pub fn is_done(&self) -> bool {
+        self.state.done.load(Ordering::Acquire)
+    }
+
+    /// Block until the request finishes. Threads sleep until the block worker completes it, boot code runs before
+    /// the worker can and dispatches requests itself
+    pub fn wait(self) -> Result<(), DiskError> {
+        let sleep = crate::scheduler::can_sleep();
+
+        while !self.is_done() {
+            if sleep {
+                self.state.event.wait();
+            } else {
+                self.queue.run();
+                core::hint::spin_loop();
+            }
+        }
+
+        *self.state.result.lock()
+    }
+}
+
+impl Future for RequestHandle {
+    type Output = Result<(), DiskError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        *self.state.waker.lock() = Some(cx.waker().clone());
+
+        // Completion may have happened before the waker was stored
+        if self.is_done() {
+            self.state.waker.lock().take();
+            Poll::Ready(*self.state.result.lock())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+pub struct DiskQueue {
+    disk: &'static dyn Disk,
+    pending: Mutex<Vec<Request>>,
+    /// Whether some thread is already dispatching
+    running: AtomicBool,
+}
+
+impl DiskQueue {
+    pub fn new(disk: &'static dyn Disk) -> Self {
+        Self {
+            disk,
+            pending: Mutex::new(Vec::new()),
+            running: AtomicBool::new(false),
+        }
+    }
+
+    pub fn disk(&self) -> &'static dyn Disk {
+        self.disk
+    }
+
+    pub fn blocksize(&self) -> usize {
+        self.disk.blocksize()
+    }
+
+    pub fn blocks(&self) -> usize {
+        self.disk.blocks()
+    }
+
+    /// Queue a request
+    /// # Safety
+    /// The request's segments must stay valid, and not be otherwise used, until it completes
+    pub unsafe fn submit(&'static self, request: Request) -> Result<RequestHandle, DiskError> {
+        // Sizes come from callers that may have done their own arithmetic wrong, so none of this can overflow
+        let len = request.segments.iter().try_fold(0usize, |len, seg| len.checked_add(seg.len));
+        let bytes = request.blocks.checked_mul(self.blocksize());
+        let end = request.lba.checked_add(request.blocks);
+
+        match request.op {
+            Op::Read | Op::Write if len.is_none() || len != bytes => return Err(DiskError::InvalidBlock),
+            Op::Read | Op::Write | Op::Discard if end.is_none_or(|end| end > self.blocks()) => {
+                return Err(DiskError::InvalidBlock)
+            },
+            _ => {}
+        }
+
+        let handle = RequestHandle {
+            queue: self,
+            state: request.state.clone(),
+        };
+
+        self.pending.lock().push(request);
+        super::WORK.signal();
+
+        Ok(handle)
+    }
+
+    /// Dispatch pending requests until there are none, unless another thread is already doing so
+    pub fn run(&self) {
+        if self.running.swap(true, Ordering::Acquire) {
+            return;
+        }
+
+        while let Some(batch) = self.next_batch() {
+            let (op, lba) = (batch[0].op, batch[0].lba);
+            let blocks = batch.iter().map(|req| req.blocks).sum();
+            let segments: Vec<Segment> = batch.iter().flat_map(|req| req.segments.iter().copied()).collect();
+
+            let result = self.disk.execute(op, lba, blocks, &segments);
+
+            for request in batch {
+                request.state.complete(result);
+            }
+        }
+
+        self.running.store(false, Ordering::Release);
+    }
+
+    /// Take the oldest request, along with the requests that can be merged into it, sorted by block
+    fn next_batch(&self) -> Option<Vec<Request>> {
+        let mut pending = self.pending.lock();
+
+        if pending.is_empty() {
+            return None;
+        }
+
+        let mut batch = alloc::vec![pending.remove(0)];
+        let op = batch[0].op;
+
+        if op != Op::Read && op != Op::Write {
+            return Some(batch);
+        }
+
+        let max_blocks = self.disk.max_blocks();
+        let max_segments = self.disk.max_segments();
+
+        let mut blocks = batch[0].blocks;
+        let mut segments = batch[0].segments.len();
+
+        // Keep going while requests keep extending the batch at either end
+        let mut merged = true;
+
+        while merged {
+            merged = false;
+
+            let (start, end) = (batch[0].lba, batch[batch.len() - 1].end());
+
+            for i in 0..pending.len() {
+                let candidate = &pending[i];
+
+                // Nothing moves past a flush or discard
+                if candidate.op != Op::Read && candidate.op != Op::Write {
+                    break;
+                }
+
+                let adjacent = candidate.lba == end || candidate.end() == start;
+                let fits = blocks + candidate.blocks <= max_blocks
+                    && segments + candidate.segments.len() <= max_segments;
+
+                // Merging moves the candidate ahead of everything queued before it
+                let conflicts = pending[..i].iter().any(|earlier| earlier.overlaps(candidate));
+
+                if candidate.op == op && adjacent && fits && !conflicts {
+                    let request = pending.remove(i);
+
+                    blocks += request.blocks;
+                    segments += request.segments.len();
+
+                    if request.lba == end {
+                        batch.push(request);
+                    } else {
+                        batch.insert(0, request);
+                    }
+
+                    merged = true;
+                    break;
+                }
+            }
+        }
+
+        Some(batch)
+    }
+
+    /// Submit a request and wait for it, for data that stays put until then
+    fn submit_wait(&'static self, request: Request) -> Result<(), DiskError> {
+        unsafe {self.submit(request)}?.wait()
+    }
+
+    /// Read `buffer.len()` bytes starting at `lba`, the last block may be partial
+    pub fn read(&'static self, buffer: &mut [u8], lba: usize) -> Result<(), DiskError> {
+        let blocksize = self.blocksize();
+        let blocks = buffer.len().div_ceil(blocksize);
+
+        if buffer.is_empty() {
+            return Ok(());
+        }
+
+        // Whole blocks in the direct map can be read into straight away
+        if let Some(phys) = crate::dma::direct_phys(buffer).filter(|_| buffer.len() % blocksize == 0) {
+            let segment = Segment::new(phys, buffer.len());
+            return self.submit_wait(Request::new(Op::Read, lba, blocks, alloc::vec![segment]));
+        }
+
+        let bounce = DmaRange::<[u8]>::new_many(blocks * blocksize);
+
+        self.submit_wait(Request::new(Op::Read, lba, blocks, alloc::vec![Segment::from(&bounce)]))?;
+
+        buffer.copy_from_slice(&bounce.buf()[..buffer.len()]);
+
+        Ok(())
+    }
+
+    /// Write `data` starting at `lba`, a partial last block keeps whatever was on disk after the data
+    pub fn write(&'static self, data: &[u8], lba: usize) -> Result<(), DiskError> {
+        let blocksize = self.blocksize();
+        let blocks = data.len().div_ceil(blocksize);
+
+        if data.is_empty() {
+            return Ok(());
+        }
+
+        // Whole blocks in the direct map can be written from straight away
+        if let Some(phys) = crate::dma::direct_phys(data).filter(|_| data.len() % blocksize == 0) {
+            let segment = Segment::new(phys, data.len());
+            return self.submit_wait(Request::new(Op::Write, lba, blocks, alloc::vec![segment]));
+        }
+
+        let mut bounce = DmaRange::<[u8]>::new_many(blocks * blocksize);
+
+        if data.len() % blocksize != 0 {
+            let last = Segment::new(bounce.phys() + (blocks - 1) * blocksize, blocksize);
+            self.submit_wait(Request::new(Op::Read, lba + blocks - 1, 1, alloc::vec![last]))?;
+        }
+
+        bounce.buf_mut()[..data.len()].copy_from_slice(data);
+
+        self.submit_wait(Request::new(Op::Write, lba, blocks, alloc::vec![Segment::from(&bounce)]))
+    }
+
+    pub fn flush(&'static self) -> Result<(), DiskError> {
+        self.submit_wait(Request::new(Op::Flush, 0, 0, Vec::new()))
+    }
+
+    pub fn discard(&'static self, lba: usize, blocks: usize) -> Result<(), DiskError> {
+        self.submit_wait(Request::new(Op::Discard, lba, blocks, Vec::new()))
+    }
+}
@@ -1,14 +1,17 @@
 // The RAMDisk driver allows access to multiple in-RAM 'disks'
 // This can be useful for testing out designs without actually messing with the disks of a system.
 
-use crate::println;
+use super::{DiskError, Op, Segment};
 
 pub struct RamDisk {
-    buffer: &'static mut [u8],
+    buffer: *mut u8,
     blocksize: usize,
     blocks: usize,
 }
 
+unsafe impl Send for RamDisk {}
+unsafe impl Sync for RamDisk {}
+
 impl RamDisk {
     pub fn new(blocks: usize, blocksize: usize) -> Self {
         let totalsize = blocksize * blocks;
@@ -23,7 +26,7 @@ impl RamDisk {
         };
 
         Self { 
-            buffer: unsafe {core::slice::from_raw_parts_mut(alloc, totalsize)}, 
+            buffer: alloc, 
             blocksize,
             blocks,
         }
@@ -43,27 +46,30 @@ impl super::Disk for RamDisk {
         0xffffffff
     }
 
-    fn read(&self, buffer: &mut [u8], block: usize) {
-        assert!(block < self.blocks, "attempt to read too many blocks");
-
-        let base = block * self.blocksize;
-
-        for (index, byte) in buffer.iter_mut().enumerate() {
-            let selfidx = base + index;
+    fn execute(&self, op: Op, lba: usize, _blocks: usize, segments: &[Segment]) -> Result<(), DiskError> {
+        let len = segments.iter().try_fold(0usize, |len, seg| len.checked_add(seg.len));
+        let start = lba.checked_mul(self.blocksize);
 
-            *byte = self.buffer[selfidx];
+        // Nothing else stops a request from running off the end of the buffer
+        match start.zip(len).and_then(|(start, len)| start.checked_add(len)) {
+            Some(end) if end <= self.blocks * self.blocksize => {},
+            _ => return Err(DiskError::InvalidBlock),
         }
-    }
-
-    fn write(&mut self, data: &[u8], block: usize) {
-        assert!(block < self.blocks, "attempt to read too many blocks");
 
-        let base = block * self.blocksize;
+        let mut base = unsafe {self.buffer.add(lba * self.blocksize)};
 
-        for (index, byte) in data.iter().enumerate() {
-            let selfidx = base + index;
+        for segment in segments {
+            unsafe {
+                match op {
+                    Op::Read => core::ptr::copy_nonoverlapping(base, segment.virt(), segment.len),
+                    Op::Write => core::ptr::copy_nonoverlapping(segment.virt(), base, segment.len),
+                    Op::Flush | Op::Discard => {},
+                }
 
-            self.buffer[selfidx] = *byte;
+                base = base.add(segment.len);
+            }
         }
+
+        Ok(())
     }
 }
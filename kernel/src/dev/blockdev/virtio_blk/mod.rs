@@ -0,0 +1,391 @@
+// virtio-blk driver.
+// Every request is a header, the data segments, and a status byte chained together.
+
+use alloc::collections::BTreeMap;
+use spin::Mutex;
+
+use crate::arch::trap::without_interrupts;
+use crate::dev::virtio::{self, feature, queue::Segment, DeviceType, Transport, VirtQueue, VirtioError};
+use super::{DiskError, Op};
+use crate::dma::DmaRange;
+use crate::println;
+use crate::scheduler::Event;
+
+linkset::entry!(virtio::VIRTIO_DRIVERS, virtio::VirtioDriverEntry, virtio::VirtioDriverEntry {
+    device: DeviceType::BlockDev,
+    name: "virtio-blk",
+    probe,
+});
+
+/// Sectors are always 512 bytes, whatever the block size is
+const SECTOR_SIZE: usize = 512;
+
+/// Largest transfer in one request when the device doesn't give a limit
+const DEFAULT_MAX_TRANSFER: usize = 0x10_0000;
+
+mod feat {
+    pub const SIZE_MAX: u64 = 1 << 1;
+    pub const SEG_MAX: u64 = 1 << 2;
+    pub const RO: u64 = 1 << 5;
+    pub const BLK_SIZE: u64 = 1 << 6;
+    pub const FLUSH: u64 = 1 << 9;
+    pub const DISCARD: u64 = 1 << 13;
+}
+
+mod config {
+    pub const CAPACITY: usize = 0;
+    pub const SIZE_MAX: usize = 8;
+    pub const SEG_MAX: usize = 12;
+    pub const BLK_SIZE: usize = 20;
+    pub const MAX_DISCARD_SECTORS: usize = 36;
+}
+
+#[repr(u32)]
+#[derive(Clone, Copy, Debug)]
+enum RequestType {
+    In = 0,
+    Out = 1,
+    Flush = 4,
+    Discard = 11,
+}
+
+const STATUS_OK: u8 = 0;
+const STATUS_UNSUPPORTED: u8 = 2;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct RequestHeader {
+    rtype: u32,
+    _reserved: u32,
+    sector: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct DiscardRange {
+    sector: u64,
+    sectors: u32,
+    flags: u32,
+}
+
+/// DMA buffers for the parts of a request that aren't data, allocated once with the queue.
+/// Requests are sent one at a time, the lock is held until the device is done with them
+struct RequestBuffers {
+    header: DmaRange<RequestHeader>,
+    status: DmaRange<u8>,
+    discard: DmaRange<DiscardRange>,
+}
+
+pub struct VirtioBlk {
+    transport: &'static dyn Transport,
+    /// Taken by the interrupt handler, so only ever locked with interrupts disabled
+    queue: Mutex<VirtQueue>,
+    buffers: Mutex<RequestBuffers>,
+    /// Key: head descriptor of a finished request
+    /// Value: bytes written by the device
+    /// Taken by the interrupt handler, so only ever locked with interrupts disabled
+    completed: Mutex<BTreeMap<u16, u32>>,
+    /// One per descriptor, signalled when the request with that head descriptor finishes
+    waiters: &'static [Event],
+    features: u64,
+    blocksize: usize,
+    blocks: usize,
+    /// Largest transfer in one request, a multiple of the block size
+    max_transfer: usize,
+    /// Largest data segment, a multiple of the block size
+    size_max: usize,
+    /// Most data segments in one request
+    max_segments: usize,
+    max_discard: usize,
+}
+
+fn probe(transport: &'static dyn Transport) -> Result<(), VirtioError> {
+    let features = transport.negotiate(
+        feat::SIZE_MAX
+        | feat::SEG_MAX
+        | feat::RO
+        | feat::BLK_SIZE
+        | feat::FLUSH
+        | feat::DISCARD
+        | feature::VERSION_1
+    )?;
+
+    let queue = VirtQueue::new(transport, 0, 128)?;
+    let queue_size = queue.size() as usize;
+
+    let capacity: u64 = transport.config(config::CAPACITY);
+
+    let blocksize = if features & feat::BLK_SIZE != 0 {
+        transport.config::<u32>(config::BLK_SIZE) as usize
+    } else {
+        SECTOR_SIZE
+    };
+
+    // The size limit is per segment, not per request
+    let size_max = if features & feat::SIZE_MAX != 0 {
+        (transport.config::<u32>(config::SIZE_MAX) as usize / blocksize).max(1) * blocksize
+    } else {
+        // Still has to fit a descriptor's length
+        u32::MAX as usize / blocksize * blocksize
+    };
+
+    let max_segments = if features & feat::SEG_MAX != 0 {
+        transport.config::<u32>(config::SEG_MAX) as usize
+    } else {
+        queue_size
+    };
+
+    // The header and status take up 2 descriptors
+    let max_segments = max_segments.min(queue_size.saturating_sub(2)).max(1);
+
+    let max_transfer = if features & feat::SIZE_MAX != 0 {
+        size_max.saturating_mul(max_segments)
+    } else {
+        DEFAULT_MAX_TRANSFER
+    };
+
+    let max_discard = if features & feat::DISCARD != 0 {
+        transport.config::<u32>(config::MAX_DISCARD_SECTORS) as usize
+    } else {
+        0
+    };
+
+    let disk = alloc::boxed::Box::leak(alloc::boxed::Box::new(VirtioBlk {
+        transport,
+        queue: Mutex::new(queue),
+        buffers: Mutex::new(RequestBuffers {
+            header: DmaRange::default(),
+            status: DmaRange::default(),
+            discard: DmaRange::default(),
+        }),
+        completed: Mutex::new(BTreeMap::new()),
+        waiters: alloc::boxed::Box::leak((0..queue_size).map(|_| Event::new()).collect::<alloc::boxed::Box<[_]>>()),
+        features,
+        blocksize,
+        blocks: capacity as usize * SECTOR_SIZE / blocksize,
+        max_transfer: (max_transfer / blocksize).max(1) * blocksize,
+        size_max,
+        max_segments,
+        max_discard,
+    }));
+
+    transport.set_handler(handle_interrupt, disk as *const VirtioBlk as usize);
+    transport.driver_ok();
+
+    println!(
+        "virtio-blk: {} blocks of {} bytes{}{}{}",
+        disk.blocks,
+        disk.blocksize,
+        if disk.read_only() {", read only"} else {""},
+        if features & feat::FLUSH != 0 {", flush"} else {""},
+        if features & feat::DISCARD != 0 {", discard"} else {""},
+    );
+
+    super::register_disk(disk);
+
+    Ok(())
+}
+
+fn handle_interrupt(data: usize) {
+    let disk = unsafe {&*(data as *const VirtioBlk)};
+
+    disk.reap();
+}
+
+impl VirtioBlk {
+    fn read_only(&self) -> bool {
+        self.features & feat::RO != 0
+    }
+
+    fn sector(&self, block: usize) -> u64 {
+        (block * self.blocksize / SECTOR_SIZE) as u64
+    }
+
+    /// Move finished requests from the used ring to `completed`, and wake their waiters.
+    /// Called from the interrupt, and by waiters that can't sleep
+    fn reap(&self) {
+        without_interrupts(|| {
+            let mut queue = self.queue.lock();
+            let mut completed = self.completed.lock();
+
+            while let Some((head, len)) = queue.pop_used() {
+                completed.insert(head, len);
+                self.waiters[head as usize].signal();
+            }
+        });
+    }
+
+    /// Send a request made of a header, data segments, and a status byte, and wait for it to finish
+    fn request(
+        &self,
+        buffers: &mut RequestBuffers,
+        rtype: RequestType,
+        sector: u64,
+        data: &[Segment]
+    ) -> Result<(), VirtioError> {
+        *buffers.header = RequestHeader {
+            rtype: rtype as u32,
+            _reserved: 0,
+            sector,
+        };
+
+        *buffers.status = 0xff;
+
+        let header_seg = Segment::read(buffers.header.phys(), core::mem::size_of::<RequestHeader>());
+        let status_seg = Segment::write(buffers.status.phys(), 1);
+
+        let head = without_interrupts(|| {
+            let mut queue = self.queue.lock();
+
+            let mut segments = alloc::vec::Vec::with_capacity(data.len() + 2);
+            segments.push(header_seg);
+            segments.extend_from_slice(data);
+            segments.push(status_seg);
+
+            let head = queue.add(&segments)?;
+
+            queue.kick(self.transport);
+            Ok::<_, VirtioError>(head)
+        })?;
+
+        // Sleep until the interrupt reaps the request. Boot code, and devices without interrupts, poll instead
+        let sleep = self.transport.interrupts() && crate::scheduler::can_sleep();
+
+        loop {
+            if without_interrupts(|| self.completed.lock().remove(&head)).is_some() {
+                break;
+            }
+
+            // A signal left over from an earlier request with the same head only costs another check
+            if sleep {
+                self.waiters[head as usize].wait();
+            } else {
+                self.reap();
+                core::hint::spin_loop();
+            }
+        }
+
+        let status = unsafe {(&*buffers.status as *const u8).read_volatile()};
+
+        match status {
+            STATUS_OK => Ok(()),
+            STATUS_UNSUPPORTED => Err(VirtioError::Unsupported),
+            _ => Err(VirtioError::IoError),
+        }
+    }
+
+    /// Read or write `segments` starting at `lba`. Segments longer than the device takes are split, and the pieces
+    /// sent in as many requests as the segment limit needs
+    fn transfer(&self, rtype: RequestType, lba: usize, segments: &[super::Segment]) -> Result<(), VirtioError> {
+        let mut pieces = alloc::vec::Vec::with_capacity(segments.len());
+
+        for seg in segments {
+            let mut offset = 0;
+
+            while offset < seg.len {
+                let len = (seg.len - offset).min(self.size_max);
+                let phys = seg.phys + offset;
+
+                // The device writes into the buffer of a read
+                pieces.push(match rtype {
+                    RequestType::In => Segment::write(phys, len),
+                    _ => Segment::read(phys, len),
+                });
+
+                offset += len;
+            }
+        }
+
+        let mut buffers = self.buffers.lock();
+        let mut sector = self.sector(lba);
+
+        for chunk in pieces.chunks(self.max_segments) {
+            self.request(&mut buffers, rtype, sector, chunk)?;
+
+            let len: usize = chunk.iter().map(|piece| piece.len as usize).sum();
+            sector += (len / SECTOR_SIZE) as u64;
+        }
+
+        Ok(())
+    }
+
+    fn discard(&self, block: usize, blocks: usize) -> Result<(), VirtioError> {
+        let mut buffers = self.buffers.lock();
+        let mut sector = self.sector(block);
+        let mut remaining = blocks * self.blocksize / SECTOR_SIZE;
+
+        while remaining > 0 {
+            let sectors = remaining.min(self.max_discard);
+
+            *buffers.discard = DiscardRange {
+                sector,
+                sectors: sectors as u32,
+                flags: 0,
+            };
+
+            let seg = Segment::read(buffers.discard.phys(), core::mem::size_of::<DiscardRange>());
+            self.request(&mut buffers, RequestType::Discard, 0, &[seg])?;
+
+            sector += sectors as u64;
+            remaining -= sectors;
+        }
+
+        Ok(())
+    }
+}
+
+impl From<VirtioError> for DiskError {
+    fn from(err: VirtioError) -> Self {
+        match err {
+            VirtioError::Unsupported => DiskError::Unsupported,
+            _ => DiskError::IoError,
+        }
+    }
+}
+
+impl super::Disk for VirtioBlk {
+    fn blocksize(&self) -> usize {
+        self.blocksize
+    }
+
+    fn blocks(&self) -> usize {
+        self.blocks
+    }
+
+    fn chs_end(&self) -> u32 {
+        0xffffffff
+    }
+
+    fn max_blocks(&self) -> usize {
+        self.max_transfer / self.blocksize
+    }
+
+    fn max_segments(&self) -> usize {
+        self.max_segments
+    }
+
+    fn execute(&self, op: Op, lba: usize, blocks: usize, segments: &[super::Segment]) -> Result<(), DiskError> {
+        let result = match op {
+            Op::Read => self.transfer(RequestType::In, lba, segments),
+            Op::Write => {
+                if self.read_only() {
+                    return Err(DiskError::ReadOnly);
+                }
+
+                self.transfer(RequestType::Out, lba, segments)
+            },
+            // Without a flush command there's no volatile cache to flush
+            Op::Flush if self.features & feat::FLUSH == 0 => Ok(()),
+            Op::Flush => self.request(&mut self.buffers.lock(), RequestType::Flush, 0, &[]),
+            // Discarding is only a hint
+            Op::Discard if self.max_discard == 0 || self.read_only() => Ok(()),
+            Op::Discard => self.discard(lba, blocks),
+        };
+
+        if let Err(err) = &result {
+            println!("virtio-blk: {:?} of block {} failed: {:?}", op, lba, err);
+        }
+
+        result.map_err(DiskError::from)
+    }
+}
@@ -0,0 +1,202 @@
+// The IMSIC receives message signaled interrupts for a single hart, each hart has its own interrupt file.
+// Identities are allocated here, and handed out to anything that can write an MSI, such as an APLIC in MSI mode.
+
+use alloc::{collections::BTreeMap, vec::Vec};
+use core::sync::atomic::{AtomicPtr, AtomicU32, Ordering};
+use spin::Mutex;
+
+use crate::println;
+
+const SISELECT: usize = 0x150;
+
+const EIDELIVERY: usize = 0x70;
+const EITHRESHOLD: usize = 0x72;
+const EIP0: usize = 0x80;
+const EIE0: usize = 0xC0;
+
+/// The interrupt file of the current hart
+pub static IMSIC: AtomicPtr<Imsic> = AtomicPtr::new(core::ptr::null_mut());
+
+/// Key: interrupt identity
+/// Value: handler, and the argument passed to it
+/// Taken by the interrupt handler, so only ever locked with interrupts disabled
+static VECTORS: Mutex<BTreeMap<u32, (fn(u32), u32)>> = Mutex::new(BTreeMap::new());
+
+pub struct Imsic {
+    /// Physical address of the interrupt file, this is what MSIs get written to
+    phys: usize,
+    /// Index of this hart within the IMSIC group
+    hart_idx: u32,
+    ids: u32,
+    next_id: AtomicU32,
+    /// Identities given back, handed out again before new ones
+    free_ids: Mutex<Vec<u32>>,
+}
+
+impl Imsic {
+    /// # Safety
+    /// `phys` must be the address of the current hart's supervisor interrupt file
+    pub unsafe fn new(phys: usize, hart_idx: u32, ids: u32) -> Self {
+        Self {
+            phys,
+            hart_idx,
+            ids,
+            // Identity 0 is reserved, and the first usable one is kept for IPIs
+            next_id: AtomicU32::new(crate::arch::ipi::IPI_ID + 1),
+            free_ids: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Turn on interrupt delivery for this hart's interrupt file
+    pub fn init(&self) {
+        self.init_hart();
+        self.set_vector(crate::arch::ipi::IPI_ID, crate::arch::ipi::imsic_ipi, crate::arch::ipi::IPI_ID);
+
+        println!("IMSIC at 0x{:x} hart index {} with {} identities", self.phys, self.hart_idx, self.ids);
+    }
+
+    /// Turn on delivery for the interrupt file of the hart this runs on, with only the IPI identity enabled.
+    /// Other harts only take IPIs, device interrupts are all routed to the boot hart
+    pub fn init_hart(&self) {
+        unsafe {
+            write_indirect(EIDELIVERY, 1);
+            write_indirect(EITHRESHOLD, 0);
+
+            for reg in 0..(self.ids as usize).div_ceil(64) {
+                write_indirect(EIE0 + reg * 2, 0);
+                write_indirect(EIP0 + reg * 2, 0);
+            }
+        }
+
+        self.enable(crate::arch::ipi::IPI_ID);
+    }
+
+    pub fn phys(&self) -> usize {
+        self.phys
+    }
+
+    pub fn hart_idx(&self) -> u32 {
+        self.hart_idx
+    }
+
+    /// Allocate an unused interrupt identity
+    pub fn alloc_id(&self) -> Option<u32> {
+        if let Some(id) = self.free_ids.lock().pop() {
+            return Some(id);
+        }
+
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+
+        if id > self.ids {
+            None
+        } else {
+            Some(id)
+        }
+    }
+
+    /// Give back an identity from `alloc_id`, its vector must already be cleared
+    pub fn free_id(&self, id: u32) {
+        self.free_ids.lock().push(id);
+    }
+
+    /// Set the handler for an identity, and enable it
+    pub fn set_vector(&self, id: u32, handler: fn(u32), arg: u32) {
+        crate::arch::trap::without_interrupts(|| VECTORS.lock().insert(id, (handler, arg)));
+        self.enable(id);
+    }
+
+    /// Disable an identity, and remove its handler
+    pub fn clear_vector(&self, id: u32) {
+        self.disable(id);
+        crate::arch::trap::without_interrupts(|| VECTORS.lock().remove(&id));
+    }
+
+    pub fn enable(&self, id: u32) {
+        let reg = EIE0 + (id as usize / 64) * 2;
+
+        unsafe {
+            set_indirect(reg, 1 << (id % 64));
+        }
+    }
+
+    pub fn disable(&self, id: u32) {
+        let reg = EIE0 + (id as usize / 64) * 2;
+
+        unsafe {
+            clear_indirect(reg, 1 << (id % 64));
+        }
+    }
+
+    /// Claim the highest priority pending identity
+    pub fn claim(&self) -> Option<u32> {
+        let topei: usize;
+
+        unsafe {
+            core::arch::asm!(
+                "csrrw {topei}, 0x15C, zero",
+                topei = out(reg) topei
+            );
+        }
+
+        let id = (topei >> 16) as u32;
+
+        if id == 0 {
+            None
+        } else {
+            Some(id)
+        }
+    }
+
+    /// Run the handlers of every pending identity
+    pub fn handle(&self) {
+        while let Some(id) = self.claim() {
+            let vector = VECTORS.lock().get(&id).copied();
+
+            match vector {
+                Some((handler, arg)) => handler(arg),
+                None => println!("Spurious IMSIC identity {}", id),
+            }
+        }
+    }
+}
+
+unsafe fn write_indirect(reg: usize, val: usize) {
+    core::arch::asm!(
+        "csrw {siselect}, {reg}",
+        "csrw 0x151, {val}",
+        siselect = const SISELECT,
+        reg = in(reg) reg,
+        val = in(reg) val,
+    );
+}
+
+unsafe fn set_indirect(reg: usize, bits: usize) {
+    core::arch::asm!(
+        "csrw {siselect}, {reg}",
+        "csrs 0x151, {bits}",
+        siselect = const SISELECT,
+        reg = in(reg) reg,
+        bits = in(reg) bits,
+    );
+}
+
+unsafe fn clear_indirect(reg: usize, bits: usize) {
+    core::arch::asm!(
+        "csrw {siselect}, {reg}",
+        "csrc 0x151, {bits}",
+        siselect = const SISELECT,
+        reg = in(reg) reg,
+        bits = in(reg) bits,
+    );
+}
+
+/// Returns the interrupt file of the current hart, if there is one
+pub fn get() -> Option<&'static Imsic> {
+    let ptr = IMSIC.load(Ordering::Relaxed);
+
+    if ptr.is_null() {
+        None
+    } else {
+        unsafe {Some(&*ptr)}
+    }
+}
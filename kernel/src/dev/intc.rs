@@ -0,0 +1,218 @@
+use alloc::{collections::BTreeMap, vec::Vec, boxed::Box};
+use spin::{Mutex, Once};
+
+use crate::acpi::tables::madt;
+use crate::arch::trap::without_interrupts;
+use crate::println;
+
+/// Every interrupt controller found in the MADT, set once by `init`
+static CONTROLLERS: Once<Vec<&'static dyn IntController>> = Once::new();
+
+/// Key: GSI
+/// Value: Handler
+/// Taken by the interrupt handler, so only ever locked with interrupts disabled
+static HANDLERS: Mutex<BTreeMap<u32, fn(u32)>> = Mutex::new(BTreeMap::new());
+
+/// A controller for wired interrupts, such as the APLIC or PLIC
+pub trait IntController: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// First GSI routed through this controller
+    fn gsi_base(&self) -> u32;
+
+    /// Number of interrupt sources, these are numbered starting at 1
+    fn sources(&self) -> u32;
+
+    /// Route `source` to the current hart and unmask it
+    fn enable(&self, source: u32, trigger: Trigger);
+
+    fn disable(&self, source: u32);
+
+    /// Claim the highest priority pending source for this hart.
+    /// Controllers that deliver through the IMSIC always return `None`
+    fn claim(&self) -> Option<u32>;
+
+    /// Signal that a claimed source has been handled
+    fn complete(&self, source: u32);
+
+    fn handles(&self, gsi: u32) -> bool {
+        let base = self.gsi_base();
+
+        gsi > base && gsi - base <= self.sources()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Trigger {
+    EdgeRising,
+    EdgeFalling,
+    LevelHigh,
+    LevelLow,
+}
+
+#[derive(Debug)]
+pub enum IrqError {
+    /// No controller handles the requested GSI
+    NoController,
+    /// The GSI already has a handler
+    InUse,
+    /// The GSI is shared, but was requested with a different trigger mode
+    Incompatible,
+}
+
+/// Find and initialize the interrupt controllers described in the MADT.
+/// Controllers supporting AIA are prefered, the PLIC is only used when no APLIC exists
+pub fn init() {
+    let madt = madt::Madt::get().expect("No MADT present");
+    let hartid = crate::cpu::hart_id() as u64;
+
+    let mut rintc = None;
+    let mut imsic = None;
+    let mut min_imsic_addr = u64::MAX;
+    let mut aplics = Vec::new();
+    let mut plics = Vec::new();
+    let mut controllers = Vec::new();
+
+    for entry in madt.iter() {
+        match entry.etype() {
+            madt::EntryType::RiscvIntController => {
+                let ctrl = madt::RiscvIntController::from_entry(entry).unwrap();
+
+                if ctrl.imsic_addr() != 0 {
+                    min_imsic_addr = core::cmp::min(min_imsic_addr, ctrl.imsic_addr());
+                }
+
+                if ctrl.hartid() == hartid {
+                    rintc = Some(ctrl);
+                }
+            },
+            madt::EntryType::Imsic => imsic = madt::Imsic::from_entry(entry),
+            madt::EntryType::Aplic => aplics.push(madt::Aplic::from_entry(entry).unwrap()),
+            madt::EntryType::Plic => plics.push(madt::Plic::from_entry(entry).unwrap()),
+            _ => {}
+        }
+    }
+
+    let rintc = rintc.expect("No RINTC for the boot hart");
+
+    if let Some(imsic) = imsic {
+        let file_size = core::cmp::max(rintc.imsic_size() as u64, 0x1000);
+        let hart_idx = (rintc.imsic_addr() - min_imsic_addr) / file_size;
+
+        let file = unsafe {
+            super::imsic::Imsic::new(
+                rintc.imsic_addr() as usize,
+                hart_idx as u32,
+                imsic.smode_int_ids() as u32
+            )
+        };
+        file.init();
+
+        super::imsic::IMSIC.store(Box::leak(Box::new(file)), core::sync::atomic::Ordering::Relaxed);
+    }
+
+    if !aplics.is_empty() {
+        for aplic in aplics {
+            let ctrl = unsafe {super::aplic::Aplic::new(aplic, rintc)};
+            ctrl.init();
+
+            controllers.push(add_controller(Box::leak(Box::new(ctrl))));
+        }
+    } else {
+        for plic in plics {
+            let ctrl = unsafe {super::plic::Plic::new(plic, rintc)};
+            ctrl.init();
+
+            controllers.push(add_controller(Box::leak(Box::new(ctrl))));
+        }
+    }
+
+    if controllers.is_empty() {
+        println!("No supported interrupt controller found");
+    }
+
+    CONTROLLERS.call_once(|| controllers);
+}
+
+/// Set up interrupt delivery on a hart other than the boot hart. Wired interrupts stay routed to the boot hart, so
+/// this only has to let IPIs in
+pub fn init_ap() {
+    if let Some(imsic) = super::imsic::get() {
+        imsic.init_hart();
+    }
+}
+
+fn add_controller(ctrl: &'static dyn IntController) -> &'static dyn IntController {
+    println!(
+        "Using {} for GSIs {}..={}",
+        ctrl.name(),
+        ctrl.gsi_base() + 1,
+        ctrl.gsi_base() + ctrl.sources()
+    );
+
+    ctrl
+}
+
+fn controllers() -> &'static [&'static dyn IntController] {
+    CONTROLLERS.get().map_or(&[], |controllers| controllers.as_slice())
+}
+
+fn controller(gsi: u32) -> Option<&'static dyn IntController> {
+    controllers().iter().find(|ctrl| ctrl.handles(gsi)).copied()
+}
+
+/// Register `handler` for `gsi` and enable it, the handler is passed the GSI that fired
+pub fn register(gsi: u32, trigger: Trigger, handler: fn(u32)) -> Result<(), IrqError> {
+    let ctrl = controller(gsi).ok_or(IrqError::NoController)?;
+
+    // The controllers take locks the interrupt handler takes too
+    without_interrupts(|| {
+        {
+            let mut handlers = HANDLERS.lock();
+
+            if handlers.contains_key(&gsi) {
+                return Err(IrqError::InUse);
+            }
+
+            handlers.insert(gsi, handler);
+        }
+
+        ctrl.enable(gsi - ctrl.gsi_base(), trigger);
+
+        Ok(())
+    })
+}
+
+pub fn unregister(gsi: u32) {
+    without_interrupts(|| {
+        if let Some(ctrl) = controller(gsi) {
+            ctrl.disable(gsi - ctrl.gsi_base());
+        }
+
+        HANDLERS.lock().remove(&gsi);
+    });
+}
+
+/// Run the handler registered for `gsi`, in interrupt context
+pub fn dispatch(gsi: u32) {
+    let handler = HANDLERS.lock().get(&gsi).copied();
+
+    match handler {
+        Some(handler) => handler(gsi),
+        None => println!("Unhandled GSI {}", gsi),
+    }
+}
+
+/// Entry point for supervisor external interrupts
+pub fn handle_external() {
+    if let Some(imsic) = super::imsic::get() {
+        imsic.handle();
+    }
+
+    for ctrl in controllers() {
+        while let Some(source) = ctrl.claim() {
+            dispatch(ctrl.gsi_base() + source);
+            ctrl.complete(source);
+        }
+    }
+}
@@ -0,0 +1,238 @@
+// Driver facing interrupt layer.
+// Lines are identified by GSI, and may be shared by several drivers. Handlers run in interrupt context,
+// anything slow should be returned as deferred work, which is then run on the IRQ worker thread.
+
+use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use crossbeam_queue::SegQueue;
+use spin::Mutex;
+
+use crate::acpi::resource::Resource;
+use crate::arch::trap::without_interrupts;
+use crate::println;
+use crate::scheduler::Event;
+use super::intc::{IrqError, Trigger};
+
+/// Key: GSI
+/// Value: The line, and everything registered on it
+/// Taken by the interrupt handler, so only ever locked with interrupts disabled
+static LINES: Mutex<BTreeMap<u32, Arc<IrqLine>>> = Mutex::new(BTreeMap::new());
+
+/// Deferred work waiting for the IRQ worker thread
+static DEFERRED: SegQueue<Deferred> = SegQueue::new();
+
+/// Signalled when deferred work is queued
+static WORK: Event = Event::new();
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IrqReturn {
+    /// The interrupt did not come from this handler's device
+    None,
+    /// The interrupt was fully handled
+    Handled,
+    /// The interrupt was acknowledged, run the deferred handler
+    WakeThread,
+}
+
+pub type Handler = fn(gsi: u32, data: usize) -> IrqReturn;
+pub type ThreadHandler = fn(gsi: u32, data: usize);
+
+#[derive(Clone, Copy)]
+pub struct IrqAction {
+    /// Name shown in the IRQ statistics, also used to remove the action
+    pub name: &'static str,
+    /// Run in interrupt context
+    pub handler: Handler,
+    /// Run on the IRQ worker thread when `handler` returns `IrqReturn::WakeThread`
+    pub thread_fn: Option<ThreadHandler>,
+    /// Passed to both handlers, usually a pointer to the driver's state
+    pub data: usize,
+    /// Allow other actions on the same line
+    pub shared: bool,
+}
+
+struct IrqLine {
+    trigger: Trigger,
+    actions: Mutex<Vec<IrqAction>>,
+    count: AtomicUsize,
+    deferred: AtomicUsize,
+    unhandled: AtomicUsize,
+}
+
+struct Deferred {
+    gsi: u32,
+    thread_fn: ThreadHandler,
+    data: usize,
+}
+
+#[derive(Debug)]
+pub struct IrqStats {
+    pub gsi: u32,
+    /// Names of every action on the line
+    pub names: Vec<&'static str>,
+    /// Number of times the line fired
+    pub count: usize,
+    /// Number of deferred handlers queued
+    pub deferred: usize,
+    /// Number of times no action claimed the interrupt
+    pub unhandled: usize,
+}
+
+/// Start the IRQ worker thread, must be called after the scheduler is initialized
+pub fn init() {
+    crate::scheduler::spawn_kernel_thread(irq_worker, 8);
+}
+
+/// Add `action` to the line `gsi`, enabling the line if this is the first action
+pub fn request_irq(gsi: u32, trigger: Trigger, action: IrqAction) -> Result<(), IrqError> {
+    // The whole way down to the controller, every lock taken is also taken by the interrupt handler
+    without_interrupts(|| {
+        let mut lines = LINES.lock();
+
+        if let Some(line) = lines.get(&gsi) {
+            let mut actions = line.actions.lock();
+
+            if !action.shared || actions.iter().any(|other| !other.shared) {
+                return Err(IrqError::InUse);
+            }
+            if line.trigger != trigger {
+                return Err(IrqError::Incompatible);
+            }
+
+            // Already enabled for the actions before this one
+            actions.push(action);
+            return Ok(());
+        }
+
+        let line = Arc::new(IrqLine {
+            trigger,
+            actions: Mutex::new(alloc::vec![action]),
+            count: AtomicUsize::new(0),
+            deferred: AtomicUsize::new(0),
+            unhandled: AtomicUsize::new(0),
+        });
+
+        lines.insert(gsi, line);
+        drop(lines);
+
+        super::intc::register(gsi, trigger, handle_line).map_err(|err| {
+            LINES.lock().remove(&gsi);
+            err
+        })
+    })
+}
+
+/// Request every interrupt listed in the `_CRS` of `node`.
+/// Returns the GSIs that were successfully requested
+pub fn request_crs_irqs(node: &lai::Node, action: IrqAction) -> Vec<u32> {
+    let mut gsis = Vec::new();
+
+    for resource in crate::acpi::resource::current_resources(node) {
+        if let Resource::Interrupt { gsi, trigger, shared } = resource {
+            let action = IrqAction {
+                shared: action.shared && shared,
+                ..action
+            };
+
+            match request_irq(gsi, trigger, action) {
+                Ok(()) => gsis.push(gsi),
+                Err(err) => println!("Failed to request GSI {} for {}: {:?}", gsi, action.name, err),
+            }
+        }
+    }
+
+    gsis
+}
+
+/// Remove the action called `name` from `gsi`, the line is disabled once no actions remain
+pub fn free_irq(gsi: u32, name: &str) {
+    without_interrupts(|| {
+        let mut lines = LINES.lock();
+
+        let empty = match lines.get(&gsi) {
+            Some(line) => {
+                let mut actions = line.actions.lock();
+                actions.retain(|action| action.name != name);
+                actions.is_empty()
+            },
+            None => return,
+        };
+
+        if empty {
+            lines.remove(&gsi);
+            drop(lines);
+
+            super::intc::unregister(gsi);
+        }
+    });
+}
+
+fn handle_line(gsi: u32) {
+    let line = match LINES.lock().get(&gsi) {
+        Some(line) => line.clone(),
+        None => return,
+    };
+
+    line.count.fetch_add(1, Ordering::Relaxed);
+
+    // Copy the actions so handlers are free to request or free IRQs
+    let actions = line.actions.lock().clone();
+    let mut handled = false;
+    let mut wake = false;
+
+    for action in actions {
+        match (action.handler)(gsi, action.data) {
+            IrqReturn::None => {},
+            IrqReturn::Handled => handled = true,
+            IrqReturn::WakeThread => {
+                handled = true;
+
+                if let Some(thread_fn) = action.thread_fn {
+                    wake = true;
+                    line.deferred.fetch_add(1, Ordering::Relaxed);
+                    DEFERRED.push(Deferred {
+                        gsi,
+                        thread_fn,
+                        data: action.data,
+                    });
+                }
+            }
+        }
+    }
+
+    if !handled {
+        line.unhandled.fetch_add(1, Ordering::Relaxed);
+    }
+
+    if wake {
+        WORK.signal();
+    }
+}
+
+fn irq_worker() -> ! {
+    loop {
+        while let Some(work) = DEFERRED.pop() {
+            (work.thread_fn)(work.gsi, work.data);
+        }
+
+        WORK.wait();
+    }
+}
+
+/// Number of times `gsi` has fired
+pub fn count(gsi: u32) -> Option<usize> {
+    without_interrupts(|| LINES.lock().get(&gsi).map(|line| line.count.load(Ordering::Relaxed)))
+}
+
+/// Statistics for every requested line
+pub fn stats() -> Vec<IrqStats> {
+    without_interrupts(|| LINES.lock().iter().map(|(gsi, line)| {
+        IrqStats {
+            gsi: *gsi,
+            names: line.actions.lock().iter().map(|action| action.name).collect(),
+            count: line.count.load(Ordering::Relaxed),
+            deferred: line.deferred.load(Ordering::Relaxed),
+            unhandled: line.unhandled.load(Ordering::Relaxed),
+        }
+    }).collect())
+}
@@ -3,7 +3,13 @@ pub mod ramfb;
 pub mod blockdev;
 pub mod virtio;
 pub mod aplic;
+pub mod plic;
+pub mod imsic;
+pub mod intc;
+pub mod irq;
 pub mod uart;
+pub mod rtc;
+pub mod pci;
 pub mod window;
 
 linkset::declare!(pub DRIVERS: DriverEntry);
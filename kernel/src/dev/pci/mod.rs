@@ -0,0 +1,564 @@
+// PCI Express configuration space, accessed through the ECAM regions listed in the MCFG.
+// Each function gets 4KiB of configuration space at base + (bus << 20 | device << 15 | function << 12).
+// Buses are scanned recursively from the root of each segment, following the bus numbers firmware gave bridges.
+
+use alloc::{collections::BTreeMap, vec::Vec};
+use spin::Mutex;
+
+use crate::acpi::tables::mcfg::Mcfg;
+use crate::println;
+
+pub mod msi;
+pub mod resource;
+
+static ECAMS: Mutex<Vec<Ecam>> = Mutex::new(Vec::new());
+
+/// Every function found by the last scan
+static FUNCTIONS: Mutex<Vec<Function>> = Mutex::new(Vec::new());
+
+/// Key: function and BAR
+/// Value: physical address of the BAR when it was mapped, and the virtual address it was mapped at
+static BAR_MAPPINGS: Mutex<BTreeMap<(Address, u8), (u64, usize)>> = Mutex::new(BTreeMap::new());
+
+linkset::declare!(pub PCI_DRIVERS: PciDriverEntry);
+
+pub struct PciDriverEntry {
+    pub name: &'static str,
+    pub ids: &'static [PciId],
+    pub probe: fn(function: &Function),
+}
+
+unsafe impl Send for PciDriverEntry {}
+unsafe impl Sync for PciDriverEntry {}
+
+/// Matches functions by vendor and device ID, or by class code
+#[derive(Clone, Copy, Debug)]
+pub struct PciId {
+    pub vendor: u16,
+    pub device: u16,
+    /// Class, subclass, and programming interface, as one value
+    pub class: u32,
+    /// Bits of `class` that have to match
+    pub class_mask: u32,
+}
+
+impl PciId {
+    pub const ANY: u16 = 0xffff;
+
+    pub const fn device(vendor: u16, device: u16) -> Self {
+        Self { vendor, device, class: 0, class_mask: 0 }
+    }
+
+    pub const fn class(class: u8, subclass: u8, prog_if: u8) -> Self {
+        Self {
+            vendor: Self::ANY,
+            device: Self::ANY,
+            class: (class as u32) << 16 | (subclass as u32) << 8 | prog_if as u32,
+            class_mask: 0xffffff,
+        }
+    }
+
+    pub fn matches(&self, function: &Function) -> bool {
+        (self.vendor == Self::ANY || self.vendor == function.vendor)
+            && (self.device == Self::ANY || self.device == function.device)
+            && function.class_code() & self.class_mask == self.class & self.class_mask
+    }
+}
+
+pub mod reg {
+    pub const VENDOR_ID: u16 = 0x00;
+    pub const DEVICE_ID: u16 = 0x02;
+    pub const COMMAND: u16 = 0x04;
+    pub const STATUS: u16 = 0x06;
+    pub const REVISION: u16 = 0x08;
+    pub const PROG_IF: u16 = 0x09;
+    pub const SUBCLASS: u16 = 0x0A;
+    pub const CLASS: u16 = 0x0B;
+    pub const HEADER_TYPE: u16 = 0x0E;
+    pub const BAR0: u16 = 0x10;
+    pub const SECONDARY_BUS: u16 = 0x19;
+    pub const SUBORDINATE_BUS: u16 = 0x1A;
+    pub const MEMORY_BASE: u16 = 0x20;
+    pub const MEMORY_LIMIT: u16 = 0x22;
+    pub const PREFETCH_BASE: u16 = 0x24;
+    pub const PREFETCH_LIMIT: u16 = 0x26;
+    pub const CAPABILITIES: u16 = 0x34;
+    pub const INTERRUPT_PIN: u16 = 0x3D;
+    pub const EXT_CAPABILITIES: u16 = 0x100;
+}
+
+pub mod command {
+    pub const IO_SPACE: u16 = 1 << 0;
+    pub const MEMORY_SPACE: u16 = 1 << 1;
+    pub const BUS_MASTER: u16 = 1 << 2;
+    pub const INTX_DISABLE: u16 = 1 << 10;
+}
+
+pub mod cap {
+    pub const POWER_MANAGEMENT: u8 = 0x01;
+    pub const MSI: u8 = 0x05;
+    pub const VENDOR: u8 = 0x09;
+    pub const PCI_EXPRESS: u8 = 0x10;
+    pub const MSIX: u8 = 0x11;
+}
+
+const STATUS_CAPABILITIES: u16 = 1 << 4;
+
+const HEADER_GENERAL: u8 = 0;
+const HEADER_BRIDGE: u8 = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Address {
+    pub segment: u16,
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl core::fmt::Display for Address {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{:04x}:{:02x}:{:02x}.{}", self.segment, self.bus, self.device, self.function)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Bar {
+    None,
+    Memory {
+        base: u64,
+        size: u64,
+        prefetchable: bool,
+        wide: bool,
+    },
+    Io {
+        base: u32,
+        size: u32,
+    },
+}
+
+impl Bar {
+    pub fn size(&self) -> u64 {
+        match *self {
+            Bar::None => 0,
+            Bar::Memory { size, .. } => size,
+            Bar::Io { size, .. } => size as u64,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Capability {
+    pub id: u16,
+    /// Offset in configuration space
+    pub offset: u16,
+    /// Extended capabilities live past the first 256 bytes, and have 16 bit IDs
+    pub extended: bool,
+}
+
+#[derive(Clone, Debug)]
+pub struct Function {
+    pub addr: Address,
+    pub vendor: u16,
+    pub device: u16,
+    pub revision: u8,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub header_type: u8,
+    pub multifunction: bool,
+    /// Bridges only have the first 2
+    pub bars: [Bar; 6],
+    pub capabilities: Vec<Capability>,
+    /// Legacy interrupt pin, 1 through 4 for INTA through INTD, 0 for none
+    pub interrupt_pin: u8,
+}
+
+impl Function {
+    pub fn class_code(&self) -> u32 {
+        (self.class as u32) << 16 | (self.subclass as u32) << 8 | self.prog_if as u32
+    }
+
+    pub fn is_bridge(&self) -> bool {
+        self.header_type == HEADER_BRIDGE
+    }
+
+    /// Offset of the first standard capability with `id`
+    pub fn capability(&self, id: u8) -> Option<u16> {
+        self.capabilities.iter()
+            .find(|cap| !cap.extended && cap.id == id as u16)
+            .map(|cap| cap.offset)
+    }
+
+    /// Every standard capability with `id`, some such as vendor specific capabilities appear several times
+    pub fn capabilities(&self, id: u8) -> impl Iterator<Item = u16> + '_ {
+        self.capabilities.iter()
+            .filter(move |cap| !cap.extended && cap.id == id as u16)
+            .map(|cap| cap.offset)
+    }
+
+    pub fn read<T: Copy + Default>(&self, offset: u16) -> T {
+        read(self.addr, offset)
+    }
+
+    pub fn write<T: Copy>(&self, offset: u16, val: T) {
+        write(self.addr, offset, val)
+    }
+}
+
+/// An ECAM region from the MCFG, mapped as IO memory
+#[derive(Clone, Copy)]
+struct Ecam {
+    segment: u16,
+    start_bus: u8,
+    end_bus: u8,
+    /// Virtual address of the configuration space of `start_bus`
+    virt: usize,
+}
+
+/// Find the ECAM regions and enumerate every bus.
+/// Runs before LAI, so AML can reach configuration space
+pub fn init() {
+    let Some(mcfg) = Mcfg::get() else {
+        println!("No MCFG, PCI is unavailable");
+        return;
+    };
+
+    let mut ecams = ECAMS.lock();
+
+    for entry in mcfg.entries() {
+        let (base, segment, start, end) = (entry.base, entry.segment, entry.start_bus, entry.end_bus);
+        println!("ECAM at 0x{:x} for segment {} buses {}..={}", base, segment, start, end);
+
+        if end < start {
+            continue;
+        }
+
+        // The base is where bus 0 would be, even when the region starts at a later bus
+        let phys = base as usize + ((start as usize) << 20);
+        let size = (end as usize - start as usize + 1) << 20;
+
+        ecams.push(Ecam {
+            segment,
+            start_bus: start,
+            end_bus: end,
+            virt: crate::mem::map_mmio(phys, size),
+        });
+    }
+
+    drop(ecams);
+
+    let functions = scan();
+
+    for function in functions.iter() {
+        println!(
+            "PCI {} {:04x}:{:04x} class {:06x}{}",
+            function.addr,
+            function.vendor,
+            function.device,
+            function.class_code(),
+            if function.is_bridge() {" bridge"} else {""}
+        );
+    }
+
+    *FUNCTIONS.lock() = functions;
+}
+
+/// Assign BARs firmware left empty, then probe drivers.
+/// Needs the ACPI namespace, for the host bridge windows
+pub fn start() {
+    resource::assign_bars();
+
+    for function in functions().iter() {
+        for driver in &PCI_DRIVERS {
+            if driver.ids.iter().any(|id| id.matches(function)) {
+                println!("PCI {}: probing {}", function.addr, driver.name);
+                (driver.probe)(function);
+                break;
+            }
+        }
+    }
+}
+
+/// Every function found during enumeration
+pub fn functions() -> Vec<Function> {
+    FUNCTIONS.lock().clone()
+}
+
+/// Pointer to the configuration space of `addr`, if an ECAM region covers it
+fn config_base(addr: Address) -> Option<*mut u8> {
+    let ecams = ECAMS.lock();
+
+    let ecam = ecams.iter().find(|ecam| {
+        ecam.segment == addr.segment && (ecam.start_bus..=ecam.end_bus).contains(&addr.bus)
+    })?;
+
+    let offset = ((addr.bus - ecam.start_bus) as usize) << 20
+        | (addr.device as usize) << 15
+        | (addr.function as usize) << 12;
+
+    Some((ecam.virt + offset) as *mut u8)
+}
+
+/// Read configuration space, reads of functions that don't exist return all ones
+pub fn read<T: Copy + Default>(addr: Address, offset: u16) -> T {
+    match config_base(addr) {
+        Some(base) if offset as usize + core::mem::size_of::<T>() <= 0x1000 => unsafe {
+            (base.add(offset as usize) as *const T).read_volatile()
+        },
+        _ => {
+            let mut val = T::default();
+            unsafe {core::ptr::write_bytes(&mut val as *mut T as *mut u8, 0xff, core::mem::size_of::<T>())};
+            val
+        }
+    }
+}
+
+pub fn write<T: Copy>(addr: Address, offset: u16, val: T) {
+    match config_base(addr) {
+        Some(base) if offset as usize + core::mem::size_of::<T>() <= 0x1000 => unsafe {
+            (base.add(offset as usize) as *mut T).write_volatile(val)
+        },
+        _ => {}
+    }
+}
+
+/// Enumerate every function reachable from the root bus of each ECAM region
+pub fn scan() -> Vec<Function> {
+    let ecams = ECAMS.lock().clone();
+    let mut functions = Vec::new();
+
+    for ecam in ecams {
+        scan_bus(ecam.segment, ecam.start_bus, &mut functions, 0);
+    }
+
+    functions
+}
+
+fn scan_bus(segment: u16, bus: u8, functions: &mut Vec<Function>, depth: usize) {
+    // Misconfigured bridges could send us around in circles
+    if depth > 32 {
+        return;
+    }
+
+    for device in 0..32 {
+        let addr = Address { segment, bus, device, function: 0 };
+
+        let Some(first) = probe_function(addr) else {
+            continue;
+        };
+
+        let count = if first.multifunction {8} else {1};
+        let mut found = alloc::vec![first];
+
+        for function in 1..count {
+            found.extend(probe_function(Address { function, ..addr }));
+        }
+
+        for function in found {
+            let secondary = if function.is_bridge() {
+                read::<u8>(function.addr, reg::SECONDARY_BUS)
+            } else {
+                0
+            };
+
+            functions.push(function);
+
+            // Firmware didn't assign the bridge a bus if the secondary bus is 0, or not below this one
+            if secondary > bus {
+                scan_bus(segment, secondary, functions, depth + 1);
+            }
+        }
+    }
+}
+
+fn probe_function(addr: Address) -> Option<Function> {
+    let vendor = read::<u16>(addr, reg::VENDOR_ID);
+
+    if vendor == 0xffff {
+        return None;
+    }
+
+    let header = read::<u8>(addr, reg::HEADER_TYPE);
+    let header_type = header & 0x7f;
+
+    let bar_count = match header_type {
+        HEADER_GENERAL => 6,
+        HEADER_BRIDGE => 2,
+        _ => 0,
+    };
+
+    let mut bars = [Bar::None; 6];
+    let mut bar = 0;
+
+    while bar < bar_count {
+        bars[bar] = size_bar(addr, bar as u8);
+
+        // 64 bit BARs take up the next slot too
+        bar += match bars[bar] {
+            Bar::Memory { wide: true, .. } => 2,
+            _ => 1,
+        };
+    }
+
+    Some(Function {
+        addr,
+        vendor,
+        device: read(addr, reg::DEVICE_ID),
+        revision: read(addr, reg::REVISION),
+        class: read(addr, reg::CLASS),
+        subclass: read(addr, reg::SUBCLASS),
+        prog_if: read(addr, reg::PROG_IF),
+        header_type,
+        multifunction: header & 0x80 != 0,
+        bars,
+        capabilities: capabilities(addr),
+        interrupt_pin: read(addr, reg::INTERRUPT_PIN),
+    })
+}
+
+/// Find the address and size of a BAR, by writing all ones and seeing which bits stick
+fn size_bar(addr: Address, bar: u8) -> Bar {
+    let offset = reg::BAR0 + bar as u16 * 4;
+
+    // Decoding has to be off while the BAR holds garbage
+    let cmd = read::<u16>(addr, reg::COMMAND);
+    write(addr, reg::COMMAND, cmd & !(command::IO_SPACE | command::MEMORY_SPACE));
+
+    let low = read::<u32>(addr, offset);
+    write(addr, offset, u32::MAX);
+    let low_mask = read::<u32>(addr, offset);
+    write(addr, offset, low);
+
+    let result = if low & 1 != 0 {
+        let size = (!(low_mask & !0x3)).wrapping_add(1) & 0xffff;
+
+        if low_mask & !0x3 == 0 {
+            Bar::None
+        } else {
+            Bar::Io { base: low & !0x3, size }
+        }
+    } else {
+        let wide = (low >> 1) & 0b11 == 0b10;
+
+        let (base, mask) = if wide {
+            let high = read::<u32>(addr, offset + 4);
+            write(addr, offset + 4, u32::MAX);
+            let high_mask = read::<u32>(addr, offset + 4);
+            write(addr, offset + 4, high);
+
+            (
+                (high as u64) << 32 | (low & !0xf) as u64,
+                (high_mask as u64) << 32 | (low_mask & !0xf) as u64,
+            )
+        } else {
+            ((low & !0xf) as u64, (low_mask & !0xf) as u64 | 0xffffffff_00000000)
+        };
+
+        // A BAR that doesn't implement any address bits doesn't exist
+        if mask & !0xf == 0 || (!wide && low_mask & !0xf == 0) {
+            Bar::None
+        } else {
+            Bar::Memory {
+                base,
+                size: (!mask).wrapping_add(1),
+                prefetchable: low & 0x8 != 0,
+                wide,
+            }
+        }
+    };
+
+    write(addr, reg::COMMAND, cmd);
+
+    result
+}
+
+/// Walk the standard capability list, and the extended list if the function has PCI Express config space
+fn capabilities(addr: Address) -> Vec<Capability> {
+    let mut caps = Vec::new();
+
+    if read::<u16>(addr, reg::STATUS) & STATUS_CAPABILITIES != 0 {
+        let mut offset = read::<u8>(addr, reg::CAPABILITIES) & !0x3;
+
+        // Each capability is at least 4 bytes, so there can't be more than 48 in 192 bytes
+        for _ in 0..48 {
+            if offset < 0x40 {
+                break;
+            }
+
+            caps.push(Capability {
+                id: read::<u8>(addr, offset as u16) as u16,
+                offset: offset as u16,
+                extended: false,
+            });
+
+            offset = read::<u8>(addr, offset as u16 + 1) & !0x3;
+        }
+    }
+
+    let mut offset = reg::EXT_CAPABILITIES;
+
+    for _ in 0..(0x1000 - 0x100) / 4 {
+        let header = read::<u32>(addr, offset);
+
+        if header == 0 || header == u32::MAX {
+            break;
+        }
+
+        caps.push(Capability {
+            id: header as u16,
+            offset,
+            extended: true,
+        });
+
+        offset = ((header >> 20) & 0xffc) as u16;
+
+        if offset < reg::EXT_CAPABILITIES {
+            break;
+        }
+    }
+
+    caps
+}
+
+/// Physical address of a memory BAR
+pub fn bar_address(function: &Function, bar: u8) -> Option<u64> {
+    match function.bars.get(bar as usize)? {
+        Bar::Memory { base, .. } if *base != 0 => Some(*base),
+        _ => None,
+    }
+}
+
+/// Map a memory BAR as device memory, returns its virtual address.
+/// Each BAR is only mapped once, later calls get the same mapping
+pub fn map_bar(function: &Function, bar: u8) -> Option<usize> {
+    let base = bar_address(function, bar)?;
+    let size = function.bars[bar as usize].size();
+
+    let mut mappings = BAR_MAPPINGS.lock();
+
+    match mappings.get(&(function.addr, bar)) {
+        Some(&(mapped, virt)) if mapped == base => Some(virt),
+        _ => {
+            let virt = crate::mem::map_mmio(base as usize, size as usize);
+            mappings.insert((function.addr, bar), (base, virt));
+
+            Some(virt)
+        }
+    }
+}
+
+/// Replace the recorded state of a function, after its BARs are changed
+fn update_function(function: Function) {
+    let mut functions = FUNCTIONS.lock();
+
+    if let Some(old) = functions.iter_mut().find(|old| old.addr == function.addr) {
+        *old = function;
+    }
+}
+
+/// Enable memory decoding and bus mastering, so the function can be used and can DMA
+pub fn enable(addr: Address) {
+    let cmd = read::<u16>(addr, reg::COMMAND);
+
+    write(addr, reg::COMMAND, cmd | command::MEMORY_SPACE | command::BUS_MASTER);
+}
@@ -0,0 +1,189 @@
+// MSI and MSI-X.
+// Every vector gets its own IMSIC identity on the current hart, messages are written straight to its interrupt file
+// with the identity as the data.
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use crate::arch::trap::without_interrupts;
+use super::{cap, command, reg, Function};
+
+/// Key: IMSIC identity
+/// Value: handler, and the argument passed to it
+/// Taken by the interrupt handler, so only ever locked with interrupts disabled
+static MSI_HANDLERS: Mutex<BTreeMap<u32, (fn(usize), usize)>> = Mutex::new(BTreeMap::new());
+
+mod msix_reg {
+    pub const CONTROL: u16 = 2;
+    pub const TABLE: u16 = 4;
+
+    pub const ENABLE: u16 = 1 << 15;
+    pub const FUNCTION_MASK: u16 = 1 << 14;
+
+    pub const ENTRY_SIZE: usize = 16;
+    pub const ENTRY_MASKED: u32 = 1;
+}
+
+mod msi_reg {
+    pub const CONTROL: u16 = 2;
+    pub const ADDRESS: u16 = 4;
+    pub const ADDRESS_HIGH: u16 = 8;
+    pub const DATA: u16 = 8;
+    pub const DATA_WIDE: u16 = 12;
+
+    pub const ENABLE: u16 = 1 << 0;
+    pub const WIDE: u16 = 1 << 7;
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum MsiError {
+    /// There's no IMSIC to deliver messages to
+    NoImsic,
+    NoCapability,
+    OutOfIds,
+    /// The BAR holding the MSI-X table isn't assigned
+    NoBar,
+}
+
+fn dispatch(id: u32) {
+    let handler = MSI_HANDLERS.lock().get(&id).copied();
+
+    if let Some((handler, data)) = handler {
+        handler(data);
+    }
+}
+
+/// Allocate an identity that runs `handler` with `data`
+fn alloc_vector(handler: fn(usize), data: usize) -> Result<u32, MsiError> {
+    let imsic = crate::dev::imsic::get().ok_or(MsiError::NoImsic)?;
+    let id = imsic.alloc_id().ok_or(MsiError::OutOfIds)?;
+
+    without_interrupts(|| MSI_HANDLERS.lock().insert(id, (handler, data)));
+    imsic.set_vector(id, dispatch, id);
+
+    Ok(id)
+}
+
+/// Give back an identity from `alloc_vector`
+fn free_vector(id: u32) {
+    if let Some(imsic) = crate::dev::imsic::get() {
+        imsic.clear_vector(id);
+        imsic.free_id(id);
+    }
+
+    without_interrupts(|| MSI_HANDLERS.lock().remove(&id));
+}
+
+/// Stop the function raising legacy interrupts, once it has messages instead
+fn disable_intx(function: &Function) {
+    let cmd = function.read::<u16>(reg::COMMAND);
+
+    function.write(reg::COMMAND, cmd | command::INTX_DISABLE);
+}
+
+/// Number of MSI-X vectors the function has, 0 without MSI-X
+pub fn msix_count(function: &Function) -> usize {
+    match function.capability(cap::MSIX) {
+        Some(cap) => (function.read::<u16>(cap + msix_reg::CONTROL) & 0x7ff) as usize + 1,
+        None => 0,
+    }
+}
+
+/// Enable MSI-X, with vector `i` running `handlers[i]`.
+/// Returns the identity of each vector
+pub fn enable_msix(function: &Function, handlers: &[(fn(usize), usize)]) -> Result<Vec<u32>, MsiError> {
+    let cap = function.capability(cap::MSIX).ok_or(MsiError::NoCapability)?;
+    let imsic = crate::dev::imsic::get().ok_or(MsiError::NoImsic)?;
+
+    let count = msix_count(function);
+
+    if handlers.len() > count {
+        return Err(MsiError::OutOfIds);
+    }
+
+    let table = function.read::<u32>(cap + msix_reg::TABLE);
+    let bir = (table & 0x7) as u8;
+    let offset = (table & !0x7) as usize;
+
+    let base = super::map_bar(function, bir).ok_or(MsiError::NoBar)?;
+    let entries = (base + offset) as *mut u32;
+
+    // Every vector is allocated before the function is touched, so failing leaves nothing behind
+    let mut ids = Vec::with_capacity(handlers.len());
+
+    for &(handler, data) in handlers {
+        match alloc_vector(handler, data) {
+            Ok(id) => ids.push(id),
+            Err(err) => {
+                ids.into_iter().for_each(free_vector);
+                return Err(err);
+            }
+        }
+    }
+
+    // Vectors stay masked until their entries are written
+    let control = function.read::<u16>(cap + msix_reg::CONTROL);
+    function.write(cap + msix_reg::CONTROL, control | msix_reg::ENABLE | msix_reg::FUNCTION_MASK);
+
+    for (i, &id) in ids.iter().enumerate() {
+        let addr = imsic.phys() as u64;
+
+        unsafe {
+            let entry = entries.add(i * msix_reg::ENTRY_SIZE / 4);
+
+            entry.write_volatile(addr as u32);
+            entry.add(1).write_volatile((addr >> 32) as u32);
+            entry.add(2).write_volatile(id);
+            entry.add(3).write_volatile(0);
+        }
+    }
+
+    // Vectors without a handler stay masked
+    for i in handlers.len()..count {
+        unsafe {
+            entries.add(i * msix_reg::ENTRY_SIZE / 4 + 3).write_volatile(msix_reg::ENTRY_MASKED);
+        }
+    }
+
+    disable_intx(function);
+
+    let control = function.read::<u16>(cap + msix_reg::CONTROL);
+    function.write(cap + msix_reg::CONTROL, (control | msix_reg::ENABLE) & !msix_reg::FUNCTION_MASK);
+
+    Ok(ids)
+}
+
+/// Enable MSI with a single vector running `handler` with `data`.
+/// Returns the identity of the vector
+pub fn enable_msi(function: &Function, handler: fn(usize), data: usize) -> Result<u32, MsiError> {
+    let cap = function.capability(cap::MSI).ok_or(MsiError::NoCapability)?;
+    let imsic = crate::dev::imsic::get().ok_or(MsiError::NoImsic)?;
+
+    let addr = imsic.phys() as u64;
+
+    let control = function.read::<u16>(cap + msi_reg::CONTROL);
+    let wide = control & msi_reg::WIDE != 0;
+
+    if !wide && addr >> 32 != 0 {
+        return Err(MsiError::NoCapability);
+    }
+
+    let id = alloc_vector(handler, data)?;
+
+    function.write(cap + msi_reg::ADDRESS, addr as u32);
+
+    if wide {
+        function.write(cap + msi_reg::ADDRESS_HIGH, (addr >> 32) as u32);
+        function.write(cap + msi_reg::DATA_WIDE, id as u16);
+    } else {
+        function.write(cap + msi_reg::DATA, id as u16);
+    }
+
+    disable_intx(function);
+
+    // Multiple message enable is left at 0, for one vector
+    function.write(cap + msi_reg::CONTROL, (control & !(0x7 << 4)) | msi_reg::ENABLE);
+
+    Ok(id)
+}
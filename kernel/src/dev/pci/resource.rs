@@ -0,0 +1,315 @@
+// BAR assignment.
+// Memory windows come from the _CRS of the host bridges, and BARs firmware left empty are packed into what's
+// left of them above the BARs firmware did assign. Bridges firmware left closed get a memory window sized for
+// everything behind them, and the BARs behind them are placed inside it.
+
+use alloc::{collections::BTreeSet, vec::Vec};
+
+use crate::acpi::resource::{Resource, SpaceKind};
+use crate::println;
+
+use super::{reg, Bar, Function};
+
+/// A range of bus addresses the host bridge forwards to PCI
+#[derive(Clone, Copy, Debug)]
+struct Window {
+    /// Bus address of the next free byte
+    next: u64,
+    end: u64,
+    prefetchable: bool,
+}
+
+/// Bridge memory windows have a 1MiB granularity
+const BRIDGE_ALIGN: u64 = 0x10_0000;
+
+impl Window {
+    fn alloc(&mut self, size: u64, align: u64) -> Option<u64> {
+        let base = self.next.checked_next_multiple_of(align)?;
+
+        if base.checked_add(size)? > self.end {
+            return None;
+        }
+
+        self.next = base + size;
+        Some(base)
+    }
+}
+
+/// Memory windows of every host bridge in the namespace.
+/// Host bridges are the only devices under `\_SB_` that decode a bus number range
+fn windows() -> Vec<Window> {
+    let mut windows = Vec::new();
+
+    let Some(sb) = lai::resolve_path(None, "\\_SB_") else {
+        return windows;
+    };
+
+    for dev in sb.into_iter() {
+        let resources = crate::acpi::resource::current_resources(&dev);
+
+        let is_bridge = resources.iter().any(|res| {
+            matches!(res, Resource::AddressSpace(space) if space.kind == SpaceKind::BusNumber)
+        });
+
+        if !is_bridge {
+            continue;
+        }
+
+        for res in resources {
+            if let Resource::AddressSpace(space) = res {
+                // BAR addresses are used as physical addresses as is, so only identity mapped windows work
+                if space.kind == SpaceKind::Memory && space.len != 0 && space.translation == 0 {
+                    println!(
+                        "PCI window 0x{:x}..=0x{:x}{}",
+                        space.min,
+                        space.max,
+                        if space.prefetchable {" prefetchable"} else {""}
+                    );
+
+                    windows.push(Window {
+                        next: space.min,
+                        end: space.max + 1,
+                        prefetchable: space.prefetchable,
+                    });
+                }
+            }
+        }
+    }
+
+    windows
+}
+
+/// Something that needs room in a window
+#[derive(Clone, Copy)]
+enum Item {
+    Bar { function: usize, bar: usize },
+    /// The memory window of a bridge firmware left closed
+    Bridge { function: usize },
+}
+
+#[derive(Clone, Copy)]
+struct Need {
+    item: Item,
+    size: u64,
+    align: u64,
+    prefetchable: bool,
+    wide: bool,
+}
+
+/// Give every unassigned memory BAR an address, and record it
+pub fn assign_bars() {
+    let mut windows = windows();
+
+    if windows.is_empty() {
+        return;
+    }
+
+    let mut functions = super::functions();
+
+    reserve(&mut windows, &functions);
+
+    // Buses no bridge leads to sit directly behind the host bridges
+    let behind_bridges = functions.iter()
+        .filter_map(|function| Some((function.addr.segment, secondary_bus(function)?)))
+        .collect::<BTreeSet<_>>();
+
+    let roots = functions.iter()
+        .map(|function| (function.addr.segment, function.addr.bus))
+        .filter(|bus| !behind_bridges.contains(bus))
+        .collect::<BTreeSet<_>>();
+
+    for (segment, bus) in roots {
+        place(&mut windows, &mut functions, segment, bus, 0);
+    }
+
+    for function in functions {
+        super::update_function(function);
+    }
+}
+
+/// Start allocating past anything firmware already placed in a window
+fn reserve(windows: &mut [Window], functions: &[Function]) {
+    for function in functions {
+        let mut used = function.bars.iter()
+            .filter_map(|bar| match *bar {
+                Bar::Memory { base, size, .. } if base != 0 => Some((base, base + size)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        used.extend(bridge_window(function));
+
+        for (base, end) in used {
+            for window in windows.iter_mut() {
+                if (window.next..window.end).contains(&base) {
+                    window.next = window.next.max(end);
+                }
+            }
+        }
+    }
+}
+
+/// The bus behind a bridge, if firmware gave it one
+fn secondary_bus(function: &Function) -> Option<u8> {
+    let secondary = function.read::<u8>(reg::SECONDARY_BUS);
+
+    (function.is_bridge() && secondary > function.addr.bus).then_some(secondary)
+}
+
+/// The memory window a bridge forwards, if it's open
+fn bridge_window(function: &Function) -> Option<(u64, u64)> {
+    if !function.is_bridge() {
+        return None;
+    }
+
+    let base = ((function.read::<u16>(reg::MEMORY_BASE) & 0xfff0) as u64) << 16;
+    let limit = ((function.read::<u16>(reg::MEMORY_LIMIT) & 0xfff0) as u64) << 16 | 0xfffff;
+
+    // Out of reset both registers are 0, which isn't a window anyone set up
+    (base != 0 && limit > base).then_some((base, limit + 1))
+}
+
+/// Everything on `bus` that still needs room, largest alignment first to keep padding down
+fn needs(functions: &[Function], segment: u16, bus: u8, depth: usize) -> Vec<Need> {
+    let mut needs = Vec::new();
+
+    // Misconfigured bridges could send us around in circles
+    if depth > 32 {
+        return needs;
+    }
+
+    for (f, function) in functions.iter().enumerate() {
+        if function.addr.segment != segment || function.addr.bus != bus {
+            continue;
+        }
+
+        for (b, bar) in function.bars.iter().enumerate() {
+            if let Bar::Memory { base: 0, size, prefetchable, wide } = *bar {
+                needs.push(Need {
+                    item: Item::Bar { function: f, bar: b },
+                    size,
+                    align: size,
+                    prefetchable,
+                    wide,
+                });
+            }
+        }
+
+        let Some(secondary) = secondary_bus(function) else {
+            continue;
+        };
+
+        if bridge_window(function).is_some() {
+            continue;
+        }
+
+        let behind = needs(functions, segment, secondary, depth + 1);
+
+        if behind.is_empty() {
+            continue;
+        }
+
+        // Lay out what's behind the bridge the same way `place` will, to find how big the window has to be
+        let align = behind.iter().map(|need| need.align).max().unwrap().max(BRIDGE_ALIGN);
+        let mut layout = Window { next: 0, end: u64::MAX, prefetchable: false };
+
+        for need in behind.iter() {
+            layout.alloc(need.size, need.align);
+        }
+
+        // The non-prefetchable window only decodes 32 bit addresses
+        needs.push(Need {
+            item: Item::Bridge { function: f },
+            size: layout.next.next_multiple_of(BRIDGE_ALIGN),
+            align,
+            prefetchable: false,
+            wide: false,
+        });
+    }
+
+    needs.sort_by(|a, b| b.align.cmp(&a.align).then(b.size.cmp(&a.size)));
+    needs
+}
+
+/// Assign what `bus` needs from `windows`, then do the same behind every bridge on it
+fn place(windows: &mut [Window], functions: &mut [Function], segment: u16, bus: u8, depth: usize) {
+    for need in needs(functions, segment, bus, depth) {
+        let fits = |window: &Window| {
+            (need.wide || window.end <= 1 << 32) && (need.prefetchable || !window.prefetchable)
+        };
+
+        // Prefer a window of the same kind, but a prefetchable BAR can live anywhere
+        let mut base = windows.iter_mut()
+            .filter(|window| fits(window) && window.prefetchable == need.prefetchable)
+            .find_map(|window| window.alloc(need.size, need.align));
+
+        if base.is_none() {
+            base = windows.iter_mut()
+                .filter(|window| fits(window))
+                .find_map(|window| window.alloc(need.size, need.align));
+        }
+
+        match (need.item, base) {
+            (Item::Bar { function: f, bar: b }, Some(base)) => {
+                let function = &mut functions[f];
+
+                write_bar(function, b as u8, base);
+
+                if let Bar::Memory { base: old, .. } = &mut function.bars[b] {
+                    *old = base;
+                }
+
+                println!("PCI {}: BAR {} at 0x{:x} size 0x{:x}", function.addr, b, base, need.size);
+            },
+            (Item::Bridge { function: f }, Some(base)) => {
+                let function = &functions[f];
+                let end = base + need.size;
+
+                function.write(reg::MEMORY_BASE, (base >> 16) as u16 & 0xfff0);
+                function.write(reg::MEMORY_LIMIT, ((end - 1) >> 16) as u16 & 0xfff0);
+
+                // Everything behind goes through the window above, keep the prefetchable one closed
+                function.write(reg::PREFETCH_BASE, 0xfff0u16);
+                function.write(reg::PREFETCH_LIMIT, 0u16);
+
+                super::enable(function.addr);
+
+                println!("PCI {}: bridge window 0x{:x}..0x{:x}", function.addr, base, end);
+            },
+            (Item::Bar { function: f, bar: b }, None) => {
+                println!("PCI {}: no room for BAR {} of 0x{:x} bytes", functions[f].addr, b, need.size);
+            },
+            (Item::Bridge { function: f }, None) => {
+                println!("PCI {}: no room for a bridge window of 0x{:x} bytes", functions[f].addr, need.size);
+            },
+        }
+    }
+
+    // Bridges with a window now, whether from firmware or from above, get what's behind them placed inside it
+    if depth >= 32 {
+        return;
+    }
+
+    let bridges = functions.iter()
+        .filter(|function| function.addr.segment == segment && function.addr.bus == bus)
+        .filter_map(|function| Some((secondary_bus(function)?, bridge_window(function)?)))
+        .collect::<Vec<_>>();
+
+    for (secondary, (base, end)) in bridges {
+        let mut window = [Window { next: base, end, prefetchable: false }];
+
+        reserve(&mut window, functions);
+        place(&mut window, functions, segment, secondary, depth + 1);
+    }
+}
+
+fn write_bar(function: &Function, bar: u8, base: u64) {
+    let offset = reg::BAR0 + bar as u16 * 4;
+    let low = function.read::<u32>(offset) & 0xf;
+
+    function.write(offset, base as u32 | low);
+
+    if let Bar::Memory { wide: true, .. } = function.bars[bar as usize] {
+        function.write(offset + 4, (base >> 32) as u32);
+    }
+}
@@ -0,0 +1,123 @@
+// The PLIC is the interrupt controller used by systems without AIA.
+// Each hart privilege level pair has its own context, which is given to us by the RINTC.
+
+use crate::acpi::tables::madt;
+use super::intc::Trigger;
+
+const PRIORITY_OFFSET: usize = 0x0;
+const ENABLE_OFFSET: usize = 0x2000;
+const ENABLE_STRIDE: usize = 0x80;
+const CONTEXT_OFFSET: usize = 0x20_0000;
+const CONTEXT_STRIDE: usize = 0x1000;
+
+pub struct Plic {
+    base: usize,
+    gsi_base: u32,
+    sources: u32,
+    max_priority: u32,
+    context: usize,
+}
+
+impl Plic {
+    /// # Safety
+    /// The MADT entries must describe a real PLIC, and the current hart
+    pub unsafe fn new(plic: &madt::Plic, rintc: &madt::RiscvIntController) -> Self {
+        let base = crate::mem::PhysicalAddress::new(plic.addr() as usize).to_virt().addr();
+
+        Self {
+            base,
+            gsi_base: plic.int_base(),
+            sources: plic.ext_ints() as u32,
+            max_priority: plic.max_priority() as u32,
+            context: rintc.ext_int_id().idc_id() as usize,
+        }
+    }
+
+    pub fn init(&self) {
+        for source in 1..=self.sources {
+            self.set_enable(source, false);
+            self.set_priority(source, 0);
+        }
+
+        self.set_threshold(0);
+    }
+
+    fn reg(&self, offset: usize) -> *mut u32 {
+        (self.base + offset) as *mut u32
+    }
+
+    pub fn set_priority(&self, source: u32, priority: u32) {
+        let priority = core::cmp::min(priority, self.max_priority);
+
+        unsafe {
+            self.reg(PRIORITY_OFFSET + source as usize * 4).write_volatile(priority);
+        }
+    }
+
+    pub fn set_threshold(&self, threshold: u32) {
+        unsafe {
+            self.reg(CONTEXT_OFFSET + self.context * CONTEXT_STRIDE).write_volatile(threshold);
+        }
+    }
+
+    fn set_enable(&self, source: u32, enable: bool) {
+        let reg = self.reg(
+            ENABLE_OFFSET + self.context * ENABLE_STRIDE + (source as usize / 32) * 4
+        );
+        let bit = 1 << (source % 32);
+
+        unsafe {
+            let val = reg.read_volatile();
+
+            if enable {
+                reg.write_volatile(val | bit);
+            } else {
+                reg.write_volatile(val & !bit);
+            }
+        }
+    }
+
+    fn claim_reg(&self) -> *mut u32 {
+        self.reg(CONTEXT_OFFSET + self.context * CONTEXT_STRIDE + 4)
+    }
+}
+
+impl super::intc::IntController for Plic {
+    fn name(&self) -> &'static str {
+        "PLIC"
+    }
+
+    fn gsi_base(&self) -> u32 {
+        self.gsi_base
+    }
+
+    fn sources(&self) -> u32 {
+        self.sources
+    }
+
+    /// The PLIC has no trigger configuration, edge and level are handled the same way
+    fn enable(&self, source: u32, _trigger: Trigger) {
+        self.set_priority(source, 1);
+        self.set_enable(source, true);
+    }
+
+    fn disable(&self, source: u32) {
+        self.set_enable(source, false);
+    }
+
+    fn claim(&self) -> Option<u32> {
+        let source = unsafe {self.claim_reg().read_volatile()};
+
+        if source == 0 {
+            None
+        } else {
+            Some(source)
+        }
+    }
+
+    fn complete(&self, source: u32) {
+        unsafe {
+            self.claim_reg().write_volatile(source);
+        }
+    }
+}
@@ -0,0 +1,72 @@
+// Goldfish RTC, as emulated by QEMU.
+// It counts nanoseconds since the Unix epoch, and is only read once to set the wall clock.
+
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+use crate::acpi::resource::Resource;
+use crate::println;
+use crate::utils::Volatile;
+
+use super::DRIVERS;
+
+linkset::entry!(DRIVERS, super::DriverEntry, super::DriverEntry {
+    id: "GFSH0001",
+    init,
+});
+
+pub static RTC: AtomicPtr<GoldfishRtc> = AtomicPtr::new(core::ptr::null_mut());
+
+#[repr(C)]
+pub struct GoldfishRtc {
+    /// Reading this latches the high half into `time_high`
+    time_low: Volatile<u32>,
+    time_high: Volatile<u32>,
+    alarm_low: Volatile<u32>,
+    alarm_high: Volatile<u32>,
+    irq_enabled: Volatile<u32>,
+    clear_alarm: Volatile<u32>,
+    alarm_status: Volatile<u32>,
+    clear_interrupt: Volatile<u32>,
+}
+
+impl GoldfishRtc {
+    /// Nanoseconds since the Unix epoch
+    pub fn read(&self) -> u64 {
+        let low = self.time_low.read() as u64;
+        let high = self.time_high.read() as u64;
+
+        (high << 32) | low
+    }
+}
+
+fn init(node: lai::Node) {
+    let base = crate::acpi::resource::current_resources(&node)
+        .into_iter()
+        .find_map(|res| match res {
+            Resource::Memory { base, .. } => Some(base),
+            Resource::AddressSpace(space) if space.kind == crate::acpi::resource::SpaceKind::Memory => {
+                Some(space.min + space.translation)
+            },
+            _ => None,
+        });
+
+    let Some(base) = base else {
+        println!("Goldfish RTC has no MMIO range");
+        return;
+    };
+
+    let rtc: *mut GoldfishRtc = crate::mem::PhysicalAddress::new(base as usize).to_virt().to_mut_ptr();
+
+    // Nothing here uses the alarm
+    unsafe {
+        (*rtc).irq_enabled.write(0);
+        (*rtc).clear_alarm.write(1);
+    }
+
+    RTC.store(rtc, Ordering::Relaxed);
+
+    let now = unsafe {(*rtc).read()};
+    crate::time::wall::set(now as u128);
+
+    println!("Goldfish RTC at 0x{:x}, time is {}", base, crate::time::wall::DateTime::from_unix_ns(now as u128));
+}
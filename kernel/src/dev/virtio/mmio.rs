@@ -0,0 +1,288 @@
+// virtio-mmio transport, found through ACPI `LNRO0005` nodes
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use libsa::endian::LittleEndianU32;
+use spin::Mutex;
+
+use crate::acpi::resource::Resource;
+use crate::dev::irq::{IrqAction, IrqReturn};
+use crate::dev::DRIVERS;
+use crate::println;
+use crate::utils::{Volatile, Read, Write, ReadWrite};
+use super::{DeviceType, Transport};
+
+#[repr(C)]
+pub struct VirtIoHeader {
+    /// MUST return 0x74726976
+    pub magic: Volatile<LittleEndianU32, Read>,
+    /// MUST return 0x2
+    pub version: Volatile<LittleEndianU32, Read>,
+    pub device_id: Volatile<LittleEndianU32, Read>,
+    pub vendor_id: Volatile<LittleEndianU32, Read>,
+
+    pub device_feat: Volatile<LittleEndianU32, Read>,
+    pub device_feat_sel: Volatile<LittleEndianU32, Write>,
+    _res0: [u8; 8],
+
+    pub driver_feat: Volatile<u32, Write>,
+    pub driver_feat_sel: Volatile<LittleEndianU32, Write>,
+    _res1: [u8; 8],
+
+    pub queue_sel: Volatile<LittleEndianU32, Write>,
+    pub queue_size_max: Volatile<LittleEndianU32, Read>,
+    pub queue_size: Volatile<LittleEndianU32, Write>,
+    _res2: [u8; 8],
+
+    pub queue_ready: Volatile<LittleEndianU32, ReadWrite>,
+    _res3: [u8; 8],
+
+    pub queue_notify: Volatile<LittleEndianU32, Write>,
+    _res4: [u8; 12],
+
+    pub int_stat: Volatile<LittleEndianU32, Read>,
+    pub int_ack: Volatile<LittleEndianU32, Write>,
+    _res5: [u8; 8],
+
+    pub status: Volatile<LittleEndianU32, ReadWrite>,
+    _res6: [u8; 12],
+
+    pub queue_desc_lo: Volatile<LittleEndianU32, Write>,
+    pub queue_desc_hi: Volatile<LittleEndianU32, Write>,
+    _res7: [u8; 8],
+
+    pub queue_avail_lo: Volatile<LittleEndianU32, Write>,
+    pub queue_avail_hi: Volatile<LittleEndianU32, Write>,
+    _res8: [u8; 8],
+
+    pub queue_used_lo: Volatile<LittleEndianU32, Write>,
+    pub queue_used_hi: Volatile<LittleEndianU32, Write>,
+    _res9: [u8; 4],
+
+    pub shared_mem_sel: Volatile<LittleEndianU32, Write>,
+    
+    pub shared_mem_len_lo: Volatile<LittleEndianU32, Read>,
+    pub shared_mem_len_hi: Volatile<LittleEndianU32, Read>,
+    
+    pub shared_mem_base_lo: Volatile<LittleEndianU32, Read>,
+    pub shared_mem_base_hi: Volatile<LittleEndianU32, Read>,
+
+    pub queue_reset: Volatile<LittleEndianU32, ReadWrite>,
+    _res10: [u8; 56],
+
+    pub config_generation: Volatile<u32, Read>,
+}
+
+impl VirtIoHeader {
+    /// # Safety
+    /// Must be valid pointer
+    pub unsafe fn from_mut_ptr<T>(ptr: *mut T) -> Option<&'static mut VirtIoHeader> {
+        let ptr = ptr as *mut VirtIoHeader;
+        if !(*ptr).is_valid() {
+            None
+        } else {
+            Some(&mut *ptr)
+        }
+    }
+
+    /// Whether this is a virtio 1.x device, legacy devices report version 1
+    pub fn is_valid(&self) -> bool {
+        self.magic.read().get() == 0x74726976 && self.version.read().get() == 2
+    }
+
+    pub fn dev_id(&self) -> DeviceType {
+        DeviceType::from(self.device_id.read().get() as usize)
+    }
+}
+
+/// Device specific configuration starts after the header
+const CONFIG_OFFSET: usize = 0x100;
+
+const INT_USED_BUFFER: u32 = 1;
+const INT_CONFIG_CHANGE: u32 = 2;
+
+pub struct MmioTransport {
+    header: &'static VirtIoHeader,
+    /// Physical address of the header
+    phys: usize,
+    /// Whether any of the device's interrupts could be requested
+    irq: AtomicBool,
+    handler: Mutex<Option<(fn(usize), usize)>>,
+}
+
+impl Transport for MmioTransport {
+    fn device_id(&self) -> u32 {
+        self.header.device_id.read().get()
+    }
+
+    fn device_features(&self) -> u64 {
+        self.header.device_feat_sel.write(LittleEndianU32::new(0));
+        let low = self.header.device_feat.read().get() as u64;
+
+        self.header.device_feat_sel.write(LittleEndianU32::new(1));
+        let high = self.header.device_feat.read().get() as u64;
+
+        (high << 32) | low
+    }
+
+    fn set_driver_features(&self, features: u64) {
+        self.header.driver_feat_sel.write(LittleEndianU32::new(0));
+        self.header.driver_feat.write(features as u32);
+
+        self.header.driver_feat_sel.write(LittleEndianU32::new(1));
+        self.header.driver_feat.write((features >> 32) as u32);
+    }
+
+    fn status(&self) -> u8 {
+        self.header.status.read().get() as u8
+    }
+
+    fn set_status(&self, status: u8) {
+        self.header.status.write(LittleEndianU32::new(status as u32));
+    }
+
+    fn max_queue_size(&self, queue: u16) -> u16 {
+        self.header.queue_sel.write(LittleEndianU32::new(queue as u32));
+
+        // A queue that's already running can't be handed out again
+        if self.header.queue_ready.read().get() != 0 {
+            return 0;
+        }
+
+        self.header.queue_size_max.read().get().min(u16::MAX as u32) as u16
+    }
+
+    fn enable_queue(&self, queue: u16, size: u16, desc: usize, avail: usize, used: usize) {
+        let header = self.header;
+
+        header.queue_sel.write(LittleEndianU32::new(queue as u32));
+        header.queue_size.write(LittleEndianU32::new(size as u32));
+
+        header.queue_desc_lo.write(LittleEndianU32::new(desc as u32));
+        header.queue_desc_hi.write(LittleEndianU32::new((desc >> 32) as u32));
+        header.queue_avail_lo.write(LittleEndianU32::new(avail as u32));
+        header.queue_avail_hi.write(LittleEndianU32::new((avail >> 32) as u32));
+        header.queue_used_lo.write(LittleEndianU32::new(used as u32));
+        header.queue_used_hi.write(LittleEndianU32::new((used >> 32) as u32));
+
+        header.queue_ready.write(LittleEndianU32::new(1));
+    }
+
+    fn notify(&self, queue: u16) {
+        self.header.queue_notify.write(LittleEndianU32::new(queue as u32));
+    }
+
+    fn config_generation(&self) -> u32 {
+        self.header.config_generation.read()
+    }
+
+    fn read_config(&self, offset: usize, buf: &mut [u8]) {
+        let base = self.config_base();
+
+        for (i, byte) in buf.iter_mut().enumerate() {
+            *byte = unsafe {base.add(offset + i).read_volatile()};
+        }
+    }
+
+    fn write_config(&self, offset: usize, buf: &[u8]) {
+        let base = self.config_base();
+
+        for (i, byte) in buf.iter().enumerate() {
+            unsafe {base.add(offset + i).write_volatile(*byte)};
+        }
+    }
+
+    fn set_handler(&self, handler: fn(usize), data: usize) {
+        // Also taken by the interrupt handler
+        crate::arch::trap::without_interrupts(|| *self.handler.lock() = Some((handler, data)));
+    }
+
+    fn interrupts(&self) -> bool {
+        self.irq.load(Ordering::Relaxed)
+    }
+}
+
+impl MmioTransport {
+    fn config_base(&self) -> *mut u8 {
+        unsafe {(self.header as *const VirtIoHeader as *mut u8).add(CONFIG_OFFSET)}
+    }
+}
+
+linkset::entry!(DRIVERS, crate::dev::DriverEntry, crate::dev::DriverEntry {
+    id: "LNRO0005",
+    init,
+});
+
+fn init(node: lai::Node) {
+    let resources = crate::acpi::resource::current_resources(&node);
+
+    let phys = resources.iter().find_map(|res| match res {
+        Resource::Memory { base, .. } => Some(*base as usize),
+        _ => None,
+    });
+
+    let Some(phys) = phys else {
+        println!("virtio-mmio: {} has no MMIO range", node.name());
+        return;
+    };
+
+    let ptr: *mut VirtIoHeader = crate::mem::PhysicalAddress::new(phys).to_virt().to_mut_ptr();
+
+    let header = match unsafe {VirtIoHeader::from_mut_ptr(ptr)} {
+        Some(header) => header,
+        None => {
+            println!("virtio-mmio: 0x{:x} is not a virtio 1.x device", phys);
+            return;
+        }
+    };
+
+    // QEMU creates every slot, empty ones have a device ID of 0
+    if header.device_id.read().get() == 0 {
+        return;
+    }
+
+    let transport: &'static MmioTransport = alloc::boxed::Box::leak(alloc::boxed::Box::new(MmioTransport {
+        header,
+        phys,
+        irq: AtomicBool::new(false),
+        handler: Mutex::new(None),
+    }));
+
+    let gsis = crate::dev::irq::request_crs_irqs(&node, IrqAction {
+        name: "virtio-mmio",
+        handler: handle_irq,
+        thread_fn: None,
+        data: transport as *const MmioTransport as usize,
+        shared: true,
+    });
+
+    transport.irq.store(!gsis.is_empty(), Ordering::Relaxed);
+
+    println!(
+        "virtio-mmio: device {} at 0x{:x}, interrupts {:?}",
+        transport.device_id(),
+        transport.phys,
+        gsis
+    );
+
+    super::probe(transport);
+}
+
+fn handle_irq(_gsi: u32, data: usize) -> IrqReturn {
+    let transport = unsafe {&*(data as *const MmioTransport)};
+    let status = transport.header.int_stat.read().get();
+
+    if status & (INT_USED_BUFFER | INT_CONFIG_CHANGE) == 0 {
+        return IrqReturn::None;
+    }
+
+    transport.header.int_ack.write(LittleEndianU32::new(status));
+
+    let handler = *transport.handler.lock();
+
+    if let Some((handler, data)) = handler {
+        handler(data);
+    }
+
+    IrqReturn::Handled
+}
+
@@ -1,92 +1,175 @@
-use libsa::endian::LittleEndianU32;
-use crate::utils::{Volatile, Read, Write, ReadWrite};
-
-#[repr(C)]
-pub struct VirtIoHeader {
-    /// MUST return 0x74726976
-    pub magic: Volatile<LittleEndianU32, Read>,
-    /// MUST return 0x2
-    pub version: Volatile<LittleEndianU32, Read>,
-    pub device_id: Volatile<LittleEndianU32, Read>,
-    pub vendor_id: Volatile<LittleEndianU32, Read>,
-
-    pub device_feat: Volatile<LittleEndianU32, Read>,
-    pub device_feat_sel: Volatile<LittleEndianU32, Write>,
-    _res0: [u8; 8],
-
-    pub driver_feat: Volatile<u32, Write>,
-    pub driver_feat_sel: Volatile<LittleEndianU32, Write>,
-    _res1: [u8; 8],
-
-    pub queue_sel: Volatile<LittleEndianU32, Write>,
-    pub queue_size_max: Volatile<LittleEndianU32, Read>,
-    pub queue_size: Volatile<LittleEndianU32, Write>,
-    _res2: [u8; 8],
-
-    pub queue_ready: Volatile<LittleEndianU32, ReadWrite>,
-    _res3: [u8; 8],
-
-    pub queue_notify: Volatile<LittleEndianU32, Write>,
-    _res4: [u8; 12],
-
-    pub int_stat: Volatile<LittleEndianU32, Read>,
-    pub int_ack: Volatile<LittleEndianU32, Write>,
-    _res5: [u8; 8],
-
-    pub status: Volatile<LittleEndianU32, ReadWrite>,
-    _res6: [u8; 12],
-
-    pub queue_desc_lo: Volatile<LittleEndianU32, Write>,
-    pub queue_desc_hi: Volatile<LittleEndianU32, Write>,
-    _res7: [u8; 8],
-
-    pub queue_avail_lo: Volatile<LittleEndianU32, Write>,
-    pub queue_avail_hi: Volatile<LittleEndianU32, Write>,
-    _res8: [u8; 8],
-
-    pub queue_used_lo: Volatile<LittleEndianU32, Write>,
-    pub queue_used_hi: Volatile<LittleEndianU32, Write>,
-    _res9: [u8; 4],
-
-    pub shared_mem_sel: Volatile<LittleEndianU32, Write>,
-    
-    pub shared_mem_len_lo: Volatile<LittleEndianU32, Read>,
-    pub shared_mem_len_hi: Volatile<LittleEndianU32, Read>,
-    
-    pub shared_mem_base_lo: Volatile<LittleEndianU32, Read>,
-    pub shared_mem_base_hi: Volatile<LittleEndianU32, Read>,
-
-    pub queue_reset: Volatile<LittleEndianU32, ReadWrite>,
-    _res10: [u8; 56],
-
-    pub config_generation: Volatile<u32, Read>,
+// Virtio 1.x core.
+// Transports (MMIO and PCI) find devices and implement `Transport`, then hand them to the matching
+// driver in `VIRTIO_DRIVERS`. Drivers negotiate features and set up their queues through the transport.
+
+use crate::dev::intc::IrqError;
+
+pub mod mmio;
+pub mod pci;
+pub mod queue;
+
+pub use mmio::VirtIoHeader;
+pub use queue::VirtQueue;
+
+linkset::declare!(pub VIRTIO_DRIVERS: VirtioDriverEntry);
+
+pub struct VirtioDriverEntry {
+    pub device: DeviceType,
+    pub name: &'static str,
+    pub probe: fn(transport: &'static dyn Transport) -> Result<(), VirtioError>,
+}
+
+unsafe impl Send for VirtioDriverEntry {}
+unsafe impl Sync for VirtioDriverEntry {}
+
+pub mod status {
+    pub const ACKNOWLEDGE: u8 = 1;
+    pub const DRIVER: u8 = 2;
+    pub const DRIVER_OK: u8 = 4;
+    pub const FEATURES_OK: u8 = 8;
+    pub const NEEDS_RESET: u8 = 64;
+    pub const FAILED: u8 = 128;
+}
+
+pub mod feature {
+    pub const INDIRECT_DESC: u64 = 1 << 28;
+    pub const EVENT_IDX: u64 = 1 << 29;
+    pub const VERSION_1: u64 = 1 << 32;
+    pub const ACCESS_PLATFORM: u64 = 1 << 33;
+}
+
+#[derive(Debug)]
+pub enum VirtioError {
+    /// The device doesn't implement virtio 1.x
+    InvalidDevice,
+    /// The device refused the negotiated feature set
+    FeaturesRejected,
+    /// The queue doesn't exist, or is already in use
+    QueueUnavailable,
+    /// Not enough free descriptors for the request
+    QueueFull,
+    /// The device reported an error for a request
+    IoError,
+    /// The device doesn't support the request
+    Unsupported,
+    Irq(IrqError),
+}
+
+impl From<IrqError> for VirtioError {
+    fn from(err: IrqError) -> Self {
+        Self::Irq(err)
+    }
 }
 
-impl VirtIoHeader {
-    /// # Safety
-    /// Must be valid pointer
-    pub unsafe fn from_mut_ptr<T>(ptr: *mut T) -> Option<&'static mut VirtIoHeader> {
-        let ptr = ptr as *mut VirtIoHeader;
-        if !(*ptr).is_valid() {
-            None
-        } else {
-            Some(&mut *ptr)
+/// Access to a virtio device, independent of how it's attached
+pub trait Transport: Send + Sync {
+    fn device_id(&self) -> u32;
+    fn device_features(&self) -> u64;
+    fn set_driver_features(&self, features: u64);
+    fn status(&self) -> u8;
+    fn set_status(&self, status: u8);
+    /// Largest size supported for `queue`, 0 if the queue doesn't exist
+    fn max_queue_size(&self, queue: u16) -> u16;
+    /// Give the device the physical addresses of a queue's rings, and enable it
+    fn enable_queue(&self, queue: u16, size: u16, desc: usize, avail: usize, used: usize);
+    /// Tell the device there are new buffers in `queue`
+    fn notify(&self, queue: u16);
+    /// Changes whenever the device changes its configuration space
+    fn config_generation(&self) -> u32;
+    fn read_config(&self, offset: usize, buf: &mut [u8]);
+    fn write_config(&self, offset: usize, buf: &[u8]);
+    /// Set the function run when the device signals a used buffer or configuration change
+    fn set_handler(&self, handler: fn(usize), data: usize);
+    /// Whether the device's interrupts reach the handler, drivers have to poll without them
+    fn interrupts(&self) -> bool;
+}
+
+impl dyn Transport {
+    pub fn device_type(&self) -> DeviceType {
+        DeviceType::from(self.device_id() as usize)
+    }
+
+    /// Reset the device and negotiate the features in `wanted` that it also supports.
+    /// Returns the accepted features, `VERSION_1` is always required
+    pub fn negotiate(&self, wanted: u64) -> Result<u64, VirtioError> {
+        self.set_status(0);
+        while self.status() != 0 {
+            core::hint::spin_loop();
         }
+
+        self.set_status(status::ACKNOWLEDGE);
+        self.set_status(status::ACKNOWLEDGE | status::DRIVER);
+
+        let offered = self.device_features();
+
+        if offered & feature::VERSION_1 == 0 {
+            self.fail();
+            return Err(VirtioError::InvalidDevice);
+        }
+
+        let accepted = offered & (wanted | feature::VERSION_1);
+        self.set_driver_features(accepted);
+
+        self.set_status(status::ACKNOWLEDGE | status::DRIVER | status::FEATURES_OK);
+
+        if self.status() & status::FEATURES_OK == 0 {
+            self.fail();
+            return Err(VirtioError::FeaturesRejected);
+        }
+
+        Ok(accepted)
     }
 
-    pub fn is_valid(&self) -> bool {
-        let mut bool = self.magic.read().get() == 0x74726976;
-        bool |= self.version.read().get() == 2;
+    /// Finish initialization, must be called after the queues are set up
+    pub fn driver_ok(&self) {
+        self.set_status(self.status() | status::DRIVER_OK);
+    }
 
-        bool
+    /// Tell the device the driver has given up on it
+    pub fn fail(&self) {
+        self.set_status(self.status() | status::FAILED);
     }
 
-    pub fn dev_id(&self) -> DeviceType {
-        DeviceType::from(self.device_id.read().get() as usize)
+    /// Read a consistent value from the configuration space
+    pub fn config<T: Copy>(&self, offset: usize) -> T {
+        let mut val = core::mem::MaybeUninit::<T>::uninit();
+
+        loop {
+            let generation = self.config_generation();
+
+            unsafe {
+                let bytes = core::slice::from_raw_parts_mut(val.as_mut_ptr().cast::<u8>(), core::mem::size_of::<T>());
+                self.read_config(offset, bytes);
+            }
+
+            if self.config_generation() == generation {
+                break;
+            }
+        }
+
+        unsafe {val.assume_init()}
     }
 }
 
-#[derive(Debug)]
+/// Hand a newly found device to its driver
+pub fn probe(transport: &'static dyn Transport) {
+    let id = transport.device_id();
+
+    for driver in &VIRTIO_DRIVERS {
+        if driver.device as u32 == id {
+            if let Err(err) = (driver.probe)(transport) {
+                crate::println!("virtio: {} failed to probe: {:?}", driver.name, err);
+                transport.fail();
+            }
+
+            return;
+        }
+    }
+
+    crate::println!("virtio: no driver for device ID {}", id);
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum DeviceType {
     Reserved = 0,
     NetworkCard = 1,
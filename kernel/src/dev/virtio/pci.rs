@@ -0,0 +1,293 @@
+// virtio-pci modern transport.
+// The common, notify, ISR, and device configuration structures are found through vendor specific capabilities,
+// each pointing into one of the function's BARs. Interrupts use a single MSI-X vector, without MSI-X drivers poll.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+
+use crate::dev::pci::{self, cap, Function, PciId};
+use crate::println;
+use crate::utils::{Volatile, Read, ReadWrite};
+use super::Transport;
+
+linkset::entry!(pci::PCI_DRIVERS, pci::PciDriverEntry, pci::PciDriverEntry {
+    name: "virtio-pci",
+    ids: &[PciId::device(VENDOR, PciId::ANY)],
+    probe,
+});
+
+const VENDOR: u16 = 0x1af4;
+
+/// Transitional devices, the device type is in the subsystem ID
+const TRANSITIONAL_IDS: core::ops::RangeInclusive<u16> = 0x1000..=0x103f;
+/// Modern devices, the device type is the device ID minus 0x1040
+const MODERN_IDS: core::ops::RangeInclusive<u16> = 0x1040..=0x107f;
+
+const SUBSYSTEM_ID: u16 = 0x2E;
+
+mod cfg_type {
+    pub const COMMON: u8 = 1;
+    pub const NOTIFY: u8 = 2;
+    pub const ISR: u8 = 3;
+    pub const DEVICE: u8 = 4;
+}
+
+/// Offsets within a virtio vendor capability
+mod cap_reg {
+    pub const CFG_TYPE: u16 = 3;
+    pub const BAR: u16 = 4;
+    pub const OFFSET: u16 = 8;
+    pub const LENGTH: u16 = 12;
+    /// Only in the notify capability
+    pub const NOTIFY_OFF_MULTIPLIER: u16 = 16;
+}
+
+/// MSI-X vector meaning no interrupt
+const NO_VECTOR: u16 = 0xffff;
+
+#[repr(C)]
+pub struct CommonCfg {
+    pub device_feat_sel: Volatile<u32, ReadWrite>,
+    pub device_feat: Volatile<u32, Read>,
+    pub driver_feat_sel: Volatile<u32, ReadWrite>,
+    pub driver_feat: Volatile<u32, ReadWrite>,
+    pub config_msix_vector: Volatile<u16, ReadWrite>,
+    pub num_queues: Volatile<u16, Read>,
+    pub status: Volatile<u8, ReadWrite>,
+    pub config_generation: Volatile<u8, Read>,
+
+    pub queue_sel: Volatile<u16, ReadWrite>,
+    pub queue_size: Volatile<u16, ReadWrite>,
+    pub queue_msix_vector: Volatile<u16, ReadWrite>,
+    pub queue_enable: Volatile<u16, ReadWrite>,
+    pub queue_notify_off: Volatile<u16, Read>,
+    pub queue_desc_lo: Volatile<u32, ReadWrite>,
+    pub queue_desc_hi: Volatile<u32, ReadWrite>,
+    pub queue_driver_lo: Volatile<u32, ReadWrite>,
+    pub queue_driver_hi: Volatile<u32, ReadWrite>,
+    pub queue_device_lo: Volatile<u32, ReadWrite>,
+    pub queue_device_hi: Volatile<u32, ReadWrite>,
+}
+
+pub struct PciTransport {
+    addr: pci::Address,
+    device_id: u32,
+    common: &'static CommonCfg,
+    /// Virtual address of the notify structure
+    notify: usize,
+    notify_off_multiplier: u32,
+    isr: *const u8,
+    /// Virtual address of the device configuration, if there is one
+    device: Option<usize>,
+    msix: AtomicBool,
+    handler: Mutex<Option<(fn(usize), usize)>>,
+}
+
+unsafe impl Send for PciTransport {}
+unsafe impl Sync for PciTransport {}
+
+impl Transport for PciTransport {
+    fn device_id(&self) -> u32 {
+        self.device_id
+    }
+
+    fn device_features(&self) -> u64 {
+        self.common.device_feat_sel.write(0);
+        let low = self.common.device_feat.read() as u64;
+
+        self.common.device_feat_sel.write(1);
+        let high = self.common.device_feat.read() as u64;
+
+        (high << 32) | low
+    }
+
+    fn set_driver_features(&self, features: u64) {
+        self.common.driver_feat_sel.write(0);
+        self.common.driver_feat.write(features as u32);
+
+        self.common.driver_feat_sel.write(1);
+        self.common.driver_feat.write((features >> 32) as u32);
+    }
+
+    fn status(&self) -> u8 {
+        self.common.status.read()
+    }
+
+    fn set_status(&self, status: u8) {
+        self.common.status.write(status);
+    }
+
+    fn max_queue_size(&self, queue: u16) -> u16 {
+        if queue >= self.common.num_queues.read() {
+            return 0;
+        }
+
+        self.common.queue_sel.write(queue);
+
+        // A queue that's already running can't be handed out again
+        if self.common.queue_enable.read() != 0 {
+            return 0;
+        }
+
+        self.common.queue_size.read()
+    }
+
+    fn enable_queue(&self, queue: u16, size: u16, desc: usize, avail: usize, used: usize) {
+        let common = self.common;
+
+        common.queue_sel.write(queue);
+        common.queue_size.write(size);
+
+        common.queue_desc_lo.write(desc as u32);
+        common.queue_desc_hi.write((desc >> 32) as u32);
+        common.queue_driver_lo.write(avail as u32);
+        common.queue_driver_hi.write((avail >> 32) as u32);
+        common.queue_device_lo.write(used as u32);
+        common.queue_device_hi.write((used >> 32) as u32);
+
+        // Every queue shares vector 0, a reset clears vectors so they're set again for each queue
+        common.queue_msix_vector.write(if self.msix() {0} else {NO_VECTOR});
+
+        // A device that couldn't take the vector reads back no vector, and is polled instead
+        if self.msix() && common.queue_msix_vector.read() == NO_VECTOR {
+            self.msix.store(false, Ordering::Relaxed);
+        }
+
+        common.queue_enable.write(1);
+    }
+
+    fn notify(&self, queue: u16) {
+        self.common.queue_sel.write(queue);
+        let offset = self.common.queue_notify_off.read() as usize * self.notify_off_multiplier as usize;
+
+        unsafe {((self.notify + offset) as *mut u16).write_volatile(queue)};
+    }
+
+    fn config_generation(&self) -> u32 {
+        self.common.config_generation.read() as u32
+    }
+
+    fn read_config(&self, offset: usize, buf: &mut [u8]) {
+        let Some(base) = self.device else {
+            return;
+        };
+
+        for (i, byte) in buf.iter_mut().enumerate() {
+            *byte = unsafe {((base + offset + i) as *const u8).read_volatile()};
+        }
+    }
+
+    fn write_config(&self, offset: usize, buf: &[u8]) {
+        let Some(base) = self.device else {
+            return;
+        };
+
+        for (i, byte) in buf.iter().enumerate() {
+            unsafe {((base + offset + i) as *mut u8).write_volatile(*byte)};
+        }
+    }
+
+    fn set_handler(&self, handler: fn(usize), data: usize) {
+        // Also taken by the interrupt handler
+        crate::arch::trap::without_interrupts(|| *self.handler.lock() = Some((handler, data)));
+
+        if self.msix() {
+            self.common.config_msix_vector.write(0);
+        }
+    }
+
+    fn interrupts(&self) -> bool {
+        self.msix()
+    }
+}
+
+impl PciTransport {
+    fn msix(&self) -> bool {
+        self.msix.load(Ordering::Relaxed)
+    }
+}
+
+/// Virtual address of the structure a virtio capability points to
+fn map_structure(function: &Function, cap: u16) -> Option<usize> {
+    let bar = function.read::<u8>(cap + cap_reg::BAR);
+    let offset = function.read::<u32>(cap + cap_reg::OFFSET) as u64;
+    let length = function.read::<u32>(cap + cap_reg::LENGTH) as u64;
+
+    let base = pci::bar_address(function, bar)?;
+
+    if offset + length > function.bars[bar as usize].size() {
+        return None;
+    }
+
+    Some(crate::mem::map_mmio((base + offset) as usize, length as usize))
+}
+
+fn probe(function: &Function) {
+    let device_id = if MODERN_IDS.contains(&function.device) {
+        (function.device - 0x1040) as u32
+    } else if TRANSITIONAL_IDS.contains(&function.device) {
+        function.read::<u16>(SUBSYSTEM_ID) as u32
+    } else {
+        return;
+    };
+
+    let (mut common, mut notify, mut isr, mut device) = (None, None, None, None);
+    let mut notify_off_multiplier = 0;
+
+    // The first capability of each type is the preferred one
+    for cap in function.capabilities(cap::VENDOR) {
+        match function.read::<u8>(cap + cap_reg::CFG_TYPE) {
+            cfg_type::COMMON if common.is_none() => common = map_structure(function, cap),
+            cfg_type::NOTIFY if notify.is_none() => {
+                notify = map_structure(function, cap);
+                notify_off_multiplier = function.read::<u32>(cap + cap_reg::NOTIFY_OFF_MULTIPLIER);
+            },
+            cfg_type::ISR if isr.is_none() => isr = map_structure(function, cap),
+            cfg_type::DEVICE if device.is_none() => device = map_structure(function, cap),
+            _ => {}
+        }
+    }
+
+    let (Some(common), Some(notify), Some(isr)) = (common, notify, isr) else {
+        println!("virtio-pci {}: missing capabilities, legacy devices aren't supported", function.addr);
+        return;
+    };
+
+    pci::enable(function.addr);
+
+    let transport: &'static PciTransport = alloc::boxed::Box::leak(alloc::boxed::Box::new(PciTransport {
+        addr: function.addr,
+        device_id,
+        common: unsafe {&*(common as *const CommonCfg)},
+        notify,
+        notify_off_multiplier,
+        isr: isr as *const u8,
+        device,
+        msix: AtomicBool::new(false),
+        handler: Mutex::new(None),
+    }));
+
+    let data = transport as *const PciTransport as usize;
+
+    match pci::msi::enable_msix(function, &[(handle_interrupt, data)]) {
+        Ok(_) => transport.msix.store(true, Ordering::Relaxed),
+        Err(err) => println!("virtio-pci {}: no MSI-X ({:?}), polling", function.addr, err),
+    }
+
+    println!("virtio-pci {}: device {}{}", transport.addr, device_id, if transport.msix() {", MSI-X"} else {""});
+
+    super::probe(transport);
+}
+
+fn handle_interrupt(data: usize) {
+    let transport = unsafe {&*(data as *const PciTransport)};
+
+    // Reading the ISR status clears it, it's only meaningful for INTx but keeps the device consistent
+    unsafe {transport.isr.read_volatile()};
+
+    let handler = *transport.handler.lock();
+
+    if let Some((handler, data)) = handler {
+        handler(data);
+    }
+}
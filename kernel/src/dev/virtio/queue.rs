@@ -0,0 +1,223 @@
+// Split virtqueues.
+// The descriptor table, available ring, and used ring each get their own DMA range. Free descriptors are
+// kept in a list threaded through their `next` fields.
+
+use core::sync::atomic::{fence, Ordering};
+
+use crate::dma::DmaRange;
+use super::{Transport, VirtioError};
+
+const DESC_F_NEXT: u16 = 1;
+const DESC_F_WRITE: u16 = 2;
+
+const USED_F_NO_NOTIFY: u16 = 1;
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+struct Descriptor {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+/// A physically contiguous buffer handed to the device
+#[derive(Clone, Copy, Debug)]
+pub struct Segment {
+    pub phys: usize,
+    pub len: u32,
+    /// The device writes to this buffer, rather than reading from it
+    pub device_writes: bool,
+}
+
+impl Segment {
+    pub fn read(phys: usize, len: usize) -> Self {
+        Self { phys, len: len as u32, device_writes: false }
+    }
+
+    pub fn write(phys: usize, len: usize) -> Self {
+        Self { phys, len: len as u32, device_writes: true }
+    }
+}
+
+pub struct VirtQueue {
+    index: u16,
+    size: u16,
+    desc: DmaRange<[Descriptor]>,
+    /// flags: u16, idx: u16, ring: [u16; size], used_event: u16
+    avail: DmaRange<[u8]>,
+    /// flags: u16, idx: u16, ring: [(id: u32, len: u32); size], avail_event: u16
+    used: DmaRange<[u8]>,
+    free_head: u16,
+    num_free: u16,
+    avail_idx: u16,
+    last_used: u16,
+}
+
+unsafe impl Send for VirtQueue {}
+unsafe impl Sync for VirtQueue {}
+
+impl VirtQueue {
+    /// Allocate queue `index` with at most `max_size` entries, and hand it to the device
+    pub fn new(transport: &dyn Transport, index: u16, max_size: u16) -> Result<Self, VirtioError> {
+        let device_max = transport.max_queue_size(index);
+
+        if device_max == 0 || max_size == 0 {
+            return Err(VirtioError::QueueUnavailable);
+        }
+
+        // Split queue sizes are powers of 2
+        let size = device_max.min(max_size);
+        let size = 1 << (15 - size.leading_zeros() as u16);
+
+        let mut desc = DmaRange::<[Descriptor]>::new_many(size as usize);
+        let mut avail = DmaRange::<[u8]>::new_many(6 + 2 * size as usize);
+        let mut used = DmaRange::<[u8]>::new_many(6 + 8 * size as usize);
+
+        for (i, entry) in desc.buf_mut().iter_mut().enumerate() {
+            *entry = Descriptor {
+                next: (i + 1) as u16,
+                ..Default::default()
+            };
+        }
+
+        avail.buf_mut().fill(0);
+        used.buf_mut().fill(0);
+
+        transport.enable_queue(index, size, desc.phys(), avail.phys(), used.phys());
+
+        Ok(Self {
+            index,
+            size,
+            desc,
+            avail,
+            used,
+            free_head: 0,
+            num_free: size,
+            avail_idx: 0,
+            last_used: 0,
+        })
+    }
+
+    pub fn index(&self) -> u16 {
+        self.index
+    }
+
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    pub fn num_free(&self) -> u16 {
+        self.num_free
+    }
+
+    /// Chain `segments` into one request and make it available to the device.
+    /// Returns the head descriptor, which identifies the request when it's used
+    pub fn add(&mut self, segments: &[Segment]) -> Result<u16, VirtioError> {
+        if segments.is_empty() || segments.len() > self.num_free as usize {
+            return Err(VirtioError::QueueFull);
+        }
+
+        let head = self.free_head;
+        let desc = self.desc.buf_mut();
+
+        for (i, segment) in segments.iter().enumerate() {
+            let idx = self.free_head;
+            let entry = &mut desc[idx as usize];
+
+            self.free_head = entry.next;
+
+            entry.addr = segment.phys as u64;
+            entry.len = segment.len;
+            entry.flags = if segment.device_writes {DESC_F_WRITE} else {0};
+
+            if i + 1 < segments.len() {
+                entry.flags |= DESC_F_NEXT;
+                entry.next = self.free_head;
+            }
+        }
+
+        self.num_free -= segments.len() as u16;
+
+        unsafe {
+            let ring = self.avail_ptr().add(2 + (self.avail_idx % self.size) as usize);
+            ring.write_volatile(head);
+
+            // The device must see the ring entry before the new index
+            fence(Ordering::SeqCst);
+
+            self.avail_idx = self.avail_idx.wrapping_add(1);
+            self.avail_ptr().add(1).write_volatile(self.avail_idx);
+        }
+
+        Ok(head)
+    }
+
+    /// Notify the device of new requests, unless it asked not to be
+    pub fn kick(&self, transport: &dyn Transport) {
+        fence(Ordering::SeqCst);
+
+        let flags = unsafe {self.used_ptr().read_volatile()};
+
+        if flags & USED_F_NO_NOTIFY == 0 {
+            transport.notify(self.index);
+        }
+    }
+
+    /// Whether the device has used any requests that haven't been popped
+    pub fn has_used(&self) -> bool {
+        fence(Ordering::SeqCst);
+
+        unsafe {self.used_ptr().add(1).read_volatile() != self.last_used}
+    }
+
+    /// Take the next request the device has finished with, returns its head descriptor and
+    /// the number of bytes the device wrote
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        if !self.has_used() {
+            return None;
+        }
+
+        let (id, len) = unsafe {
+            let elem = self.used_ptr().add(2 + 4 * (self.last_used % self.size) as usize) as *const u32;
+
+            (elem.read_volatile() as u16, elem.add(1).read_volatile())
+        };
+
+        self.last_used = self.last_used.wrapping_add(1);
+        self.free_chain(id);
+
+        Some((id, len))
+    }
+
+    /// Return a chain of descriptors to the free list
+    fn free_chain(&mut self, head: u16) {
+        let desc = self.desc.buf_mut();
+        let mut idx = head;
+
+        loop {
+            let entry = &mut desc[idx as usize];
+            let next = entry.next;
+            let chained = entry.flags & DESC_F_NEXT != 0;
+
+            self.num_free += 1;
+
+            if !chained {
+                entry.next = self.free_head;
+                break;
+            }
+
+            idx = next;
+        }
+
+        self.free_head = head;
+    }
+
+    fn avail_ptr(&self) -> *mut u16 {
+        self.avail.buf().as_ptr() as *mut u16
+    }
+
+    fn used_ptr(&self) -> *mut u16 {
+        self.used.buf().as_ptr() as *mut u16
+    }
+}
@@ -3,6 +3,8 @@ use core::marker::PhantomData;
 pub struct DmaRange<T: ?Sized> {
     /// Number of `T`s it has
     length: usize,
+    /// Size in bytes, what was allocated
+    size: usize,
     virt: usize,
     phys: usize,
     data: PhantomData<T>
@@ -14,7 +16,7 @@ impl<T: Sized> DmaRange<[T]> {
 
         let size = core::mem::size_of::<T>() * amount;
 
-        let phys = crate::mem::PHYS.alloc(size, vmem::AllocStrategy::NextFit).unwrap();
+        let phys = crate::mem::swap::alloc_frame(size);
         let virt = crate::mem::VIRT.alloc(size, vmem::AllocStrategy::NextFit).unwrap();
 
         let map_size = size.div_ceil(0x1000) * 0x1000;
@@ -41,6 +43,7 @@ impl<T: Sized> DmaRange<[T]> {
 
         Self { 
             length: amount, 
+            size,
             virt, 
             phys,
             data: PhantomData 
@@ -71,11 +74,15 @@ impl<T> DmaRange<T> {
     /// Takes the self and returns a reference, and physical address
     pub fn leak(self) -> (&'static mut [T], usize) {
         let ptr = self.virt as *mut T;
-        let amount = self.length / core::mem::size_of::<T>();
+        let amount = self.length;
 
         let slice: &'static mut [T] = unsafe {core::slice::from_raw_parts_mut(ptr, amount)};
+        let phys = self.phys;
+
+        // Dropping would free what was just handed out
+        core::mem::forget(self);
 
-        (slice, self.phys)
+        (slice, phys)
     }
 }
 
@@ -85,7 +92,7 @@ impl<T> Default for DmaRange<T> {
 
         let size = core::mem::size_of::<T>();
 
-        let phys = crate::mem::PHYS.alloc(size, vmem::AllocStrategy::NextFit).unwrap();
+        let phys = crate::mem::swap::alloc_frame(size);
         let virt = crate::mem::VIRT.alloc(size, vmem::AllocStrategy::NextFit).unwrap();
 
         let map_size = size.div_ceil(0x1000) * 0x1000;
@@ -112,6 +119,7 @@ impl<T> Default for DmaRange<T> {
 
         Self { 
             length: 1, 
+            size,
             virt, 
             phys,
             data: PhantomData 
@@ -125,15 +133,38 @@ impl<T: ?Sized> DmaRange<T> {
     }
 }
 
+/// Physical address of `buf` if it's in the higher half direct map, which makes it physically contiguous and
+/// never swapped, so a device can use it as is
+pub fn direct_phys(buf: &[u8]) -> Option<usize> {
+    let hhdm = crate::mem::HHDM_OFFSET.load(core::sync::atomic::Ordering::Relaxed);
+    let start = buf.as_ptr() as usize;
+    let phys = start.checked_sub(hhdm)?;
+
+    // Other mappings are mixed in with the direct map, so every page has to be checked
+    let root = crate::arch::paging::get_root_table();
+
+    for page in (start & !0xfff..start + buf.len()).step_by(0x1000) {
+        let (entry, level) = root.read(crate::mem::VirtualAddress::new(page));
+        let size = crate::arch::paging::PageSize::from_level(level) as usize;
+
+        match entry {
+            crate::arch::paging::Entry::Page(base) if base as usize + (page & (size - 1)) == page => {},
+            _ => return None,
+        }
+    }
+
+    Some(phys)
+}
+
 impl<T: ?Sized> Drop for DmaRange<T> {
     fn drop(&mut self) {
         unsafe {
             let mut root = crate::arch::paging::get_root_table();
 
-            crate::mem::PHYS.free(self.phys, self.length);
-            crate::mem::VIRT.free(self.virt, self.length);
+            crate::mem::PHYS.free(self.phys, self.size);
+            crate::mem::VIRT.free(self.virt, self.size);
 
-            let size = self.length;
+            let size = self.size;
             let virt = self.virt;
 
             let map_size = size.div_ceil(0x1000) * 0x1000;
@@ -0,0 +1,125 @@
+// Byte addressed access to a partition, for filesystems.
+// Everything goes through the buffer cache, at LBAs translated from the partition's start, so the partition table
+// isn't locked during I/O. Partial blocks are modified in place in their cache buffer, under its lock, so on-disk
+// structures don't need to line up with the disk's blocks and writes to different bytes of a block can't undo each
+// other.
+
+use crate::dev::blockdev::{cache, DiskError, PARTS};
+
+use super::FsError;
+
+pub struct PartitionDevice {
+    part: usize,
+    disk: usize,
+    start: usize,
+    blocksize: usize,
+    size: u64,
+}
+
+impl PartitionDevice {
+    pub fn new(part: usize) -> Result<Self, FsError> {
+        let parts = PARTS.lock();
+        let partition = parts.get(&part).ok_or(DiskError::NoDisk)?;
+
+        Ok(Self {
+            part,
+            disk: partition.diskid(),
+            start: partition.start(),
+            blocksize: partition.blocksize(),
+            size: (partition.blocks() * partition.blocksize()) as u64,
+        })
+    }
+
+    pub fn part(&self) -> usize {
+        self.part
+    }
+
+    /// In bytes
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn check(&self, offset: u64, len: usize) -> Result<(), FsError> {
+        match offset.checked_add(len as u64) {
+            Some(end) if end <= self.size => Ok(()),
+            _ => Err(DiskError::PartitionRangeError.into()),
+        }
+    }
+
+    pub fn read(&self, offset: u64, buf: &mut [u8]) -> Result<(), FsError> {
+        self.check(offset, buf.len())?;
+
+        let mut done = 0;
+
+        while done < buf.len() {
+            let pos = offset + done as u64;
+            let lba = self.start + (pos / self.blocksize as u64) as usize;
+            let within = (pos % self.blocksize as u64) as usize;
+
+            // Whole blocks go straight into the buffer
+            let whole = (buf.len() - done) / self.blocksize * self.blocksize;
+
+            if within == 0 && whole != 0 {
+                cache::read(self.disk, &mut buf[done..done + whole], lba)?;
+                done += whole;
+                continue;
+            }
+
+            let len = (self.blocksize - within).min(buf.len() - done);
+
+            let out = &mut buf[done..done + len];
+            cache::get(self.disk, lba)?.read(|block| out.copy_from_slice(&block[within..within + len]));
+            done += len;
+        }
+
+        Ok(())
+    }
+
+    pub fn write(&self, offset: u64, data: &[u8]) -> Result<(), FsError> {
+        self.check(offset, data.len())?;
+
+        let mut done = 0;
+
+        while done < data.len() {
+            let pos = offset + done as u64;
+            let lba = self.start + (pos / self.blocksize as u64) as usize;
+            let within = (pos % self.blocksize as u64) as usize;
+
+            let whole = (data.len() - done) / self.blocksize * self.blocksize;
+
+            if within == 0 && whole != 0 {
+                cache::write(self.disk, &data[done..done + whole], lba)?;
+                done += whole;
+                continue;
+            }
+
+            let len = (self.blocksize - within).min(data.len() - done);
+
+            let chunk = &data[done..done + len];
+            cache::get(self.disk, lba)?.write(|block| block[within..within + len].copy_from_slice(chunk));
+            done += len;
+        }
+
+        Ok(())
+    }
+
+    /// Write `len` zero bytes at `offset`
+    pub fn zero(&self, offset: u64, len: u64) -> Result<(), FsError> {
+        let zeroes = alloc::vec![0; self.blocksize.max(4096)];
+        let mut done = 0;
+
+        while done < len {
+            let chunk = (len - done).min(zeroes.len() as u64);
+            self.write(offset + done, &zeroes[..chunk as usize])?;
+            done += chunk;
+        }
+
+        Ok(())
+    }
+
+    /// Write back everything cached for the partition's disk
+    pub fn sync(&self) -> Result<(), FsError> {
+        cache::sync(Some(self.disk))?;
+        Ok(())
+    }
+}
@@ -0,0 +1,1160 @@
+// The second extended filesystem.
+// Blocks are split into groups, each with a block bitmap, an inode bitmap and a slice of the inode table. Files map
+// their blocks through 12 direct pointers and single, double and triple indirect blocks. Vnodes only hold an inode
+// number and read the inode from disk for every operation, under the volume's lock, so they never go stale.
+// Volumes with features that change the on-disk layout are refused, and ones with features we'd break by writing
+// are mounted read only.
+
+use alloc::{string::String, sync::{Arc, Weak}, vec, vec::Vec};
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use spin::Mutex;
+
+use structs::*;
+use super::device::PartitionDevice;
+use super::{DirEntry, Filesystem, FsError, Metadata, Vnode, VnodeKind};
+
+mod structs;
+
+linkset::entry!(crate::fs::FILESYSTEMS, crate::fs::FsTypeEntry, crate::fs::FsTypeEntry {
+    name: "ext2",
+    mount,
+});
+
+fn mount(source: Option<usize>) -> Result<Arc<dyn Filesystem>, FsError> {
+    Ok(Ext2Fs::open(source.ok_or(FsError::InvalidArgument)?)?)
+}
+
+/// Whether partition `part` holds an ext2 volume
+pub fn probe(part: usize) -> bool {
+    let mut raw = [0; SUPERBLOCK_SIZE];
+
+    PartitionDevice::new(part)
+        .and_then(|device| device.read(SUPERBLOCK_OFFSET, &mut raw))
+        .is_ok_and(|_| Superblock::from_bytes(&raw).magic == MAGIC)
+}
+
+/// Directory entry lengths are 16 bits, so bigger blocks need an encoding we don't do
+const MAX_LOG_BLOCK_SIZE: u32 = 5;
+
+pub struct Ext2Fs {
+    this: Weak<Ext2Fs>,
+    device: PartitionDevice,
+    sb: Superblock,
+    block_size: u64,
+    groups: u32,
+    read_only: bool,
+    lock: Mutex<()>,
+    free_blocks: AtomicU32,
+    free_inodes: AtomicU32,
+    /// A file grew past 2GiB, so the large file feature has to be set
+    large_file: AtomicBool,
+    /// The superblock's counts are out of date
+    dirty: AtomicBool,
+}
+
+impl Ext2Fs {
+    pub fn open(part: usize) -> Result<Arc<Self>, FsError> {
+        let device = PartitionDevice::new(part)?;
+        let mut raw = [0; SUPERBLOCK_SIZE];
+
+        device.read(SUPERBLOCK_OFFSET, &mut raw)?;
+
+        let sb = Superblock::from_bytes(&raw);
+
+        if sb.magic != MAGIC {
+            return Err(FsError::UnknownFs);
+        }
+
+        if sb.log_block_size > MAX_LOG_BLOCK_SIZE || sb.feature_incompat & !INCOMPAT_FILETYPE != 0 {
+            return Err(FsError::Unsupported);
+        }
+
+        if sb.blocks_per_group == 0
+            || sb.inodes_per_group == 0
+            || sb.first_data_block >= sb.blocks_count
+            || (sb.inode_size as usize) < INODE_BASE_SIZE
+            || !sb.inode_size.is_power_of_two()
+            || sb.inode_size as u64 > sb.block_size()
+            || sb.blocks_count as u64 * sb.block_size() > device.size()
+        {
+            return Err(FsError::Corrupt);
+        }
+
+        let read_only = sb.feature_ro_compat & !(RO_COMPAT_SPARSE_SUPER | RO_COMPAT_LARGE_FILE) != 0;
+
+        crate::println!(
+            "ext2: partition {} has {} blocks of {} bytes in {} groups{}",
+            part,
+            sb.blocks_count,
+            sb.block_size(),
+            sb.groups(),
+            if read_only { ", mounting read only" } else { "" }
+        );
+
+        Ok(Arc::new_cyclic(|this| Self {
+            this: this.clone(),
+            device,
+            sb,
+            block_size: sb.block_size(),
+            groups: sb.groups(),
+            read_only,
+            lock: Mutex::new(()),
+            free_blocks: AtomicU32::new(sb.free_blocks),
+            free_inodes: AtomicU32::new(sb.free_inodes),
+            large_file: AtomicBool::new(false),
+            dirty: AtomicBool::new(false),
+        }))
+    }
+
+    fn writable(&self) -> Result<(), FsError> {
+        if self.read_only {
+            Err(FsError::ReadOnly)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn node(&self, ino: u32) -> Arc<Ext2Node> {
+        Arc::new(Ext2Node {
+            fs: self.this.upgrade().unwrap(),
+            ino,
+        })
+    }
+
+    /// The descriptor table starts in the block after the superblock
+    fn desc_offset(&self, group: u32) -> u64 {
+        (self.sb.first_data_block as u64 + 1) * self.block_size + group as u64 * DESC_SIZE
+    }
+
+    fn desc(&self, group: u32) -> Result<GroupDesc, FsError> {
+        let mut raw = [0; DESC_SIZE as usize];
+        self.device.read(self.desc_offset(group), &mut raw)?;
+
+        Ok(GroupDesc::from_bytes(&raw))
+    }
+
+    fn update_desc(&self, group: u32, f: impl FnOnce(&mut GroupDesc)) -> Result<(), FsError> {
+        let offset = self.desc_offset(group);
+        let mut raw = [0; DESC_SIZE as usize];
+        self.device.read(offset, &mut raw)?;
+
+        let mut desc = GroupDesc::from_bytes(&raw);
+        f(&mut desc);
+        desc.write_counts(&mut raw);
+
+        self.device.write(offset, &raw)
+    }
+
+    /// The group an inode is in, where its blocks are allocated first
+    fn group_of(&self, ino: u32) -> u32 {
+        (ino - 1) / self.sb.inodes_per_group
+    }
+
+    /// Blocks in `group`, the last is usually short
+    fn group_blocks(&self, group: u32) -> u32 {
+        let start = self.sb.first_data_block + group * self.sb.blocks_per_group;
+
+        (self.sb.blocks_count - start).min(self.sb.blocks_per_group)
+    }
+
+    fn inode_offset(&self, ino: u32) -> Result<u64, FsError> {
+        if ino == 0 || ino > self.sb.inodes_count {
+            return Err(FsError::Corrupt);
+        }
+
+        let index = (ino - 1) % self.sb.inodes_per_group;
+        let table = self.desc(self.group_of(ino))?.inode_table as u64;
+
+        Ok(table * self.block_size + index as u64 * self.sb.inode_size as u64)
+    }
+
+    fn read_inode(&self, ino: u32) -> Result<Inode, FsError> {
+        let mut raw = [0; INODE_BASE_SIZE];
+        self.device.read(self.inode_offset(ino)?, &mut raw)?;
+
+        Ok(Inode::from_bytes(&raw))
+    }
+
+    fn write_inode(&self, ino: u32, inode: &Inode) -> Result<(), FsError> {
+        let offset = self.inode_offset(ino)?;
+        let mut raw = [0; INODE_BASE_SIZE];
+
+        self.device.read(offset, &mut raw)?;
+        inode.write_to(&mut raw);
+        self.device.write(offset, &raw)
+    }
+
+    /// Write a new inode, clearing whatever its slot held before
+    fn init_inode(&self, ino: u32, inode: &Inode) -> Result<(), FsError> {
+        let mut raw = vec![0; self.sb.inode_size as usize];
+        inode.write_to(&mut raw);
+
+        self.device.write(self.inode_offset(ino)?, &raw)
+    }
+
+    /// A directory's inode, which must still be linked
+    fn dir_inode(&self, ino: u32) -> Result<Inode, FsError> {
+        let inode = self.read_inode(ino)?;
+
+        if inode.links == 0 {
+            Err(FsError::NotFound)
+        } else if !inode.is_dir() {
+            Err(FsError::NotDirectory)
+        } else {
+            Ok(inode)
+        }
+    }
+
+    /// Find a clear bit among the first `len` of the bitmap in `block` and set it
+    fn claim_bit(&self, block: u32, len: u32) -> Result<Option<u32>, FsError> {
+        let offset = block as u64 * self.block_size;
+        let mut bitmap = vec![0u8; self.block_size as usize];
+
+        self.device.read(offset, &mut bitmap)?;
+
+        for (i, byte) in bitmap.iter().enumerate().take(len.div_ceil(8) as usize) {
+            if *byte == 0xff {
+                continue;
+            }
+
+            let bit = byte.trailing_ones();
+            let index = i as u32 * 8 + bit;
+
+            if index >= len {
+                break;
+            }
+
+            self.device.write(offset + i as u64, &[byte | 1 << bit])?;
+            return Ok(Some(index));
+        }
+
+        Ok(None)
+    }
+
+    fn release_bit(&self, block: u32, index: u32) -> Result<(), FsError> {
+        let offset = block as u64 * self.block_size + index as u64 / 8;
+        let mut byte = [0];
+
+        self.device.read(offset, &mut byte)?;
+
+        // Freeing something twice would throw the counts off
+        if byte[0] & 1 << (index % 8) == 0 {
+            return Err(FsError::Corrupt);
+        }
+
+        self.device.write(offset, &[byte[0] & !(1 << (index % 8))])
+    }
+
+    /// Allocate a zeroed block, trying `goal`'s group first
+    fn alloc_block(&self, goal: u32) -> Result<u32, FsError> {
+        for group in (goal..self.groups).chain(0..goal) {
+            let desc = self.desc(group)?;
+
+            if desc.free_blocks == 0 {
+                continue;
+            }
+
+            let Some(index) = self.claim_bit(desc.block_bitmap, self.group_blocks(group))? else {
+                continue;
+            };
+
+            self.update_desc(group, |desc| desc.free_blocks = desc.free_blocks.saturating_sub(1))?;
+            self.free_blocks.fetch_sub(1, Ordering::Relaxed);
+            self.dirty.store(true, Ordering::Relaxed);
+
+            let block = self.sb.first_data_block + group * self.sb.blocks_per_group + index;
+            self.device.zero(block as u64 * self.block_size, self.block_size)?;
+
+            return Ok(block);
+        }
+
+        Err(FsError::NoSpace)
+    }
+
+    fn free_block(&self, block: u32) -> Result<(), FsError> {
+        if block < self.sb.first_data_block || block >= self.sb.blocks_count {
+            return Err(FsError::Corrupt);
+        }
+
+        let group = (block - self.sb.first_data_block) / self.sb.blocks_per_group;
+        let index = (block - self.sb.first_data_block) % self.sb.blocks_per_group;
+
+        self.release_bit(self.desc(group)?.block_bitmap, index)?;
+        self.update_desc(group, |desc| desc.free_blocks += 1)?;
+        self.free_blocks.fetch_add(1, Ordering::Relaxed);
+        self.dirty.store(true, Ordering::Relaxed);
+
+        Ok(())
+    }
+
+    /// Allocate an inode number, trying `goal`'s group first
+    fn alloc_inode(&self, goal: u32, dir: bool) -> Result<u32, FsError> {
+        for group in (goal..self.groups).chain(0..goal) {
+            let desc = self.desc(group)?;
+
+            if desc.free_inodes == 0 {
+                continue;
+            }
+
+            let Some(index) = self.claim_bit(desc.inode_bitmap, self.sb.inodes_per_group)? else {
+                continue;
+            };
+
+            let ino = group * self.sb.inodes_per_group + index + 1;
+
+            // The reserved inodes are marked used by mke2fs, finding one free means the bitmap is wrong
+            if ino < self.sb.first_ino || ino > self.sb.inodes_count {
+                return Err(FsError::Corrupt);
+            }
+
+            self.update_desc(group, |desc| {
+                desc.free_inodes = desc.free_inodes.saturating_sub(1);
+
+                if dir {
+                    desc.used_dirs += 1;
+                }
+            })?;
+
+            self.free_inodes.fetch_sub(1, Ordering::Relaxed);
+            self.dirty.store(true, Ordering::Relaxed);
+
+            return Ok(ino);
+        }
+
+        Err(FsError::NoSpace)
+    }
+
+    fn free_inode(&self, ino: u32, dir: bool) -> Result<(), FsError> {
+        let group = self.group_of(ino);
+
+        self.release_bit(self.desc(group)?.inode_bitmap, (ino - 1) % self.sb.inodes_per_group)?;
+
+        self.update_desc(group, |desc| {
+            desc.free_inodes += 1;
+
+            if dir {
+                desc.used_dirs = desc.used_dirs.saturating_sub(1);
+            }
+        })?;
+
+        self.free_inodes.fetch_add(1, Ordering::Relaxed);
+        self.dirty.store(true, Ordering::Relaxed);
+
+        Ok(())
+    }
+
+    fn per_block(&self) -> u64 {
+        self.block_size / 4
+    }
+
+    fn block_sectors(&self) -> u32 {
+        (self.block_size / 512) as u32
+    }
+
+    /// The disk block holding block `index` of `inode`. With a `goal` group missing blocks, indirect ones
+    /// included, are allocated, otherwise holes are `None`.
+    fn bmap(&self, inode: &mut Inode, index: u64, goal: Option<u32>) -> Result<Option<u32>, FsError> {
+        let per = self.per_block();
+        let direct = DIRECT_BLOCKS as u64;
+
+        // The pointer in the inode, then the index at each level of indirection below it
+        let (slot, path) = if index < direct {
+            (index as usize, Vec::new())
+        } else if index - direct < per {
+            (DIRECT_BLOCKS, vec![index - direct])
+        } else if index - direct - per < per * per {
+            let index = index - direct - per;
+            (DIRECT_BLOCKS + 1, vec![index / per, index % per])
+        } else if index - direct - per - per * per < per * per * per {
+            let index = index - direct - per - per * per;
+            (DIRECT_BLOCKS + 2, vec![index / (per * per), index / per % per, index % per])
+        } else {
+            return Err(FsError::NoSpace);
+        };
+
+        let mut block = inode.block[slot];
+
+        if block == 0 {
+            let Some(goal) = goal else {
+                return Ok(None);
+            };
+
+            block = self.alloc_block(goal)?;
+            inode.block[slot] = block;
+            inode.sectors += self.block_sectors();
+        }
+
+        for index in path {
+            let offset = block as u64 * self.block_size + index * 4;
+            let mut ptr = [0; 4];
+
+            self.device.read(offset, &mut ptr)?;
+            let mut next = u32::from_le_bytes(ptr);
+
+            if next == 0 {
+                let Some(goal) = goal else {
+                    return Ok(None);
+                };
+
+                next = self.alloc_block(goal)?;
+                self.device.write(offset, &next.to_le_bytes())?;
+                inode.sectors += self.block_sectors();
+            }
+
+            block = next;
+        }
+
+        Ok(Some(block))
+    }
+
+    /// Read the data of `inode` at `offset`, holes read as zeroes
+    fn read_data(&self, inode: &Inode, offset: u64, buf: &mut [u8]) -> Result<(), FsError> {
+        let mut inode = *inode;
+        let mut done = 0;
+
+        while done < buf.len() {
+            let pos = offset + done as u64;
+            let within = pos % self.block_size;
+            let len = ((self.block_size - within) as usize).min(buf.len() - done);
+            let chunk = &mut buf[done..done + len];
+
+            match self.bmap(&mut inode, pos / self.block_size, None)? {
+                Some(block) => self.device.read(block as u64 * self.block_size + within, chunk)?,
+                None => chunk.fill(0),
+            }
+
+            done += len;
+        }
+
+        Ok(())
+    }
+
+    /// Write `data` into inode `ino` at `offset`, allocating blocks and growing it as needed. The inode is left for
+    /// the caller to write back, which it must do even if this fails.
+    fn write_data(&self, ino: u32, inode: &mut Inode, offset: u64, data: &[u8]) -> Result<(), FsError> {
+        let end = offset.checked_add(data.len() as u64).ok_or(FsError::NoSpace)?;
+
+        // Without the large file feature sizes are 31 bits, and revision 0 can't have the feature
+        if end > i32::MAX as u64 && self.sb.feature_ro_compat & RO_COMPAT_LARGE_FILE == 0 {
+            if self.sb.rev_level == 0 || !inode.is_file() {
+                return Err(FsError::NoSpace);
+            }
+
+            self.large_file.store(true, Ordering::Relaxed);
+            self.dirty.store(true, Ordering::Relaxed);
+        }
+
+        let goal = self.group_of(ino);
+        let mut done = 0;
+
+        while done < data.len() {
+            let pos = offset + done as u64;
+            let within = pos % self.block_size;
+            let len = ((self.block_size - within) as usize).min(data.len() - done);
+
+            let block = self.bmap(inode, pos / self.block_size, Some(goal))?.unwrap();
+            self.device.write(block as u64 * self.block_size + within, &data[done..done + len])?;
+
+            done += len;
+            inode.size = inode.size.max(pos + len as u64);
+        }
+
+        Ok(())
+    }
+
+    /// Free the blocks under `block`, a pointer at `level` of indirection covering the blocks from `base`, that are
+    /// past the first `keep`. Returns whether `block` itself was freed.
+    fn free_tree(&self, inode: &mut Inode, block: u32, level: u32, base: u64, keep: u64) -> Result<bool, FsError> {
+        let per = self.per_block();
+
+        if block == 0 || base + per.pow(level) <= keep {
+            return Ok(false);
+        }
+
+        if level > 0 {
+            let mut ptrs = vec![0; self.block_size as usize];
+            let mut changed = false;
+
+            self.device.read(block as u64 * self.block_size, &mut ptrs)?;
+
+            for i in 0..per as usize {
+                let child = le32(&ptrs, i * 4);
+
+                if self.free_tree(inode, child, level - 1, base + i as u64 * per.pow(level - 1), keep)? {
+                    put32(&mut ptrs, i * 4, 0);
+                    changed = true;
+                }
+            }
+
+            // Still holds some of the blocks kept
+            if base < keep {
+                if changed {
+                    self.device.write(block as u64 * self.block_size, &ptrs)?;
+                }
+
+                return Ok(false);
+            }
+        }
+
+        self.free_block(block)?;
+        inode.sectors = inode.sectors.saturating_sub(self.block_sectors());
+
+        Ok(true)
+    }
+
+    /// Free every block of `inode` past `size` bytes, and zero the rest of the last block kept
+    fn truncate_blocks(&self, inode: &mut Inode, size: u64) -> Result<(), FsError> {
+        // Fast symbolic links and device nodes keep other things in their block pointers
+        if inode.sectors == 0 {
+            return Ok(());
+        }
+
+        let within = size % self.block_size;
+
+        if within != 0 {
+            if let Some(block) = self.bmap(inode, size / self.block_size, None)? {
+                self.device.zero(block as u64 * self.block_size + within, self.block_size - within)?;
+            }
+        }
+
+        let keep = size.div_ceil(self.block_size);
+
+        for i in 0..DIRECT_BLOCKS {
+            let ptr = inode.block[i];
+
+            if self.free_tree(inode, ptr, 0, i as u64, keep)? {
+                inode.block[i] = 0;
+            }
+        }
+
+        let mut base = DIRECT_BLOCKS as u64;
+
+        for level in 1..=3 {
+            let slot = DIRECT_BLOCKS - 1 + level as usize;
+            let ptr = inode.block[slot];
+
+            if self.free_tree(inode, ptr, level, base, keep)? {
+                inode.block[slot] = 0;
+            }
+
+            base += self.per_block().pow(level);
+        }
+
+        Ok(())
+    }
+
+    /// Free an inode that's lost its last link, along with its blocks
+    fn release(&self, ino: u32, inode: &mut Inode) -> Result<(), FsError> {
+        self.truncate_blocks(inode, 0)?;
+
+        inode.links = 0;
+        inode.size = 0;
+        // Deletion times below the inode count read as links in the orphan list, as 0 would before the clock is set
+        inode.dtime = (super::now() as u32).max(self.sb.inodes_count);
+
+        self.write_inode(ino, inode)?;
+        self.free_inode(ino, inode.is_dir())
+    }
+
+    /// The file type byte for entries, which is always 0 without the feature
+    fn file_type(&self, mode: u16) -> u8 {
+        if self.sb.feature_incompat & INCOMPAT_FILETYPE != 0 {
+            file_type(mode)
+        } else {
+            0
+        }
+    }
+
+    /// Every entry of a directory, unused ones included
+    fn records(&self, dir: &Inode) -> Result<Vec<DirRecord>, FsError> {
+        let bs = self.block_size as usize;
+        let mut block = vec![0; bs];
+        let mut records = Vec::new();
+
+        for b in 0..dir.size / self.block_size {
+            self.read_data(dir, b * self.block_size, &mut block)?;
+
+            let mut off = 0;
+
+            while off < bs {
+                let ino = le32(&block, off);
+                let rec_len = le16(&block, off + 4);
+                let name_len = block[off + 6] as usize;
+
+                if (rec_len as usize) < DIR_HEADER
+                    || off + rec_len as usize > bs
+                    || DIR_HEADER + name_len > rec_len as usize
+                {
+                    return Err(FsError::Corrupt);
+                }
+
+                records.push(DirRecord {
+                    offset: b * self.block_size + off as u64,
+                    ino,
+                    rec_len,
+                    name: block[off + DIR_HEADER..off + DIR_HEADER + name_len].to_vec(),
+                });
+
+                off += rec_len as usize;
+            }
+        }
+
+        Ok(records)
+    }
+
+    fn find(&self, dir: &Inode, name: &str) -> Result<Option<DirRecord>, FsError> {
+        Ok(self.records(dir)?.into_iter().find(|record| record.ino != 0 && record.name == name.as_bytes()))
+    }
+
+    /// Whether a directory has nothing but `.` and `..`
+    fn is_empty(&self, dir: &Inode) -> Result<bool, FsError> {
+        Ok(self
+            .records(dir)?
+            .iter()
+            .all(|record| record.ino == 0 || record.name == b"." || record.name == b".."))
+    }
+
+    /// Add an entry to directory `dir_ino`, in the first gap big enough or a new block at the end
+    fn add_entry(&self, dir_ino: u32, dir: &mut Inode, name: &str, ino: u32, file_type: u8) -> Result<(), FsError> {
+        let needed = rec_size(name.len());
+        let mut entry = vec![0; needed as usize];
+
+        // Entries are changed without updating an index, so it has to go
+        dir.flags &= !INDEX_FL;
+
+        for record in self.records(dir)? {
+            let used = if record.ino == 0 { 0 } else { rec_size(record.name.len()) };
+
+            if record.rec_len.saturating_sub(used) < needed {
+                continue;
+            }
+
+            if used != 0 {
+                self.write_data(dir_ino, dir, record.offset + 4, &used.to_le_bytes())?;
+            }
+
+            write_record(&mut entry, ino, record.rec_len - used, name.as_bytes(), file_type);
+            return self.write_data(dir_ino, dir, record.offset + used as u64, &entry);
+        }
+
+        let mut block = vec![0; self.block_size as usize];
+        write_record(&mut block, ino, self.block_size as u16, name.as_bytes(), file_type);
+
+        self.write_data(dir_ino, dir, dir.size, &block)
+    }
+
+    /// Remove the entry `name` from directory `dir_ino`, merging its space into the entry before it
+    fn remove_entry(&self, dir_ino: u32, dir: &mut Inode, name: &str) -> Result<DirRecord, FsError> {
+        let records = self.records(dir)?;
+        let index = records
+            .iter()
+            .position(|record| record.ino != 0 && record.name == name.as_bytes())
+            .ok_or(FsError::NotFound)?;
+
+        let record = &records[index];
+
+        dir.flags &= !INDEX_FL;
+
+        match index.checked_sub(1).map(|prev| &records[prev]) {
+            Some(prev) if prev.offset / self.block_size == record.offset / self.block_size => {
+                let rec_len = prev.rec_len + record.rec_len;
+                self.write_data(dir_ino, dir, prev.offset + 4, &rec_len.to_le_bytes())?;
+            },
+            // The first entry of a block just becomes unused
+            _ => self.write_data(dir_ino, dir, record.offset, &0u32.to_le_bytes())?,
+        }
+
+        Ok(record.clone())
+    }
+
+    /// The first block of a new directory
+    fn dir_block(&self, ino: u32, parent: u32) -> Vec<u8> {
+        let mut block = vec![0; self.block_size as usize];
+        let file_type = self.file_type(S_IFDIR);
+
+        write_record(&mut block, ino, 12, b".", file_type);
+        write_record(&mut block[12..], parent, self.block_size as u16 - 12, b"..", file_type);
+
+        block
+    }
+
+    /// The parent of directory `ino`
+    fn parent(&self, ino: u32) -> Result<u32, FsError> {
+        Ok(self.find(&self.dir_inode(ino)?, "..")?.ok_or(FsError::Corrupt)?.ino)
+    }
+}
+
+impl Filesystem for Ext2Fs {
+    fn name(&self) -> &'static str {
+        "ext2"
+    }
+
+    fn root(&self) -> Arc<dyn Vnode> {
+        self.node(ROOT_INO)
+    }
+
+    fn sync(&self) -> Result<(), FsError> {
+        let _guard = self.lock.lock();
+
+        if self.dirty.swap(false, Ordering::Relaxed) {
+            let mut raw = [0; SUPERBLOCK_SIZE];
+            self.device.read(SUPERBLOCK_OFFSET, &mut raw)?;
+
+            put32(&mut raw, sb_field::FREE_BLOCKS, self.free_blocks.load(Ordering::Relaxed));
+            put32(&mut raw, sb_field::FREE_INODES, self.free_inodes.load(Ordering::Relaxed));
+            put32(&mut raw, sb_field::WTIME, super::now() as u32);
+
+            if self.large_file.load(Ordering::Relaxed) {
+                let features = le32(&raw, sb_field::FEATURE_RO_COMPAT) | RO_COMPAT_LARGE_FILE;
+                put32(&mut raw, sb_field::FEATURE_RO_COMPAT, features);
+            }
+
+            self.device.write(SUPERBLOCK_OFFSET, &raw)?;
+        }
+
+        self.device.sync()
+    }
+}
+
+fn kind(mode: u16) -> VnodeKind {
+    match mode & S_IFMT {
+        S_IFREG => VnodeKind::File,
+        S_IFDIR => VnodeKind::Directory,
+        S_IFLNK => VnodeKind::Symlink,
+        _ => VnodeKind::Device,
+    }
+}
+
+pub struct Ext2Node {
+    fs: Arc<Ext2Fs>,
+    ino: u32,
+}
+
+impl Ext2Node {
+    /// The inode, which is gone once its last link is
+    fn inode(&self) -> Result<Inode, FsError> {
+        let inode = self.fs.read_inode(self.ino)?;
+
+        if inode.links == 0 {
+            return Err(FsError::NotFound);
+        }
+
+        Ok(inode)
+    }
+
+    fn file(&self) -> Result<Inode, FsError> {
+        let inode = self.inode()?;
+
+        match kind(inode.mode) {
+            VnodeKind::File => Ok(inode),
+            VnodeKind::Directory => Err(FsError::IsDirectory),
+            _ => Err(FsError::Unsupported),
+        }
+    }
+
+    fn dir(&self) -> Result<Inode, FsError> {
+        self.fs.dir_inode(self.ino)
+    }
+
+    /// Write back the directory after changing its entries
+    fn touch_dir(&self, ino: u32, dir: &mut Inode) -> Result<(), FsError> {
+        let now = super::now() as u32;
+        dir.mtime = now;
+        dir.ctime = now;
+
+        self.fs.write_inode(ino, dir)
+    }
+
+    /// Drop a link to inode `ino`, freeing it if it was the last
+    fn drop_link(&self, ino: u32) -> Result<(), FsError> {
+        let mut inode = self.fs.read_inode(ino)?;
+
+        // Directories are only linked from their parent, their `.` goes with it
+        inode.links = if inode.is_dir() { 0 } else { inode.links.saturating_sub(1) };
+        inode.ctime = super::now() as u32;
+
+        if inode.links == 0 {
+            self.fs.release(ino, &mut inode)
+        } else {
+            self.fs.write_inode(ino, &inode)
+        }
+    }
+
+    fn update(&self, f: impl FnOnce(&mut Inode)) -> Result<(), FsError> {
+        self.fs.writable()?;
+
+        let _guard = self.fs.lock.lock();
+        let mut inode = self.inode()?;
+
+        f(&mut inode);
+        inode.ctime = super::now() as u32;
+
+        self.fs.write_inode(self.ino, &inode)
+    }
+}
+
+impl Vnode for Ext2Node {
+    fn metadata(&self) -> Result<Metadata, FsError> {
+        let _guard = self.fs.lock.lock();
+        let inode = self.inode()?;
+
+        Ok(Metadata {
+            kind: kind(inode.mode),
+            ino: self.ino as u64,
+            size: inode.size,
+            mode: inode.mode & 0o7777,
+            uid: inode.uid,
+            gid: inode.gid,
+            links: inode.links as u32,
+            atime: inode.atime as u64,
+            mtime: inode.mtime as u64,
+            ctime: inode.ctime as u64,
+        })
+    }
+
+    fn read(&self, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        let _guard = self.fs.lock.lock();
+        let inode = self.inode()?;
+
+        if offset >= inode.size {
+            return Ok(0);
+        }
+
+        let len = (inode.size - offset).min(buf.len() as u64) as usize;
+
+        if inode.is_fast_symlink() {
+            let target: Vec<u8> = inode.block.iter().flat_map(|ptr| ptr.to_le_bytes()).collect();
+            let end = (offset as usize + len).min(target.len());
+
+            buf[..end - offset as usize].copy_from_slice(&target[offset as usize..end]);
+            return Ok(end - offset as usize);
+        }
+
+        match kind(inode.mode) {
+            VnodeKind::File | VnodeKind::Symlink => {},
+            VnodeKind::Directory => return Err(FsError::IsDirectory),
+            VnodeKind::Device => return Err(FsError::Unsupported),
+        }
+
+        self.fs.read_data(&inode, offset, &mut buf[..len])?;
+
+        Ok(len)
+    }
+
+    fn write(&self, offset: u64, data: &[u8]) -> Result<usize, FsError> {
+        self.fs.writable()?;
+
+        let _guard = self.fs.lock.lock();
+        let mut inode = self.file()?;
+
+        if data.is_empty() {
+            return Ok(0);
+        }
+
+        let result = self.fs.write_data(self.ino, &mut inode, offset, data);
+
+        // Blocks may have been allocated even if the write failed
+        let now = super::now() as u32;
+        inode.mtime = now;
+        inode.ctime = now;
+        self.fs.write_inode(self.ino, &inode)?;
+
+        result.map(|_| data.len())
+    }
+
+    fn truncate(&self, size: u64) -> Result<(), FsError> {
+        self.fs.writable()?;
+
+        let _guard = self.fs.lock.lock();
+        let mut inode = self.file()?;
+
+        if size > i32::MAX as u64 && self.fs.sb.feature_ro_compat & RO_COMPAT_LARGE_FILE == 0 {
+            if self.fs.sb.rev_level == 0 {
+                return Err(FsError::NoSpace);
+            }
+
+            self.fs.large_file.store(true, Ordering::Relaxed);
+            self.fs.dirty.store(true, Ordering::Relaxed);
+        }
+
+        // Growing leaves a hole, which reads as zeroes
+        if size < inode.size {
+            self.fs.truncate_blocks(&mut inode, size)?;
+        }
+
+        inode.size = size;
+
+        let now = super::now() as u32;
+        inode.mtime = now;
+        inode.ctime = now;
+
+        self.fs.write_inode(self.ino, &inode)
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Vnode>, FsError> {
+        let _guard = self.fs.lock.lock();
+        let record = self.fs.find(&self.dir()?, name)?.ok_or(FsError::NotFound)?;
+
+        Ok(self.fs.node(record.ino))
+    }
+
+    fn readdir(&self, index: usize) -> Result<Option<DirEntry>, FsError> {
+        let _guard = self.fs.lock.lock();
+
+        let record = self
+            .fs
+            .records(&self.dir()?)?
+            .into_iter()
+            .filter(|record| record.ino != 0 && record.name != b"." && record.name != b"..")
+            .nth(index);
+
+        let Some(record) = record else {
+            return Ok(None);
+        };
+
+        Ok(Some(DirEntry {
+            name: String::from_utf8_lossy(&record.name).into_owned(),
+            ino: record.ino as u64,
+            kind: kind(self.fs.read_inode(record.ino)?.mode),
+        }))
+    }
+
+    fn create(&self, name: &str, kind: VnodeKind) -> Result<Arc<dyn Vnode>, FsError> {
+        let mode = match kind {
+            VnodeKind::File => S_IFREG | 0o644,
+            VnodeKind::Directory => S_IFDIR | 0o755,
+            _ => return Err(FsError::Unsupported),
+        };
+
+        self.fs.writable()?;
+
+        let _guard = self.fs.lock.lock();
+        let mut dir = self.dir()?;
+        let is_dir = kind == VnodeKind::Directory;
+
+        if self.fs.find(&dir, name)?.is_some() {
+            return Err(FsError::Exists);
+        }
+
+        if is_dir && dir.links >= LINK_MAX {
+            return Err(FsError::NoSpace);
+        }
+
+        let now = super::now() as u32;
+        let ino = self.fs.alloc_inode(self.fs.group_of(self.ino), is_dir)?;
+
+        let mut inode = Inode {
+            mode,
+            links: if is_dir { 2 } else { 1 },
+            atime: now,
+            ctime: now,
+            mtime: now,
+            ..Default::default()
+        };
+
+        let mut result = Ok(());
+
+        if is_dir {
+            result = self.fs.write_data(ino, &mut inode, 0, &self.fs.dir_block(ino, self.ino));
+        }
+
+        self.fs.init_inode(ino, &inode)?;
+
+        let result = result.and_then(|_| self.fs.add_entry(self.ino, &mut dir, name, ino, self.fs.file_type(mode)));
+
+        // Don't leak the inode if the directory couldn't take it
+        if let Err(err) = result {
+            self.fs.write_inode(self.ino, &dir)?;
+            self.fs.release(ino, &mut inode)?;
+
+            return Err(err);
+        }
+
+        if is_dir {
+            dir.links += 1;
+        }
+
+        self.touch_dir(self.ino, &mut dir)?;
+
+        Ok(self.fs.node(ino))
+    }
+
+    fn unlink(&self, name: &str) -> Result<(), FsError> {
+        self.fs.writable()?;
+
+        let _guard = self.fs.lock.lock();
+        let mut dir = self.dir()?;
+        let record = self.fs.find(&dir, name)?.ok_or(FsError::NotFound)?;
+        let inode = self.fs.read_inode(record.ino)?;
+
+        if inode.is_dir() && !self.fs.is_empty(&inode)? {
+            return Err(FsError::NotEmpty);
+        }
+
+        let result = self.fs.remove_entry(self.ino, &mut dir, name);
+
+        // A child directory's `..` was a link to this one
+        if result.is_ok() && inode.is_dir() {
+            dir.links = dir.links.saturating_sub(1);
+        }
+
+        self.touch_dir(self.ino, &mut dir)?;
+        result?;
+
+        self.drop_link(record.ino)
+    }
+
+    fn link(&self, name: &str, target: &Arc<dyn Vnode>) -> Result<(), FsError> {
+        let target = target.metadata()?;
+
+        if target.kind == VnodeKind::Directory {
+            return Err(FsError::IsDirectory);
+        }
+
+        self.fs.writable()?;
+
+        let _guard = self.fs.lock.lock();
+        let ino = target.ino as u32;
+        let mut dir = self.dir()?;
+
+        if self.fs.find(&dir, name)?.is_some() {
+            return Err(FsError::Exists);
+        }
+
+        let mut inode = self.fs.read_inode(ino)?;
+
+        if inode.links == 0 {
+            return Err(FsError::NotFound);
+        }
+
+        if inode.links >= LINK_MAX {
+            return Err(FsError::NoSpace);
+        }
+
+        let result = self.fs.add_entry(self.ino, &mut dir, name, ino, self.fs.file_type(inode.mode));
+        self.touch_dir(self.ino, &mut dir)?;
+        result?;
+
+        inode.links += 1;
+        inode.ctime = super::now() as u32;
+
+        self.fs.write_inode(ino, &inode)
+    }
+
+    fn rename(&self, from: &str, dir: &Arc<dyn Vnode>, to: &str) -> Result<(), FsError> {
+        let dst_ino = dir.metadata()?.ino as u32;
+
+        self.fs.writable()?;
+
+        let _guard = self.fs.lock.lock();
+        let record = self.fs.find(&self.dir()?, from)?.ok_or(FsError::NotFound)?;
+        let ino = record.ino;
+        let node = self.fs.read_inode(ino)?;
+        let moved = dst_ino != self.ino;
+
+        // A directory can't go anywhere beneath itself
+        if node.is_dir() && moved {
+            let mut ancestor = dst_ino;
+
+            for _ in 0..self.fs.sb.inodes_count {
+                if ancestor == ino {
+                    return Err(FsError::InvalidArgument);
+                }
+
+                if ancestor == ROOT_INO {
+                    break;
+                }
+
+                ancestor = self.fs.parent(ancestor)?;
+            }
+        }
+
+        let mut dst = self.fs.dir_inode(dst_ino)?;
+
+        if let Some(existing) = self.fs.find(&dst, to)? {
+            // Already another name for the same file, only the old name goes
+            if existing.ino == ino {
+                if !moved && from == to {
+                    return Ok(());
+                }
+
+                let mut src = self.dir()?;
+                let result = self.fs.remove_entry(self.ino, &mut src, from);
+                self.touch_dir(self.ino, &mut src)?;
+                result?;
+
+                return self.drop_link(ino);
+            }
+
+            // Directories only replace empty directories, and files only files
+            let old = self.fs.read_inode(existing.ino)?;
+
+            match (node.is_dir(), old.is_dir()) {
+                (true, false) => return Err(FsError::NotDirectory),
+                (false, true) => return Err(FsError::IsDirectory),
+                (true, true) if !self.fs.is_empty(&old)? => return Err(FsError::NotEmpty),
+                _ => {},
+            }
+
+            let result = self.fs.remove_entry(dst_ino, &mut dst, to);
+
+            if result.is_ok() && old.is_dir() {
+                dst.links = dst.links.saturating_sub(1);
+            }
+
+            self.touch_dir(dst_ino, &mut dst)?;
+            result?;
+
+            self.drop_link(existing.ino)?;
+        }
+
+        if node.is_dir() && moved && dst.links >= LINK_MAX {
+            return Err(FsError::NoSpace);
+        }
+
+        let result = self.fs.add_entry(dst_ino, &mut dst, to, ino, self.fs.file_type(node.mode));
+
+        if result.is_ok() && node.is_dir() && moved {
+            dst.links += 1;
+        }
+
+        self.touch_dir(dst_ino, &mut dst)?;
+        result?;
+
+        // Read again, it's the same directory if the name is all that changed
+        let mut src = self.dir()?;
+        let result = self.fs.remove_entry(self.ino, &mut src, from);
+
+        if result.is_ok() && node.is_dir() && moved {
+            src.links = src.links.saturating_sub(1);
+        }
+
+        self.touch_dir(self.ino, &mut src)?;
+        result?;
+
+        let mut node = self.fs.read_inode(ino)?;
+
+        // Point `..` at the new parent
+        if node.is_dir() && moved {
+            let parent = self.fs.find(&node, "..")?.ok_or(FsError::Corrupt)?;
+            let result = self.fs.write_data(ino, &mut node, parent.offset, &dst_ino.to_le_bytes());
+
+            self.fs.write_inode(ino, &node)?;
+            result?;
+        }
+
+        node.ctime = super::now() as u32;
+        self.fs.write_inode(ino, &node)
+    }
+
+    fn set_mode(&self, mode: u16) -> Result<(), FsError> {
+        self.update(|inode| inode.mode = (inode.mode & S_IFMT) | (mode & 0o7777))
+    }
+
+    fn set_owner(&self, uid: u32, gid: u32) -> Result<(), FsError> {
+        self.update(|inode| {
+            inode.uid = uid;
+            inode.gid = gid;
+        })
+    }
+}
@@ -0,0 +1,269 @@
+// On-disk structures: the superblock, block group descriptors, inodes and directory entries.
+// Everything is little endian. Only the fields the driver uses are decoded, writes patch those fields into the
+// bytes already on disk so everything else is kept.
+
+pub const MAGIC: u16 = 0xEF53;
+/// Byte offset of the superblock, whatever the block size
+pub const SUPERBLOCK_OFFSET: u64 = 1024;
+pub const SUPERBLOCK_SIZE: usize = 1024;
+pub const DESC_SIZE: u64 = 32;
+pub const ROOT_INO: u32 = 2;
+
+/// Directory entries have a file type byte
+pub const INCOMPAT_FILETYPE: u32 = 0x0002;
+/// Groups without superblock backups, which doesn't matter to us
+pub const RO_COMPAT_SPARSE_SUPER: u32 = 0x0001;
+/// Files can be 2GiB or more
+pub const RO_COMPAT_LARGE_FILE: u32 = 0x0002;
+
+/// The directory has a hash tree index, which goes stale once entries are changed without it
+pub const INDEX_FL: u32 = 0x1000;
+
+pub const S_IFMT: u16 = 0o170000;
+pub const S_IFDIR: u16 = 0o040000;
+pub const S_IFREG: u16 = 0o100000;
+pub const S_IFLNK: u16 = 0o120000;
+
+/// Most links an inode can have
+pub const LINK_MAX: u16 = 32000;
+
+/// The file type byte of a directory entry for an inode with `mode`
+pub fn file_type(mode: u16) -> u8 {
+    match mode & S_IFMT {
+        S_IFREG => 1,
+        S_IFDIR => 2,
+        0o020000 => 3,
+        0o060000 => 4,
+        0o010000 => 5,
+        0o140000 => 6,
+        S_IFLNK => 7,
+        _ => 0,
+    }
+}
+
+/// Direct block pointers, followed by single, double and triple indirect ones
+pub const DIRECT_BLOCKS: usize = 12;
+
+pub fn le16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+pub fn le32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+pub fn put16(bytes: &mut [u8], offset: usize, value: u16) {
+    bytes[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+pub fn put32(bytes: &mut [u8], offset: usize, value: u32) {
+    bytes[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Superblock {
+    pub inodes_count: u32,
+    pub blocks_count: u32,
+    pub free_blocks: u32,
+    pub free_inodes: u32,
+    pub first_data_block: u32,
+    pub log_block_size: u32,
+    pub blocks_per_group: u32,
+    pub inodes_per_group: u32,
+    pub magic: u16,
+    pub rev_level: u32,
+    pub first_ino: u32,
+    pub inode_size: u16,
+    pub feature_incompat: u32,
+    pub feature_ro_compat: u32,
+}
+
+impl Superblock {
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        let rev_level = le32(bytes, 76);
+
+        // Revision 0 has fixed inodes, and none of the later fields
+        let (first_ino, inode_size, feature_incompat, feature_ro_compat) = if rev_level == 0 {
+            (11, 128, 0, 0)
+        } else {
+            (le32(bytes, 84), le16(bytes, 88), le32(bytes, 96), le32(bytes, 100))
+        };
+
+        Self {
+            inodes_count: le32(bytes, 0),
+            blocks_count: le32(bytes, 4),
+            free_blocks: le32(bytes, 12),
+            free_inodes: le32(bytes, 16),
+            first_data_block: le32(bytes, 20),
+            log_block_size: le32(bytes, 24),
+            blocks_per_group: le32(bytes, 32),
+            inodes_per_group: le32(bytes, 40),
+            magic: le16(bytes, 56),
+            rev_level,
+            first_ino,
+            inode_size,
+            feature_incompat,
+            feature_ro_compat,
+        }
+    }
+
+    pub fn block_size(&self) -> u64 {
+        1024 << self.log_block_size
+    }
+
+    pub fn groups(&self) -> u32 {
+        (self.blocks_count - self.first_data_block).div_ceil(self.blocks_per_group)
+    }
+}
+
+/// Offsets of the superblock fields written back on sync
+pub mod sb_field {
+    pub const FREE_BLOCKS: usize = 12;
+    pub const FREE_INODES: usize = 16;
+    pub const WTIME: usize = 48;
+    pub const FEATURE_RO_COMPAT: usize = 100;
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct GroupDesc {
+    pub block_bitmap: u32,
+    pub inode_bitmap: u32,
+    pub inode_table: u32,
+    pub free_blocks: u16,
+    pub free_inodes: u16,
+    pub used_dirs: u16,
+}
+
+impl GroupDesc {
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        Self {
+            block_bitmap: le32(bytes, 0),
+            inode_bitmap: le32(bytes, 4),
+            inode_table: le32(bytes, 8),
+            free_blocks: le16(bytes, 12),
+            free_inodes: le16(bytes, 14),
+            used_dirs: le16(bytes, 16),
+        }
+    }
+
+    /// The counts, the only fields that change
+    pub fn write_counts(&self, bytes: &mut [u8]) {
+        put16(bytes, 12, self.free_blocks);
+        put16(bytes, 14, self.free_inodes);
+        put16(bytes, 16, self.used_dirs);
+    }
+}
+
+/// The first 128 bytes of an inode, larger inodes keep the rest as it is
+pub const INODE_BASE_SIZE: usize = 128;
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Inode {
+    pub mode: u16,
+    pub uid: u32,
+    pub size: u64,
+    pub atime: u32,
+    pub ctime: u32,
+    pub mtime: u32,
+    pub dtime: u32,
+    pub gid: u32,
+    pub links: u16,
+    /// In 512 byte sectors, indirect blocks included
+    pub sectors: u32,
+    pub flags: u32,
+    pub block: [u32; 15],
+}
+
+impl Inode {
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        let mode = le16(bytes, 0);
+
+        // The high half of the size is only a size for regular files, for directories it's an ACL
+        let size_high = if mode & S_IFMT == S_IFREG { le32(bytes, 108) as u64 } else { 0 };
+
+        let mut block = [0; 15];
+
+        for (i, ptr) in block.iter_mut().enumerate() {
+            *ptr = le32(bytes, 40 + i * 4);
+        }
+
+        Self {
+            mode,
+            uid: le16(bytes, 2) as u32 | (le16(bytes, 120) as u32) << 16,
+            size: le32(bytes, 4) as u64 | size_high << 32,
+            atime: le32(bytes, 8),
+            ctime: le32(bytes, 12),
+            mtime: le32(bytes, 16),
+            dtime: le32(bytes, 20),
+            gid: le16(bytes, 24) as u32 | (le16(bytes, 122) as u32) << 16,
+            links: le16(bytes, 26),
+            sectors: le32(bytes, 28),
+            flags: le32(bytes, 32),
+            block,
+        }
+    }
+
+    pub fn write_to(&self, bytes: &mut [u8]) {
+        put16(bytes, 0, self.mode);
+        put16(bytes, 2, self.uid as u16);
+        put32(bytes, 4, self.size as u32);
+        put32(bytes, 8, self.atime);
+        put32(bytes, 12, self.ctime);
+        put32(bytes, 16, self.mtime);
+        put32(bytes, 20, self.dtime);
+        put16(bytes, 24, self.gid as u16);
+        put16(bytes, 26, self.links);
+        put32(bytes, 28, self.sectors);
+        put32(bytes, 32, self.flags);
+
+        for (i, ptr) in self.block.iter().enumerate() {
+            put32(bytes, 40 + i * 4, *ptr);
+        }
+
+        if self.is_file() {
+            put32(bytes, 108, (self.size >> 32) as u32);
+        }
+
+        put16(bytes, 120, (self.uid >> 16) as u16);
+        put16(bytes, 122, (self.gid >> 16) as u16);
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.mode & S_IFMT == S_IFDIR
+    }
+
+    pub fn is_file(&self) -> bool {
+        self.mode & S_IFMT == S_IFREG
+    }
+
+    /// Symbolic links short enough are stored in the block pointers, with no blocks
+    pub fn is_fast_symlink(&self) -> bool {
+        self.mode & S_IFMT == S_IFLNK && self.sectors == 0
+    }
+}
+
+/// A directory entry, `offset` is where it is within the directory
+#[derive(Clone, Debug)]
+pub struct DirRecord {
+    pub offset: u64,
+    pub ino: u32,
+    pub rec_len: u16,
+    pub name: alloc::vec::Vec<u8>,
+}
+
+/// Header of a directory entry, the name follows
+pub const DIR_HEADER: usize = 8;
+
+/// Space an entry with a name of `len` bytes takes up
+pub fn rec_size(len: usize) -> u16 {
+    (DIR_HEADER + len).next_multiple_of(4) as u16
+}
+
+/// Write an entry into `bytes`
+pub fn write_record(bytes: &mut [u8], ino: u32, rec_len: u16, name: &[u8], file_type: u8) {
+    put32(bytes, 0, ino);
+    put16(bytes, 4, rec_len);
+    bytes[6] = name.len() as u8;
+    bytes[7] = file_type;
+    bytes[DIR_HEADER..DIR_HEADER + name.len()].copy_from_slice(name);
+}
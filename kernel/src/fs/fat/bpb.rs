@@ -0,0 +1,154 @@
+// The BIOS parameter block at the start of a FAT volume, and the layout of the volume it describes.
+// The FAT type is decided by the number of clusters alone, as the spec says, never by the type string.
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FatType {
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+/// Where everything on the volume is, all offsets in bytes from the start of the partition
+#[derive(Clone, Copy, Debug)]
+pub struct Layout {
+    pub fat_type: FatType,
+    pub cluster_size: u64,
+    /// Start of the first FAT
+    pub fat_offset: u64,
+    /// Size of each FAT
+    pub fat_size: u64,
+    pub fats: u64,
+    /// The fixed root directory, FAT12 and FAT16 only
+    pub root_offset: u64,
+    pub root_size: u64,
+    /// First cluster of the root directory, FAT32 only
+    pub root_cluster: u32,
+    /// Start of cluster 2
+    pub data_offset: u64,
+    /// Clusters are numbered from 2 up to this, exclusive
+    pub cluster_end: u32,
+    /// The FSInfo sector, FAT32 only
+    pub fs_info: Option<u64>,
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+impl Layout {
+    /// Parse the boot sector, returns `None` if it doesn't describe a FAT volume
+    pub fn parse(sector: &[u8]) -> Option<Self> {
+        // Every FAT boot sector starts with a jump over the BPB
+        if sector.len() < 512 || !matches!(sector[0], 0xEB | 0xE9) {
+            return None;
+        }
+
+        let sector_size = u16_at(sector, 11) as u64;
+        let sectors_per_cluster = sector[13] as u64;
+        let reserved = u16_at(sector, 14) as u64;
+        let fats = sector[16] as u64;
+        let root_entries = u16_at(sector, 17) as u64;
+
+        let total = match u16_at(sector, 19) {
+            0 => u32_at(sector, 32) as u64,
+            total => total as u64,
+        };
+
+        let fat_sectors = match u16_at(sector, 22) {
+            0 => u32_at(sector, 36) as u64,
+            size => size as u64,
+        };
+
+        if !matches!(sector_size, 512 | 1024 | 2048 | 4096)
+            || !sectors_per_cluster.is_power_of_two()
+            || reserved == 0
+            || fats == 0
+            || fat_sectors == 0
+        {
+            return None;
+        }
+
+        let root_sectors = (root_entries * 32).div_ceil(sector_size);
+        let data_start = reserved + fats * fat_sectors + root_sectors;
+
+        if total <= data_start {
+            return None;
+        }
+
+        let clusters = (total - data_start) / sectors_per_cluster;
+
+        let fat_type = match clusters {
+            0..=4084 => FatType::Fat12,
+            4085..=65524 => FatType::Fat16,
+            _ => FatType::Fat32,
+        };
+
+        // FAT32 has no fixed root directory, the others have nothing else
+        if (fat_type == FatType::Fat32) != (root_entries == 0) {
+            return None;
+        }
+
+        let (root_cluster, fs_info) = if fat_type == FatType::Fat32 {
+            let fs_info = match u16_at(sector, 48) as u64 {
+                0 | 0xffff => None,
+                sector => Some(sector * sector_size),
+            };
+
+            (u32_at(sector, 44) & 0x0fff_ffff, fs_info)
+        } else {
+            (0, None)
+        };
+
+        // The FAT has to have room for every cluster
+        let entry_bits = match fat_type {
+            FatType::Fat12 => 12,
+            FatType::Fat16 => 16,
+            FatType::Fat32 => 32,
+        };
+
+        if (clusters + 2) * entry_bits > fat_sectors * sector_size * 8 {
+            return None;
+        }
+
+        Some(Self {
+            fat_type,
+            cluster_size: sectors_per_cluster * sector_size,
+            fat_offset: reserved * sector_size,
+            fat_size: fat_sectors * sector_size,
+            fats,
+            root_offset: (reserved + fats * fat_sectors) * sector_size,
+            root_size: root_sectors * sector_size,
+            root_cluster,
+            data_offset: data_start * sector_size,
+            cluster_end: (clusters + 2) as u32,
+            fs_info,
+        })
+    }
+
+    pub fn cluster_offset(&self, cluster: u32) -> u64 {
+        self.data_offset + (cluster - 2) as u64 * self.cluster_size
+    }
+
+    /// Bytes taken up on the partition
+    pub fn end(&self) -> u64 {
+        self.cluster_offset(self.cluster_end)
+    }
+
+    /// Value marking the end of a chain
+    pub fn eoc(&self) -> u32 {
+        match self.fat_type {
+            FatType::Fat12 => 0xfff,
+            FatType::Fat16 => 0xffff,
+            FatType::Fat32 => 0x0fff_ffff,
+        }
+    }
+
+    /// Smallest value that ends a chain
+    pub fn eoc_min(&self) -> u32 {
+        self.eoc() - 7
+    }
+}
@@ -0,0 +1,384 @@
+// Directory entries.
+// Each file has a 32 byte short entry holding an 8.3 name, preceded by long name entries holding its name in
+// UTF-16, 13 units each, last part first. Long names are tied to their short entry by a checksum of the short name.
+// Windows marks 8.3 names that are all lowercase with case flags instead of adding a long name, and so do we.
+
+use alloc::{string::String, vec::Vec};
+
+use crate::fs::FsError;
+
+pub const ENTRY_SIZE: usize = 32;
+
+pub const ATTR_READ_ONLY: u8 = 0x01;
+pub const ATTR_VOLUME_ID: u8 = 0x08;
+pub const ATTR_DIRECTORY: u8 = 0x10;
+pub const ATTR_ARCHIVE: u8 = 0x20;
+/// Read only, hidden, system and volume ID all at once mark a long name entry
+pub const ATTR_LONG_NAME: u8 = 0x0F;
+
+/// First byte of a deleted entry
+pub const DELETED: u8 = 0xE5;
+
+/// Case flags, the base name or the extension is lowercase
+const CASE_LOWER_BASE: u8 = 0x08;
+const CASE_LOWER_EXT: u8 = 0x10;
+
+/// Longest long name, in UTF-16 units
+const LONG_NAME_MAX: usize = 255;
+const LONG_NAME_UNITS: usize = 13;
+/// Offsets of the UTF-16 units of a long name entry
+const LONG_NAME_OFFSETS: [usize; LONG_NAME_UNITS] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+const LONG_NAME_LAST: u8 = 0x40;
+
+/// A file's entries in a directory, as read
+#[derive(Clone, Debug)]
+pub struct Entry {
+    pub name: String,
+    pub short: [u8; 11],
+    pub attr: u8,
+    pub cluster: u32,
+    pub size: u32,
+    /// Unix seconds
+    pub mtime: u64,
+    pub ctime: u64,
+    pub atime: u64,
+    /// Index of the first slot, which is a long name entry if there are any
+    pub first_slot: usize,
+    /// Index of the short entry
+    pub slot: usize,
+}
+
+impl Entry {
+    pub fn is_dir(&self) -> bool {
+        self.attr & ATTR_DIRECTORY != 0
+    }
+
+    /// Names are compared the way Windows does, ignoring case
+    pub fn matches(&self, name: &str) -> bool {
+        self.name.eq_ignore_ascii_case(name) || short_display(&self.short, 0).eq_ignore_ascii_case(name)
+    }
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+/// Every file in a directory's raw contents, skipping `.`, `..`, volume labels and deleted entries
+pub fn parse(data: &[u8]) -> Vec<Entry> {
+    let mut entries = Vec::new();
+
+    // Long name parts seen so far, with the index of the first one, how many there should be, and the checksum
+    let mut long: Vec<u16> = Vec::new();
+    let mut long_start = 0;
+    let mut long_next = 0;
+    let mut long_sum = 0;
+
+    for (slot, raw) in data.chunks_exact(ENTRY_SIZE).enumerate() {
+        match raw[0] {
+            0x00 => break,
+            DELETED => {
+                long.clear();
+                long_next = 0;
+                continue;
+            },
+            _ => {},
+        }
+
+        let attr = raw[11];
+
+        if attr & 0x3F == ATTR_LONG_NAME {
+            let ord = raw[0] & 0x1F;
+
+            if raw[0] & LONG_NAME_LAST != 0 {
+                long.clear();
+                long_start = slot;
+                long_sum = raw[13];
+            } else if ord == 0 || ord != long_next || raw[13] != long_sum {
+                // Out of order, so it's orphaned
+                long.clear();
+                long_next = 0;
+                continue;
+            }
+
+            // Parts come last first, so each one goes in front of the rest
+            let units: Vec<u16> = LONG_NAME_OFFSETS.iter().map(|offset| u16_at(raw, *offset)).collect();
+            long.splice(0..0, units);
+            long_next = ord.wrapping_sub(1);
+
+            continue;
+        }
+
+        let short: [u8; 11] = raw[..11].try_into().unwrap();
+        let long_valid = long_next == 0 && !long.is_empty() && long_sum == checksum(&short);
+
+        if attr & ATTR_VOLUME_ID != 0 || short[0] == b'.' {
+            long.clear();
+            continue;
+        }
+
+        let (name, first_slot) = if long_valid {
+            let len = long.iter().position(|unit| *unit == 0x0000).unwrap_or(long.len());
+            let name = char::decode_utf16(long[..len].iter().copied())
+                .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+                .collect();
+
+            (name, long_start)
+        } else {
+            (short_display(&short, raw[12]), slot)
+        };
+
+        long.clear();
+        long_next = 0;
+
+        entries.push(Entry {
+            name,
+            short,
+            attr,
+            cluster: cluster(raw),
+            size: u32::from_le_bytes(raw[28..32].try_into().unwrap()),
+            mtime: to_unix(u16_at(raw, 24), u16_at(raw, 22)),
+            ctime: to_unix(u16_at(raw, 16), u16_at(raw, 14)),
+            atime: to_unix(u16_at(raw, 18), 0),
+            first_slot,
+            slot,
+        });
+    }
+
+    entries
+}
+
+/// The name an 8.3 entry shows as, `case` holds the case flags
+pub fn short_display(short: &[u8; 11], case: u8) -> String {
+    let part = |bytes: &[u8], lower: bool| -> String {
+        let len = bytes.iter().rposition(|b| *b != b' ').map_or(0, |pos| pos + 1);
+
+        bytes[..len].iter().enumerate().map(|(i, b)| {
+            // 0x05 stands in for a leading 0xE5, which would mark the entry deleted
+            let b = if i == 0 && *b == 0x05 { DELETED } else { *b };
+            let c = char::from(b);
+
+            if lower { c.to_ascii_lowercase() } else { c }
+        }).collect()
+    };
+
+    let base = part(&short[..8], case & CASE_LOWER_BASE != 0);
+    let ext = part(&short[8..], case & CASE_LOWER_EXT != 0);
+
+    if ext.is_empty() {
+        base
+    } else {
+        base + "." + &ext
+    }
+}
+
+pub fn checksum(short: &[u8; 11]) -> u8 {
+    short.iter().fold(0u8, |sum, b| sum.rotate_right(1).wrapping_add(*b))
+}
+
+fn valid_short_char(c: u8) -> bool {
+    c.is_ascii_uppercase() || c.is_ascii_digit() || b"!#$%&'()-@^_`{}~".contains(&c)
+}
+
+/// Check `name` can be stored as a long name
+pub fn check_long_name(name: &str) -> Result<(), FsError> {
+    if name.encode_utf16().count() > LONG_NAME_MAX {
+        return Err(FsError::NameTooLong);
+    }
+
+    // Windows drops trailing dots and spaces, so names ending in them can't be looked up again
+    if name.chars().any(|c| c < ' ' || "\"*/:<>?\\|".contains(c)) || name.ends_with(['.', ' ']) {
+        return Err(FsError::InvalidPath);
+    }
+
+    Ok(())
+}
+
+/// The 8.3 name and case flags for `name`, if it needs no long name
+pub fn as_short(name: &str) -> Option<([u8; 11], u8)> {
+    let (base, ext) = name.rsplit_once('.').unwrap_or((name, ""));
+
+    if base.is_empty() || base.len() > 8 || ext.len() > 3 || (name.contains('.') && ext.is_empty()) {
+        return None;
+    }
+
+    let mut short = [b' '; 11];
+    let mut case = 0;
+
+    for (part, range, flag) in [(base, 0..8, CASE_LOWER_BASE), (ext, 8..11, CASE_LOWER_EXT)] {
+        let lower = part.bytes().any(|b| b.is_ascii_lowercase());
+        let upper = part.bytes().any(|b| b.is_ascii_uppercase());
+
+        // Mixed case needs a long name
+        if lower && upper {
+            return None;
+        }
+
+        if lower {
+            case |= flag;
+        }
+
+        for (dest, b) in short[range].iter_mut().zip(part.bytes()) {
+            *dest = b.to_ascii_uppercase();
+
+            if !valid_short_char(*dest) {
+                return None;
+            }
+        }
+    }
+
+    Some((short, case))
+}
+
+/// The 8.3 name for a long name, with a `~n` tail so it doesn't collide with `taken`
+pub fn generate_short(name: &str, taken: impl Fn(&[u8; 11]) -> bool) -> Result<[u8; 11], FsError> {
+    let convert = |part: &str, len: usize| -> Vec<u8> {
+        part.chars()
+            .filter(|c| *c != ' ' && *c != '.')
+            .map(|c| {
+                let b = if c.is_ascii() { c.to_ascii_uppercase() as u8 } else { b'_' };
+                if valid_short_char(b) { b } else { b'_' }
+            })
+            .take(len)
+            .collect()
+    };
+
+    let trimmed = name.trim_start_matches('.');
+    let (base, ext) = trimmed.rsplit_once('.').unwrap_or((trimmed, ""));
+
+    let mut base = convert(base, 8);
+    let ext = convert(ext, 3);
+
+    if base.is_empty() {
+        base.push(b'_');
+    }
+
+    for n in 1..1_000_000 {
+        let tail = alloc::format!("~{}", n);
+        let keep = base.len().min(8 - tail.len());
+
+        let mut short = [b' '; 11];
+        short[..keep].copy_from_slice(&base[..keep]);
+        short[keep..keep + tail.len()].copy_from_slice(tail.as_bytes());
+        short[8..8 + ext.len()].copy_from_slice(&ext);
+
+        if !taken(&short) {
+            return Ok(short);
+        }
+    }
+
+    Err(FsError::Exists)
+}
+
+/// The short name and case flags for `name`, and the long name entries it needs if any
+pub fn names(name: &str, taken: impl Fn(&[u8; 11]) -> bool) -> Result<([u8; 11], u8, Vec<[u8; ENTRY_SIZE]>), FsError> {
+    match as_short(name) {
+        Some((short, case)) if !taken(&short) => Ok((short, case, Vec::new())),
+        _ => {
+            let short = generate_short(name, taken)?;
+            Ok((short, 0, long_entries(name, &short)))
+        },
+    }
+}
+
+/// The long name entries for `name`, in the order they go on disk
+pub fn long_entries(name: &str, short: &[u8; 11]) -> Vec<[u8; ENTRY_SIZE]> {
+    let mut units: Vec<u16> = name.encode_utf16().collect();
+    let count = units.len().div_ceil(LONG_NAME_UNITS);
+
+    // Terminated with a zero if there's room, then padded
+    if units.len() % LONG_NAME_UNITS != 0 {
+        units.push(0x0000);
+    }
+
+    units.resize(count * LONG_NAME_UNITS, 0xFFFF);
+
+    let sum = checksum(short);
+
+    (1..=count).rev().map(|ord| {
+        let mut raw = [0; ENTRY_SIZE];
+
+        raw[0] = ord as u8 | if ord == count { LONG_NAME_LAST } else { 0 };
+        raw[11] = ATTR_LONG_NAME;
+        raw[13] = sum;
+
+        let part = &units[(ord - 1) * LONG_NAME_UNITS..ord * LONG_NAME_UNITS];
+
+        for (offset, unit) in LONG_NAME_OFFSETS.iter().zip(part) {
+            raw[*offset..*offset + 2].copy_from_slice(&unit.to_le_bytes());
+        }
+
+        raw
+    }).collect()
+}
+
+/// A short entry, with every timestamp set to `now`
+pub fn short_entry(short: &[u8; 11], case: u8, attr: u8, cluster: u32, now: u64) -> [u8; ENTRY_SIZE] {
+    let mut raw = [0; ENTRY_SIZE];
+    let (date, time) = from_unix(now);
+
+    raw[..11].copy_from_slice(short);
+    raw[11] = attr;
+    raw[12] = case;
+    raw[14..16].copy_from_slice(&time.to_le_bytes());
+    raw[16..18].copy_from_slice(&date.to_le_bytes());
+    raw[18..20].copy_from_slice(&date.to_le_bytes());
+    raw[22..24].copy_from_slice(&time.to_le_bytes());
+    raw[24..26].copy_from_slice(&date.to_le_bytes());
+    set_cluster(&mut raw, cluster);
+
+    raw
+}
+
+pub fn cluster(raw: &[u8]) -> u32 {
+    (u16_at(raw, 20) as u32) << 16 | u16_at(raw, 26) as u32
+}
+
+pub fn set_cluster(raw: &mut [u8], cluster: u32) {
+    raw[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+    raw[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+}
+
+/// Set the size and the modification time of a short entry
+pub fn set_modified(raw: &mut [u8], size: u32, now: u64) {
+    let (date, time) = from_unix(now);
+
+    raw[22..24].copy_from_slice(&time.to_le_bytes());
+    raw[24..26].copy_from_slice(&date.to_le_bytes());
+    raw[28..32].copy_from_slice(&size.to_le_bytes());
+}
+
+// FAT timestamps are local time, which is taken to be UTC. Dates count years from 1980 and times have a two
+// second resolution.
+
+fn to_unix(date: u16, time: u16) -> u64 {
+    if date == 0 {
+        return 0;
+    }
+
+    let datetime = crate::time::wall::DateTime {
+        year: 1980 + (date >> 9) as i64,
+        month: ((date >> 5) & 0xF).clamp(1, 12) as u8,
+        day: (date & 0x1F).max(1) as u8,
+        hour: (time >> 11) as u8,
+        minute: ((time >> 5) & 0x3F) as u8,
+        second: ((time & 0x1F) * 2) as u8,
+        nanosecond: 0,
+    };
+
+    datetime.unix_secs().max(0) as u64
+}
+
+/// Returns the date and the time
+fn from_unix(secs: u64) -> (u16, u16) {
+    let datetime = crate::time::wall::DateTime::from_unix_ns(secs as u128 * crate::time::NS_PER_SEC);
+
+    // Nothing before 1980 can be stored
+    if datetime.year < 1980 {
+        return (1 << 5 | 1, 0);
+    }
+
+    let date = ((datetime.year - 1980).min(127) as u16) << 9 | (datetime.month as u16) << 5 | datetime.day as u16;
+    let time = (datetime.hour as u16) << 11 | (datetime.minute as u16) << 5 | (datetime.second as u16 / 2);
+
+    (date, time)
+}
@@ -0,0 +1,910 @@
+// FAT12, FAT16 and FAT32, with long names.
+// Files are chains of clusters linked through the FAT, and directories are files of 32 byte entries, apart from the
+// fixed root directory of FAT12 and FAT16. Every copy of the FAT is kept in sync. Operations on a volume hold its
+// lock, and vnodes are shared by the offset of their short entry, so everyone sees the same size and clusters. A
+// renamed file's short entry moves, and its vnode moves with it.
+
+use alloc::{collections::BTreeMap, sync::{Arc, Weak}, vec::Vec};
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use spin::Mutex;
+
+use bpb::{FatType, Layout};
+use dir::{Entry, ENTRY_SIZE};
+use super::device::PartitionDevice;
+use super::{DirEntry, Filesystem, FsError, Metadata, Vnode, VnodeKind};
+
+mod bpb;
+mod dir;
+
+linkset::entry!(crate::fs::FILESYSTEMS, crate::fs::FsTypeEntry, crate::fs::FsTypeEntry {
+    name: "fat",
+    mount,
+});
+
+fn mount(source: Option<usize>) -> Result<Arc<dyn Filesystem>, FsError> {
+    Ok(FatFs::open(source.ok_or(FsError::InvalidArgument)?)?)
+}
+
+/// Whether partition `part` holds a FAT volume
+pub fn probe(part: usize) -> bool {
+    let mut sector = [0; 512];
+
+    PartitionDevice::new(part)
+        .and_then(|device| device.read(0, &mut sector))
+        .is_ok_and(|_| Layout::parse(&sector).is_some())
+}
+
+/// ID of the root directory, every other vnode's is the offset of its short entry in entries
+const ROOT_INO: u64 = 1;
+
+/// FSInfo signatures, and the offsets of the free cluster count and next free cluster hint
+const FS_INFO_LEAD_SIG: u32 = 0x41615252;
+const FS_INFO_FREE: u64 = 488;
+const FS_INFO_NEXT: u64 = 492;
+
+pub struct FatFs {
+    this: Weak<FatFs>,
+    device: PartitionDevice,
+    layout: Layout,
+    lock: Mutex<()>,
+    /// Where to start looking for a free cluster
+    next_free: AtomicU32,
+    /// Clusters were allocated or freed since FSInfo was last updated
+    dirty: AtomicBool,
+    /// Key: offset of the short entry
+    nodes: Mutex<BTreeMap<u64, Weak<FatNode>>>,
+}
+
+/// Where a directory's entries are
+#[derive(Clone, Copy)]
+enum DirLoc {
+    /// The fixed root directory of FAT12 and FAT16
+    Root,
+    Chain(u32),
+}
+
+impl FatFs {
+    pub fn open(part: usize) -> Result<Arc<Self>, FsError> {
+        let device = PartitionDevice::new(part)?;
+        let mut sector = [0; 512];
+
+        device.read(0, &mut sector)?;
+
+        let layout = Layout::parse(&sector).ok_or(FsError::UnknownFs)?;
+
+        if layout.end() > device.size() {
+            return Err(FsError::Corrupt);
+        }
+
+        if layout.fat_type == FatType::Fat32 && !(2..layout.cluster_end).contains(&layout.root_cluster) {
+            return Err(FsError::Corrupt);
+        }
+
+        crate::println!(
+            "FAT: partition {} is {:?} with {} clusters of {} bytes",
+            part,
+            layout.fat_type,
+            layout.cluster_end - 2,
+            layout.cluster_size
+        );
+
+        Ok(Arc::new_cyclic(|this| Self {
+            this: this.clone(),
+            device,
+            layout,
+            lock: Mutex::new(()),
+            next_free: AtomicU32::new(2),
+            dirty: AtomicBool::new(false),
+            nodes: Mutex::new(BTreeMap::new()),
+        }))
+    }
+
+    /// Offset of the FAT entry for `cluster` within a FAT
+    fn entry_offset(&self, cluster: u32) -> u64 {
+        let n = cluster as u64;
+
+        match self.layout.fat_type {
+            FatType::Fat12 => n + n / 2,
+            FatType::Fat16 => n * 2,
+            FatType::Fat32 => n * 4,
+        }
+    }
+
+    fn get(&self, cluster: u32) -> Result<u32, FsError> {
+        let offset = self.layout.fat_offset + self.entry_offset(cluster);
+
+        match self.layout.fat_type {
+            FatType::Fat12 | FatType::Fat16 => {
+                let mut bytes = [0; 2];
+                self.device.read(offset, &mut bytes)?;
+                let value = u16::from_le_bytes(bytes) as u32;
+
+                // FAT12 entries are a byte and a half, odd ones take the top of their first byte
+                Ok(match self.layout.fat_type {
+                    FatType::Fat12 if cluster & 1 == 1 => value >> 4,
+                    FatType::Fat12 => value & 0xfff,
+                    _ => value,
+                })
+            },
+            FatType::Fat32 => {
+                let mut bytes = [0; 4];
+                self.device.read(offset, &mut bytes)?;
+
+                Ok(u32::from_le_bytes(bytes) & 0x0fff_ffff)
+            },
+        }
+    }
+
+    /// Set the FAT entry for `cluster` in every FAT
+    fn set(&self, cluster: u32, value: u32) -> Result<(), FsError> {
+        for copy in 0..self.layout.fats {
+            let offset = self.layout.fat_offset + copy * self.layout.fat_size + self.entry_offset(cluster);
+
+            match self.layout.fat_type {
+                FatType::Fat12 => {
+                    let mut bytes = [0; 2];
+                    self.device.read(offset, &mut bytes)?;
+                    let old = u16::from_le_bytes(bytes);
+
+                    let new = if cluster & 1 == 1 {
+                        (old & 0x000f) | (value as u16) << 4
+                    } else {
+                        (old & 0xf000) | (value as u16 & 0x0fff)
+                    };
+
+                    self.device.write(offset, &new.to_le_bytes())?;
+                },
+                FatType::Fat16 => self.device.write(offset, &(value as u16).to_le_bytes())?,
+                FatType::Fat32 => {
+                    // The top four bits are reserved and kept as they are
+                    let mut bytes = [0; 4];
+                    self.device.read(offset, &mut bytes)?;
+                    let new = (u32::from_le_bytes(bytes) & 0xf000_0000) | (value & 0x0fff_ffff);
+
+                    self.device.write(offset, &new.to_le_bytes())?;
+                },
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The cluster after `cluster` in its chain, `None` at the end
+    fn next(&self, cluster: u32) -> Result<Option<u32>, FsError> {
+        match self.get(cluster)? {
+            value if value >= self.layout.eoc_min() => Ok(None),
+            value if value < 2 || value >= self.layout.cluster_end => Err(FsError::Corrupt),
+            value => Ok(Some(value)),
+        }
+    }
+
+    /// Every cluster in the chain starting at `first`, cluster 0 is an empty chain
+    fn chain(&self, first: u32) -> Result<Vec<u32>, FsError> {
+        let mut chain = Vec::new();
+        let mut cluster = (first != 0).then_some(first);
+
+        while let Some(current) = cluster {
+            // A chain longer than the volume has clusters loops
+            if !(2..self.layout.cluster_end).contains(&current) || chain.len() >= self.layout.cluster_end as usize {
+                return Err(FsError::Corrupt);
+            }
+
+            chain.push(current);
+            cluster = self.next(current)?;
+        }
+
+        Ok(chain)
+    }
+
+    /// Allocate a zeroed cluster and link it after `prev`
+    fn alloc(&self, prev: Option<u32>) -> Result<u32, FsError> {
+        let end = self.layout.cluster_end;
+        let start = self.next_free.load(Ordering::Relaxed).clamp(2, end - 1);
+
+        for cluster in (start..end).chain(2..start) {
+            if self.get(cluster)? != 0 {
+                continue;
+            }
+
+            self.device.zero(self.layout.cluster_offset(cluster), self.layout.cluster_size)?;
+            self.set(cluster, self.layout.eoc())?;
+
+            if let Some(prev) = prev {
+                self.set(prev, cluster)?;
+            }
+
+            self.next_free.store(cluster + 1, Ordering::Relaxed);
+            self.dirty.store(true, Ordering::Relaxed);
+
+            return Ok(cluster);
+        }
+
+        Err(FsError::NoSpace)
+    }
+
+    fn free_chain(&self, first: u32) -> Result<(), FsError> {
+        for cluster in self.chain(first)? {
+            self.set(cluster, 0)?;
+        }
+
+        self.dirty.store(true, Ordering::Relaxed);
+
+        Ok(())
+    }
+
+    /// The vnode for a directory of this volume
+    fn find(&self, vnode: &Arc<dyn Vnode>) -> Result<Arc<FatNode>, FsError> {
+        let ino = vnode.metadata()?.ino;
+
+        if ino == ROOT_INO {
+            return Ok(self.root_node());
+        }
+
+        self.nodes.lock().get(&(ino * ENTRY_SIZE as u64)).and_then(Weak::upgrade).ok_or(FsError::CrossDevice)
+    }
+
+    /// Offset of the `..` entry of the directory starting at `cluster`
+    fn dotdot(&self, cluster: u32) -> Result<u64, FsError> {
+        let offset = self.layout.cluster_offset(cluster);
+        let mut data = alloc::vec![0; self.layout.cluster_size as usize];
+        self.device.read(offset, &mut data)?;
+
+        // Usually the second entry, but some tools give `.` and `..` long names too
+        data.chunks_exact(ENTRY_SIZE)
+            .position(|raw| raw[..11] == *b"..         " && raw[11] & 0x3F != dir::ATTR_LONG_NAME)
+            .map(|slot| offset + (slot * ENTRY_SIZE) as u64)
+            .ok_or(FsError::Corrupt)
+    }
+
+    /// The first cluster of the parent of the directory starting at `cluster`, 0 is the root
+    fn parent(&self, cluster: u32) -> Result<u32, FsError> {
+        let mut raw = [0; ENTRY_SIZE];
+        self.device.read(self.dotdot(cluster)?, &mut raw)?;
+
+        Ok(dir::cluster(&raw))
+    }
+
+    fn set_parent(&self, cluster: u32, parent: u32) -> Result<(), FsError> {
+        let offset = self.dotdot(cluster)?;
+        let mut raw = [0; ENTRY_SIZE];
+        self.device.read(offset, &mut raw)?;
+
+        dir::set_cluster(&mut raw, parent);
+
+        self.device.write(offset, &raw)
+    }
+
+    /// Delete `entry` and its long name entries from a directory read by `read_dir`, and free its clusters
+    fn remove(&self, offsets: &[u64], entry: &Entry) -> Result<(), FsError> {
+        for offset in &offsets[entry.first_slot..=entry.slot] {
+            self.device.write(*offset, &[dir::DELETED])?;
+        }
+
+        if entry.cluster != 0 {
+            self.free_chain(entry.cluster)?;
+        }
+
+        if let Some(node) = self.nodes.lock().remove(&offsets[entry.slot]).and_then(|node| node.upgrade()) {
+            node.state.lock().removed = true;
+        }
+
+        Ok(())
+    }
+
+    /// The offset of every slot of a directory, and its raw contents
+    fn read_dir(&self, loc: DirLoc) -> Result<(Vec<u64>, Vec<u8>), FsError> {
+        let extents: Vec<(u64, u64)> = match loc {
+            DirLoc::Root => alloc::vec![(self.layout.root_offset, self.layout.root_size)],
+            DirLoc::Chain(first) => self.chain(first)?
+                .into_iter()
+                .map(|cluster| (self.layout.cluster_offset(cluster), self.layout.cluster_size))
+                .collect(),
+        };
+
+        let mut offsets = Vec::new();
+        let mut data = Vec::new();
+
+        for (offset, len) in extents {
+            let start = data.len();
+            data.resize(start + len as usize, 0);
+
+            self.device.read(offset, &mut data[start..])?;
+            offsets.extend((0..len).step_by(ENTRY_SIZE).map(|within| offset + within));
+        }
+
+        Ok((offsets, data))
+    }
+
+    /// The shared vnode for the short entry at `offset`
+    fn node(&self, offset: u64, state: NodeState) -> Arc<FatNode> {
+        let mut nodes = self.nodes.lock();
+
+        if let Some(node) = nodes.get(&offset).and_then(Weak::upgrade) {
+            return node;
+        }
+
+        nodes.retain(|_, node| node.strong_count() != 0);
+
+        let node = Arc::new(FatNode {
+            fs: self.this.upgrade().unwrap(),
+            entry: AtomicU64::new(offset),
+            state: Mutex::new(state),
+        });
+
+        nodes.insert(offset, Arc::downgrade(&node));
+
+        node
+    }
+
+    fn root_node(&self) -> Arc<FatNode> {
+        Arc::new(FatNode {
+            fs: self.this.upgrade().unwrap(),
+            entry: AtomicU64::new(0),
+            state: Mutex::new(NodeState {
+                cluster: self.layout.root_cluster,
+                size: 0,
+                attr: dir::ATTR_DIRECTORY,
+                mtime: 0,
+                ctime: 0,
+                atime: 0,
+                removed: false,
+            }),
+        })
+    }
+}
+
+impl Filesystem for FatFs {
+    fn name(&self) -> &'static str {
+        "fat"
+    }
+
+    fn root(&self) -> Arc<dyn Vnode> {
+        self.root_node()
+    }
+
+    fn sync(&self) -> Result<(), FsError> {
+        let _guard = self.lock.lock();
+
+        // The free count isn't tracked, so mark it unknown rather than leave it wrong
+        if let Some(fs_info) = self.layout.fs_info {
+            let mut sig = [0; 4];
+            self.device.read(fs_info, &mut sig)?;
+
+            if self.dirty.swap(false, Ordering::Relaxed) && u32::from_le_bytes(sig) == FS_INFO_LEAD_SIG {
+                self.device.write(fs_info + FS_INFO_FREE, &u32::MAX.to_le_bytes())?;
+                self.device.write(fs_info + FS_INFO_NEXT, &self.next_free.load(Ordering::Relaxed).to_le_bytes())?;
+            }
+        }
+
+        self.device.sync()
+    }
+}
+
+struct NodeState {
+    cluster: u32,
+    size: u32,
+    attr: u8,
+    mtime: u64,
+    ctime: u64,
+    atime: u64,
+    /// Unlinked, nothing more can be done with it
+    removed: bool,
+}
+
+impl From<&Entry> for NodeState {
+    fn from(entry: &Entry) -> Self {
+        Self {
+            cluster: entry.cluster,
+            size: entry.size,
+            attr: entry.attr,
+            mtime: entry.mtime,
+            ctime: entry.ctime,
+            atime: entry.atime,
+            removed: false,
+        }
+    }
+}
+
+impl NodeState {
+    fn is_dir(&self) -> bool {
+        self.attr & dir::ATTR_DIRECTORY != 0
+    }
+}
+
+pub struct FatNode {
+    fs: Arc<FatFs>,
+    /// Offset of the short entry, 0 for the root directory which has none. Changes when it's renamed
+    entry: AtomicU64,
+    state: Mutex<NodeState>,
+}
+
+impl FatNode {
+    /// Offset of the short entry, `None` for the root directory
+    fn entry(&self) -> Option<u64> {
+        Some(self.entry.load(Ordering::Relaxed)).filter(|offset| *offset != 0)
+    }
+
+    fn ino(&self) -> u64 {
+        self.entry().map_or(ROOT_INO, |offset| offset / ENTRY_SIZE as u64)
+    }
+
+    /// Fails if the vnode was unlinked
+    fn check(&self, state: &NodeState) -> Result<(), FsError> {
+        if state.removed {
+            return Err(FsError::NotFound);
+        }
+
+        Ok(())
+    }
+
+    fn check_dir(&self, state: &NodeState) -> Result<DirLoc, FsError> {
+        self.check(state)?;
+
+        if !state.is_dir() {
+            return Err(FsError::NotDirectory);
+        }
+
+        if self.entry().is_none() && self.fs.layout.fat_type != FatType::Fat32 {
+            Ok(DirLoc::Root)
+        } else {
+            Ok(DirLoc::Chain(state.cluster))
+        }
+    }
+
+    fn check_file(&self, state: &NodeState) -> Result<(), FsError> {
+        self.check(state)?;
+
+        if state.is_dir() {
+            return Err(FsError::IsDirectory);
+        }
+
+        Ok(())
+    }
+
+    /// Write the size, first cluster and modification time back to the short entry
+    fn update_entry(&self, state: &NodeState) -> Result<(), FsError> {
+        let Some(offset) = self.entry() else {
+            return Ok(());
+        };
+
+        let mut raw = [0; ENTRY_SIZE];
+        self.fs.device.read(offset, &mut raw)?;
+
+        dir::set_cluster(&mut raw, state.cluster);
+        dir::set_modified(&mut raw, if state.is_dir() { 0 } else { state.size }, state.mtime);
+
+        self.fs.device.write(offset, &raw)
+    }
+
+    /// Zero the rest of the last cluster after the end of the file, so growing the file doesn't expose old data
+    fn zero_tail(&self, state: &NodeState) -> Result<(), FsError> {
+        let cluster_size = self.fs.layout.cluster_size;
+        let size = state.size as u64;
+
+        if size % cluster_size == 0 {
+            return Ok(());
+        }
+
+        let chain = self.fs.chain(state.cluster)?;
+        let cluster = *chain.get((size / cluster_size) as usize).ok_or(FsError::Corrupt)?;
+
+        self.fs.device.zero(self.fs.layout.cluster_offset(cluster) + size % cluster_size, cluster_size - size % cluster_size)
+    }
+
+    /// Make the chain long enough to hold `bytes` bytes, returns it
+    fn ensure_clusters(&self, state: &mut NodeState, bytes: u64) -> Result<Vec<u32>, FsError> {
+        let mut chain = self.fs.chain(state.cluster)?;
+        let needed = bytes.div_ceil(self.fs.layout.cluster_size) as usize;
+
+        while chain.len() < needed {
+            let cluster = self.fs.alloc(chain.last().copied())?;
+
+            if chain.is_empty() {
+                state.cluster = cluster;
+            }
+
+            chain.push(cluster);
+        }
+
+        Ok(chain)
+    }
+
+    /// Copy between `buf` and the file's clusters starting at `offset`, which must all be allocated
+    fn transfer(&self, chain: &[u32], offset: u64, len: usize, mut op: impl FnMut(u64, core::ops::Range<usize>) -> Result<(), FsError>) -> Result<(), FsError> {
+        let cluster_size = self.fs.layout.cluster_size;
+        let mut done = 0;
+
+        while done < len {
+            let pos = offset + done as u64;
+            let cluster = *chain.get((pos / cluster_size) as usize).ok_or(FsError::Corrupt)?;
+            let within = pos % cluster_size;
+            let chunk = ((cluster_size - within) as usize).min(len - done);
+
+            op(self.fs.layout.cluster_offset(cluster) + within, done..done + chunk)?;
+            done += chunk;
+        }
+
+        Ok(())
+    }
+
+    /// Write into the file, growing it as needed
+    fn write_data(&self, state: &mut NodeState, offset: u64, data: &[u8]) -> Result<(), FsError> {
+        if offset > state.size as u64 {
+            self.zero_tail(state)?;
+        }
+
+        let chain = self.ensure_clusters(state, offset + data.len() as u64)?;
+
+        self.transfer(&chain, offset, data.len(), |disk, range| self.fs.device.write(disk, &data[range]))
+    }
+
+    /// Add `slots` to the directory in a run of free slots, growing it if there isn't one.
+    /// Returns the offset of the last slot
+    fn add_slots(&self, state: &mut NodeState, slots: &[[u8; ENTRY_SIZE]]) -> Result<u64, FsError> {
+        loop {
+            let loc = self.check_dir(state)?;
+            let (offsets, data) = self.fs.read_dir(loc)?;
+            let mut run = 0;
+
+            for (i, raw) in data.chunks_exact(ENTRY_SIZE).enumerate() {
+                if raw[0] != 0x00 && raw[0] != dir::DELETED {
+                    run = 0;
+                    continue;
+                }
+
+                run += 1;
+
+                if run == slots.len() {
+                    let first = i + 1 - run;
+
+                    for (offset, slot) in offsets[first..=i].iter().zip(slots) {
+                        self.fs.device.write(*offset, slot)?;
+                    }
+
+                    return Ok(offsets[i]);
+                }
+            }
+
+            match loc {
+                DirLoc::Root => return Err(FsError::NoSpace),
+                DirLoc::Chain(first) => {
+                    let last = self.fs.chain(first)?.last().copied();
+                    self.fs.alloc(last)?;
+                },
+            }
+        }
+    }
+}
+
+impl Vnode for FatNode {
+    fn metadata(&self) -> Result<Metadata, FsError> {
+        let _guard = self.fs.lock.lock();
+        let state = self.state.lock();
+
+        self.check(&state)?;
+
+        // Directories have no size of their own, they're as big as their clusters
+        let (kind, size, mode) = if state.is_dir() {
+            let size = match self.check_dir(&state)? {
+                DirLoc::Root => self.fs.layout.root_size,
+                DirLoc::Chain(first) => self.fs.chain(first)?.len() as u64 * self.fs.layout.cluster_size,
+            };
+
+            (VnodeKind::Directory, size, 0o755)
+        } else if state.attr & dir::ATTR_READ_ONLY != 0 {
+            (VnodeKind::File, state.size as u64, 0o444)
+        } else {
+            (VnodeKind::File, state.size as u64, 0o644)
+        };
+
+        Ok(Metadata {
+            kind,
+            ino: self.ino(),
+            size,
+            mode,
+            uid: 0,
+            gid: 0,
+            links: 1,
+            atime: state.atime,
+            mtime: state.mtime,
+            ctime: state.ctime,
+        })
+    }
+
+    fn read(&self, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        let _guard = self.fs.lock.lock();
+        let state = self.state.lock();
+
+        self.check_file(&state)?;
+
+        let size = state.size as u64;
+
+        if offset >= size {
+            return Ok(0);
+        }
+
+        let len = (size - offset).min(buf.len() as u64) as usize;
+        let chain = self.fs.chain(state.cluster)?;
+
+        self.transfer(&chain, offset, len, |disk, range| self.fs.device.read(disk, &mut buf[range]))?;
+
+        Ok(len)
+    }
+
+    fn write(&self, offset: u64, data: &[u8]) -> Result<usize, FsError> {
+        let _guard = self.fs.lock.lock();
+        let mut state = self.state.lock();
+
+        self.check_file(&state)?;
+
+        if state.attr & dir::ATTR_READ_ONLY != 0 {
+            return Err(FsError::AccessDenied);
+        }
+
+        // Sizes are 32 bits
+        let end = match offset.checked_add(data.len() as u64) {
+            Some(end) if end <= u32::MAX as u64 => end,
+            _ => return Err(FsError::NoSpace),
+        };
+
+        if data.is_empty() {
+            return Ok(0);
+        }
+
+        let result = self.write_data(&mut state, offset, data);
+
+        if result.is_ok() {
+            state.size = state.size.max(end as u32);
+            state.mtime = super::now();
+        }
+
+        // The first cluster may have changed even if the write failed
+        self.update_entry(&state)?;
+        result.map(|_| data.len())
+    }
+
+    fn truncate(&self, size: u64) -> Result<(), FsError> {
+        let _guard = self.fs.lock.lock();
+        let mut state = self.state.lock();
+
+        self.check_file(&state)?;
+
+        if state.attr & dir::ATTR_READ_ONLY != 0 {
+            return Err(FsError::AccessDenied);
+        }
+
+        if size > u32::MAX as u64 {
+            return Err(FsError::NoSpace);
+        }
+
+        if size > state.size as u64 {
+            self.zero_tail(&state)?;
+            self.ensure_clusters(&mut state, size)?;
+        } else {
+            let keep = size.div_ceil(self.fs.layout.cluster_size) as usize;
+            let chain = self.fs.chain(state.cluster)?;
+
+            if keep == 0 && !chain.is_empty() {
+                self.fs.free_chain(state.cluster)?;
+                state.cluster = 0;
+            } else if keep < chain.len() {
+                self.fs.set(chain[keep - 1], self.fs.layout.eoc())?;
+                self.fs.free_chain(chain[keep])?;
+            }
+        }
+
+        state.size = size as u32;
+        state.mtime = super::now();
+
+        self.update_entry(&state)
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Vnode>, FsError> {
+        let _guard = self.fs.lock.lock();
+        let state = self.state.lock();
+
+        let (offsets, data) = self.fs.read_dir(self.check_dir(&state)?)?;
+        let entry = dir::parse(&data).into_iter().find(|entry| entry.matches(name)).ok_or(FsError::NotFound)?;
+
+        Ok(self.fs.node(offsets[entry.slot], NodeState::from(&entry)))
+    }
+
+    fn readdir(&self, index: usize) -> Result<Option<DirEntry>, FsError> {
+        let _guard = self.fs.lock.lock();
+        let state = self.state.lock();
+
+        let (offsets, data) = self.fs.read_dir(self.check_dir(&state)?)?;
+
+        Ok(dir::parse(&data).into_iter().nth(index).map(|entry| DirEntry {
+            ino: offsets[entry.slot] / ENTRY_SIZE as u64,
+            kind: if entry.is_dir() { VnodeKind::Directory } else { VnodeKind::File },
+            name: entry.name,
+        }))
+    }
+
+    fn create(&self, name: &str, kind: VnodeKind) -> Result<Arc<dyn Vnode>, FsError> {
+        let _guard = self.fs.lock.lock();
+        let mut state = self.state.lock();
+
+        let loc = self.check_dir(&state)?;
+
+        if kind != VnodeKind::File && kind != VnodeKind::Directory {
+            return Err(FsError::Unsupported);
+        }
+
+        dir::check_long_name(name)?;
+
+        let (_, data) = self.fs.read_dir(loc)?;
+        let entries = dir::parse(&data);
+
+        if entries.iter().any(|entry| entry.matches(name)) {
+            return Err(FsError::Exists);
+        }
+
+        let (short, case, mut slots) = dir::names(name, |short| entries.iter().any(|entry| entry.short == *short))?;
+
+        let now = super::now();
+
+        let (attr, cluster) = if kind == VnodeKind::Directory {
+            let cluster = self.fs.alloc(None)?;
+            let parent = if self.entry().is_none() { 0 } else { state.cluster };
+            let offset = self.fs.layout.cluster_offset(cluster);
+
+            let dot = dir::short_entry(b".          ", 0, dir::ATTR_DIRECTORY, cluster, now);
+            let dotdot = dir::short_entry(b"..         ", 0, dir::ATTR_DIRECTORY, parent, now);
+
+            self.fs.device.write(offset, &dot)?;
+            self.fs.device.write(offset + ENTRY_SIZE as u64, &dotdot)?;
+
+            (dir::ATTR_DIRECTORY, cluster)
+        } else {
+            (dir::ATTR_ARCHIVE, 0)
+        };
+
+        slots.push(dir::short_entry(&short, case, attr, cluster, now));
+
+        let offset = match self.add_slots(&mut state, &slots) {
+            Ok(offset) => offset,
+            Err(err) => {
+                if cluster != 0 {
+                    self.fs.free_chain(cluster)?;
+                }
+
+                return Err(err);
+            },
+        };
+
+        Ok(self.fs.node(offset, NodeState {
+            cluster,
+            size: 0,
+            attr,
+            mtime: now,
+            ctime: now,
+            atime: now,
+            removed: false,
+        }))
+    }
+
+    fn unlink(&self, name: &str) -> Result<(), FsError> {
+        let _guard = self.fs.lock.lock();
+        let state = self.state.lock();
+
+        let (offsets, data) = self.fs.read_dir(self.check_dir(&state)?)?;
+        let entry = dir::parse(&data).into_iter().find(|entry| entry.matches(name)).ok_or(FsError::NotFound)?;
+
+        if entry.is_dir() {
+            let (_, contents) = self.fs.read_dir(DirLoc::Chain(entry.cluster))?;
+
+            if !dir::parse(&contents).is_empty() {
+                return Err(FsError::NotEmpty);
+            }
+        }
+
+        self.fs.remove(&offsets, &entry)
+    }
+
+    fn rename(&self, from: &str, dir: &Arc<dyn Vnode>, to: &str) -> Result<(), FsError> {
+        // Before the lock, finding it takes it too
+        let dst = self.fs.find(dir)?;
+
+        let _guard = self.fs.lock.lock();
+
+        let (offsets, data) = self.fs.read_dir(self.check_dir(&self.state.lock())?)?;
+        let entry = dir::parse(&data).into_iter().find(|entry| entry.matches(from)).ok_or(FsError::NotFound)?;
+
+        let (dst_loc, dst_cluster) = {
+            let state = dst.state.lock();
+            (dst.check_dir(&state)?, state.cluster)
+        };
+
+        let moved = dst.ino() != self.ino();
+
+        // A directory can't go anywhere beneath itself
+        if entry.is_dir() && moved {
+            let mut ancestor = dst.entry().map(|_| dst_cluster);
+            let mut depth = 0;
+
+            while let Some(cluster) = ancestor {
+                if cluster == entry.cluster {
+                    return Err(FsError::InvalidArgument);
+                }
+
+                // More ancestors than the volume has clusters loops
+                depth += 1;
+
+                if depth >= self.fs.layout.cluster_end {
+                    return Err(FsError::Corrupt);
+                }
+
+                // Some tools point `..` at the FAT32 root's cluster rather than 0
+                let parent = self.fs.parent(cluster)?;
+                ancestor = (parent != 0 && parent != self.fs.layout.root_cluster).then_some(parent);
+            }
+        }
+
+        dir::check_long_name(to)?;
+
+        let (dst_offsets, dst_data) = self.fs.read_dir(dst_loc)?;
+        let mut entries = dir::parse(&dst_data);
+
+        if let Some(index) = entries.iter().position(|existing| existing.matches(to)) {
+            let existing = entries.remove(index);
+
+            if !moved && existing.slot == entry.slot {
+                // The same file, only the case of its name can change
+                if existing.name == to {
+                    return Ok(());
+                }
+            } else {
+                // Directories only replace empty directories, and files only files
+                match (entry.is_dir(), existing.is_dir()) {
+                    (true, false) => return Err(FsError::NotDirectory),
+                    (false, true) => return Err(FsError::IsDirectory),
+                    (true, true) => {
+                        let (_, contents) = self.fs.read_dir(DirLoc::Chain(existing.cluster))?;
+
+                        if !dir::parse(&contents).is_empty() {
+                            return Err(FsError::NotEmpty);
+                        }
+                    },
+                    _ => {},
+                }
+
+                self.fs.remove(&dst_offsets, &existing)?;
+            }
+        }
+
+        let (short, case, mut slots) = dir::names(to, |short| entries.iter().any(|entry| entry.short == *short))?;
+
+        // Everything but the name stays as it was
+        let mut raw = [0; ENTRY_SIZE];
+        self.fs.device.read(offsets[entry.slot], &mut raw)?;
+        raw[..11].copy_from_slice(&short);
+        raw[12] = case;
+        slots.push(raw);
+
+        // The old entries are only free once the new ones are written, so they aren't reused
+        let offset = dst.add_slots(&mut dst.state.lock(), &slots)?;
+
+        for old in &offsets[entry.first_slot..=entry.slot] {
+            self.fs.device.write(*old, &[dir::DELETED])?;
+        }
+
+        if entry.is_dir() && moved {
+            self.fs.set_parent(entry.cluster, if dst.entry().is_none() { 0 } else { dst_cluster })?;
+        }
+
+        let mut nodes = self.fs.nodes.lock();
+
+        if let Some(node) = nodes.remove(&offsets[entry.slot]).and_then(|node| node.upgrade()) {
+            node.entry.store(offset, Ordering::Relaxed);
+            nodes.insert(offset, Arc::downgrade(&node));
+        }
+
+        Ok(())
+    }
+
+    fn sync(&self) -> Result<(), FsError> {
+        self.fs.sync()
+    }
+}
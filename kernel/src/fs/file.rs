@@ -0,0 +1,136 @@
+// Open files.
+// An open file is a vnode with the flags it was opened with and an offset. For directories the offset counts
+// entries rather than bytes.
+
+use alloc::{sync::Arc, vec::Vec};
+use spin::Mutex;
+
+use super::{DirEntry, FsError, Metadata, Vnode};
+
+bitflags::bitflags! {
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub struct OpenFlags : u32 {
+        const READ      = 1 << 0;
+        const WRITE     = 1 << 1;
+        /// Create the file if it doesn't exist
+        const CREATE    = 1 << 2;
+        /// With `CREATE`, fail if the file exists
+        const EXCLUSIVE = 1 << 3;
+        /// Cut the file to zero length
+        const TRUNCATE  = 1 << 4;
+        /// Every write goes to the end of the file
+        const APPEND    = 1 << 5;
+        /// Fail unless it's a directory
+        const DIRECTORY = 1 << 6;
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SeekFrom {
+    Start(u64),
+    Current(i64),
+    End(i64),
+}
+
+pub struct OpenFile {
+    vnode: Arc<dyn Vnode>,
+    flags: OpenFlags,
+    offset: Mutex<u64>,
+}
+
+impl OpenFile {
+    pub fn new(vnode: Arc<dyn Vnode>, flags: OpenFlags) -> Self {
+        Self {
+            vnode,
+            flags,
+            offset: Mutex::new(0),
+        }
+    }
+
+    pub fn vnode(&self) -> &Arc<dyn Vnode> {
+        &self.vnode
+    }
+
+    pub fn flags(&self) -> OpenFlags {
+        self.flags
+    }
+
+    pub fn offset(&self) -> u64 {
+        *self.offset.lock()
+    }
+
+    pub fn metadata(&self) -> Result<Metadata, FsError> {
+        self.vnode.metadata()
+    }
+
+    /// Read from the offset, returns the number of bytes read, 0 at the end of the file
+    pub fn read(&self, buf: &mut [u8]) -> Result<usize, FsError> {
+        if !self.flags.contains(OpenFlags::READ) {
+            return Err(FsError::AccessDenied);
+        }
+
+        let mut offset = self.offset.lock();
+        let read = self.vnode.read(*offset, buf)?;
+        *offset += read as u64;
+
+        Ok(read)
+    }
+
+    /// Write at the offset, or the end of the file if opened with `APPEND`, returns the number of bytes written
+    pub fn write(&self, data: &[u8]) -> Result<usize, FsError> {
+        if !self.flags.contains(OpenFlags::WRITE) {
+            return Err(FsError::AccessDenied);
+        }
+
+        let mut offset = self.offset.lock();
+
+        if self.flags.contains(OpenFlags::APPEND) {
+            *offset = self.vnode.metadata()?.size;
+        }
+
+        let written = self.vnode.write(*offset, data)?;
+        *offset += written as u64;
+
+        Ok(written)
+    }
+
+    /// Read everything from the offset to the end of the file
+    pub fn read_to_end(&self) -> Result<Vec<u8>, FsError> {
+        let mut data = Vec::new();
+        let mut chunk = alloc::vec![0; 4096];
+
+        loop {
+            match self.read(&mut chunk)? {
+                0 => return Ok(data),
+                read => data.extend_from_slice(&chunk[..read]),
+            }
+        }
+    }
+
+    /// Move the offset, returns the new offset
+    pub fn seek(&self, pos: SeekFrom) -> Result<u64, FsError> {
+        let mut offset = self.offset.lock();
+
+        let new = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::Current(delta) => offset.checked_add_signed(delta),
+            SeekFrom::End(delta) => self.vnode.metadata()?.size.checked_add_signed(delta),
+        };
+
+        *offset = new.ok_or(FsError::InvalidArgument)?;
+
+        Ok(*offset)
+    }
+
+    /// The next entry of a directory, `None` after the last one
+    pub fn read_dir(&self) -> Result<Option<DirEntry>, FsError> {
+        let mut offset = self.offset.lock();
+        let entry = self.vnode.readdir(*offset as usize)?;
+
+        if entry.is_some() {
+            *offset += 1;
+        }
+
+        Ok(entry)
+    }
+}
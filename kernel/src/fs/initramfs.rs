@@ -0,0 +1,252 @@
+// Initial RAM filesystem.
+// Archives passed as Limine modules are unpacked into the root filesystem at boot. Both newc cpio archives and
+// ustar tar archives are understood, symbolic links and device nodes are skipped.
+
+use alloc::{string::String, vec::Vec};
+
+use super::{FsError, VnodeKind};
+use crate::println;
+
+enum Kind<'a> {
+    File(&'a [u8]),
+    Directory,
+    /// A hard link to an earlier entry
+    Link(String),
+    Other,
+}
+
+struct Entry<'a> {
+    /// Relative to the root of the archive
+    path: String,
+    kind: Kind<'a>,
+    mode: u16,
+    uid: u32,
+    gid: u32,
+}
+
+/// Unpack every module that's an archive into `/`
+pub fn load() {
+    let Some(response) = crate::MODULES.response() else {
+        return;
+    };
+
+    for (i, module) in response.modules().iter().enumerate() {
+        let data = module.data();
+
+        let entries = if data.starts_with(b"070701") || data.starts_with(b"070702") {
+            parse_cpio(data)
+        } else if data.get(257..262) == Some(&b"ustar"[..]) {
+            parse_tar(data)
+        } else {
+            println!("initramfs: module {} isn't a cpio or tar archive, skipping", i);
+            continue;
+        };
+
+        match entries {
+            Ok(entries) => {
+                println!("initramfs: unpacking {} entries from module {}", entries.len(), i);
+
+                for entry in entries {
+                    if let Err(err) = unpack(&entry) {
+                        println!("initramfs: failed to unpack {}: {:?}", entry.path, err);
+                    }
+                }
+            },
+            Err(err) => println!("initramfs: module {} is damaged: {:?}", i, err),
+        }
+    }
+}
+
+fn unpack(entry: &Entry) -> Result<(), FsError> {
+    let path = super::path::normalize(&(String::from("/") + &entry.path))?;
+
+    if path == "/" {
+        return Ok(());
+    }
+
+    // Archives don't have to list parent directories before their contents
+    if let Some((parent, _)) = path.rsplit_once('/') {
+        if !parent.is_empty() {
+            super::create_dir_all(parent)?;
+        }
+    }
+
+    let vnode = match &entry.kind {
+        Kind::File(data) => {
+            super::write(&path, data)?;
+            super::lookup(&path)?
+        },
+        Kind::Directory => match super::create(&path, VnodeKind::Directory) {
+            Ok(vnode) => vnode,
+            Err(FsError::Exists) => super::lookup(&path)?,
+            Err(err) => return Err(err),
+        },
+        Kind::Link(target) => {
+            let target = super::path::normalize(&(String::from("/") + target))?;
+            return super::link(&target, &path);
+        },
+        Kind::Other => {
+            println!("initramfs: {} isn't a file or directory, skipping", path);
+            return Ok(());
+        },
+    };
+
+    // Not every filesystem can store these
+    for result in [vnode.set_mode(entry.mode), vnode.set_owner(entry.uid, entry.gid)] {
+        match result {
+            Ok(()) | Err(FsError::Unsupported) => {},
+            Err(err) => return Err(err),
+        }
+    }
+
+    Ok(())
+}
+
+/// The type bits of a Unix mode
+const S_IFMT: u32 = 0o170000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+
+/// Parse a newc cpio archive, where every field is 8 hex digits and everything is padded to 4 bytes
+fn parse_cpio(data: &[u8]) -> Result<Vec<Entry<'_>>, FsError> {
+    const HEADER: usize = 110;
+
+    let field = |header: &[u8], index: usize| -> Result<u32, FsError> {
+        let digits = core::str::from_utf8(&header[6 + index * 8..14 + index * 8]).map_err(|_| FsError::Corrupt)?;
+        u32::from_str_radix(digits, 16).map_err(|_| FsError::Corrupt)
+    };
+
+    let mut entries = Vec::new();
+    // Hard links share an inode number and only the last of them has the data, the others wait for it
+    let mut pending: Vec<(u32, String)> = Vec::new();
+    let mut offset = 0;
+
+    loop {
+        let header = data.get(offset..offset + HEADER).ok_or(FsError::Corrupt)?;
+
+        if !header.starts_with(b"07070") {
+            return Err(FsError::Corrupt);
+        }
+
+        let ino = field(header, 0)?;
+        let mode = field(header, 1)?;
+        let nlink = field(header, 4)?;
+        let size = field(header, 6)? as usize;
+        let name_size = field(header, 11)? as usize;
+
+        let name_start = offset + HEADER;
+        let name = data.get(name_start..name_start + name_size.saturating_sub(1)).ok_or(FsError::Corrupt)?;
+        let name = String::from(core::str::from_utf8(name).map_err(|_| FsError::Corrupt)?);
+
+        let data_start = (name_start + name_size).next_multiple_of(4);
+        let contents = data.get(data_start..data_start + size).ok_or(FsError::Corrupt)?;
+        offset = (data_start + size).next_multiple_of(4);
+
+        if name == "TRAILER!!!" {
+            break;
+        }
+
+        if mode & S_IFMT == S_IFREG && nlink > 1 && size == 0 {
+            pending.push((ino, name));
+            continue;
+        }
+
+        let kind = match mode & S_IFMT {
+            S_IFDIR => Kind::Directory,
+            S_IFREG => Kind::File(contents),
+            _ => Kind::Other,
+        };
+
+        entries.push(Entry {
+            path: name.clone(),
+            kind,
+            mode: (mode & 0o7777) as u16,
+            uid: field(header, 2)?,
+            gid: field(header, 3)?,
+        });
+
+        if mode & S_IFMT == S_IFREG && nlink > 1 {
+            for (_, link) in pending.iter().filter(|(other, _)| *other == ino) {
+                entries.push(Entry {
+                    path: link.clone(),
+                    kind: Kind::Link(name.clone()),
+                    mode: 0,
+                    uid: 0,
+                    gid: 0,
+                });
+            }
+
+            pending.retain(|(other, _)| *other != ino);
+        }
+    }
+
+    // Links to an empty file never got their data
+    for (_, path) in pending {
+        entries.push(Entry {
+            path,
+            kind: Kind::File(&[]),
+            mode: 0o644,
+            uid: 0,
+            gid: 0,
+        });
+    }
+
+    Ok(entries)
+}
+
+/// Parse a ustar archive, 512 byte headers with octal fields, each followed by the data padded to 512 bytes
+fn parse_tar(data: &[u8]) -> Result<Vec<Entry<'_>>, FsError> {
+    const BLOCK: usize = 512;
+
+    let octal = |bytes: &[u8]| -> Result<u32, FsError> {
+        let digits = core::str::from_utf8(bytes).map_err(|_| FsError::Corrupt)?;
+        let digits = digits.trim_matches(|c: char| c == '\0' || c == ' ');
+
+        if digits.is_empty() {
+            return Ok(0);
+        }
+
+        u32::from_str_radix(digits, 8).map_err(|_| FsError::Corrupt)
+    };
+
+    let string = |bytes: &[u8]| -> Result<String, FsError> {
+        let len = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+        core::str::from_utf8(&bytes[..len]).map(String::from).map_err(|_| FsError::Corrupt)
+    };
+
+    let mut entries = Vec::new();
+    let mut offset = 0;
+
+    // Ends with zeroed blocks, or just the end of the data
+    while let Some(header) = data.get(offset..offset + BLOCK) {
+        if header.iter().all(|b| *b == 0) {
+            break;
+        }
+
+        let size = octal(&header[124..136])? as usize;
+        let contents = data.get(offset + BLOCK..offset + BLOCK + size).ok_or(FsError::Corrupt)?;
+
+        let prefix = string(&header[345..500])?;
+        let name = string(&header[0..100])?;
+        let path = if prefix.is_empty() { name } else { prefix + "/" + &name };
+
+        let kind = match header[156] {
+            b'0' | b'\0' | b'7' => Kind::File(contents),
+            b'1' => Kind::Link(string(&header[157..257])?),
+            b'5' => Kind::Directory,
+            _ => Kind::Other,
+        };
+
+        entries.push(Entry {
+            path,
+            kind,
+            mode: (octal(&header[100..108])? & 0o7777) as u16,
+            uid: octal(&header[108..116])?,
+            gid: octal(&header[116..124])?,
+        });
+
+        offset += BLOCK + size.next_multiple_of(BLOCK);
+    }
+
+    Ok(entries)
+}
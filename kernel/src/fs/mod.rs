@@ -0,0 +1,382 @@
+// Virtual filesystem.
+// Filesystems hand out vnodes, reference counted handles to files and directories, starting from their root. Paths
+// are resolved through the mount table in `mount`, and files are accessed through `OpenFile`s. Filesystem types
+// register themselves in `FILESYSTEMS` so they can be mounted by name.
+
+use alloc::{string::String, sync::Arc, vec::Vec};
+
+pub use file::{OpenFile, OpenFlags, SeekFrom};
+pub use mount::{is_mount_point, mounts, resolve as lookup, unmount};
+
+use crate::dev::blockdev::DiskError;
+
+pub mod device;
+pub mod ext2;
+pub mod fat;
+pub mod file;
+pub mod initramfs;
+pub mod mount;
+pub mod path;
+pub mod tmpfs;
+
+linkset::declare!(pub FILESYSTEMS: FsTypeEntry);
+
+pub struct FsTypeEntry {
+    pub name: &'static str,
+    /// Mount an instance backed by a partition, by partition ID, or by nothing
+    pub mount: fn(source: Option<usize>) -> Result<Arc<dyn Filesystem>, FsError>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FsError {
+    NotFound,
+    Exists,
+    NotDirectory,
+    IsDirectory,
+    /// Removing a directory that still has entries
+    NotEmpty,
+    InvalidPath,
+    NameTooLong,
+    InvalidArgument,
+    /// The file wasn't opened for that
+    AccessDenied,
+    ReadOnly,
+    NoSpace,
+    /// Something is mounted there, or beneath it
+    Busy,
+    /// Links and renames can't cross filesystems
+    CrossDevice,
+    /// The filesystem doesn't support the operation
+    Unsupported,
+    /// No filesystem type with that name, or the source doesn't hold one
+    UnknownFs,
+    /// The on-disk structures don't make sense
+    Corrupt,
+    Disk(DiskError),
+}
+
+impl From<DiskError> for FsError {
+    fn from(err: DiskError) -> Self {
+        Self::Disk(err)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VnodeKind {
+    File,
+    Directory,
+    Symlink,
+    Device,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Metadata {
+    pub kind: VnodeKind,
+    /// Unique within the filesystem
+    pub ino: u64,
+    /// In bytes
+    pub size: u64,
+    /// Unix permission bits
+    pub mode: u16,
+    pub uid: u32,
+    pub gid: u32,
+    /// Number of directory entries pointing at it
+    pub links: u32,
+    /// Unix seconds
+    pub atime: u64,
+    pub mtime: u64,
+    pub ctime: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DirEntry {
+    pub name: String,
+    pub ino: u64,
+    pub kind: VnodeKind,
+}
+
+/// A file or directory of a mounted filesystem. Operations a vnode doesn't support fail with a fitting error
+pub trait Vnode: Send + Sync {
+    fn metadata(&self) -> Result<Metadata, FsError>;
+
+    /// Read from `offset`, returns the number of bytes read, short only at the end of the file
+    fn read(&self, _offset: u64, _buf: &mut [u8]) -> Result<usize, FsError> {
+        Err(FsError::IsDirectory)
+    }
+
+    /// Write at `offset`, growing the file if needed, returns the number of bytes written
+    fn write(&self, _offset: u64, _data: &[u8]) -> Result<usize, FsError> {
+        Err(FsError::IsDirectory)
+    }
+
+    /// Set the size, new bytes read as zero
+    fn truncate(&self, _size: u64) -> Result<(), FsError> {
+        Err(FsError::IsDirectory)
+    }
+
+    fn lookup(&self, _name: &str) -> Result<Arc<dyn Vnode>, FsError> {
+        Err(FsError::NotDirectory)
+    }
+
+    /// The entry at `index`, `None` past the last one. `.` and `..` aren't included
+    fn readdir(&self, _index: usize) -> Result<Option<DirEntry>, FsError> {
+        Err(FsError::NotDirectory)
+    }
+
+    /// Create an empty file or directory in this directory
+    fn create(&self, _name: &str, _kind: VnodeKind) -> Result<Arc<dyn Vnode>, FsError> {
+        Err(FsError::NotDirectory)
+    }
+
+    /// Remove an entry of this directory, directories must be empty
+    fn unlink(&self, _name: &str) -> Result<(), FsError> {
+        Err(FsError::NotDirectory)
+    }
+
+    /// Add an entry for `target`, a vnode of the same filesystem, to this directory
+    fn link(&self, _name: &str, _target: &Arc<dyn Vnode>) -> Result<(), FsError> {
+        Err(FsError::Unsupported)
+    }
+
+    /// Move the entry `from` of this directory to `to` in `dir`, a directory of the same filesystem, replacing
+    /// any file there
+    fn rename(&self, _from: &str, _dir: &Arc<dyn Vnode>, _to: &str) -> Result<(), FsError> {
+        Err(FsError::Unsupported)
+    }
+
+    fn set_mode(&self, _mode: u16) -> Result<(), FsError> {
+        Err(FsError::Unsupported)
+    }
+
+    fn set_owner(&self, _uid: u32, _gid: u32) -> Result<(), FsError> {
+        Err(FsError::Unsupported)
+    }
+
+    /// Write back anything of this vnode that's only in memory
+    fn sync(&self) -> Result<(), FsError> {
+        Ok(())
+    }
+}
+
+/// A mounted instance of a filesystem
+pub trait Filesystem: Send + Sync {
+    /// The filesystem type's name
+    fn name(&self) -> &'static str;
+
+    fn root(&self) -> Arc<dyn Vnode>;
+
+    /// Write back everything that's only in memory
+    fn sync(&self) -> Result<(), FsError> {
+        Ok(())
+    }
+}
+
+/// Mount a tmpfs as the root, and unpack the initramfs into it
+pub fn init() {
+    if let Err(err) = mount("tmpfs", None, "/") {
+        panic!("Failed to mount the root tmpfs: {:?}", err);
+    }
+
+    initramfs::load();
+}
+
+/// Mount the first FAT partition at `/boot` and the first ext2 partition at `/mnt`, once disks have been found
+pub fn mount_disks() {
+    let parts: Vec<usize> = crate::dev::blockdev::PARTS.lock().keys().copied().collect();
+    let mut boot = false;
+    let mut data = false;
+
+    for part in parts {
+        let (fstype, path, mounted) = if !boot && fat::probe(part) {
+            ("fat", "/boot", &mut boot)
+        } else if !data && ext2::probe(part) {
+            ("ext2", "/mnt", &mut data)
+        } else {
+            continue;
+        };
+
+        match create_dir_all(path).and_then(|_| mount(fstype, Some(part), path)) {
+            Ok(()) => *mounted = true,
+            Err(err) => crate::println!("VFS: failed to mount partition {}: {:?}", part, err),
+        }
+    }
+
+    if !boot {
+        crate::println!("VFS: no boot partition");
+    }
+}
+
+/// The current time for timestamps, 0 before an RTC sets the time
+pub fn now() -> u64 {
+    crate::time::wall::unix_secs().unwrap_or(0)
+}
+
+/// Mount a filesystem of type `fstype`, backed by partition `source` if it needs one, at `path`
+pub fn mount(fstype: &str, source: Option<usize>, path: &str) -> Result<(), FsError> {
+    for entry in &FILESYSTEMS {
+        if entry.name == fstype {
+            return mount::mount(path, (entry.mount)(source)?);
+        }
+    }
+
+    Err(FsError::UnknownFs)
+}
+
+pub fn open(path: &str, flags: OpenFlags) -> Result<Arc<OpenFile>, FsError> {
+    let vnode = match lookup(path) {
+        Ok(_) if flags.contains(OpenFlags::CREATE | OpenFlags::EXCLUSIVE) => return Err(FsError::Exists),
+        Ok(vnode) => vnode,
+        Err(FsError::NotFound) if flags.contains(OpenFlags::CREATE) => create(path, VnodeKind::File)?,
+        Err(err) => return Err(err),
+    };
+
+    let kind = vnode.metadata()?.kind;
+
+    if kind != VnodeKind::Directory && flags.contains(OpenFlags::DIRECTORY) {
+        return Err(FsError::NotDirectory);
+    }
+
+    if kind == VnodeKind::Directory && flags.intersects(OpenFlags::WRITE | OpenFlags::TRUNCATE) {
+        return Err(FsError::IsDirectory);
+    }
+
+    if flags.contains(OpenFlags::TRUNCATE) {
+        vnode.truncate(0)?;
+    }
+
+    Ok(Arc::new(OpenFile::new(vnode, flags)))
+}
+
+/// Create an empty file or directory at `path`, its parent must exist
+pub fn create(path: &str, kind: VnodeKind) -> Result<Arc<dyn Vnode>, FsError> {
+    let (parent, name) = path::split_last(path)?;
+
+    mount::resolve_components(&parent)?.create(name, kind)
+}
+
+pub fn mkdir(path: &str) -> Result<(), FsError> {
+    create(path, VnodeKind::Directory).map(|_| ())
+}
+
+/// Create a directory and any missing parents, succeeds if it already exists
+pub fn create_dir_all(path: &str) -> Result<(), FsError> {
+    let components = path::components(path)?;
+
+    for depth in 1..=components.len() {
+        match mkdir(&path::join(&components[..depth])) {
+            Ok(()) | Err(FsError::Exists) => {},
+            Err(err) => return Err(err),
+        }
+    }
+
+    match metadata(path)?.kind {
+        VnodeKind::Directory => Ok(()),
+        _ => Err(FsError::NotDirectory),
+    }
+}
+
+/// Remove a file, or an empty directory
+pub fn unlink(path: &str) -> Result<(), FsError> {
+    if mount::covers_mount(path)? {
+        return Err(FsError::Busy);
+    }
+
+    let (parent, name) = path::split_last(path)?;
+
+    mount::resolve_components(&parent)?.unlink(name)
+}
+
+/// Add a hard link at `path` to the file at `target`
+pub fn link(target: &str, path: &str) -> Result<(), FsError> {
+    let (parent, name) = path::split_last(path)?;
+
+    if !same_mount(&path::components(target)?, &parent) {
+        return Err(FsError::CrossDevice);
+    }
+
+    let target = lookup(target)?;
+
+    mount::resolve_components(&parent)?.link(name, &target)
+}
+
+pub fn rename(from: &str, to: &str) -> Result<(), FsError> {
+    // Moving a directory would carry anything mounted beneath it along, out from under the mount table
+    if mount::covers_mount(from)? || mount::covers_mount(to)? {
+        return Err(FsError::Busy);
+    }
+
+    let (from_parent, from_name) = path::split_last(from)?;
+    let (to_parent, to_name) = path::split_last(to)?;
+
+    // A directory can't be moved inside itself, it would be cut off from the root
+    let (from_path, to_path) = (path::components(from)?, path::components(to)?);
+
+    if to_path.len() > from_path.len() && to_path.starts_with(&from_path) {
+        return Err(FsError::InvalidArgument);
+    }
+
+    if !same_mount(&from_parent, &to_parent) {
+        return Err(FsError::CrossDevice);
+    }
+
+    let dir = mount::resolve_components(&to_parent)?;
+
+    mount::resolve_components(&from_parent)?.rename(from_name, &dir, to_name)
+}
+
+/// Whether two paths are in the same mounted filesystem
+fn same_mount(a: &[&str], b: &[&str]) -> bool {
+    match (mount::find_mount(a), mount::find_mount(b)) {
+        (Some((a_depth, _)), Some((b_depth, _))) => a_depth == b_depth && a[..a_depth] == b[..b_depth],
+        _ => false,
+    }
+}
+
+pub fn metadata(path: &str) -> Result<Metadata, FsError> {
+    lookup(path)?.metadata()
+}
+
+/// Every entry of the directory at `path`
+pub fn read_dir(path: &str) -> Result<Vec<DirEntry>, FsError> {
+    let dir = lookup(path)?;
+    let mut entries = Vec::new();
+
+    while let Some(entry) = dir.readdir(entries.len())? {
+        entries.push(entry);
+    }
+
+    Ok(entries)
+}
+
+/// The whole contents of the file at `path`
+pub fn read(path: &str) -> Result<Vec<u8>, FsError> {
+    open(path, OpenFlags::READ)?.read_to_end()
+}
+
+/// Replace the contents of the file at `path` with `data`, creating it if needed
+pub fn write(path: &str, data: &[u8]) -> Result<(), FsError> {
+    let file = open(path, OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::TRUNCATE)?;
+    let mut written = 0;
+
+    while written < data.len() {
+        match file.write(&data[written..])? {
+            0 => return Err(FsError::NoSpace),
+            len => written += len,
+        }
+    }
+
+    Ok(())
+}
+
+/// Write back every mounted filesystem
+pub fn sync() -> Result<(), FsError> {
+    let mut result = Ok(());
+
+    for fs in mount::filesystems() {
+        if let Err(err) = fs.sync() {
+            result = Err(err);
+        }
+    }
+
+    result
+}
@@ -0,0 +1,99 @@
+// The mount table.
+// Filesystems are mounted at normalized paths. Resolving a path starts at the root of the mount with the longest
+// matching prefix, then looks up the rest of the components one at a time.
+
+use alloc::{collections::BTreeMap, string::String, sync::Arc, vec::Vec};
+use spin::Mutex;
+
+use super::{path, Filesystem, FsError, Vnode, VnodeKind};
+
+/// Key: mount point
+static MOUNTS: Mutex<BTreeMap<String, Arc<dyn Filesystem>>> = Mutex::new(BTreeMap::new());
+
+/// Mount `fs` at `path`, which must be an existing directory unless it's the root
+pub fn mount(path: &str, fs: Arc<dyn Filesystem>) -> Result<(), FsError> {
+    let path = path::normalize(path)?;
+
+    if path != "/" && resolve(&path)?.metadata()?.kind != VnodeKind::Directory {
+        return Err(FsError::NotDirectory);
+    }
+
+    let mut mounts = MOUNTS.lock();
+
+    if mounts.contains_key(&path) {
+        return Err(FsError::Busy);
+    }
+
+    crate::println!("VFS: mounted {} at {}", fs.name(), path);
+    mounts.insert(path, fs);
+
+    Ok(())
+}
+
+/// Whether `other` is a mount point strictly beneath `path`, both normalized
+fn is_beneath(other: &str, path: &str) -> bool {
+    let prefix = if path == "/" { String::from("/") } else { String::from(path) + "/" };
+
+    other != path && other.starts_with(&prefix)
+}
+
+/// Sync and remove the filesystem mounted at `path`, fails if anything is mounted beneath it
+pub fn unmount(path: &str) -> Result<(), FsError> {
+    let path = path::normalize(path)?;
+    let mut mounts = MOUNTS.lock();
+
+    if mounts.keys().any(|other| is_beneath(other, &path)) {
+        return Err(FsError::Busy);
+    }
+
+    let fs = mounts.remove(&path).ok_or(FsError::NotFound)?;
+    drop(mounts);
+
+    fs.sync()
+}
+
+/// Whether something is mounted at `path`
+pub fn is_mount_point(path: &str) -> Result<bool, FsError> {
+    Ok(MOUNTS.lock().contains_key(&path::normalize(path)?))
+}
+
+/// Whether something is mounted at `path` or anywhere beneath it
+pub fn covers_mount(path: &str) -> Result<bool, FsError> {
+    let path = path::normalize(path)?;
+
+    Ok(MOUNTS.lock().keys().any(|other| *other == path || is_beneath(other, &path)))
+}
+
+/// Every mount point with the name of the filesystem type mounted there
+pub fn mounts() -> Vec<(String, &'static str)> {
+    MOUNTS.lock().iter().map(|(path, fs)| (path.clone(), fs.name())).collect()
+}
+
+/// Every mounted filesystem
+pub(super) fn filesystems() -> Vec<Arc<dyn Filesystem>> {
+    MOUNTS.lock().values().cloned().collect()
+}
+
+/// The mount covering `components`, with how many of the components lead to its mount point
+pub(super) fn find_mount(components: &[&str]) -> Option<(usize, Arc<dyn Filesystem>)> {
+    let mounts = MOUNTS.lock();
+
+    (0..=components.len())
+        .rev()
+        .find_map(|depth| mounts.get(&path::join(&components[..depth])).map(|fs| (depth, fs.clone())))
+}
+
+pub fn resolve(path: &str) -> Result<Arc<dyn Vnode>, FsError> {
+    resolve_components(&path::components(path)?)
+}
+
+pub(super) fn resolve_components(components: &[&str]) -> Result<Arc<dyn Vnode>, FsError> {
+    let (depth, fs) = find_mount(components).ok_or(FsError::NotFound)?;
+    let mut vnode = fs.root();
+
+    for name in &components[depth..] {
+        vnode = vnode.lookup(name)?;
+    }
+
+    Ok(vnode)
+}
@@ -0,0 +1,75 @@
+// Path handling.
+// Paths are absolute and resolved lexically: `.` is dropped and `..` removes the component before it, stopping at
+// the root, before any vnode is looked at.
+
+use alloc::{string::String, vec::Vec};
+
+use super::FsError;
+
+/// Longest name a single component can have
+pub const NAME_MAX: usize = 255;
+
+/// Check `name` can be used as a single component
+pub fn check_name(name: &str) -> Result<(), FsError> {
+    if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\0']) {
+        return Err(FsError::InvalidPath);
+    }
+
+    if name.len() > NAME_MAX {
+        return Err(FsError::NameTooLong);
+    }
+
+    Ok(())
+}
+
+/// Split an absolute path into its components, with `.` and `..` resolved
+pub fn components(path: &str) -> Result<Vec<&str>, FsError> {
+    if !path.starts_with('/') {
+        return Err(FsError::InvalidPath);
+    }
+
+    let mut components = Vec::new();
+
+    for component in path.split('/') {
+        match component {
+            "" | "." => {},
+            ".." => {
+                components.pop();
+            },
+            name => {
+                check_name(name)?;
+                components.push(name);
+            },
+        }
+    }
+
+    Ok(components)
+}
+
+/// Build a path from its components
+pub fn join(components: &[&str]) -> String {
+    if components.is_empty() {
+        return String::from("/");
+    }
+
+    let mut path = String::new();
+
+    for component in components {
+        path.push('/');
+        path.push_str(component);
+    }
+
+    path
+}
+
+pub fn normalize(path: &str) -> Result<String, FsError> {
+    Ok(join(&components(path)?))
+}
+
+/// Split a path into the components of its parent and its last component, the root has neither
+pub fn split_last(path: &str) -> Result<(Vec<&str>, &str), FsError> {
+    let mut components = components(path)?;
+    let name = components.pop().ok_or(FsError::InvalidPath)?;
+
+    Ok((components, name))
+}
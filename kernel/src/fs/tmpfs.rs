@@ -0,0 +1,390 @@
+// RAM backed filesystem.
+// Files are vectors and directories are maps of names to vnodes, nothing is ever written anywhere. Vnodes are
+// found by inode number for links and renames, which the VFS only allows within one filesystem.
+
+use alloc::{collections::BTreeMap, string::String, sync::{Arc, Weak}, vec::Vec};
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+
+use super::{DirEntry, Filesystem, FsError, Metadata, Vnode, VnodeKind};
+
+linkset::entry!(crate::fs::FILESYSTEMS, crate::fs::FsTypeEntry, crate::fs::FsTypeEntry {
+    name: "tmpfs",
+    mount,
+});
+
+fn mount(_source: Option<usize>) -> Result<Arc<dyn Filesystem>, FsError> {
+    Ok(TmpFs::new())
+}
+
+pub struct TmpFs {
+    root: Arc<TmpNode>,
+    next_ino: AtomicU64,
+    /// Every vnode still linked somewhere or in use, by inode number
+    nodes: Mutex<BTreeMap<u64, Weak<TmpNode>>>,
+}
+
+enum Contents {
+    File(Vec<u8>),
+    Directory(BTreeMap<String, Arc<TmpNode>>),
+}
+
+struct Inner {
+    contents: Contents,
+    mode: u16,
+    uid: u32,
+    gid: u32,
+    links: u32,
+    atime: u64,
+    mtime: u64,
+    ctime: u64,
+}
+
+pub struct TmpNode {
+    fs: Weak<TmpFs>,
+    ino: u64,
+    inner: Mutex<Inner>,
+}
+
+impl TmpFs {
+    pub fn new() -> Arc<Self> {
+        Arc::new_cyclic(|this: &Weak<TmpFs>| {
+            let root = TmpNode::new(this.clone(), 1, Contents::Directory(BTreeMap::new()), 0o755);
+            let nodes = BTreeMap::from([(1, Arc::downgrade(&root))]);
+
+            Self {
+                root,
+                next_ino: AtomicU64::new(2),
+                nodes: Mutex::new(nodes),
+            }
+        })
+    }
+
+    /// The vnode behind a vnode of this filesystem
+    fn find(&self, vnode: &Arc<dyn Vnode>) -> Result<Arc<TmpNode>, FsError> {
+        let ino = vnode.metadata()?.ino;
+
+        self.nodes.lock().get(&ino).and_then(Weak::upgrade).ok_or(FsError::CrossDevice)
+    }
+}
+
+impl Filesystem for TmpFs {
+    fn name(&self) -> &'static str {
+        "tmpfs"
+    }
+
+    fn root(&self) -> Arc<dyn Vnode> {
+        self.root.clone()
+    }
+}
+
+impl TmpNode {
+    fn new(fs: Weak<TmpFs>, ino: u64, contents: Contents, mode: u16) -> Arc<Self> {
+        let now = super::now();
+
+        Arc::new(Self {
+            fs,
+            ino,
+            inner: Mutex::new(Inner {
+                contents,
+                mode,
+                uid: 0,
+                gid: 0,
+                links: 1,
+                atime: now,
+                mtime: now,
+                ctime: now,
+            }),
+        })
+    }
+
+    fn fs(&self) -> Arc<TmpFs> {
+        self.fs.upgrade().unwrap()
+    }
+
+    fn kind(inner: &Inner) -> VnodeKind {
+        match inner.contents {
+            Contents::File(_) => VnodeKind::File,
+            Contents::Directory(_) => VnodeKind::Directory,
+        }
+    }
+}
+
+/// The entries of a directory
+fn entries(inner: &mut Inner) -> Result<&mut BTreeMap<String, Arc<TmpNode>>, FsError> {
+    match &mut inner.contents {
+        Contents::Directory(entries) => Ok(entries),
+        Contents::File(_) => Err(FsError::NotDirectory),
+    }
+}
+
+/// Fails if `node` is a directory with entries
+fn check_removable(node: &TmpNode) -> Result<(), FsError> {
+    match &node.inner.lock().contents {
+        Contents::Directory(entries) if !entries.is_empty() => Err(FsError::NotEmpty),
+        _ => Ok(()),
+    }
+}
+
+/// Whether `target` is `node` or somewhere beneath it. Nodes don't know their parents, so this searches down
+fn is_within(node: &Arc<TmpNode>, target: &Arc<TmpNode>) -> bool {
+    if Arc::ptr_eq(node, target) {
+        return true;
+    }
+
+    // Collect the children first so only one directory is locked at a time
+    let children: Vec<Arc<TmpNode>> = match &node.inner.lock().contents {
+        Contents::Directory(entries) => entries.values().cloned().collect(),
+        Contents::File(_) => return false,
+    };
+
+    children.iter().any(|child| is_within(child, target))
+}
+
+fn drop_link(node: &TmpNode) {
+    let mut inner = node.inner.lock();
+    inner.links = inner.links.saturating_sub(1);
+    inner.ctime = super::now();
+}
+
+impl Vnode for TmpNode {
+    fn metadata(&self) -> Result<Metadata, FsError> {
+        let inner = self.inner.lock();
+
+        let size = match &inner.contents {
+            Contents::File(data) => data.len() as u64,
+            Contents::Directory(entries) => entries.len() as u64,
+        };
+
+        Ok(Metadata {
+            kind: Self::kind(&inner),
+            ino: self.ino,
+            size,
+            mode: inner.mode,
+            uid: inner.uid,
+            gid: inner.gid,
+            links: inner.links,
+            atime: inner.atime,
+            mtime: inner.mtime,
+            ctime: inner.ctime,
+        })
+    }
+
+    fn read(&self, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        let inner = self.inner.lock();
+
+        let Contents::File(data) = &inner.contents else {
+            return Err(FsError::IsDirectory);
+        };
+
+        let start = (offset as usize).min(data.len());
+        let len = buf.len().min(data.len() - start);
+
+        buf[..len].copy_from_slice(&data[start..start + len]);
+
+        Ok(len)
+    }
+
+    fn write(&self, offset: u64, buf: &[u8]) -> Result<usize, FsError> {
+        let mut inner = self.inner.lock();
+
+        let Contents::File(data) = &mut inner.contents else {
+            return Err(FsError::IsDirectory);
+        };
+
+        let start = usize::try_from(offset).map_err(|_| FsError::NoSpace)?;
+        let end = start.checked_add(buf.len()).ok_or(FsError::NoSpace)?;
+
+        if end > data.len() {
+            data.resize(end, 0);
+        }
+
+        data[start..end].copy_from_slice(buf);
+        inner.mtime = super::now();
+
+        Ok(buf.len())
+    }
+
+    fn truncate(&self, size: u64) -> Result<(), FsError> {
+        let mut inner = self.inner.lock();
+
+        let Contents::File(data) = &mut inner.contents else {
+            return Err(FsError::IsDirectory);
+        };
+
+        data.resize(usize::try_from(size).map_err(|_| FsError::NoSpace)?, 0);
+        inner.mtime = super::now();
+
+        Ok(())
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Vnode>, FsError> {
+        let mut inner = self.inner.lock();
+
+        match entries(&mut inner)?.get(name) {
+            Some(node) => Ok(node.clone()),
+            None => Err(FsError::NotFound),
+        }
+    }
+
+    fn readdir(&self, index: usize) -> Result<Option<DirEntry>, FsError> {
+        let mut inner = self.inner.lock();
+
+        Ok(entries(&mut inner)?.iter().nth(index).map(|(name, node)| DirEntry {
+            name: name.clone(),
+            ino: node.ino,
+            kind: TmpNode::kind(&node.inner.lock()),
+        }))
+    }
+
+    fn create(&self, name: &str, kind: VnodeKind) -> Result<Arc<dyn Vnode>, FsError> {
+        let (contents, mode) = match kind {
+            VnodeKind::File => (Contents::File(Vec::new()), 0o644),
+            VnodeKind::Directory => (Contents::Directory(BTreeMap::new()), 0o755),
+            _ => return Err(FsError::Unsupported),
+        };
+
+        let fs = self.fs();
+        let mut inner = self.inner.lock();
+        let entries = entries(&mut inner)?;
+
+        if entries.contains_key(name) {
+            return Err(FsError::Exists);
+        }
+
+        let ino = fs.next_ino.fetch_add(1, Ordering::Relaxed);
+        let node = TmpNode::new(self.fs.clone(), ino, contents, mode);
+
+        entries.insert(String::from(name), node.clone());
+        inner.mtime = super::now();
+
+        let mut nodes = fs.nodes.lock();
+        nodes.retain(|_, node| node.strong_count() != 0);
+        nodes.insert(ino, Arc::downgrade(&node));
+
+        Ok(node)
+    }
+
+    fn unlink(&self, name: &str) -> Result<(), FsError> {
+        let mut inner = self.inner.lock();
+        let entries = entries(&mut inner)?;
+
+        check_removable(entries.get(name).ok_or(FsError::NotFound)?)?;
+
+        let node = entries.remove(name).unwrap();
+        inner.mtime = super::now();
+        drop(inner);
+
+        drop_link(&node);
+
+        Ok(())
+    }
+
+    fn link(&self, name: &str, target: &Arc<dyn Vnode>) -> Result<(), FsError> {
+        let target = self.fs().find(target)?;
+
+        if target.metadata()?.kind == VnodeKind::Directory {
+            return Err(FsError::IsDirectory);
+        }
+
+        let mut inner = self.inner.lock();
+        let entries = entries(&mut inner)?;
+
+        if entries.contains_key(name) {
+            return Err(FsError::Exists);
+        }
+
+        entries.insert(String::from(name), target.clone());
+        inner.mtime = super::now();
+        drop(inner);
+
+        let mut target_inner = target.inner.lock();
+        target_inner.links += 1;
+        target_inner.ctime = super::now();
+
+        Ok(())
+    }
+
+    fn rename(&self, from: &str, dir: &Arc<dyn Vnode>, to: &str) -> Result<(), FsError> {
+        let dir = self.fs().find(dir)?;
+
+        // Take the entry out first, so only one directory is locked at a time
+        let node = {
+            let node = entries(&mut self.inner.lock())?.get(from).ok_or(FsError::NotFound)?.clone();
+
+            // A directory can't go inside itself or anything beneath it
+            if is_within(&node, &dir) {
+                return Err(FsError::InvalidArgument);
+            }
+
+            let mut inner = self.inner.lock();
+            let entries = entries(&mut inner)?;
+
+            // It may have been replaced while unlocked
+            match entries.get(from) {
+                Some(current) if Arc::ptr_eq(current, &node) => {},
+                _ => return Err(FsError::NotFound),
+            }
+
+            entries.remove(from);
+            inner.mtime = super::now();
+            node
+        };
+
+        let mut dir_inner = dir.inner.lock();
+
+        let result = entries(&mut dir_inner).and_then(|entries| {
+            if let Some(existing) = entries.get(to) {
+                // Directories only replace empty directories, and files only files
+                let existing_dir = TmpNode::kind(&existing.inner.lock()) == VnodeKind::Directory;
+                let node_dir = TmpNode::kind(&node.inner.lock()) == VnodeKind::Directory;
+
+                match (node_dir, existing_dir) {
+                    (true, false) => return Err(FsError::NotDirectory),
+                    (false, true) => return Err(FsError::IsDirectory),
+                    _ => check_removable(existing)?,
+                }
+            }
+
+            Ok(entries.insert(String::from(to), node.clone()))
+        });
+
+        match result {
+            Ok(replaced) => {
+                dir_inner.mtime = super::now();
+                drop(dir_inner);
+
+                if let Some(replaced) = replaced {
+                    drop_link(&replaced);
+                }
+
+                Ok(())
+            },
+            Err(err) => {
+                drop(dir_inner);
+
+                // Put it back where it was
+                entries(&mut self.inner.lock())?.insert(String::from(from), node);
+
+                Err(err)
+            },
+        }
+    }
+
+    fn set_mode(&self, mode: u16) -> Result<(), FsError> {
+        let mut inner = self.inner.lock();
+        inner.mode = mode & 0o7777;
+        inner.ctime = super::now();
+
+        Ok(())
+    }
+
+    fn set_owner(&self, uid: u32, gid: u32) -> Result<(), FsError> {
+        let mut inner = self.inner.lock();
+        inner.uid = uid;
+        inner.gid = gid;
+        inner.ctime = super::now();
+
+        Ok(())
+    }
+}
@@ -28,11 +28,14 @@ pub mod scheduler;
 pub mod acpi;
 pub mod object;
 mod utils;
-mod cpu;
+pub mod cpu;
+pub mod time;
+pub mod fs;
 
 pub static FBREQ: limine::FramebufferRequest = limine::FramebufferRequest::new();
 static KERN_FILE: limine::KernelFileRequest = limine::KernelFileRequest::new();
 pub static RSDP: limine::RsdpRequest = limine::RsdpRequest::new();
+pub static MODULES: limine::ModuleRequest = limine::ModuleRequest::new();
 static HHDM: limine::HhdmRequest = limine::HhdmRequest::new();
 pub static MODE: limine::PagingModeRequest = limine::PagingModeRequest::new(
     limine::PagingMode::Sv57, 
@@ -114,7 +117,10 @@ impl log::Log for Logger {
     }
 
     fn log(&self, record: &log::Record) {
-        println!("[{}]{}: {}", record.level(), record.target(), record.args());
+        match time::wall::now() {
+            Some(now) => println!("{} [{}]{}: {}", now, record.level(), record.target(), record.args()),
+            None => println!("[{}]{}: {}", record.level(), record.target(), record.args()),
+        }
     }
 }
 
@@ -44,10 +44,18 @@ extern "C" fn kinit() -> ! {
     gent_kern::arch::init();
 
     gent_kern::parse_kern_file();
+    gent_kern::cpu::init_bsp();
     gent_kern::arch::trap::init_traps();
     println!("Traps initialized");
 
+    gent_kern::dev::intc::init();
+    println!("Interrupt controllers initialized");
+
     gent_kern::scheduler::init_scheduler();
+    gent_kern::dev::irq::init();
+
+    gent_kern::cpu::start_aps();
+    check_cross_hart_calls();
 
     gent_kern::dev::window::init();
 
@@ -66,6 +74,12 @@ extern "C" fn kinit() -> ! {
     gent_kern::dev::blockdev::init();
     println!("Block device initialized");
 
+    gent_kern::fs::init();
+    println!("Root filesystem initialized");
+
+    gent_kern::dev::pci::init();
+    println!("PCI initialized");
+
     let host = alloc::sync::Arc::new(gent_kern::acpi::Host);
 
     lai::init(host);
@@ -94,6 +108,11 @@ extern "C" fn kinit() -> ! {
         }
     }
 
+    gent_kern::dev::pci::start();
+
+    gent_kern::fs::mount_disks();
+    println!("Disks mounted");
+
     fn print_nodes(tabs: usize, node: lai::Node) {
         print!("{}-└{} {:?}", "  ".repeat(tabs), node.name(), node.object().typ());
     
@@ -278,8 +297,7 @@ extern "C" fn kinit() -> ! {
     gent_kern::scheduler::spawn_kernel_thread(gent_kern::dev::window::display_thread, 4);
     gent_kern::scheduler::spawn_kernel_thread(draw, 4);
 
-    let timer = gent_kern::arch::timer::get_timer();
-    gent_kern::arch::timer::set_timer(timer);
+    gent_kern::scheduler::yield_now();
     slow_loop();
 }
 
@@ -343,6 +361,23 @@ fn panic(info: &core::panic::PanicInfo) -> ! {
     }
 }
 
+static CALLS: core::sync::atomic::AtomicUsize = core::sync::atomic::AtomicUsize::new(0);
+
+/// Run a function on every hart, to show IPIs reach all of them
+fn check_cross_hart_calls() {
+    fn count_call(_: usize) {
+        CALLS.fetch_add(1, core::sync::atomic::Ordering::Relaxed);
+    }
+
+    gent_kern::arch::global::ipi::call_on_all(count_call, 0);
+
+    let harts = gent_kern::cpu::online_harts().len();
+    let calls = CALLS.load(core::sync::atomic::Ordering::Relaxed);
+
+    assert_eq!(calls, harts, "Cross-hart call reached {} of {} harts", calls, harts);
+    println!("Cross-hart call delivered to {} harts", calls);
+}
+
 fn slow_loop() -> ! {
     loop {
         gent_kern::arch::utils::slow();
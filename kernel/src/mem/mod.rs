@@ -29,4 +29,27 @@ impl PhysicalAddress {
     }
 }
 
-pub type VirtualAddress = crate::arch::mem::VirtualAddress;
\ No newline at end of file
+pub type VirtualAddress = crate::arch::mem::VirtualAddress;
+
+/// Map device registers at `phys` as uncached IO memory, returns the virtual address of `phys`
+pub fn map_mmio(phys: usize, size: usize) -> usize {
+    let base = phys & !0xfff;
+    let size = (phys - base + size).div_ceil(0x1000) * 0x1000;
+
+    let virt = VIRT.alloc(size, vmem::AllocStrategy::NextFit).unwrap();
+    let mut root = crate::arch::paging::get_root_table();
+
+    for offset in (0..size).step_by(0x1000) {
+        unsafe {
+            root.map_typed(
+                VirtualAddress::new(virt + offset),
+                PhysicalAddress::new(base + offset),
+                crate::arch::paging::PagePermissions::K_WRITE,
+                crate::arch::paging::PageSize::Kilopage,
+                crate::arch::paging::MemoryType::Io,
+            ).unwrap();
+        }
+    }
+
+    virt + (phys - base)
+}
\ No newline at end of file
@@ -13,7 +13,29 @@ pub static KERN_SWAP: Mutex<BTreeMap<VirtualAddress, (usize, usize)>> = Mutex::n
 
 pub static SWAP_PARTS: Mutex<Vec<usize>> = Mutex::new(Vec::new());
 
+/// Use partition `id` for swap. Partitions of GeNT's swap type are added when they're registered, anything else
+/// has to be configured explicitly
+pub fn add_partition(id: usize) {
+    let mut parts = SWAP_PARTS.lock();
+
+    if !parts.contains(&id) {
+        parts.push(id);
+    }
+}
+
 pub static SWAP_LOC: vmem::Vmem = vmem::Vmem::new(alloc::borrow::Cow::Borrowed("SWAP_LOCATIONS"), 4096, None);
 
 pub fn init_swap() {
-}
\ No newline at end of file
+}
+
+/// Allocate physical memory, evicting from the buffer cache if memory is short. Not for heap imports or page tables,
+/// eviction allocates from the heap and may map memory
+pub fn alloc_frame(size: usize) -> usize {
+    if let Ok(phys) = super::PHYS.alloc(size, vmem::AllocStrategy::NextFit) {
+        return phys;
+    }
+
+    crate::dev::blockdev::cache::shrink(size);
+
+    super::PHYS.alloc(size, vmem::AllocStrategy::NextFit).expect("Out of physical memory")
+}
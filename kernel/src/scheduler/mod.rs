@@ -1,12 +1,18 @@
 use alloc::{collections::BTreeMap, sync::Arc};
+use core::sync::atomic::{AtomicBool, Ordering};
 use crossbeam_queue::SegQueue;
 use spin::Mutex;
 
 use crate::println;
 
 pub fn exit_kthread() {
-    use crate::arch::timer;
-    timer::set_timer(timer::get_timer());
+    yield_now();
+}
+
+/// Give up the rest of the current time slice
+pub fn yield_now() {
+    set_need_resched();
+    crate::time::kick();
 }
 
 pub fn init_scheduler() {
@@ -43,7 +49,7 @@ pub fn spawn_kernel_thread(f: fn() -> !, priority: i8) {
     let mut root_table = unsafe {crate::arch::paging::RootTable::from_ptr(process.page_table_addr)};
 
     for i in (0..0x10_0000).step_by(0x1000) {
-        let phys_addr = crate::mem::PHYS.alloc(0x1000, vmem::AllocStrategy::NextFit).unwrap();
+        let phys_addr = crate::mem::swap::alloc_frame(0x1000);
         let vaddr = crate::mem::VirtualAddress::new(stack_addr + i);
         let paddr = crate::mem::PhysicalAddress::new(phys_addr);
 
@@ -68,13 +74,58 @@ pub fn spawn_kernel_thread(f: fn() -> !, priority: i8) {
         mode: crate::arch::Mode::Supervisor,
         trapframe,
         priority,
-        priority_mod: 0
+        priority_mod: 0,
+        exception_handler: None,
+        fpu: Default::default(),
     });
+    wake_idle();
     println!("Added kernel thread to queue");
 }
 
 pub fn next(frame: &mut crate::arch::trap::TrapFrame) {
+    switch(frame, true)
+}
+
+/// Destroy the current thread, and switch to the next one
+pub fn kill_current(frame: &mut crate::arch::trap::TrapFrame) {
+    if let Some(thread) = CUR_TASK.lock().as_ref() {
+        println!("Killing thread {} of process {}", thread.thread_id, thread.process.proc_id);
+    }
+
+    switch(frame, false)
+}
+
+/// User address of the current thread's exception dispatcher
+pub fn exception_handler() -> Option<usize> {
+    CUR_TASK.lock().as_ref().and_then(|thread| thread.exception_handler)
+}
+
+pub fn set_exception_handler(handler: Option<usize>) {
+    if let Some(thread) = CUR_TASK.lock().as_mut() {
+        thread.exception_handler = handler;
+    }
+}
+
+/// Print information about the current thread, for crash reports
+pub fn dump_current() {
+    // A fault while the lock is held must still produce a report
+    match CUR_TASK.try_lock() {
+        Some(task) => match task.as_ref() {
+            Some(thread) => println!(
+                "Thread: {} Process: {} Priority: {}",
+                thread.thread_id,
+                thread.process.proc_id,
+                thread.priority
+            ),
+            None => println!("Thread: none"),
+        },
+        None => println!("Thread: unknown, scheduler locked"),
+    }
+}
+
+fn switch(frame: &mut crate::arch::trap::TrapFrame, requeue: bool) {
     let mut cur_task = CUR_TASK.lock();
+    let park = PARK.lock().take();
 
     let mut next_thread = QUEUE.pop().unwrap_or_else(|| {
         Thread {
@@ -84,14 +135,18 @@ pub fn next(frame: &mut crate::arch::trap::TrapFrame) {
             trapframe: crate::arch::trap::TrapFrame::with_pc(crate::arch::idle_thread as usize),
             priority: 1,
             priority_mod: 0,
+            exception_handler: None,
+            fpu: Default::default(),
         }
     });
     
     let timeshare = next_thread.time_share();
+    let idle = next_thread.is_idle();
 
     if let Some(cur_thread) = cur_task.as_mut() {
-        // Store the current frame
+        // Store the current frame, and any FP or vector state it dirtied
         cur_thread.trapframe = *frame;
+        crate::arch::fpu::save(&mut cur_thread.fpu);
 
         // Load new frame, mode, and page table
         next_thread.load_thread(frame);
@@ -102,9 +157,17 @@ pub fn next(frame: &mut crate::arch::trap::TrapFrame) {
         // rename old thread for clarity
         let old_thread = next_thread;
 
-        // If old thread wasnt an idle thread, put it into the scheduler
-        if old_thread.process.proc_id != 0 || old_thread.thread_id != 0 {
-            QUEUE.push(old_thread);
+        // If old thread wasnt an idle thread, or a killed thread, put it into the scheduler, unless it's going to
+        // sleep on an event
+        if requeue && !old_thread.is_idle() {
+            match park {
+                Some(event) => event.park(old_thread),
+                None => QUEUE.push(old_thread),
+            }
+        } else if !requeue && old_thread.thread_id != 0 {
+            unsafe {
+                old_thread.process.thread_ids.free(old_thread.thread_id, 1);
+            }
         }
     } else {
         // Load new frame, mode, and page table
@@ -114,13 +177,143 @@ pub fn next(frame: &mut crate::arch::trap::TrapFrame) {
         *cur_task = Some(next_thread);
     }
 
-    let timer = crate::arch::timer::get_timer();
-    crate::arch::timer::set_timer(timer + timeshare);
+    // The next thread restores its own FP and vector state when it uses them
+    crate::arch::fpu::disable();
+
+    // The time slice is just another timer, replace the previous thread's
+    let mut slice = SLICE_TIMER.lock();
+
+    if let Some(old) = slice.take() {
+        crate::time::cancel(old);
+    }
+
+    // The idle thread gets no slice, the hart only wakes for its own timers, interrupts, and new work
+    if !idle {
+        *slice = Some(crate::time::add_oneshot(crate::time::ticks_to_ns(timeshare), slice_expired, 0));
+    }
+
+    if let Some(hart) = crate::cpu::hart(crate::cpu::hart_id()) {
+        hart.set_idle(idle);
+    }
+}
+
+/// Interrupt every idle hart so it picks up newly queued threads
+fn wake_idle() {
+    for hart in crate::cpu::online_harts() {
+        if hart.idle() {
+            crate::arch::global::ipi::reschedule(hart.hartid);
+        }
+    }
+}
+
+fn slice_expired(_: usize) {
+    SLICE_TIMER.lock().take();
+    set_need_resched();
+}
+
+/// Handle an illegal instruction that may have been an access to a disabled FP or vector unit.
+/// Returns true if the current thread can retry the instruction
+pub fn fpu_trap(frame: &crate::arch::trap::TrapFrame) -> bool {
+    match CUR_TASK.lock().as_mut() {
+        Some(thread) => crate::arch::fpu::handle_illegal(frame, &mut thread.fpu),
+        None => false,
+    }
+}
+
+/// Whether the caller is a thread that can sleep on an `Event`. Boot code runs before any thread, and isn't
+/// resumed once the hart switches away from it
+pub fn can_sleep() -> bool {
+    crate::arch::trap::enabled()
+        && crate::arch::trap::without_interrupts(|| CUR_TASK.lock().as_ref().is_some_and(|thread| !thread.is_idle()))
+}
+
+/// Something one kernel thread sleeps on until another thread or an interrupt signals it
+pub struct Event {
+    pending: AtomicBool,
+    waiter: Mutex<Option<Thread>>,
+}
+
+impl Event {
+    pub const fn new() -> Self {
+        Self {
+            pending: AtomicBool::new(false),
+            waiter: Mutex::new(None),
+        }
+    }
+
+    /// Sleep until the event is signalled, returns straight away if it was signalled since the last wait
+    pub fn wait(&self) {
+        // The switch only parks the thread on the event, and nothing resumes it except a signal, so the event
+        // outlives its use by the scheduler for as long as this borrow lasts
+        let this: &'static Self = unsafe {&*(self as *const Self)};
+
+        loop {
+            // An interrupt between checking and asking to park would otherwise be able to park us after the signal
+            let parking = crate::arch::trap::without_interrupts(|| {
+                if self.pending.swap(false, Ordering::SeqCst) {
+                    return false;
+                }
+
+                *PARK.lock() = Some(this);
+                true
+            });
+
+            if !parking {
+                return;
+            }
+
+            yield_now();
+        }
+    }
+
+    /// Wake the waiting thread, or let the next wait return straight away
+    pub fn signal(&self) {
+        self.pending.store(true, Ordering::SeqCst);
+
+        let thread = crate::arch::trap::without_interrupts(|| self.waiter.lock().take());
+
+        if let Some(thread) = thread {
+            QUEUE.push(thread);
+            wake_idle();
+        }
+    }
+
+    /// Put a thread that asked to wait to sleep, called from the switch
+    fn park(&self, thread: Thread) {
+        let mut waiter = self.waiter.lock();
+
+        // Signalled after the thread checked, it has to run again to see it
+        if self.pending.load(Ordering::SeqCst) {
+            QUEUE.push(thread);
+        } else {
+            *waiter = Some(thread);
+        }
+    }
+}
+
+/// Run the scheduler once the current interrupt is handled
+pub fn set_need_resched() {
+    NEED_RESCHED.store(true, core::sync::atomic::Ordering::Relaxed);
+}
+
+pub fn take_need_resched() -> bool {
+    NEED_RESCHED.swap(false, core::sync::atomic::Ordering::Relaxed)
 }
 
 #[thread_local]
 static CUR_TASK: Mutex<Option<Thread>> = Mutex::new(None);
 
+#[thread_local]
+/// Timer ending the current thread's time slice
+static SLICE_TIMER: Mutex<Option<crate::time::TimerId>> = Mutex::new(None);
+
+#[thread_local]
+static NEED_RESCHED: core::sync::atomic::AtomicBool = core::sync::atomic::AtomicBool::new(false);
+
+#[thread_local]
+/// Event the current thread is about to sleep on, taken by the next switch
+static PARK: Mutex<Option<&'static Event>> = Mutex::new(None);
+
 static QUEUE: SegQueue<Thread> = SegQueue::new();
 static PROC_LIST: Mutex<BTreeMap<usize, Arc<Proc>>> = Mutex::new(BTreeMap::new());
 
@@ -141,6 +334,8 @@ impl Proc {
             trapframe: crate::arch::trap::TrapFrame::with_pc(pc),
             priority,
             priority_mod: 0,
+            exception_handler: None,
+            fpu: Default::default(),
         };
 
         let mut root_table = unsafe {crate::arch::paging::RootTable::from_ptr(thread.process.page_table_addr)};
@@ -149,7 +344,7 @@ impl Proc {
         let stack_addr = addr_space.alloc(0x10_0000, vmem::AllocStrategy::NextFit).unwrap();
 
         for i in (0..0x10_0000).step_by(0x1000) {
-            let phys_addr = crate::mem::PHYS.alloc(0x1000, vmem::AllocStrategy::NextFit).unwrap();
+            let phys_addr = crate::mem::swap::alloc_frame(0x1000);
             let vaddr = crate::mem::VirtualAddress::new(stack_addr + i);
             let paddr = crate::mem::PhysicalAddress::new(phys_addr);
 
@@ -171,6 +366,7 @@ impl Proc {
         thread.trapframe.set_stack(stack_addr);
 
         QUEUE.push(thread);
+        wake_idle();
     }
 }
 
@@ -181,9 +377,17 @@ struct Thread {
     trapframe: crate::arch::trap::TrapFrame,
     priority: i8,
     priority_mod: i8,
+    /// User address that exceptions get delivered to, the thread is killed on faults without one
+    exception_handler: Option<usize>,
+    /// Floating point and vector registers, saved lazily
+    fpu: crate::arch::fpu::FpuContext,
 }
 
 impl Thread {
+    fn is_idle(&self) -> bool {
+        self.process.proc_id == 0 && self.thread_id == 0
+    }
+
     fn load_thread(&self, trapframe: &mut crate::arch::trap::TrapFrame) {
         *trapframe = self.trapframe;
         let proc_lock = PROC_LIST.lock();
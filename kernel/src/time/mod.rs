@@ -0,0 +1,171 @@
+// Kernel timekeeping.
+// Each hart has one comparator, this multiplexes it between any number of one-shot and periodic timers
+// kept in a per-hart heap ordered by deadline. Timers fire on the hart that added them, from the timer interrupt.
+
+use alloc::collections::BinaryHeap;
+use core::cmp::{Ordering as CmpOrdering, Reverse};
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+
+use crate::arch::timer;
+
+pub mod wall;
+
+pub const NS_PER_SEC: u128 = 1_000_000_000;
+
+#[thread_local]
+/// Pending timers on the current hart, the earliest deadline is at the top
+static TIMERS: Mutex<BinaryHeap<Reverse<Timer>>> = Mutex::new(BinaryHeap::new());
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Identifies a pending timer, for cancellation
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TimerId(u64);
+
+struct Timer {
+    /// Deadline in timer ticks
+    deadline: u128,
+    /// Ticks between each firing, for periodic timers
+    period: Option<u128>,
+    id: TimerId,
+    callback: fn(usize),
+    arg: usize,
+}
+
+impl PartialEq for Timer {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == CmpOrdering::Equal
+    }
+}
+
+impl Eq for Timer {}
+
+impl PartialOrd for Timer {
+    fn partial_cmp(&self, other: &Self) -> Option<CmpOrdering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Timer {
+    fn cmp(&self, other: &Self) -> CmpOrdering {
+        // Timers with the same deadline fire in the order they were added
+        self.deadline.cmp(&other.deadline).then(self.id.0.cmp(&other.id.0))
+    }
+}
+
+/// Before the RHCT gives us the frequency no time has passed as far as anything can tell
+pub fn ticks_to_ns(ticks: u128) -> u128 {
+    (ticks * NS_PER_SEC).checked_div(timer::freq()).unwrap_or(0)
+}
+
+pub fn ns_to_ticks(ns: u128) -> u128 {
+    // Round up, so a timer never fires before its deadline
+    (ns * timer::freq()).div_ceil(NS_PER_SEC)
+}
+
+/// Nanoseconds since the timer started counting, never goes backwards
+pub fn monotonic_ns() -> u128 {
+    ticks_to_ns(timer::get_timer())
+}
+
+/// Run `callback` once, `ns` nanoseconds from now
+pub fn add_oneshot(ns: u128, callback: fn(usize), arg: usize) -> TimerId {
+    add(timer::get_timer() + ns_to_ticks(ns), None, callback, arg)
+}
+
+/// Run `callback` every `ns` nanoseconds, starting `ns` nanoseconds from now
+pub fn add_periodic(ns: u128, callback: fn(usize), arg: usize) -> TimerId {
+    let period = ns_to_ticks(ns).max(1);
+
+    add(timer::get_timer() + period, Some(period), callback, arg)
+}
+
+/// Run `callback` once at the absolute monotonic time `deadline_ns`
+pub fn add_at(deadline_ns: u128, callback: fn(usize), arg: usize) -> TimerId {
+    add(ns_to_ticks(deadline_ns), None, callback, arg)
+}
+
+fn add(deadline: u128, period: Option<u128>, callback: fn(usize), arg: usize) -> TimerId {
+    let id = TimerId(NEXT_ID.fetch_add(1, Ordering::Relaxed));
+
+    with_timers(|timers| {
+        timers.push(Reverse(Timer { deadline, period, id, callback, arg }));
+        program(timers);
+    });
+
+    id
+}
+
+/// Remove a timer from the current hart, returns false if it already fired or was never added here
+pub fn cancel(id: TimerId) -> bool {
+    with_timers(|timers| {
+        let len = timers.len();
+        timers.retain(|Reverse(timer)| timer.id != id);
+
+        let removed = timers.len() != len;
+
+        if removed {
+            program(timers);
+        }
+
+        removed
+    })
+}
+
+/// Spin until `ns` nanoseconds have passed
+pub fn busy_wait(ns: u128) {
+    let deadline = monotonic_ns() + ns;
+
+    while monotonic_ns() < deadline {
+        core::hint::spin_loop();
+    }
+}
+
+/// Take a timer interrupt as soon as possible, even if no timer is due
+pub fn kick() {
+    timer::set_timer(timer::get_timer());
+}
+
+/// Run every expired timer on the current hart, and program the comparator for the next one.
+/// Called from the timer interrupt
+pub fn handle_timer() {
+    loop {
+        let now = timer::get_timer();
+
+        let mut expired = {
+            let mut timers = TIMERS.lock();
+
+            match timers.peek() {
+                Some(Reverse(timer)) if timer.deadline <= now => timers.pop().unwrap().0,
+                _ => {
+                    program(&mut timers);
+                    return;
+                }
+            }
+        };
+
+        // Run without the lock, so callbacks can add and cancel timers
+        (expired.callback)(expired.arg);
+
+        if let Some(period) = expired.period {
+            // Skip periods that were missed entirely, rather than firing in a burst
+            expired.deadline += period * ((now - expired.deadline) / period + 1);
+            TIMERS.lock().push(Reverse(expired));
+        }
+    }
+}
+
+/// Set the comparator to the earliest deadline
+fn program(timers: &mut BinaryHeap<Reverse<Timer>>) {
+    match timers.peek() {
+        Some(Reverse(timer)) => timer::set_timer(timer.deadline),
+        None => timer::set_timer(u128::MAX),
+    }
+}
+
+/// Lock the timer heap with timer interrupts masked, so the interrupt can't deadlock on it.
+/// Callers that already had interrupts off keep them off
+fn with_timers<R>(f: impl FnOnce(&mut BinaryHeap<Reverse<Timer>>) -> R) -> R {
+    crate::arch::trap::without_interrupts(|| f(&mut TIMERS.lock()))
+}
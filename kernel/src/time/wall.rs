@@ -0,0 +1,115 @@
+// Wall clock time, an RTC reading pinned against the monotonic clock.
+// The RTC is only read when it's registered, after that the monotonic clock keeps time.
+
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+/// Unix time in nanoseconds at monotonic time 0
+static EPOCH_OFFSET: AtomicU64 = AtomicU64::new(0);
+static VALID: AtomicBool = AtomicBool::new(false);
+
+/// Set the current wall clock time, in nanoseconds since the Unix epoch
+pub fn set(unix_ns: u128) {
+    let offset = unix_ns.saturating_sub(super::monotonic_ns());
+
+    EPOCH_OFFSET.store(offset as u64, Ordering::Relaxed);
+    VALID.store(true, Ordering::Release);
+}
+
+/// Nanoseconds since the Unix epoch, if an RTC has set the time
+pub fn unix_ns() -> Option<u128> {
+    if !VALID.load(Ordering::Acquire) {
+        return None;
+    }
+
+    Some(EPOCH_OFFSET.load(Ordering::Relaxed) as u128 + super::monotonic_ns())
+}
+
+/// Seconds since the Unix epoch, if an RTC has set the time
+pub fn unix_secs() -> Option<u64> {
+    unix_ns().map(|ns| (ns / super::NS_PER_SEC) as u64)
+}
+
+/// The current date and time in UTC
+pub fn now() -> Option<DateTime> {
+    unix_ns().map(DateTime::from_unix_ns)
+}
+
+/// A UTC calendar date and time
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: i64,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+    pub nanosecond: u32,
+}
+
+impl DateTime {
+    pub fn from_unix_ns(ns: u128) -> Self {
+        let secs = (ns / super::NS_PER_SEC) as i64;
+        let nanosecond = (ns % super::NS_PER_SEC) as u32;
+
+        let days = secs.div_euclid(86400);
+        let rem = secs.rem_euclid(86400);
+
+        let (year, month, day) = civil_from_days(days);
+
+        Self {
+            year,
+            month,
+            day,
+            hour: (rem / 3600) as u8,
+            minute: (rem / 60 % 60) as u8,
+            second: (rem % 60) as u8,
+            nanosecond,
+        }
+    }
+
+    /// Seconds since the Unix epoch
+    pub fn unix_secs(&self) -> i64 {
+        let days = days_from_civil(self.year, self.month, self.day);
+
+        days * 86400 + self.hour as i64 * 3600 + self.minute as i64 * 60 + self.second as i64
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+// Conversions between days since the epoch and the proleptic Gregorian calendar.
+// Years are counted from March, so the leap day falls at the end of each 400 year era.
+
+fn civil_from_days(days: i64) -> (i64, u8, u8) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u8;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u8;
+    let year = yoe + era * 400 + (month <= 2) as i64;
+
+    (year, month, day)
+}
+
+fn days_from_civil(year: i64, month: u8, day: u8) -> i64 {
+    let year = year - (month <= 2) as i64;
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let month = month as i64;
+    let doy = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+
+    era * 146097 + doe - 719468
+}
@@ -34,10 +34,14 @@ cp -v kernel/target/riscv64imac-unknown-none-elf/release/gent-kern config/limine
 mkdir -p .root/EFI/BOOT
 cp -v limine/BOOTRISCV64.EFI .root/EFI/BOOT/
 
+# Everything in ./initramfs is unpacked into the root filesystem at boot
+mkdir -p initramfs
+tar -cf .root/initramfs.tar --format=ustar -C initramfs .
+
 qemu-system-riscv64-acpi \
     -machine virt,aclint=on,acpi=on,aia=aplic-imsic \
     -cpu rv64,svpbmt=on \
-    -smp 1 \
+    -smp 4 \
     -m 4G \
     -pflash CODE.fd \
     -device nvme,serial=deadbeff,drive=disk1 \